@@ -5,12 +5,29 @@ sudo mount -o loop ./build/app.img ./mnt
 sudo mkdir -p ./mnt/EFI/BOOT
 sudo cp ../target/x86_64-unknown-uefi/release/bootloader.efi ./mnt/EFI/BOOT/BOOTX64.EFI
 sudo cp ../kernel.elf ./mnt/kernel.elf
+rm -rf ./build/initrd && mkdir -p ./build/initrd/bin
+# examples/にはhello-<hash>のような中間ファイルもあるので、examplesの名前のものだけを入れる
+(cd ../userland && cargo build --examples)
+for example in ../userland/examples/*.rs; do
+    cp "../target/x86_64-user/debug/examples/$(basename "$example" .rs)" ./build/initrd/bin/
+done
+(cd ./build/initrd && find . | cpio -o -H newc > ../initrd.cpio)
+sudo cp ./build/initrd.cpio ./mnt/initrd
+if [ -f ../cmdline ]; then sudo cp ../cmdline ./mnt/cmdline; fi
 sleep 1
 sudo umount ./mnt
+# ext2のドライバを試すディスク。ホストのmke2fsで作り、起動すると/mnt/sdXにマウントされる
+rm -rf ./build/ext2 && mkdir -p ./build/ext2
+echo "created by mke2fs on the host" > ./build/ext2/hello.txt
+rm -f ./build/ext2.img
+qemu-img create -f raw ./build/ext2.img 32M
+mke2fs -q -F -t ext2 -b 4096 -d ./build/ext2 ./build/ext2.img
 cd .. && qemu-system-x86_64 \
          -bios OVMF_CODE.fd \
          -device ahci,id=ahci \
          -device ide-cd,drive=disk,bus=ahci.0 \
          -drive id=disk,if=none,format=raw,file=bootloader/build/app.img \
+         -device ide-hd,drive=ext2,bus=ahci.1 \
+         -drive id=ext2,if=none,format=raw,file=bootloader/build/ext2.img \
          -device nec-usb-xhci,id=xhci \
          -device usb-mouse 
\ No newline at end of file
@@ -27,6 +27,7 @@ use uefi::table::Runtime;
 use log::{trace, info};
 use goblin::elf::{Elf, program_header};
 use uefi::table::boot::MemoryDescriptor;
+use common::boot_info::{BootInfo, CommandLine, Initrd};
 use common::frame_buffer;
 use common::memory_map;
 
@@ -46,10 +47,18 @@ fn efi_main(handle: Handle, mut st: SystemTable<Boot>) -> Status {
     let elf_entry = load_kernel(handle, bs);
 
     trace!("entry_point_addr = 0x{:x}", elf_entry);
-    let entry_point: extern "sysv64" fn(&frame_buffer::FrameBufferConfig, &memory_map::MemoryMap, u64) = unsafe {
+    let entry_point: extern "sysv64" fn(&BootInfo) = unsafe {
         mem::transmute(elf_entry)
     };
 
+    trace!("load initrd");
+    let initrd = load_initrd(handle, bs);
+    info!("initrd: 0x{:x} ({} bytes)", initrd.base, initrd.size);
+
+    trace!("load cmdline");
+    let cmdline = load_cmdline(handle, bs);
+    info!("cmdline: {}", cmdline.as_str());
+
     info!("get_frame_buffer_config");
     let frame_buffer = get_frame_buffer(st.boot_services());
 
@@ -59,7 +68,14 @@ fn efi_main(handle: Handle, mut st: SystemTable<Boot>) -> Status {
     trace!("exit_boot_serveces");
     let (_st, memory_map) = exit_boot_services(handle, st);
 
-    entry_point(&frame_buffer, &memory_map, rsdp);
+    let boot_info = BootInfo {
+        frame_buffer_config: frame_buffer,
+        memory_map,
+        rsdp,
+        initrd,
+        cmdline,
+    };
+    entry_point(&boot_info);
 
     trace!("you cannot see this message");
 
@@ -89,12 +105,12 @@ fn open_directory(_image: Handle, boot_services: &BootServices) -> Directory {
     }
 }
 
-fn open_file(root_dir: &mut Directory) -> RegularFile {
+fn open_file(root_dir: &mut Directory, name: &str) -> Option<RegularFile> {
     let mut cstr_buf = [0u16; 32];
-    let cstr_file_name = CStr16::from_str_with_buf("kernel.elf", &mut cstr_buf).unwrap();
-    let file_handle = root_dir.open(cstr_file_name, FileMode::Read, FileAttribute::empty()).unwrap();
+    let cstr_file_name = CStr16::from_str_with_buf(name, &mut cstr_buf).unwrap();
+    let file_handle = root_dir.open(cstr_file_name, FileMode::Read, FileAttribute::empty()).ok()?;
     unsafe {
-        RegularFile::new(file_handle)
+        Some(RegularFile::new(file_handle))
     }
 }
 
@@ -130,7 +146,7 @@ fn load_elf(boot_services: &BootServices, buf: Vec<u8>) -> usize {
 
 fn load_kernel(_image: Handle, boot_services: &BootServices) -> usize {
     let mut root_dir = open_directory(_image, &boot_services);
-    let mut file = open_file(&mut root_dir);
+    let mut file = open_file(&mut root_dir, "kernel.elf").expect("Could not find kernel.elf");
     let file_size = file.get_boxed_info::<FileInfo>().unwrap().file_size() as usize;
     let mut buf = vec![0; file_size];
     let _ = file.read(&mut buf);
@@ -138,6 +154,41 @@ fn load_kernel(_image: Handle, boot_services: &BootServices) -> usize {
     load_elf(&boot_services, buf)
 }
 
+// initrdはLOADER_DATAのページに置く。カーネルのメモリマップには含まれないので、展開するまで上書きされない
+fn load_initrd(_image: Handle, boot_services: &BootServices) -> Initrd {
+    let mut root_dir = open_directory(_image, &boot_services);
+    let Some(mut file) = open_file(&mut root_dir, "initrd") else {
+        info!("initrd not found");
+        return Initrd::EMPTY;
+    };
+    let file_size = file.get_boxed_info::<FileInfo>().unwrap().file_size() as usize;
+    if file_size == 0 {
+        return Initrd::EMPTY;
+    }
+    let pages = (file_size + UEFI_PAGE_SIZE - 1) / UEFI_PAGE_SIZE;
+    let base = boot_services.allocate_pages(boot::AllocateType::AnyPages, MemoryType::LOADER_DATA, pages).unwrap();
+    let buf = unsafe {
+        slice::from_raw_parts_mut(base as *mut u8, file_size)
+    };
+    let _ = file.read(buf);
+    file.close();
+    Initrd {
+        base,
+        size: file_size as u64,
+    }
+}
+
+fn load_cmdline(_image: Handle, boot_services: &BootServices) -> CommandLine {
+    let mut root_dir = open_directory(_image, &boot_services);
+    let Some(mut file) = open_file(&mut root_dir, "cmdline") else {
+        return CommandLine::EMPTY;
+    };
+    let mut buf = [0; CommandLine::CAPACITY];
+    let len = file.read(&mut buf).unwrap_or(0);
+    file.close();
+    CommandLine::new(&buf[..len])
+}
+
 fn get_frame_buffer(boot_services: &BootServices) -> frame_buffer::FrameBufferConfig {
     let gop = boot_services.locate_protocol::<GraphicsOutput>().unwrap();
     let gop = unsafe {&mut *gop.get()};
@@ -9,6 +9,14 @@ if [ $? -ne 0 ]; then
     exit 1
 fi
 
+cd ../userland
+cargo build --examples
+
+if [ $? -ne 0 ]; then
+    echo "Userland build failed."
+    exit 1
+fi
+
 cd ../bootloader
 ./run.sh
 
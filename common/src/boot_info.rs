@@ -0,0 +1,72 @@
+use crate::frame_buffer::FrameBufferConfig;
+use crate::memory_map::MemoryMap;
+
+// bootloaderからカーネルに渡す情報
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BootInfo {
+    pub frame_buffer_config: FrameBufferConfig,
+    pub memory_map: MemoryMap,
+    pub rsdp: u64,
+    pub initrd: Initrd,
+    pub cmdline: CommandLine,
+}
+
+// ESPのinitrdを読み込んだLOADER_DATAのページ。無ければsizeが0
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Initrd {
+    pub base: u64,
+    pub size: u64,
+}
+
+impl Initrd {
+    pub const EMPTY: Self = Self { base: 0, size: 0 };
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // Safety: bootloaderが渡したままで、まだ解放していないこと
+    pub unsafe fn as_slice(&self) -> &'static [u8] {
+        if self.is_empty() {
+            return &[];
+        }
+        core::slice::from_raw_parts(self.base as *const u8, self.size as usize)
+    }
+}
+
+// ESPのcmdlineファイルの中身。"log=info,net=debug"のような空白区切りのオプションを書く
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CommandLine {
+    pub bytes: [u8; CommandLine::CAPACITY],
+    pub len: u64,
+}
+
+impl CommandLine {
+    pub const CAPACITY: usize = 256;
+    pub const EMPTY: Self = Self { bytes: [0; Self::CAPACITY], len: 0 };
+
+    // 入りきらない分は捨てる
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut cmdline = Self::EMPTY;
+        let len = bytes.len().min(Self::CAPACITY);
+        cmdline.bytes[..len].copy_from_slice(&bytes[..len]);
+        cmdline.len = len as u64;
+        cmdline
+    }
+
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..(self.len as usize).min(Self::CAPACITY)];
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s.trim(),
+            Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap().trim(),
+        }
+    }
+
+    // "key=value"のvalueを返す
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.as_str().split_whitespace().find_map(|option| option.strip_prefix(key)?.strip_prefix('='))
+    }
+}
@@ -1,4 +1,6 @@
 #![no_std]
 
+pub mod boot_info;
 pub mod frame_buffer;
-pub mod memory_map;
\ No newline at end of file
+pub mod memory_map;
+pub mod syscall;
\ No newline at end of file
@@ -0,0 +1,116 @@
+// System call ABI
+//
+// Issued with the `syscall` instruction:
+//   rax         system call number (SYS_*)
+//   rdi, rsi, rdx, r10, r8, r9
+//               arguments 1-6
+//   rax         return value. Values in -4095..=-1 are a negated errno (E*)
+// rcx and r11 are clobbered by the instruction itself; every other register is preserved.
+//
+// Strings (paths, arguments) are passed as a pointer and a length and need not be
+// NUL-terminated. Every pointer must lie in the caller's address space; the kernel
+// returns -EFAULT otherwise.
+
+pub const SYS_EXIT: u64 = 0; // exit(code: i32) -> !
+pub const SYS_WRITE: u64 = 1; // write(fd, buf, len) -> written
+pub const SYS_READ: u64 = 2; // read(fd, buf, len) -> read
+pub const SYS_GETPID: u64 = 3; // getpid() -> pid
+pub const SYS_SLEEP: u64 = 4; // sleep(ms) -> 0
+pub const SYS_MMAP: u64 = 5; // mmap(addr_hint, len, prot, flags) -> addr
+pub const SYS_MUNMAP: u64 = 6; // munmap(addr, len) -> 0
+pub const SYS_OPEN: u64 = 7; // open(path, path_len, flags) -> fd
+pub const SYS_CLOSE: u64 = 8; // close(fd) -> 0
+pub const SYS_SPAWN: u64 = 9; // spawn(path, path_len, args, args_len) -> exit code
+pub const SYS_SEEK: u64 = 10; // seek(fd, offset: i64, whence) -> new offset
+pub const SYS_READDIR: u64 = 11; // readdir(fd, *mut Dirent) -> 1, or 0 at the end
+pub const SYS_STAT: u64 = 12; // stat(path, path_len, *mut Stat) -> 0
+pub const SYS_FSTAT: u64 = 13; // fstat(fd, *mut Stat) -> 0
+pub const SYS_CHDIR: u64 = 14; // chdir(path, path_len) -> 0
+pub const SYS_MKDIR: u64 = 15; // mkdir(path, path_len) -> 0
+pub const SYS_UNLINK: u64 = 16; // unlink(path, path_len) -> 0. Also removes empty directories
+pub const SYS_RENAME: u64 = 17; // rename(old, old_len, new, new_len) -> 0
+
+pub const SYSCALL_COUNT: usize = 18;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+// mmap prot
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+// mmap flags. Only private anonymous mappings exist for now
+pub const MAP_PRIVATE: u64 = 1 << 1;
+pub const MAP_ANONYMOUS: u64 = 1 << 5;
+
+// open flags
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_ACCMODE: u64 = 3;
+pub const O_CREAT: u64 = 1 << 6;
+pub const O_TRUNC: u64 = 1 << 9;
+pub const O_APPEND: u64 = 1 << 10;
+
+// seek whence
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// Stat::kind and Dirent::kind
+pub const DT_CHR: u32 = 2;
+pub const DT_DIR: u32 = 4;
+pub const DT_BLK: u32 = 6;
+pub const DT_REG: u32 = 8;
+pub const DT_LNK: u32 = 10;
+
+pub const NAME_MAX: usize = 255;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub kind: u32,
+    pub nlink: u32,
+    pub size: u64,
+    pub blocks: u64, // 512-byte units actually allocated
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Dirent {
+    pub ino: u64,
+    pub kind: u32,
+    pub name_len: u32,
+    pub name: [u8; NAME_MAX + 1], // NUL-terminated as well
+}
+
+pub const EPERM: u64 = 1;
+pub const ENOENT: u64 = 2;
+pub const EINTR: u64 = 4;
+pub const EIO: u64 = 5;
+pub const ENOEXEC: u64 = 8;
+pub const EBADF: u64 = 9;
+pub const ENOMEM: u64 = 12;
+pub const EACCES: u64 = 13;
+pub const EFAULT: u64 = 14;
+pub const EBUSY: u64 = 16;
+pub const EEXIST: u64 = 17;
+pub const EXDEV: u64 = 18;
+pub const ENOTDIR: u64 = 20;
+pub const EISDIR: u64 = 21;
+pub const EINVAL: u64 = 22;
+pub const EMFILE: u64 = 24;
+pub const ENOSPC: u64 = 28;
+pub const ESPIPE: u64 = 29;
+pub const EROFS: u64 = 30;
+pub const ENAMETOOLONG: u64 = 36;
+pub const ENOSYS: u64 = 38;
+pub const ENOTEMPTY: u64 = 39;
+pub const ELOOP: u64 = 40;
+pub const EOPNOTSUPP: u64 = 95;
+
+pub const MAX_ERRNO: u64 = 4095;
@@ -6,9 +6,13 @@
 bits 64
 
 extern kernel_stack_main
+extern syscall_handler
+extern syscall_kernel_stack
 section .bss align=16
 kernel_main_stack:
     resb 1024 * 1024
+syscall_user_rsp:
+    resq 1
 
 section .text
 global IoOut32  ; IoOut32(addr: u16, data: u32) -> ()
@@ -24,6 +28,101 @@ IoIn32:
     in eax, dx
     ret
 
+global IoOut8   ; IoOut8(addr: u16, data: u8) -> ()
+IoOut8:
+    mov dx, di      ; dx = addr
+    mov al, sil     ; al = data
+    out dx, al
+    ret
+
+global IoIn8    ; IoIn8(addr: u16) -> u8
+IoIn8:
+    mov dx, di      ; dx = addr
+    in al, dx
+    ret
+
+global SwitchStack  ; SwitchStack(stack_top: u64, entry: fn(arg: u64) -> !, arg: u64) -> !
+SwitchStack:
+    mov rsp, rdi
+    xor rbp, rbp
+    mov rdi, rdx
+    call rsi
+.fin:
+    hlt
+    jmp .fin
+
+global CallApp  ; CallApp(argc: u64, argv: u64, ss: u16, rip: u64, rsp: u64, os_stack_ptr: *mut u64) -> i32
+CallApp:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [r9], rsp   ; ExitAppで戻ってくるためにOSのスタックポインタを保存する
+
+    push rdx        ; SS
+    push r8         ; RSP
+    push 0x002      ; RFLAGS
+    add rdx, 8
+    push rdx        ; CS (user codeはuser dataの次)
+    push rcx        ; RIP
+    iretq
+    ; アプリケーションが終了してもここには来ない
+
+global ExitApp  ; ExitApp(rsp: u64, ret_val: i32) -> !
+ExitApp:
+    mov rsp, rdi
+    mov eax, esi
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+
+    ret             ; CallAppの呼び出し元に戻る
+
+global SyscallEntry  ; SYSCALLで呼ばれる。rcx = user RIP, r11 = user RFLAGS
+SyscallEntry:
+    ; SFMASKでIFを落としているので、カーネルのスタックに移るまで割り込まれない
+    mov [rel syscall_user_rsp], rsp
+    mov rsp, [rel syscall_kernel_stack]
+
+    push qword [rel syscall_user_rsp]
+    push rcx
+    push r11
+    push rbp
+    mov rbp, rsp
+
+    ; syscall::SyscallFrame
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+
+    mov rdi, rsp
+    sub rsp, 8      ; 16バイト境界に揃える
+    call syscall_handler
+    add rsp, 16     ; 境界合わせとシステムコール番号を捨てる
+
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+
+    pop rbp
+    pop r11
+    pop rcx
+    pop rsp
+    o64 sysret
+
 global kernel_main 
 kernel_main:
     mov rsp, kernel_main_stack + 1024 * 1024
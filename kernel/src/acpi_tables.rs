@@ -0,0 +1,127 @@
+// ACPI tables
+//
+// bootloaderから受け取ったRSDPからテーブルを読み、PCIのECAMやAPICの情報を取り出す
+
+use acpi::platform::interrupt::{InterruptModel, Polarity, TriggerMode};
+use acpi::{AcpiTables, PciConfigRegions};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use crate::paging::KernelAcpiHandler;
+
+lazy_static! {
+    static ref ACPI_TABLES: Mutex<Option<AcpiTables<KernelAcpiHandler>>> = Mutex::new(None);
+}
+
+pub fn init(rsdp: u64) {
+    match unsafe { AcpiTables::from_rsdp(KernelAcpiHandler, rsdp as usize) } {
+        Ok(tables) => *ACPI_TABLES.lock() = Some(tables),
+        Err(e) => crate::println!("ACPI: failed to parse tables: {:?}", e),
+    }
+}
+
+pub fn acpi_tables() -> spin::MutexGuard<'static, Option<AcpiTables<KernelAcpiHandler>>> {
+    ACPI_TABLES.lock()
+}
+
+// MCFGにあるセグメント0のECAMの先頭(bus 0, device 0, function 0)の物理アドレス
+pub fn pci_config_base() -> Option<u64> {
+    let tables = acpi_tables();
+    let regions = PciConfigRegions::new(tables.as_ref()?).ok()?;
+    regions.physical_address(0, 0, 0, 0)
+}
+
+pub struct TableInfo {
+    pub signature: String,
+    pub address: u64,
+    pub length: u32,
+}
+
+pub fn revision() -> Option<u8> {
+    Some(acpi_tables().as_ref()?.revision)
+}
+
+// RSDT(XSDT)に載っているテーブルと、DSDT、SSDT
+pub fn tables() -> Vec<TableInfo> {
+    let tables = acpi_tables();
+    let Some(tables) = tables.as_ref() else {
+        return Vec::new();
+    };
+    let mut infos: Vec<TableInfo> = tables
+        .sdts
+        .iter()
+        .map(|(signature, sdt)| TableInfo {
+            signature: signature.to_string(),
+            address: sdt.physical_address as u64,
+            length: sdt.length,
+        })
+        .collect();
+    let aml_tables =
+        tables.dsdt.iter().map(|dsdt| ("DSDT", dsdt)).chain(tables.ssdts.iter().map(|ssdt| ("SSDT", ssdt)));
+    infos.extend(aml_tables.map(|(signature, table)| TableInfo {
+        signature: signature.to_string(),
+        address: table.address as u64,
+        length: table.length,
+    }));
+    infos
+}
+
+pub struct IoApicInfo {
+    pub address: u64,
+    pub gsi_base: u32,
+}
+
+// ISAのIRQがどのGSIに繋がっているか。Noneはバスの既定のまま
+pub struct IrqOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: Option<bool>,
+    pub level_triggered: Option<bool>,
+}
+
+pub struct ApicInfo {
+    pub local_apic_address: u64,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<IrqOverride>,
+    pub legacy_pics: bool,
+}
+
+// MADTにある割り込みコントローラーの情報
+pub fn apic_info() -> Option<ApicInfo> {
+    let tables = acpi_tables();
+    let InterruptModel::Apic(apic) = tables.as_ref()?.platform_info().ok()?.interrupt_model else {
+        return None;
+    };
+    Some(ApicInfo {
+        local_apic_address: apic.local_apic_address,
+        io_apics: apic
+            .io_apics
+            .iter()
+            .map(|io_apic| IoApicInfo {
+                address: io_apic.address as u64,
+                gsi_base: io_apic.global_system_interrupt_base,
+            })
+            .collect(),
+        overrides: apic
+            .interrupt_source_overrides
+            .iter()
+            .map(|o| IrqOverride {
+                irq: o.isa_source,
+                gsi: o.global_system_interrupt,
+                active_low: match o.polarity {
+                    Polarity::SameAsBus => None,
+                    Polarity::ActiveHigh => Some(false),
+                    Polarity::ActiveLow => Some(true),
+                },
+                level_triggered: match o.trigger_mode {
+                    TriggerMode::SameAsBus => None,
+                    TriggerMode::Edge => Some(false),
+                    TriggerMode::Level => Some(true),
+                },
+            })
+            .collect(),
+        legacy_pics: apic.also_has_legacy_pics,
+    })
+}
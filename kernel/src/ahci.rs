@@ -0,0 +1,504 @@
+// AHCI (SATA) driver
+//
+// BAR5のHBAレジスタからポートを初期化し、command listとPRDTを使ってDMAで読み書きする。
+// 割り込みはまだ使わず、コマンドの完了はポーリングで待つ。ATAPI(CD-ROM)は読み込みだけ
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::mutex::Mutex;
+use x86_64::PhysAddr;
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::dma::DmaBuffer;
+use crate::error::OsError;
+use crate::paging;
+use crate::pci::{self, Bar, BusDeviceFunction, PciDevice, PciDeviceDriver, PciDeviceDriverInstance};
+use crate::println;
+use crate::timer;
+
+// class 01 (mass storage)、subclass 06 (SATA)、prog-if 01 (AHCI 1.0)ならどのコントローラでも扱える
+const CLASS_MASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SATA: u8 = 0x06;
+const PROG_IF_AHCI: u8 = 0x01;
+
+// HBAのレジスタ
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_PI: usize = 0x0c;
+const GHC_HBA_RESET: u32 = 1 << 0;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+const CAP_64BIT: u32 = 1 << 31;
+
+// ポートのレジスタ
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_FB: usize = 0x08;
+const PX_IS: usize = 0x10;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const CMD_START: u32 = 1 << 0;
+const CMD_SPIN_UP: u32 = 1 << 1;
+const CMD_POWER_ON: u32 = 1 << 2;
+const CMD_FIS_RECEIVE: u32 = 1 << 4;
+const CMD_FIS_RUNNING: u32 = 1 << 14;
+const CMD_LIST_RUNNING: u32 = 1 << 15;
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+const IS_TASK_FILE_ERROR: u32 = 1 << 30;
+const SSTS_DET_PRESENT: u32 = 3;
+const SSTS_IPM_ACTIVE: u32 = 1;
+
+const SIG_ATA: u32 = 0x0000_0101;
+const SIG_ATAPI: u32 = 0xeb14_0101;
+
+// ATAのコマンド
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_PACKET: u8 = 0xa0;
+const ATA_IDENTIFY_PACKET: u8 = 0xa1;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_IDENTIFY: u8 = 0xec;
+
+// ATAPIで送るSCSIのコマンド
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+// ポートごとのメモリ。1ページにcommand list、受信FIS、command tableを並べる
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x800;
+const PRDT_OFFSET: usize = 0x80;
+const BOUNCE_BUFFER_SIZE: usize = 64 * 1024;
+
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+const LINK_TIMEOUT_MS: u64 = 100;
+
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+pub struct AhciDriver;
+
+impl PciDeviceDriver for AhciDriver {
+    fn supports(&self, device: &PciDevice) -> bool {
+        (device.class, device.subclass, device.prog_if) == (CLASS_MASS_STORAGE, SUBCLASS_SATA, PROG_IF_AHCI)
+    }
+
+    fn attach(&self, bdf: BusDeviceFunction) -> Result<Box<dyn PciDeviceDriverInstance>, OsError> {
+        let pci = pci::pci();
+        let Bar::Memory(abar) = pci.read_bar(bdf, 5)? else {
+            return Err(OsError::NotSupported);
+        };
+        pci.enable_bus_master(bdf)?;
+        let hba = Registers::new(abar)?;
+        reset(hba)?;
+
+        let mut disks = Vec::new();
+        let implemented = hba.read(HBA_PI);
+        for index in (0..32).filter(|i| implemented & (1 << i) != 0) {
+            let registers = Registers(hba.0 + PORT_BASE + index * PORT_SIZE);
+            match AhciDisk::probe(registers) {
+                Ok(Some(disk)) => {
+                    let disk = Arc::new(disk);
+                    block::register(disk.clone());
+                    disks.push(disk);
+                }
+                Ok(None) => {}
+                Err(e) => println!("ahci: port {}: {:?}", index, e),
+            }
+        }
+        Ok(Box::new(AhciController { bdf, _disks: disks }))
+    }
+
+    fn name(&self) -> &str {
+        "ahci"
+    }
+}
+
+pub struct AhciController {
+    bdf: BusDeviceFunction,
+    _disks: Vec<Arc<AhciDisk>>,
+}
+
+impl PciDeviceDriverInstance for AhciController {
+    fn name(&self) -> &str {
+        "ahci"
+    }
+
+    fn bdf(&self) -> BusDeviceFunction {
+        self.bdf
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Registers(usize);
+
+impl Registers {
+    fn new(phys: u64) -> Result<Self, OsError> {
+        let virt = paging::as_virt_addr(PhysAddr::new(phys)).ok_or(OsError::NotSupported)?;
+        Ok(Self(virt.as_u64() as usize))
+    }
+
+    fn read(self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write(self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.0 + offset) as *mut u32, value) }
+    }
+
+    fn wait(self, offset: usize, mask: u32, value: u32) -> Result<(), OsError> {
+        if timer::wait_until(COMMAND_TIMEOUT_MS, || self.read(offset) & mask == value) {
+            Ok(())
+        } else {
+            Err(OsError::Timeout)
+        }
+    }
+}
+
+fn reset(hba: Registers) -> Result<(), OsError> {
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_AHCI_ENABLE);
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_HBA_RESET);
+    hba.wait(HBA_GHC, GHC_HBA_RESET, 0)?;
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_AHCI_ENABLE);
+    if hba.read(HBA_CAP) & CAP_64BIT == 0 {
+        // DMAのバッファを4GiBより下から確保する仕組みはまだない
+        println!("ahci: HBA does not support 64-bit addressing");
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DeviceKind {
+    Ata,
+    Atapi,
+}
+
+#[repr(C)]
+struct CommandHeader {
+    flags: u32, // CFL, ATAPI, Write, PRDTL
+    prd_byte_count: u32,
+    command_table: u64,
+    reserved: [u32; 4],
+}
+
+#[repr(C)]
+struct PrdEntry {
+    data_base: u64,
+    reserved: u32,
+    byte_count: u32, // 転送するバイト数-1
+}
+
+struct Port {
+    registers: Registers,
+    kind: DeviceKind,
+    memory: DmaBuffer,
+    bounce: DmaBuffer,
+}
+
+impl Port {
+    fn stop(&self) -> Result<(), OsError> {
+        let r = self.registers;
+        r.write(PX_CMD, r.read(PX_CMD) & !CMD_START);
+        r.wait(PX_CMD, CMD_LIST_RUNNING, 0)?;
+        r.write(PX_CMD, r.read(PX_CMD) & !CMD_FIS_RECEIVE);
+        r.wait(PX_CMD, CMD_FIS_RUNNING, 0)
+    }
+
+    fn start(&self) -> Result<(), OsError> {
+        let r = self.registers;
+        let base = self.memory.phys_addr().as_u64();
+        r.write(PX_CLB, base as u32);
+        r.write(PX_CLB + 4, (base >> 32) as u32);
+        let fis = base + RECEIVED_FIS_OFFSET as u64;
+        r.write(PX_FB, fis as u32);
+        r.write(PX_FB + 4, (fis >> 32) as u32);
+        r.write(PX_SERR, u32::MAX);
+        r.write(PX_IS, u32::MAX);
+
+        r.write(PX_CMD, r.read(PX_CMD) | CMD_FIS_RECEIVE);
+        r.wait(PX_TFD, TFD_BSY | TFD_DRQ, 0)?;
+        r.write(PX_CMD, r.read(PX_CMD) | CMD_START);
+        Ok(())
+    }
+
+    // スロット0だけを使い、コマンドが終わるまで待つ。データはbounceに置く
+    fn issue(&mut self, fis: [u8; 20], packet: Option<[u8; 12]>, write: bool, len: usize) -> Result<(), BlockError> {
+        let r = self.registers;
+        if r.wait(PX_TFD, TFD_BSY | TFD_DRQ, 0).is_err() {
+            return Err(BlockError::Timeout);
+        }
+
+        let base = self.memory.as_mut_ptr::<u8>();
+        let table_phys = self.memory.phys_addr().as_u64() + COMMAND_TABLE_OFFSET as u64;
+        unsafe {
+            let table = base.add(COMMAND_TABLE_OFFSET);
+            core::ptr::write_bytes(table, 0, PRDT_OFFSET + core::mem::size_of::<PrdEntry>());
+            core::ptr::copy_nonoverlapping(fis.as_ptr(), table, fis.len());
+            if let Some(packet) = packet {
+                core::ptr::copy_nonoverlapping(packet.as_ptr(), table.add(0x40), packet.len());
+            }
+            if len > 0 {
+                write_volatile(
+                    table.add(PRDT_OFFSET) as *mut PrdEntry,
+                    PrdEntry {
+                        data_base: self.bounce.phys_addr().as_u64(),
+                        reserved: 0,
+                        byte_count: (len - 1) as u32,
+                    },
+                );
+            }
+
+            let mut flags = (fis.len() / 4) as u32;
+            if packet.is_some() {
+                flags |= 1 << 5;
+            }
+            if write {
+                flags |= 1 << 6;
+            }
+            if len > 0 {
+                flags |= 1 << 16;
+            }
+            write_volatile(
+                base as *mut CommandHeader,
+                CommandHeader {
+                    flags,
+                    prd_byte_count: 0,
+                    command_table: table_phys,
+                    reserved: [0; 4],
+                },
+            );
+        }
+
+        r.write(PX_IS, u32::MAX);
+        r.write(PX_CI, 1);
+        let mut failed = false;
+        let finished = timer::wait_until(COMMAND_TIMEOUT_MS, || {
+            failed = r.read(PX_IS) & IS_TASK_FILE_ERROR != 0;
+            failed || r.read(PX_CI) & 1 == 0
+        });
+        if !finished {
+            return Err(BlockError::Timeout);
+        }
+        if failed || r.read(PX_TFD) & TFD_ERR != 0 {
+            // エラーを消すにはポートを止めてから動かし直す
+            let _ = self.stop().and_then(|_| self.start());
+            return Err(BlockError::DeviceError);
+        }
+        Ok(())
+    }
+
+    fn identify(&mut self) -> Result<[u16; 256], BlockError> {
+        let command = match self.kind {
+            DeviceKind::Ata => ATA_IDENTIFY,
+            DeviceKind::Atapi => ATA_IDENTIFY_PACKET,
+        };
+        self.issue(ata_fis(command, 0, 0), None, false, 512)?;
+        let mut words = [0u16; 256];
+        for (i, word) in words.iter_mut().enumerate() {
+            let bytes = &self.bounce.as_slice()[i * 2..i * 2 + 2];
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(words)
+    }
+
+    fn packet(&mut self, packet: [u8; 12], len: usize) -> Result<(), BlockError> {
+        let mut fis = ata_fis(ATA_PACKET, 0, 0);
+        fis[3] = 1; // DMA
+        self.issue(fis, Some(packet), false, len)
+    }
+}
+
+// H2D Register FIS
+fn ata_fis(command: u8, lba: u64, count: u16) -> [u8; 20] {
+    let mut fis = [0u8; 20];
+    fis[0] = FIS_TYPE_REG_H2D;
+    fis[1] = 0x80; // Command
+    fis[2] = command;
+    fis[4] = lba as u8;
+    fis[5] = (lba >> 8) as u8;
+    fis[6] = (lba >> 16) as u8;
+    fis[7] = 1 << 6; // LBA mode
+    fis[8] = (lba >> 24) as u8;
+    fis[9] = (lba >> 32) as u8;
+    fis[10] = (lba >> 40) as u8;
+    fis[12] = count as u8;
+    fis[13] = (count >> 8) as u8;
+    fis
+}
+
+// IDENTIFYのモデル名はワードごとにバイトが入れ替わっている
+fn identify_string(words: &[u16]) -> String {
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+    String::from_utf8_lossy(&bytes).trim().into()
+}
+
+pub struct AhciDisk {
+    name: String,
+    kind: DeviceKind,
+    block_size: usize,
+    block_count: u64,
+    port: Mutex<Port>,
+}
+
+impl AhciDisk {
+    // デバイスが繋がっていなければNone
+    fn probe(registers: Registers) -> Result<Option<Self>, OsError> {
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_SPIN_UP | CMD_POWER_ON);
+        // HBAをリセットした後、リンクが上がるまで少しかかる
+        timer::wait_until(LINK_TIMEOUT_MS, || registers.read(PX_SSTS) & 0xf == SSTS_DET_PRESENT);
+        let ssts = registers.read(PX_SSTS);
+        if ssts & 0xf != SSTS_DET_PRESENT || (ssts >> 8) & 0xf != SSTS_IPM_ACTIVE {
+            return Ok(None);
+        }
+        let kind = match registers.read(PX_SIG) {
+            SIG_ATA => DeviceKind::Ata,
+            SIG_ATAPI => DeviceKind::Atapi,
+            _ => return Ok(None),
+        };
+
+        let mut port = Port {
+            registers,
+            kind,
+            memory: DmaBuffer::new(4096)?,
+            bounce: DmaBuffer::new(BOUNCE_BUFFER_SIZE)?,
+        };
+        port.stop()?;
+        port.start()?;
+
+        let identify = port.identify().map_err(|_| OsError::DeviceError)?;
+        let model = identify_string(&identify[27..47]);
+        // メディアが無いか読めない大きさのCD-ROMは、0ブロックのディスクにしておく
+        let (block_size, block_count) = match kind {
+            DeviceKind::Ata => ata_capacity(&identify).ok_or(OsError::DeviceError)?,
+            DeviceKind::Atapi => atapi_capacity(&mut port).unwrap_or((2048, 0)),
+        };
+
+        let prefix = match kind {
+            DeviceKind::Ata => "sd",
+            DeviceKind::Atapi => "sr",
+        };
+        let name = block::disk_name(prefix, NEXT_DISK.fetch_add(1, Ordering::Relaxed));
+        println!("ahci: {}: {}", name, model);
+        Ok(Some(Self {
+            name,
+            kind,
+            block_size,
+            block_count,
+            port: Mutex::new(port),
+        }))
+    }
+}
+
+// ブロックサイズで割って転送の長さを決めるので、0や半端な値は受け付けない
+fn valid_block_size(block_size: usize) -> bool {
+    (512..=BOUNCE_BUFFER_SIZE).contains(&block_size) && block_size.is_power_of_two()
+}
+
+fn ata_capacity(identify: &[u16; 256]) -> Option<(usize, u64)> {
+    let lba48 = identify[83] & (1 << 10) != 0;
+    let block_count = if lba48 {
+        (0..4).map(|i| (identify[100 + i] as u64) << (16 * i)).sum()
+    } else {
+        identify[60] as u64 | (identify[61] as u64) << 16
+    };
+    // word 106が有効で、論理セクタが512バイトより大きいとき
+    let block_size = if identify[106] & 0xc000 == 0x4000 && identify[106] & (1 << 12) != 0 {
+        (identify[117] as usize | (identify[118] as usize) << 16) * 2
+    } else {
+        512
+    };
+    valid_block_size(block_size).then_some((block_size, block_count))
+}
+
+fn atapi_capacity(port: &mut Port) -> Result<(usize, u64), BlockError> {
+    let mut packet = [0u8; 12];
+    packet[0] = SCSI_READ_CAPACITY_10;
+    // リセット直後はUNIT ATTENTIONで失敗することがあるので、何度か試す
+    let mut result = Err(BlockError::DeviceError);
+    for _ in 0..3 {
+        result = port.packet(packet, 8);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result?;
+    let data = port.bounce.as_slice();
+    let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as u64;
+    let block_size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    if !valid_block_size(block_size) {
+        return Err(BlockError::DeviceError);
+    }
+    Ok((block_size, last_lba + 1))
+}
+
+impl BlockDevice for AhciDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_only(&self) -> bool {
+        self.kind == DeviceKind::Atapi
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(lba, buf.len())?;
+        let mut port = self.port.lock();
+        for (i, chunk) in buf.chunks_mut(BOUNCE_BUFFER_SIZE).enumerate() {
+            let lba = lba + (i * BOUNCE_BUFFER_SIZE / self.block_size) as u64;
+            let count = chunk.len() / self.block_size;
+            match self.kind {
+                DeviceKind::Ata => port.issue(ata_fis(ATA_READ_DMA_EXT, lba, count as u16), None, false, chunk.len())?,
+                DeviceKind::Atapi => {
+                    let mut packet = [0u8; 12];
+                    packet[0] = SCSI_READ_10;
+                    packet[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+                    packet[7..9].copy_from_slice(&(count as u16).to_be_bytes());
+                    port.packet(packet, chunk.len())?
+                }
+            }
+            chunk.copy_from_slice(&port.bounce.as_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_request(lba, buf.len())?;
+        let mut port = self.port.lock();
+        for (i, chunk) in buf.chunks(BOUNCE_BUFFER_SIZE).enumerate() {
+            let lba = lba + (i * BOUNCE_BUFFER_SIZE / self.block_size) as u64;
+            let count = chunk.len() / self.block_size;
+            port.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            port.issue(ata_fis(ATA_WRITE_DMA_EXT, lba, count as u16), None, true, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.read_only() {
+            return Ok(());
+        }
+        self.port.lock().issue(ata_fis(ATA_FLUSH_CACHE_EXT, 0, 0), None, false, 0)
+    }
+}
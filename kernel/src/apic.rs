@@ -0,0 +1,190 @@
+// Local APIC and I/O APIC
+//
+// 8259 PICは全てマスクし、デバイスの割り込みはMADTにあるI/O APICからLocal APICへ送る。
+// Local APICのタイマーで一定間隔の割り込みを起こし、hltで待っていても時間が進むようにする
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+use x86_64::PhysAddr;
+
+use crate::acpi_tables::{self, IrqOverride};
+use crate::error::OsError;
+use crate::interrupts::{SPURIOUS_VECTOR, TIMER_VECTOR};
+use crate::{io, paging, println, timer};
+
+// Local APICのレジスタ
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+// I/O APICのレジスタ
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+const PIC1_DATA: u16 = 0x21;
+const PIC2_DATA: u16 = 0xa1;
+
+const TICK_MS: u64 = 10;
+const CALIBRATION_MS: u64 = 10;
+
+#[derive(Clone, Copy)]
+struct Registers(usize);
+
+impl Registers {
+    fn new(phys: u64) -> Result<Self, OsError> {
+        let virt = paging::as_virt_addr(PhysAddr::new(phys)).ok_or(OsError::NotSupported)?;
+        Ok(Self(virt.as_u64() as usize))
+    }
+
+    fn read(self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write(self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.0 + offset) as *mut u32, value) }
+    }
+}
+
+struct IoApic {
+    registers: Registers,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, index: u32) -> u32 {
+        self.registers.write(IOAPIC_REGSEL, index);
+        self.registers.read(IOAPIC_WINDOW)
+    }
+
+    fn write(&self, index: u32, value: u32) {
+        self.registers.write(IOAPIC_REGSEL, index);
+        self.registers.write(IOAPIC_WINDOW, value);
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let index = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // 書き換えている途中で割り込みが来ないよう、先にマスクする
+        self.write(index, REDIRECTION_MASKED as u32);
+        self.write(index + 1, (entry >> 32) as u32);
+        self.write(index, entry as u32);
+    }
+}
+
+static LOCAL_APIC: AtomicUsize = AtomicUsize::new(0);
+static TICKING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+    static ref OVERRIDES: Mutex<Vec<IrqOverride>> = Mutex::new(Vec::new());
+}
+
+pub fn init() {
+    let Some(info) = acpi_tables::apic_info() else {
+        return println!("apic: no MADT, interrupts are not available");
+    };
+    let Ok(local_apic) = Registers::new(info.local_apic_address) else {
+        return println!("apic: local APIC at {:#x} is not mapped", info.local_apic_address);
+    };
+    if info.legacy_pics {
+        unsafe {
+            io::out8(PIC1_DATA, 0xff);
+            io::out8(PIC2_DATA, 0xff);
+        }
+    }
+    LOCAL_APIC.store(local_apic.0, Ordering::Relaxed);
+    local_apic.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+    let mut io_apics = IO_APICS.lock();
+    for io_apic in info.io_apics {
+        let Ok(registers) = Registers::new(io_apic.address) else {
+            continue;
+        };
+        let mut io_apic = IoApic { registers, gsi_base: io_apic.gsi_base, entries: 0 };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+    *OVERRIDES.lock() = info.overrides;
+
+    start_timer(local_apic);
+}
+
+// 分周して数え、TSCで測ったTICK_MSごとに割り込みを起こす
+fn start_timer(local_apic: Registers) {
+    local_apic.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    local_apic.write(LAPIC_LVT_TIMER, LVT_MASKED);
+    local_apic.write(LAPIC_TIMER_INITIAL, u32::MAX);
+    timer::sleep_ms(CALIBRATION_MS);
+    let counts_per_ms = (u32::MAX - local_apic.read(LAPIC_TIMER_CURRENT)) as u64 / CALIBRATION_MS;
+    local_apic.write(LAPIC_TIMER_INITIAL, 0);
+    if counts_per_ms == 0 {
+        return println!("apic: timer is not running");
+    }
+
+    local_apic.write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+    local_apic.write(LAPIC_TIMER_INITIAL, (counts_per_ms * TICK_MS).min(u32::MAX as u64) as u32);
+    TICKING.store(true, Ordering::Relaxed);
+}
+
+fn local_apic() -> Option<Registers> {
+    match LOCAL_APIC.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(Registers(base)),
+    }
+}
+
+// タイマーの割り込みが来ていれば、hltで待っても起こしてもらえる
+pub fn ticking() -> bool {
+    TICKING.load(Ordering::Relaxed)
+}
+
+pub fn id() -> u8 {
+    local_apic().map_or(0, |local_apic| (local_apic.read(LAPIC_ID) >> 24) as u8)
+}
+
+pub fn end_of_interrupt() {
+    if let Some(local_apic) = local_apic() {
+        local_apic.write(LAPIC_EOI, 0);
+    }
+}
+
+// ISAのIRQ番号のirqをvectorへ送る。PCIのINTxはレベルトリガーでアクティブローになる
+pub fn route_irq(irq: u8, vector: u8, pci: bool) -> Result<(), OsError> {
+    let (gsi, active_low, level_triggered) = match OVERRIDES.lock().iter().find(|o| o.irq == irq) {
+        Some(o) => (o.gsi, o.active_low.unwrap_or(pci), o.level_triggered.unwrap_or(pci)),
+        None => (irq as u32, pci, pci),
+    };
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| (io_apic.gsi_base..io_apic.gsi_base + io_apic.entries).contains(&gsi))
+        .ok_or(OsError::DeviceNotFound)?;
+    let mut entry = vector as u64 | (id() as u64) << 56;
+    if active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if level_triggered {
+        entry |= REDIRECTION_LEVEL;
+    }
+    io_apic.set_redirection(gsi, entry);
+    Ok(())
+}
@@ -0,0 +1,181 @@
+// Block devices
+//
+// ディスクのドライバはBlockDeviceを実装してregisterする。/dev/<name>としても開ける。
+// ディスクにパーティションテーブルがあれば、パーティションもそれぞれBlockDeviceとして登録する
+
+pub mod cache;
+pub mod partition;
+pub mod request;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use crate::fs::devfs::{self, Device};
+use crate::fs::{FileType, FsError};
+use crate::println;
+
+pub use cache::BufferCache;
+pub use request::{BlockFuture, BlockRequest};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    OutOfRange,
+    // バッファの長さがブロックサイズの倍数でない
+    UnalignedBuffer,
+    ReadOnly,
+    DeviceError,
+    Timeout,
+    OutOfMemory,
+}
+
+impl From<BlockError> for FsError {
+    fn from(e: BlockError) -> Self {
+        match e {
+            BlockError::OutOfRange | BlockError::UnalignedBuffer => FsError::InvalidArgument,
+            BlockError::ReadOnly => FsError::ReadOnly,
+            BlockError::OutOfMemory => FsError::OutOfMemory,
+            BlockError::DeviceError | BlockError::Timeout => FsError::Io,
+        }
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    // bufの長さはblock_sizeの倍数
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    // 完了を待たずにリクエストを出す。デフォルトではその場で処理して完了済みのものを返す
+    fn submit(&self, request: BlockRequest) -> BlockFuture {
+        request::completed(request.execute(self))
+    }
+
+    // 範囲と長さを確かめて、読み書きするブロック数を返す
+    fn check_request(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        if len % self.block_size() != 0 {
+            return Err(BlockError::UnalignedBuffer);
+        }
+        let count = (len / self.block_size()) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.block_count() => Ok(count),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+}
+
+// ディスクを登録し、パーティションを探す
+pub fn register(device: Arc<dyn BlockDevice>) {
+    add(device.clone());
+    match partition::scan(&device) {
+        Ok(partitions) => {
+            for partition in partitions {
+                add(Arc::new(partition));
+            }
+        }
+        Err(e) => println!("block: {}: failed to read the partition table: {:?}", device.name(), e),
+    }
+}
+
+fn add(device: Arc<dyn BlockDevice>) {
+    println!(
+        "block: {} ({} blocks of {} bytes{})",
+        device.name(),
+        device.block_count(),
+        device.block_size(),
+        if device.read_only() { ", read-only" } else { "" }
+    );
+    if let Err(e) = devfs::register(device.name(), Arc::new(BlockDeviceNode(device.clone()))) {
+        println!("block: failed to create /dev/{}: {:?}", device.name(), e);
+    }
+    DEVICES.lock().push(device);
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|d| d.name() == name).cloned()
+}
+
+// 全てのバッファキャッシュを書き戻す
+pub fn sync() -> Result<(), BlockError> {
+    cache::sync_all()
+}
+
+// "sd" + 0 -> "sda"のように、ディスクの番号から名前をつける
+pub fn disk_name(prefix: &str, index: usize) -> String {
+    let mut name = String::from(prefix);
+    name.push((b'a' + (index % 26) as u8) as char);
+    name
+}
+
+// /dev/<name>。ブロックの境界に揃っていない読み書きは、ブロック単位で読んでから切り出す
+struct BlockDeviceNode(Arc<dyn BlockDevice>);
+
+impl Device for BlockDeviceNode {
+    fn file_type(&self) -> FileType {
+        FileType::BlockDevice
+    }
+
+    fn size(&self) -> u64 {
+        self.0.block_count() * self.0.block_size() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let size = self.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let block_size = self.0.block_size() as u64;
+        let first = offset / block_size;
+        let last = (offset + len as u64).div_ceil(block_size);
+        let mut blocks = vec![0; ((last - first) * block_size) as usize];
+        self.0.read_blocks(first, &mut blocks)?;
+        let start = (offset - first * block_size) as usize;
+        buf[..len].copy_from_slice(&blocks[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let size = self.size();
+        if offset >= size {
+            return Err(FsError::NoSpace);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let block_size = self.0.block_size() as u64;
+        let first = offset / block_size;
+        let last = (offset + len as u64).div_ceil(block_size);
+        let mut blocks = vec![0; ((last - first) * block_size) as usize];
+        let start = (offset - first * block_size) as usize;
+        if start != 0 || len as u64 % block_size != 0 {
+            self.0.read_blocks(first, &mut blocks)?;
+        }
+        blocks[start..start + len].copy_from_slice(&buf[..len]);
+        self.0.write_blocks(first, &blocks)?;
+        Ok(len)
+    }
+}
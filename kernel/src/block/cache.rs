@@ -0,0 +1,169 @@
+// Buffer cache
+//
+// ブロックデバイスのブロックをメモリに置いておく。書き込みはキャッシュ上で済ませ、
+// 追い出すときかsyncのときにデバイスへ書き戻す。一杯になったら最も長く使われていないものから追い出す
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use super::{BlockDevice, BlockError};
+
+lazy_static! {
+    static ref CACHES: Mutex<Vec<Weak<BufferCache>>> = Mutex::new(Vec::new());
+}
+
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    blocks: BTreeMap<u64, CachedBlock>,
+    // 使うたびに増やし、last_usedが最も小さいものを追い出す
+    clock: u64,
+}
+
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+impl BufferCache {
+    // capacityはキャッシュするブロックの数
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Arc<Self> {
+        let cache = Arc::new(Self {
+            device,
+            capacity: capacity.max(1),
+            inner: Mutex::new(Inner { blocks: BTreeMap::new(), clock: 0 }),
+        });
+        CACHES.lock().push(Arc::downgrade(&cache));
+        cache
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    // デバイスの先頭からのバイト位置で読む。ブロックの境界を跨いでもよい
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(offset, buf.len())?;
+        let block_size = self.block_size() as u64;
+        let mut inner = self.inner.lock();
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let len = (buf.len() - done).min(block_size as usize - start);
+            let block = self.block(&mut inner, position / block_size, true)?;
+            buf[done..done + len].copy_from_slice(&block.data[start..start + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<(), BlockError> {
+        if self.device.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_range(offset, data.len())?;
+        let block_size = self.block_size() as u64;
+        let mut inner = self.inner.lock();
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let len = (data.len() - done).min(block_size as usize - start);
+            // ブロック全体を書き換えるなら読む必要は無い
+            let block = self.block(&mut inner, position / block_size, len != block_size as usize)?;
+            block.data[start..start + len].copy_from_slice(&data[done..done + len]);
+            block.dirty = true;
+            done += len;
+        }
+        Ok(())
+    }
+
+    // 変更されたブロックを全て書き戻し、デバイスのキャッシュもフラッシュする
+    pub fn sync(&self) -> Result<(), BlockError> {
+        let mut inner = self.inner.lock();
+        for (&lba, block) in inner.blocks.iter_mut().filter(|(_, b)| b.dirty) {
+            self.device.write_blocks(lba, &block.data)?;
+            block.dirty = false;
+        }
+        self.device.flush()
+    }
+
+    // 変更されていないものは捨てる。デバイスを直接書き換えた後に使う
+    pub fn invalidate(&self) {
+        self.inner.lock().blocks.retain(|_, block| block.dirty);
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<(), BlockError> {
+        let size = self.device.block_count() * self.block_size() as u64;
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= size => Ok(()),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    fn block<'a>(&self, inner: &'a mut Inner, lba: u64, load: bool) -> Result<&'a mut CachedBlock, BlockError> {
+        inner.clock += 1;
+        let clock = inner.clock;
+        if !inner.blocks.contains_key(&lba) {
+            if inner.blocks.len() >= self.capacity {
+                self.evict(inner)?;
+            }
+            let mut data = vec![0; self.block_size()];
+            if load {
+                self.device.read_blocks(lba, &mut data)?;
+            }
+            inner.blocks.insert(lba, CachedBlock { data, dirty: false, last_used: clock });
+        }
+        let block = inner.blocks.get_mut(&lba).unwrap();
+        block.last_used = clock;
+        Ok(block)
+    }
+
+    fn evict(&self, inner: &mut Inner) -> Result<(), BlockError> {
+        let Some((&lba, _)) = inner.blocks.iter().min_by_key(|(_, block)| block.last_used) else {
+            return Ok(());
+        };
+        let block = inner.blocks.remove(&lba).unwrap();
+        if block.dirty {
+            if let Err(e) = self.device.write_blocks(lba, &block.data) {
+                // 書き戻せなかったら変更を失わないように戻しておく
+                inner.blocks.insert(lba, block);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BufferCache {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+// 全てのキャッシュをsyncする
+pub fn sync_all() -> Result<(), BlockError> {
+    let caches: Vec<Arc<BufferCache>> = {
+        let mut caches = CACHES.lock();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.iter().filter_map(Weak::upgrade).collect()
+    };
+    for cache in caches {
+        cache.sync()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+// Partition tables
+//
+// ディスクの先頭のMBRを読み、protective MBRならGPTを読む。見つけたパーティションは
+// 元のディスクへLBAをずらして読み書きするBlockDeviceになる
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BlockDevice, BlockError, BlockFuture, BlockRequest};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PARTITION_TABLE: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_BOOT_FLAGS: &[u8] = &[0x00, 0x80];
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+// 論理パーティションはまだ読まない
+const MBR_TYPES_EXTENDED: &[u8] = &[0x05, 0x0f, 0x85];
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_LBA: u64 = 1;
+const GPT_MAX_ENTRIES: usize = 128;
+
+pub struct Partition {
+    name: String,
+    disk: Arc<dyn BlockDevice>,
+    start: u64,
+    block_count: u64,
+}
+
+impl Partition {
+    pub fn disk(&self) -> &Arc<dyn BlockDevice> {
+        &self.disk
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(lba, buf.len())?;
+        self.disk.read_blocks(self.start + lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_request(lba, buf.len())?;
+        self.disk.write_blocks(self.start + lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+
+    fn submit(&self, mut request: BlockRequest) -> BlockFuture {
+        if let Err(e) = self.check_request(request.lba, request.buffer.len()) {
+            return super::request::completed(Err(e));
+        }
+        request.lba += self.start;
+        self.disk.submit(request)
+    }
+}
+
+// パーティションテーブルが無ければ空
+pub fn scan(disk: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let block_size = disk.block_size();
+    if block_size < 512 || disk.block_count() == 0 {
+        return Ok(Vec::new());
+    }
+    let mut mbr = vec![0; block_size];
+    disk.read_blocks(0, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries: Vec<&[u8]> = (0..4)
+        .map(|i| &mbr[MBR_PARTITION_TABLE + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE])
+        .collect();
+    // パーティションの無いFATのブートセクタも0x55 0xaaで終わる。そこではブートフラグの位置に
+    // 関係の無いバイトが入っているので、0x00か0x80でなければMBRとして読まない
+    if entries.iter().any(|entry| !MBR_BOOT_FLAGS.contains(&entry[0])) {
+        return Ok(Vec::new());
+    }
+    if entries.iter().any(|entry| entry[4] == MBR_TYPE_GPT_PROTECTIVE) {
+        return scan_gpt(disk);
+    }
+
+    let mut ranges = Vec::new();
+    for (slot, entry) in entries.into_iter().enumerate() {
+        let kind = entry[4];
+        if kind == MBR_TYPE_EMPTY || MBR_TYPES_EXTENDED.contains(&kind) {
+            continue;
+        }
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let count = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        ranges.push((slot, start, count));
+    }
+    Ok(partitions(disk, ranges))
+}
+
+// ヘッダとエントリのCRCは確かめない
+fn scan_gpt(disk: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let block_size = disk.block_size();
+    let mut header = vec![0; block_size];
+    disk.read_blocks(GPT_HEADER_LBA, &mut header)?;
+    if !header.starts_with(GPT_SIGNATURE) {
+        return Ok(Vec::new());
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count = (u32::from_le_bytes(header[80..84].try_into().unwrap()) as usize).min(GPT_MAX_ENTRIES);
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    if entry_size < 128 {
+        return Ok(Vec::new());
+    }
+
+    let blocks = (entry_count * entry_size).div_ceil(block_size);
+    if entries_lba.saturating_add(blocks as u64) > disk.block_count() {
+        return Ok(Vec::new());
+    }
+    let mut table = vec![0; blocks * block_size];
+    disk.read_blocks(entries_lba, &mut table)?;
+
+    let mut ranges = Vec::new();
+    for (slot, entry) in table.chunks_exact(entry_size).take(entry_count).enumerate() {
+        // 種類のGUIDが0なら使われていない
+        if entry[0..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        if last >= first {
+            ranges.push((slot, first, last - first + 1));
+        }
+    }
+    Ok(partitions(disk, ranges))
+}
+
+// rangesは(テーブルの何番目か, 先頭のLBA, ブロック数)。番号は空きのエントリも数える
+fn partitions(disk: &Arc<dyn BlockDevice>, ranges: Vec<(usize, u64, u64)>) -> Vec<Partition> {
+    // "sda" -> "sda1"、数字で終わる名前なら "nvme0n1" -> "nvme0n1p1"
+    let separator = if disk.name().ends_with(|c: char| c.is_ascii_digit()) { "p" } else { "" };
+    ranges
+        .into_iter()
+        .filter(|&(_, start, count)| count > 0 && start.checked_add(count).is_some_and(|end| end <= disk.block_count()))
+        .map(|(slot, start, block_count)| Partition {
+            name: format!("{}{}{}", disk.name(), separator, slot + 1),
+            disk: disk.clone(),
+            start,
+            block_count,
+        })
+        .collect()
+}
@@ -0,0 +1,115 @@
+// Block I/O requests
+//
+// submitは完了を待たずにBlockFutureを返す。割り込みで完了を知るドライバは後からcompleteを呼べばよい。
+// BlockFutureはasyncの中でawaitしても、waitでその場で待ってもよい
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::mutex::Mutex;
+
+use super::{BlockDevice, BlockError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+    Flush,
+}
+
+// Readならbufferの長さだけ読み、読んだ内容を入れて返す。Writeならbufferを書く
+pub struct BlockRequest {
+    pub operation: Operation,
+    pub lba: u64,
+    pub buffer: Vec<u8>,
+}
+
+impl BlockRequest {
+    pub fn read(lba: u64, len: usize) -> Self {
+        Self { operation: Operation::Read, lba, buffer: alloc::vec![0; len] }
+    }
+
+    pub fn write(lba: u64, buffer: Vec<u8>) -> Self {
+        Self { operation: Operation::Write, lba, buffer }
+    }
+
+    pub fn flush() -> Self {
+        Self { operation: Operation::Flush, lba: 0, buffer: Vec::new() }
+    }
+
+    // read_blocks / write_blocks / flushで同期的に処理する
+    pub fn execute<D: BlockDevice + ?Sized>(mut self, device: &D) -> Result<Vec<u8>, BlockError> {
+        match self.operation {
+            Operation::Read => device.read_blocks(self.lba, &mut self.buffer)?,
+            Operation::Write => device.write_blocks(self.lba, &self.buffer)?,
+            Operation::Flush => device.flush()?,
+        }
+        Ok(self.buffer)
+    }
+}
+
+pub type BlockResult = Result<Vec<u8>, BlockError>;
+
+#[derive(Default)]
+struct State {
+    result: Option<BlockResult>,
+    waker: Option<Waker>,
+}
+
+// ドライバ側が持つ完了の通知先
+pub struct Completion(Mutex<State>);
+
+impl Completion {
+    pub fn complete(&self, result: BlockResult) {
+        let waker = {
+            let mut state = self.0.lock();
+            state.result = Some(result);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+pub struct BlockFuture(Arc<Completion>);
+
+impl BlockFuture {
+    // 完了するまで待つ
+    pub fn wait(self) -> BlockResult {
+        loop {
+            if let Some(result) = self.0 .0.lock().result.take() {
+                return result;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Future for BlockFuture {
+    type Output = BlockResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<BlockResult> {
+        let mut state = self.0 .0.lock();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub fn pending() -> (Arc<Completion>, BlockFuture) {
+    let completion = Arc::new(Completion(Mutex::new(State::default())));
+    (completion.clone(), BlockFuture(completion))
+}
+
+pub fn completed(result: BlockResult) -> BlockFuture {
+    let (completion, future) = pending();
+    completion.complete(result);
+    future
+}
@@ -1,114 +1,561 @@
-use crate::graphics;
-use crate::graphics::{write_ascii, PixelColor};
+// Framebuffer console
+//
+// 文字を8x16のセルに並べて描く。行と列の数はフレームバッファの解像度から決める。
+// ANSIのエスケープシーケンスを解釈し、色やカーソルの位置を変えられる。
+// カーネルのprintkは"\n"だけで改行するので、LFは行の先頭にも戻す。
+// 上から消えた行はscrollbackに残し、Shift+PageUp/PageDownで遡って見られる
+
+mod ansi;
+mod scrollback;
+
+use crate::graphics::{self, FrameBufferWriter, PixelColor};
+use crate::library::math::vector::Vector2D;
+use crate::{println, serial};
+use alloc::string::String;
+use alloc::vec::Vec;
+use ansi::{Action, Params, Parser};
 use core::fmt;
 use core::fmt::Write;
 use lazy_static::lazy_static;
+use scrollback::Scrollback;
 use spin::mutex::Mutex;
 
-const ROWS: usize = 125;
-const COLUMNS: usize = 80;
+// 3840x2160まで。それより大きい画面では左上だけを使う
+const MAX_COLUMNS: usize = 480;
+const MAX_CELLS: usize = MAX_COLUMNS * 135;
+const CELL_WIDTH: u32 = 8;
+const CELL_HEIGHT: u32 = 16;
+const TAB_WIDTH: usize = 8;
+// cmdlineのscrollback=で変えられる
+const DEFAULT_SCROLLBACK_LINES: usize = 2000;
+
+// 0から15はxtermの色、16から231は6x6x6の色、232から255は灰色
+const BASE_COLORS: [PixelColor; 16] = [
+    PixelColor { r: 0x00, g: 0x00, b: 0x00 },
+    PixelColor { r: 0xcd, g: 0x00, b: 0x00 },
+    PixelColor { r: 0x00, g: 0xcd, b: 0x00 },
+    PixelColor { r: 0xcd, g: 0xcd, b: 0x00 },
+    PixelColor { r: 0x00, g: 0x00, b: 0xee },
+    PixelColor { r: 0xcd, g: 0x00, b: 0xcd },
+    PixelColor { r: 0x00, g: 0xcd, b: 0xcd },
+    PixelColor { r: 0xe5, g: 0xe5, b: 0xe5 },
+    PixelColor { r: 0x7f, g: 0x7f, b: 0x7f },
+    PixelColor { r: 0xff, g: 0x00, b: 0x00 },
+    PixelColor { r: 0x00, g: 0xff, b: 0x00 },
+    PixelColor { r: 0xff, g: 0xff, b: 0x00 },
+    PixelColor { r: 0x5c, g: 0x5c, b: 0xff },
+    PixelColor { r: 0xff, g: 0x00, b: 0xff },
+    PixelColor { r: 0x00, g: 0xff, b: 0xff },
+    PixelColor { r: 0xff, g: 0xff, b: 0xff },
+];
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn palette(index: u8) -> PixelColor {
+    match index {
+        0..=15 => BASE_COLORS[index as usize],
+        16..=231 => {
+            let i = index - 16;
+            PixelColor {
+                r: CUBE_LEVELS[(i / 36) as usize],
+                g: CUBE_LEVELS[(i / 6 % 6) as usize],
+                b: CUBE_LEVELS[(i % 6) as usize],
+            }
+        }
+        _ => {
+            let level = 8 + 10 * (index - 232);
+            PixelColor { r: level, g: level, b: level }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Default,
+    Indexed(u8),
+    Rgb(PixelColor),
+}
+
+// SGRで変わる文字の属性
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Attributes {
+    fg: Color,
+    bg: Color,
+    // 太字のフォントは無いので、0から7の色を明るい色にする
+    bold: bool,
+    reverse: bool,
+}
+
+impl Attributes {
+    const DEFAULT: Self = Self { fg: Color::Default, bg: Color::Default, bold: false, reverse: false };
+}
+
+#[derive(Clone, Copy)]
+struct Cell {
+    char: char,
+    fg: PixelColor,
+    bg: PixelColor,
+}
 
-pub struct Console<'a> {
-    fg_color: &'a PixelColor,
-    bg_color: &'a PixelColor,
+impl Cell {
+    const EMPTY: Self = Self { char: ' ', fg: PixelColor::BLACK, bg: PixelColor::BLACK };
+}
+
+// ヒープの準備より前から使うので、セルはstatic mutに置く。触るのはConsoleだけ
+static mut CELLS: [Cell; MAX_CELLS] = [Cell::EMPTY; MAX_CELLS];
+
+#[derive(Clone, Copy)]
+struct Cursor {
+    row: usize,
+    column: usize,
+    attributes: Attributes,
+}
+
+pub struct Console {
+    fg_color: PixelColor,
+    bg_color: PixelColor,
+    attributes: Attributes,
     cursor_row: usize,
     cursor_column: usize,
-    buffer: [[char; COLUMNS + 1]; ROWS],
+    // 最後の列に書いた後。次の文字を書くときに折り返す
+    wrap_pending: bool,
+    saved_cursor: Cursor,
+    parser: Parser,
+    rows: usize,
+    columns: usize,
+    // rows * columns個のセルを行ごとに並べたもの
+    buffer: &'static mut [Cell],
+    scrollback: Scrollback,
+    // 遡って見ている行数。0なら今の画面を見ている
+    view_offset: usize,
 }
 
-impl<'a> Console<'a> {
-    pub fn new(fg_color: &'a PixelColor, bg_color: &'a PixelColor) -> Console<'a> {
+impl Console {
+    // 一度しか呼ばない。CELLSを使うConsoleは一つだけ
+    fn new(fg_color: PixelColor, bg_color: PixelColor) -> Console {
+        let (width, height) =
+            graphics::frame_buffer_config().as_ref().map_or((0, 0), |config| (config.width(), config.height()));
+        let columns = ((width / CELL_WIDTH) as usize).min(MAX_COLUMNS);
+        let rows = MAX_CELLS.checked_div(columns).map_or(0, |max_rows| ((height / CELL_HEIGHT) as usize).min(max_rows));
+        let cells = unsafe { &mut *core::ptr::addr_of_mut!(CELLS) };
+        let buffer = &mut cells[..rows * columns];
+        buffer.fill(Cell { char: ' ', fg: fg_color, bg: bg_color });
         Self {
             fg_color,
             bg_color,
+            attributes: Attributes::DEFAULT,
             cursor_row: 0,
             cursor_column: 0,
-            buffer: [['\0'; COLUMNS + 1]; ROWS],
+            wrap_pending: false,
+            saved_cursor: Cursor { row: 0, column: 0, attributes: Attributes::DEFAULT },
+            parser: Parser::new(),
+            rows,
+            columns,
+            buffer,
+            scrollback: Scrollback::empty(),
+            view_offset: 0,
         }
     }
 
     pub fn put_string(&mut self, str: &str) {
-        let mut writer_guard = graphics::pixel_writer();
+        // 画面が無ければ何も描けない
+        if self.rows == 0 {
+            return;
+        }
+        // 何か出たら今の画面に戻る
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.draw_view();
+        }
         for char in str.chars() {
-            if char == '\n' || self.cursor_column >= COLUMNS - 1 {
-                self.new_line();
-                if char == '\n' {
-                    continue;
-                }
+            match self.parser.advance(char) {
+                Some(Action::Print(c)) => self.put_char(c),
+                Some(Action::Control(c)) => self.control(c),
+                Some(Action::Escape(c)) => self.escape(c),
+                Some(Action::Csi { params, private: None, command }) => self.csi(&params, command),
+                // ESC [ ? 25 lなどのモードの切り替えは扱わない
+                Some(Action::Csi { .. }) | None => {}
             }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        if self.rows == 0 {
+            return;
+        }
+        self.erase_display(2);
+        self.move_cursor(0, 0);
+    }
+
+    fn resolve(&self, color: Color, default: PixelColor) -> PixelColor {
+        match color {
+            Color::Default => default,
+            Color::Indexed(index) if self.attributes.bold && index < 8 => palette(index + 8),
+            Color::Indexed(index) => palette(index),
+            Color::Rgb(color) => color,
+        }
+    }
 
-            if let Some(writer) = writer_guard.as_mut() {
-                write_ascii(
-                    writer,
-                    8 * self.cursor_column as u32,
-                    16 * self.cursor_row as u32,
-                    char,
-                    &self.fg_color,
-                )
+    // 今の属性で描く文字の色と背景の色
+    fn colors(&self) -> (PixelColor, PixelColor) {
+        let fg = self.resolve(self.attributes.fg, self.fg_color);
+        let bg = match self.attributes.bg {
+            Color::Default => self.bg_color,
+            Color::Indexed(index) => palette(index),
+            Color::Rgb(color) => color,
+        };
+        if self.attributes.reverse {
+            (bg, fg)
+        } else {
+            (fg, bg)
+        }
+    }
+
+    fn blank(&self) -> Cell {
+        let (fg, bg) = self.colors();
+        Cell { char: ' ', fg, bg }
+    }
+
+    fn index(&self, row: usize, column: usize) -> usize {
+        row * self.columns + column
+    }
+
+    fn draw_cell(&self, writer: &FrameBufferWriter, row: usize, column: usize) {
+        draw(writer, row, column, &self.buffer[self.index(row, column)]);
+    }
+
+    // scrollbackと画面の行を続けて、古い方から数えたもの
+    fn line(&self, index: usize) -> &[Cell] {
+        match index.checked_sub(self.scrollback.len()) {
+            Some(row) => &self.buffer[self.index(row, 0)..self.index(row + 1, 0)],
+            None => self.scrollback.line(index),
+        }
+    }
+
+    fn draw_view(&self) {
+        let first = self.scrollback.len() - self.view_offset;
+        if let Some(writer) = graphics::pixel_writer().as_ref() {
+            for row in 0..self.rows {
+                for (column, cell) in self.line(first + row).iter().enumerate() {
+                    draw(writer, row, column, cell);
+                }
             }
+        }
+    }
+
+    // 正なら古い方へ、負なら新しい方へ動かす
+    fn scroll_view(&mut self, lines: isize) {
+        if self.rows == 0 {
+            return;
+        }
+        let offset = self.view_offset.saturating_add_signed(lines).min(self.scrollback.len());
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.draw_view();
+        }
+    }
+
+    // 古い方からindex番目の行の文字をbufに写し、(行の番号, 文字数)を返す。
+    // 行の番号は今の画面の一番上の行を0とし、scrollbackの行は負になる。
+    // 古い行から数えると、scrollbackがあふれるたびに番号が変わってしまう
+    fn copy_line(&self, index: usize, buf: &mut [char; MAX_COLUMNS]) -> Option<(isize, usize)> {
+        if index >= self.scrollback.len() + self.rows {
+            return None;
+        }
+        let line = self.line(index);
+        for (c, cell) in buf.iter_mut().zip(line) {
+            *c = cell.char;
+        }
+        Some((index as isize - self.scrollback.len() as isize, line.len()))
+    }
 
-            self.buffer[self.cursor_row][self.cursor_column] = char;
+    fn put_char(&mut self, char: char) {
+        if self.wrap_pending {
+            self.new_line();
+        }
+        let (fg, bg) = self.colors();
+        let index = self.index(self.cursor_row, self.cursor_column);
+        self.buffer[index] = Cell { char, fg, bg };
+        if let Some(writer) = graphics::pixel_writer().as_ref() {
+            self.draw_cell(writer, self.cursor_row, self.cursor_column);
+        }
+        if self.cursor_column < self.columns - 1 {
             self.cursor_column += 1;
+        } else {
+            self.wrap_pending = true;
         }
     }
 
-    fn new_line(&mut self) {
-        self.cursor_column = 0;
-        if self.cursor_row < ROWS - 1 {
-            self.cursor_row += 1;
+    fn control(&mut self, char: char) {
+        match char {
+            '\n' => self.new_line(),
+            '\r' => self.move_cursor(self.cursor_row, 0),
+            '\t' => self.move_cursor(self.cursor_row, (self.cursor_column / TAB_WIDTH + 1) * TAB_WIDTH),
+            // 戻るだけで消さない。シェルは"\x08 \x08"で消す
+            '\x08' => self.move_cursor(self.cursor_row, self.cursor_column.saturating_sub(1)),
+            _ => {}
+        }
+    }
+
+    fn escape(&mut self, char: char) {
+        match char {
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            'c' => {
+                self.attributes = Attributes::DEFAULT;
+                self.saved_cursor = Cursor { row: 0, column: 0, attributes: Attributes::DEFAULT };
+                self.clear();
+            }
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, params: &Params, command: char) {
+        let n = params.get_or(0, 1) as usize;
+        let (row, column) = (self.cursor_row, self.cursor_column);
+        match command {
+            'A' => self.move_cursor(row.saturating_sub(n), column),
+            'B' => self.move_cursor(row + n, column),
+            'C' => self.move_cursor(row, column + n),
+            'D' => self.move_cursor(row, column.saturating_sub(n)),
+            'E' => self.move_cursor(row + n, 0),
+            'F' => self.move_cursor(row.saturating_sub(n), 0),
+            'G' => self.move_cursor(row, n - 1),
+            'd' => self.move_cursor(n - 1, column),
+            // 行と列は1から数える
+            'H' | 'f' => self.move_cursor(n - 1, params.get_or(1, 1) as usize - 1),
+            'J' => self.erase_display(params.get(0).unwrap_or(0)),
+            'K' => self.erase_line(params.get(0).unwrap_or(0)),
+            'm' => self.select_graphic_rendition(params),
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn move_cursor(&mut self, row: usize, column: usize) {
+        if self.rows == 0 {
             return;
-        } else {
-            let mut writer_guard = graphics::pixel_writer();
-            for row in 1..ROWS {
-                self.buffer[row - 1] = self.buffer[row];
-                for col in 0..COLUMNS {
-                    let char = self.buffer[row - 1][col];
-                    if let Some(writer) = writer_guard.as_mut() {
-                        write_ascii(
-                            writer,
-                            8 * col as u32,
-                            16 * (row - 1) as u32,
-                            char,
-                            &self.fg_color,
-                        );
-                    }
-                }
+        }
+        self.cursor_row = row.min(self.rows - 1);
+        self.cursor_column = column.min(self.columns - 1);
+        self.wrap_pending = false;
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = Cursor { row: self.cursor_row, column: self.cursor_column, attributes: self.attributes };
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved_cursor;
+        self.attributes = saved.attributes;
+        self.move_cursor(saved.row, saved.column);
+    }
+
+    fn erase(&mut self, row: usize, columns: core::ops::Range<usize>) {
+        let blank = self.blank();
+        let start = self.index(row, 0);
+        self.buffer[start + columns.start..start + columns.end].fill(blank);
+        if let Some(writer) = graphics::pixel_writer().as_ref() {
+            for column in columns {
+                self.draw_cell(writer, row, column);
             }
-            self.buffer[ROWS - 1] = [char::from(0); COLUMNS + 1];
-
-            for col in 0..COLUMNS {
-                if let Some(writer) = writer_guard.as_mut() {
-                    write_ascii(
-                        writer,
-                        8 * col as u32,
-                        16 * (ROWS - 1) as u32,
-                        ' ',
-                        &self.fg_color,
-                    )
+        }
+    }
+
+    // 0はカーソルから後ろ、1は先頭からカーソルまで、2は全体
+    fn erase_line(&mut self, mode: u16) {
+        let columns = match mode {
+            0 => self.cursor_column..self.columns,
+            1 => 0..self.cursor_column + 1,
+            2 => 0..self.columns,
+            _ => return,
+        };
+        self.erase(self.cursor_row, columns);
+    }
+
+    // 3はscrollbackも消す
+    fn erase_display(&mut self, mode: u16) {
+        if mode == 3 {
+            self.scrollback.clear();
+        }
+        let rows = match mode {
+            0 => self.cursor_row + 1..self.rows,
+            1 => 0..self.cursor_row,
+            2 | 3 => 0..self.rows,
+            _ => return,
+        };
+        if mode < 2 {
+            self.erase_line(mode);
+        }
+        for row in rows {
+            self.erase(row, 0..self.columns);
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &Params) {
+        if params.len() == 0 {
+            self.attributes = Attributes::DEFAULT;
+            return;
+        }
+        let mut i = 0;
+        while i < params.len() {
+            let value = params.get(i).unwrap_or(0);
+            let attributes = &mut self.attributes;
+            match value {
+                0 => *attributes = Attributes::DEFAULT,
+                1 => attributes.bold = true,
+                22 => attributes.bold = false,
+                7 => attributes.reverse = true,
+                27 => attributes.reverse = false,
+                30..=37 => attributes.fg = Color::Indexed((value - 30) as u8),
+                39 => attributes.fg = Color::Default,
+                40..=47 => attributes.bg = Color::Indexed((value - 40) as u8),
+                49 => attributes.bg = Color::Default,
+                90..=97 => attributes.fg = Color::Indexed((value - 90 + 8) as u8),
+                100..=107 => attributes.bg = Color::Indexed((value - 100 + 8) as u8),
+                // 38;5;nと38;2;r;g;b
+                38 | 48 => {
+                    let (color, used) = match params.get(i + 1) {
+                        Some(5) => (params.get(i + 2).map(|n| Color::Indexed(n as u8)), 2),
+                        Some(2) => {
+                            let component = |j| params.get(i + j).unwrap_or(0).min(255) as u8;
+                            (Some(Color::Rgb(PixelColor { r: component(2), g: component(3), b: component(4) })), 4)
+                        }
+                        _ => (None, 1),
+                    };
+                    if let Some(color) = color {
+                        if value == 38 {
+                            attributes.fg = color;
+                        } else {
+                            attributes.bg = color;
+                        }
+                    }
+                    i += used;
                 }
+                _ => {}
             }
+            i += 1;
         }
     }
+
+    fn new_line(&mut self) {
+        self.cursor_column = 0;
+        self.wrap_pending = false;
+        if self.cursor_row < self.rows - 1 {
+            self.cursor_row += 1;
+        } else {
+            self.scroll_up();
+        }
+    }
+
+    // 描き直さず、フレームバッファの画素を1行分上にずらして最後の行だけ消す
+    fn scroll_up(&mut self) {
+        let columns = self.columns;
+        self.scrollback.push(&self.buffer[..columns]);
+        self.buffer.copy_within(columns.., 0);
+        if let Some(writer) = graphics::pixel_writer().as_ref() {
+            let height = CELL_HEIGHT * (self.rows - 1) as u32;
+            writer.move_rows(0, CELL_HEIGHT, height);
+        }
+        self.erase(self.rows - 1, 0..columns);
+    }
+}
+
+fn draw(writer: &FrameBufferWriter, row: usize, column: usize, cell: &Cell) {
+    let x = CELL_WIDTH * column as u32;
+    let y = CELL_HEIGHT * row as u32;
+    writer.fill_rectangle(Vector2D::new(x, y), Vector2D::new(CELL_WIDTH, CELL_HEIGHT), &cell.bg);
+    writer.write_ascii(x, y, cell.char, &cell.fg);
 }
 
 lazy_static! {
-    static ref CONSOLE: Mutex<Option<Console<'static>>> = Mutex::new(None);
+    static ref CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
 }
 
 pub fn init() -> () {
     let mut console = CONSOLE.lock();
-    *console = Some(Console::new(&PixelColor::GREEN, &PixelColor::DESKTOP_BG));
+    *console = Some(Console::new(PixelColor::GREEN, PixelColor::DESKTOP_BG));
 }
 
-fn console() -> spin::MutexGuard<'static, Option<Console<'static>>> {
+fn console() -> spin::MutexGuard<'static, Option<Console>> {
     CONSOLE.lock()
 }
 
+pub fn clear() {
+    if let Some(console) = console().as_mut() {
+        console.clear();
+    }
+}
+
+// ヒープの準備ができてから呼ぶ。linesはcmdlineのscrollback=で指定された行数
+pub fn init_scrollback(lines: Option<&str>) {
+    let lines = match lines.map(str::parse::<usize>) {
+        None => DEFAULT_SCROLLBACK_LINES,
+        Some(Ok(lines)) => lines,
+        Some(Err(_)) => {
+            println!("console: invalid scrollback {}", lines.unwrap_or_default());
+            DEFAULT_SCROLLBACK_LINES
+        }
+    };
+    let Some(columns) = console().as_ref().map(|console| console.columns) else {
+        return;
+    };
+    // 確保に失敗するとアロケーターがprintlnするので、CONSOLEのロックは持たずに確保する
+    let Some(scrollback) = Scrollback::new(lines, columns) else {
+        return println!("console: failed to allocate {} lines of scrollback", lines);
+    };
+    if let Some(console) = console().as_mut() {
+        console.scrollback = scrollback;
+        console.view_offset = 0;
+    }
+}
+
+// 1画面分ずつ遡る
+pub fn page_up() {
+    if let Some(console) = console().as_mut() {
+        console.scroll_view(console.rows as isize);
+    }
+}
+
+pub fn page_down() {
+    if let Some(console) = console().as_mut() {
+        console.scroll_view(-(console.rows as isize));
+    }
+}
+
+// 見つかった行の番号と中身。結果のVecやStringを作る途中でアロケーションに失敗すると
+// printlnがCONSOLEのロックを取りに来るので、ロックは1行をスタックに写す間だけ持つ
+pub fn search(pattern: &str) -> Vec<(isize, String)> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut matches = Vec::new();
+    let mut buf = [' '; MAX_COLUMNS];
+    for index in 0.. {
+        let Some((number, len)) = console().as_ref().and_then(|console| console.copy_line(index, &mut buf)) else {
+            break;
+        };
+        let text = &buf[..len];
+        let text = &text[..text.iter().rposition(|c| !c.is_whitespace()).map_or(0, |end| end + 1)];
+        if pattern.is_empty() || text.windows(pattern.len()).any(|window| window == pattern) {
+            matches.push((number, text.iter().collect()));
+        }
+    }
+    matches
+}
+
+// シリアルポートにも同じものを出す。フレームバッファの準備ができる前はシリアルだけ
 pub fn _printk(args: fmt::Arguments) {
-    console().as_mut().unwrap().write_fmt(args).unwrap();
+    serial::_print(args);
+    _print(args);
+}
+
+// フレームバッファにだけ出す
+pub fn _print(args: fmt::Arguments) {
+    if let Some(console) = console().as_mut() {
+        console.write_fmt(args).unwrap();
+    }
 }
 
-impl<'a> fmt::Write for Console<'a> {
+impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.put_string(s);
         Ok(())
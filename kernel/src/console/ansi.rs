@@ -0,0 +1,122 @@
+// ANSI escape sequences
+//
+// 文字を1つずつ受け取り、表示する文字、制御文字、エスケープシーケンスに分ける。
+// CSIはESC [の後に;区切りの数字の引数が続き、0x40から0x7eの文字で終わる
+
+const MAX_PARAMS: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // 省略された引数はNone
+    pub fn get(&self, index: usize) -> Option<u16> {
+        (index < self.len).then(|| self.values[index])
+    }
+
+    // カーソルの移動などでは、省略や0はdefaultと同じ
+    pub fn get_or(&self, index: usize, default: u16) -> u16 {
+        match self.get(index) {
+            None | Some(0) => default,
+            Some(value) => value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    Print(char),
+    // 0x00から0x1fの制御文字
+    Control(char),
+    // ESC 7のような、CSIでないエスケープシーケンス
+    Escape(char),
+    // privateはESC [ ? 25 lの?のような印
+    Csi { params: Params, private: Option<char>, command: char },
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Ground,
+    Escape,
+    // ESC (などの文字集合の指定。次の1文字は読み捨てる
+    Charset,
+    Csi,
+}
+
+pub struct Parser {
+    state: State,
+    params: Params,
+    private: Option<char>,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self { state: State::Ground, params: Params { values: [0; MAX_PARAMS], len: 0 }, private: None }
+    }
+
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match (self.state, c) {
+            (_, '\x1b') => {
+                self.state = State::Escape;
+                None
+            }
+            (State::Ground, '\x7f') => None,
+            (State::Ground, c) if c < ' ' => Some(Action::Control(c)),
+            (State::Ground, c) => Some(Action::Print(c)),
+            (State::Escape, '[') => {
+                self.state = State::Csi;
+                self.params = Params::default();
+                self.private = None;
+                None
+            }
+            (State::Escape, '(' | ')' | '*' | '+') => {
+                self.state = State::Charset;
+                None
+            }
+            (State::Escape, c) => {
+                self.state = State::Ground;
+                Some(Action::Escape(c))
+            }
+            (State::Charset, _) => {
+                self.state = State::Ground;
+                None
+            }
+            // シーケンスの途中の制御文字はそのまま実行する
+            (State::Csi, c) if c < ' ' => Some(Action::Control(c)),
+            (State::Csi, '0'..='9') => {
+                let params = &mut self.params;
+                if params.len == 0 {
+                    params.len = 1;
+                }
+                if let Some(value) = params.values.get_mut(params.len - 1) {
+                    *value = value.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                }
+                None
+            }
+            (State::Csi, ';') => {
+                let params = &mut self.params;
+                // ";5"のように先頭が省略されていれば、そこにも0を置く
+                params.len = (params.len.max(1) + 1).min(MAX_PARAMS);
+                params.values[params.len - 1] = 0;
+                None
+            }
+            (State::Csi, '<'..='?') if self.params.len == 0 => {
+                self.private = Some(c);
+                None
+            }
+            (State::Csi, '@'..='~') => {
+                self.state = State::Ground;
+                Some(Action::Csi { params: self.params, private: self.private, command: c })
+            }
+            // 中間文字などは無視する
+            (State::Csi, _) => None,
+        }
+    }
+}
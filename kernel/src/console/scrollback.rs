@@ -0,0 +1,62 @@
+// Scrollback
+//
+// 画面の上から消えた行を覚えておく。ヒープは確保のたびにフレームを使うので、行ごとには確保せず、
+// 全部の行を1つのVecに並べて古い行から上書きする
+
+use alloc::vec::Vec;
+
+use super::Cell;
+
+pub struct Scrollback {
+    cells: Vec<Cell>,
+    columns: usize,
+    // 覚えておける行数
+    capacity: usize,
+    // 一番古い行の位置
+    start: usize,
+    len: usize,
+}
+
+impl Scrollback {
+    // 何も覚えない。ヒープの準備ができる前から使える
+    pub const fn empty() -> Self {
+        Self { cells: Vec::new(), columns: 0, capacity: 0, start: 0, len: 0 }
+    }
+
+    // 確保できなければNone
+    pub fn new(capacity: usize, columns: usize) -> Option<Self> {
+        let size = capacity.checked_mul(columns)?;
+        let mut cells = Vec::new();
+        cells.try_reserve_exact(size).ok()?;
+        cells.resize(size, Cell::EMPTY);
+        Some(Self { cells, columns, capacity, start: 0, len: 0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn push(&mut self, line: &[Cell]) {
+        if self.capacity == 0 || line.len() != self.columns {
+            return;
+        }
+        let slot = (self.start + self.len) % self.capacity;
+        if self.len == self.capacity {
+            self.start = (self.start + 1) % self.capacity;
+        } else {
+            self.len += 1;
+        }
+        self.cells[slot * self.columns..(slot + 1) * self.columns].copy_from_slice(line);
+    }
+
+    // 0が一番古い行
+    pub fn line(&self, index: usize) -> &[Cell] {
+        let slot = (self.start + index) % self.capacity;
+        &self.cells[slot * self.columns..(slot + 1) * self.columns]
+    }
+
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
@@ -0,0 +1,59 @@
+// DMA buffers
+//
+// 物理メモリはそのままの仮想アドレスでマップしてあるので、物理的に連続したフレームを確保すれば
+// デバイスに渡す物理アドレスとカーネルから触るポインタが同じになる
+
+use x86_64::PhysAddr;
+
+use crate::error::OsError;
+use crate::memory_manager::{frame_manager, Frame};
+
+pub struct DmaBuffer {
+    frame: Frame,
+    pages: usize,
+}
+
+impl DmaBuffer {
+    // ゼロで埋めた、ページ境界から始まる連続したバッファ
+    pub fn new(size: usize) -> Result<Self, OsError> {
+        let pages = size.div_ceil(Frame::SIZE).max(1);
+        let frame = frame_manager().allocate(pages).map_err(|_| OsError::OutOfMemory)?;
+        let buffer = Self { frame, pages };
+        unsafe { core::ptr::write_bytes(buffer.as_mut_ptr::<u8>(), 0, buffer.len()) };
+        Ok(buffer)
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frame.phys_addr()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages * Frame::SIZE
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.phys_addr().as_u64() as *const T
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.phys_addr().as_u64() as *mut T
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        frame_manager().free(self.frame, self.pages);
+    }
+}
+
+// Frameはただの番号なので、どのスレッドに渡してもよい
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
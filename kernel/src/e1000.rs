@@ -0,0 +1,380 @@
+// Intel 8254x (e1000) driver
+//
+// BAR0のレジスタで初期化し、受信と送信のディスクリプタリングをDMAのメモリに置く。
+// 受信の割り込みはhltで待っているkernel_main_loopを起こすためだけに使う。割り込みの中では受信リングに触れず、
+// フレームはnet::pollからpollで取り出す
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use spin::mutex::Mutex;
+use x86_64::PhysAddr;
+
+use crate::dma::DmaBuffer;
+use crate::error::OsError;
+use crate::net::{self, MacAddress, NetError, NetworkDevice};
+use crate::pci::{self, Bar, BusDeviceFunction, PciDevice, PciDeviceDriver, PciDeviceDriverInstance};
+use crate::{interrupts, paging, println, timer};
+
+const SUPPORTED_DEVICES: &[(u16, u16)] = &[
+    (0x8086, 0x100e), // 82540EM (QEMU)
+    (0x8086, 0x100f), // 82545EM
+];
+
+// レジスタ
+const REG_CTRL: usize = 0x0000;
+const REG_STATUS: usize = 0x0008;
+const REG_EERD: usize = 0x0014;
+const REG_ICR: usize = 0x00c0;
+const REG_IMS: usize = 0x00d0;
+const REG_IMC: usize = 0x00d8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_RDTR: usize = 0x2820;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+const REG_MTA: usize = 0x5200;
+const REG_RAL0: usize = 0x5400;
+const REG_RAH0: usize = 0x5404;
+const MTA_ENTRIES: usize = 128;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+const RAH_AV: u32 = 1 << 31;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+// BSIZEが0なら2048バイト
+const RCTL_BSIZE_2048: u32 = 0;
+const RCTL_SECRC: u32 = 1 << 26;
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0f << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+// 割り込みの要因
+const ICR_LSC: u32 = 1 << 2;
+const ICR_RXDMT0: u32 = 1 << 4;
+const ICR_RXO: u32 = 1 << 6;
+const ICR_RXT0: u32 = 1 << 7;
+
+const RX_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+const TX_STATUS_DD: u8 = 1 << 0;
+
+// リングの長さは128バイト(8ディスクリプタ)の倍数でなければならない
+const RX_DESCRIPTORS: usize = 64;
+const TX_DESCRIPTORS: usize = 64;
+const BUFFER_SIZE: usize = 2048;
+// FCSを除いたEthernetフレームの最大
+const MAX_FRAME_SIZE: usize = 1514;
+
+const RESET_TIMEOUT_MS: u64 = 100;
+const EEPROM_TIMEOUT_MS: u64 = 10;
+
+pub struct E1000Driver;
+
+impl PciDeviceDriver for E1000Driver {
+    fn supports(&self, device: &PciDevice) -> bool {
+        SUPPORTED_DEVICES.contains(&(device.vd.vendor, device.vd.device))
+    }
+
+    fn attach(&self, bdf: BusDeviceFunction) -> Result<Box<dyn PciDeviceDriverInstance>, OsError> {
+        let pci = pci::pci();
+        let Bar::Memory(bar0) = pci.read_bar(bdf, 0)? else {
+            return Err(OsError::NotSupported);
+        };
+        pci.enable_bus_master(bdf)?;
+        let registers = Registers::new(bar0)?;
+        reset(registers)?;
+
+        let mac = read_mac_address(registers);
+        registers.write(REG_RAL0, u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]));
+        registers.write(REG_RAH0, u16::from_le_bytes([mac[4], mac[5]]) as u32 | RAH_AV);
+        for i in 0..MTA_ENTRIES {
+            registers.write(REG_MTA + i * 4, 0);
+        }
+        let rx = Rx::new(registers)?;
+        let tx = Tx::new(registers)?;
+
+        let device = Arc::new(E1000 { name: net::next_name(), mac, registers, rx: Mutex::new(rx), tx: Mutex::new(tx) });
+        // 割り込みが使えなくても、pollで回していれば受信はできる
+        let irq = pci.interrupt_line(bdf)?;
+        let handler = device.clone();
+        match interrupts::register_irq(irq, true, Box::new(move || handler.handle_interrupt())) {
+            Ok(()) => registers.write(REG_IMS, ICR_LSC | ICR_RXDMT0 | ICR_RXO | ICR_RXT0),
+            Err(e) => println!("e1000: {} cannot use IRQ {}: {:?}", device.name, irq, e),
+        }
+        net::register(device.clone());
+        Ok(Box::new(E1000Instance { bdf, _device: device }))
+    }
+
+    fn name(&self) -> &str {
+        "e1000"
+    }
+}
+
+pub struct E1000Instance {
+    bdf: BusDeviceFunction,
+    _device: Arc<E1000>,
+}
+
+impl PciDeviceDriverInstance for E1000Instance {
+    fn name(&self) -> &str {
+        "e1000"
+    }
+
+    fn bdf(&self) -> BusDeviceFunction {
+        self.bdf
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Registers(usize);
+
+impl Registers {
+    fn new(phys: u64) -> Result<Self, OsError> {
+        let virt = paging::as_virt_addr(PhysAddr::new(phys)).ok_or(OsError::NotSupported)?;
+        Ok(Self(virt.as_u64() as usize))
+    }
+
+    fn read(self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write(self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.0 + offset) as *mut u32, value) }
+    }
+}
+
+fn reset(registers: Registers) -> Result<(), OsError> {
+    registers.write(REG_IMC, u32::MAX);
+    registers.write(REG_CTRL, registers.read(REG_CTRL) | CTRL_RST);
+    // リセットの直後はレジスタに触れないので、少し待ってから見る
+    timer::sleep_ms(1);
+    if !timer::wait_until(RESET_TIMEOUT_MS, || registers.read(REG_CTRL) & CTRL_RST == 0) {
+        return Err(OsError::Timeout);
+    }
+    registers.write(REG_IMC, u32::MAX);
+    registers.read(REG_ICR);
+    registers.write(REG_CTRL, registers.read(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+    Ok(())
+}
+
+// EEPROMの先頭3ワードがMACアドレス。読めなければ、ファームウェアがRAL0/RAH0に入れた値を使う
+fn read_mac_address(registers: Registers) -> MacAddress {
+    let read_word = |address: u32| {
+        registers.write(REG_EERD, address << 8 | EERD_START);
+        let mut value = 0;
+        let done = timer::wait_until(EEPROM_TIMEOUT_MS, || {
+            value = registers.read(REG_EERD);
+            value & EERD_DONE != 0
+        });
+        done.then_some((value >> 16) as u16)
+    };
+    if let (Some(a), Some(b), Some(c)) = (read_word(0), read_word(1), read_word(2)) {
+        let [m0, m1] = a.to_le_bytes();
+        let [m2, m3] = b.to_le_bytes();
+        let [m4, m5] = c.to_le_bytes();
+        return [m0, m1, m2, m3, m4, m5];
+    }
+    let low = registers.read(REG_RAL0).to_le_bytes();
+    let high = registers.read(REG_RAH0).to_le_bytes();
+    [low[0], low[1], low[2], low[3], high[0], high[1]]
+}
+
+#[repr(C)]
+struct RxDescriptor {
+    addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+struct TxDescriptor {
+    addr: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+// ディスクリプタ1つにバッファ1つを割り当てる
+struct Rx {
+    ring: DmaBuffer,
+    buffers: DmaBuffer,
+    // 次にデバイスが書き終えるディスクリプタ
+    next: usize,
+}
+
+impl Rx {
+    fn new(registers: Registers) -> Result<Self, OsError> {
+        let ring = DmaBuffer::new(RX_DESCRIPTORS * size_of::<RxDescriptor>())?;
+        let buffers = DmaBuffer::new(RX_DESCRIPTORS * BUFFER_SIZE)?;
+        let rx = Self { ring, buffers, next: 0 };
+        for i in 0..RX_DESCRIPTORS {
+            unsafe { (*rx.descriptor(i)).addr = rx.buffers.phys_addr().as_u64() + (i * BUFFER_SIZE) as u64 };
+        }
+        let ring_addr = rx.ring.phys_addr().as_u64();
+        registers.write(REG_RDBAL, ring_addr as u32);
+        registers.write(REG_RDBAH, (ring_addr >> 32) as u32);
+        registers.write(REG_RDLEN, (RX_DESCRIPTORS * size_of::<RxDescriptor>()) as u32);
+        registers.write(REG_RDH, 0);
+        // 1つ空けておかないと、満杯と空の区別が付かない
+        registers.write(REG_RDT, (RX_DESCRIPTORS - 1) as u32);
+        registers.write(REG_RDTR, 0);
+        registers.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_BSIZE_2048 | RCTL_SECRC);
+        Ok(rx)
+    }
+
+    fn descriptor(&self, index: usize) -> *mut RxDescriptor {
+        unsafe { self.ring.as_mut_ptr::<RxDescriptor>().add(index) }
+    }
+}
+
+struct Tx {
+    ring: DmaBuffer,
+    buffers: DmaBuffer,
+    // 次に使うディスクリプタ
+    tail: usize,
+}
+
+impl Tx {
+    fn new(registers: Registers) -> Result<Self, OsError> {
+        let ring = DmaBuffer::new(TX_DESCRIPTORS * size_of::<TxDescriptor>())?;
+        let buffers = DmaBuffer::new(TX_DESCRIPTORS * BUFFER_SIZE)?;
+        let tx = Self { ring, buffers, tail: 0 };
+        for i in 0..TX_DESCRIPTORS {
+            // まだ使っていないものは送信済みとして扱う
+            unsafe { (*tx.descriptor(i)).status = TX_STATUS_DD };
+        }
+        let ring_addr = tx.ring.phys_addr().as_u64();
+        registers.write(REG_TDBAL, ring_addr as u32);
+        registers.write(REG_TDBAH, (ring_addr >> 32) as u32);
+        registers.write(REG_TDLEN, (TX_DESCRIPTORS * size_of::<TxDescriptor>()) as u32);
+        registers.write(REG_TDH, 0);
+        registers.write(REG_TDT, 0);
+        registers.write(REG_TIPG, TIPG_DEFAULT);
+        registers.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        Ok(tx)
+    }
+
+    fn descriptor(&self, index: usize) -> *mut TxDescriptor {
+        unsafe { self.ring.as_mut_ptr::<TxDescriptor>().add(index) }
+    }
+}
+
+pub struct E1000 {
+    name: String,
+    mac: MacAddress,
+    registers: Registers,
+    rx: Mutex<Rx>,
+    tx: Mutex<Tx>,
+}
+
+impl E1000 {
+    // ICRは読むと消え、レベルトリガーの割り込みも下がる。受信はnet::pollで、リンクの状態はlink_upで見るので、
+    // ここでは割り込みを下げてhltから起こすだけにする。割り込みの中ではコンソールのロックを取らない
+    fn handle_interrupt(&self) {
+        self.registers.read(REG_ICR);
+    }
+}
+
+impl NetworkDevice for E1000 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.registers.read(REG_STATUS) & STATUS_LU != 0
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::FrameTooLarge);
+        }
+        if !self.link_up() {
+            return Err(NetError::LinkDown);
+        }
+        let mut tx = self.tx.lock();
+        let index = tx.tail;
+        let descriptor = tx.descriptor(index);
+        if unsafe { read_volatile(&(*descriptor).status) } & TX_STATUS_DD == 0 {
+            return Err(NetError::Busy);
+        }
+        let offset = index * BUFFER_SIZE;
+        tx.buffers.as_mut_slice()[offset..offset + frame.len()].copy_from_slice(frame);
+        unsafe {
+            write_volatile(
+                descriptor,
+                TxDescriptor {
+                    addr: tx.buffers.phys_addr().as_u64() + offset as u64,
+                    length: frame.len() as u16,
+                    cso: 0,
+                    cmd: TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS,
+                    status: 0,
+                    css: 0,
+                    special: 0,
+                },
+            );
+        }
+        tx.tail = (index + 1) % TX_DESCRIPTORS;
+        self.registers.write(REG_TDT, tx.tail as u32);
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut rx = self.rx.lock();
+        loop {
+            let index = rx.next;
+            let descriptor = rx.descriptor(index);
+            let status = unsafe { read_volatile(&(*descriptor).status) };
+            if status & RX_STATUS_DD == 0 {
+                return None;
+            }
+            let (length, errors) =
+                unsafe { (read_volatile(&(*descriptor).length), read_volatile(&(*descriptor).errors)) };
+            // バッファより大きいフレームは受け取らない設定なので、EOPが無いものは壊れている
+            let frame = (status & RX_STATUS_EOP != 0 && errors == 0).then(|| {
+                let offset = index * BUFFER_SIZE;
+                rx.buffers.as_slice()[offset..offset + (length as usize).min(BUFFER_SIZE)].to_vec()
+            });
+
+            // 同じバッファをまた受信に使う
+            unsafe { write_volatile(&mut (*descriptor).status, 0) };
+            self.registers.write(REG_RDT, index as u32);
+            rx.next = (index + 1) % RX_DESCRIPTORS;
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+// ELF64 loader for user programs
+//
+// bootloaderのload_elfと違い、PT_LOADはp_paddrではなくp_vaddrに、プロセスのアドレス空間へ置く
+
+use alloc::vec::Vec;
+use goblin::elf::{header, program_header, Elf};
+use x86_64::VirtAddr;
+
+use crate::memory_manager::Frame;
+use crate::paging::{self, USER_SPACE_END};
+use crate::process::Process;
+use crate::vma::{AddressSpace, Backing, PageFaultError, Permissions, Vma, VmaError};
+
+const USER_STACK_TOP: u64 = USER_SPACE_END;
+const USER_STACK_PAGES: u64 = 64; // 256KiB
+
+// auxvのタイプ
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ElfError {
+    Malformed,
+    NotElf64,
+    NotLittleEndian,
+    NotExecutable,
+    UnsupportedMachine(u16),
+    NoLoadableSegment,
+    InvalidSegmentSize,
+    SegmentOutOfFile,
+    SegmentOutsideUserSpace,
+    SegmentOverlap,
+    EntryOutsideSegments,
+    StackTooLarge,
+    OutOfMemory,
+}
+
+impl From<VmaError> for ElfError {
+    fn from(e: VmaError) -> Self {
+        match e {
+            VmaError::Overlap => ElfError::SegmentOverlap,
+            VmaError::Unaligned => ElfError::Malformed,
+            VmaError::OutOfMemory => ElfError::OutOfMemory,
+        }
+    }
+}
+
+impl From<PageFaultError> for ElfError {
+    fn from(_: PageFaultError) -> Self {
+        ElfError::OutOfMemory
+    }
+}
+
+// Process::runにそのまま渡せる
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    pub argc: u64,
+    pub argv: VirtAddr,
+}
+
+pub fn load(process: &Process, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<LoadedImage, ElfError> {
+    let elf = Elf::parse(image).map_err(|_| ElfError::Malformed)?;
+    if !elf.is_64 {
+        return Err(ElfError::NotElf64);
+    }
+    if !elf.little_endian {
+        return Err(ElfError::NotLittleEndian);
+    }
+    if elf.header.e_type != header::ET_EXEC {
+        return Err(ElfError::NotExecutable);
+    }
+    if elf.header.e_machine != header::EM_X86_64 {
+        return Err(ElfError::UnsupportedMachine(elf.header.e_machine));
+    }
+
+    let mut address_space = process.address_space();
+    let mut entry_is_executable = false;
+    let mut phdr = None;
+    let mut loaded = 0;
+    for ph in elf.program_headers.iter() {
+        if ph.p_type == program_header::PT_PHDR {
+            phdr = Some(ph.p_vaddr);
+        }
+        if ph.p_type != program_header::PT_LOAD || ph.p_memsz == 0 {
+            continue;
+        }
+
+        let range = load_segment(&mut address_space, image, ph)?;
+        if range.contains(&elf.entry) && ph.is_executable() {
+            entry_is_executable = true;
+        }
+        // PT_PHDRがなければ、ファイル先頭を含むセグメントからプログラムヘッダの位置を求める
+        if phdr.is_none() && ph.p_offset == 0 {
+            phdr = Some(ph.p_vaddr + elf.header.e_phoff);
+        }
+        loaded += 1;
+    }
+    if loaded == 0 {
+        return Err(ElfError::NoLoadableSegment);
+    }
+    if !entry_is_executable {
+        return Err(ElfError::EntryOutsideSegments);
+    }
+
+    let auxv = [
+        (AT_PHDR, phdr.unwrap_or(0)),
+        (AT_PHENT, elf.header.e_phentsize as u64),
+        (AT_PHNUM, elf.header.e_phnum as u64),
+        (AT_PAGESZ, Frame::SIZE as u64),
+        (AT_ENTRY, elf.entry),
+    ];
+    let (stack_pointer, user_argv) = setup_stack(&mut address_space, process.name(), argv, envp, &auxv)?;
+
+    Ok(LoadedImage {
+        entry: VirtAddr::new(elf.entry),
+        stack_pointer,
+        argc: argv.len() as u64,
+        argv: user_argv,
+    })
+}
+
+fn load_segment(
+    address_space: &mut AddressSpace,
+    image: &[u8],
+    ph: &program_header::ProgramHeader,
+) -> Result<core::ops::Range<u64>, ElfError> {
+    if ph.p_filesz > ph.p_memsz {
+        return Err(ElfError::InvalidSegmentSize);
+    }
+    let file_end = ph.p_offset.checked_add(ph.p_filesz).ok_or(ElfError::SegmentOutOfFile)?;
+    if file_end > image.len() as u64 {
+        return Err(ElfError::SegmentOutOfFile);
+    }
+    let end = ph.p_vaddr.checked_add(ph.p_memsz).ok_or(ElfError::SegmentOutsideUserSpace)?;
+    let in_user_space = VirtAddr::try_new(ph.p_vaddr)
+        .map(paging::is_user_address)
+        .unwrap_or(false)
+        && end <= USER_SPACE_END;
+    if !in_user_space {
+        return Err(ElfError::SegmentOutsideUserSpace);
+    }
+
+    let start = VirtAddr::new(ph.p_vaddr);
+    let page_start = start.align_down(Frame::SIZE as u64);
+    let page_end = VirtAddr::new(end).align_up(Frame::SIZE as u64);
+    let permissions = Permissions {
+        write: ph.is_write(),
+        execute: ph.is_executable(),
+        user: true,
+    };
+    address_space.insert(Vma::new(page_start..page_end, permissions, Backing::Anonymous))?;
+    // 残り(.bssなど)はゼロ埋めされたページのまま
+    address_space.populate(page_start..page_end)?;
+    address_space.write_bytes(start, &image[ph.p_offset as usize..file_end as usize])?;
+
+    Ok(ph.p_vaddr..end)
+}
+
+// System V ABIの形でスタックを作る。上から文字列、auxv、envp、argv、argcの順に並ぶ
+fn setup_stack(
+    address_space: &mut AddressSpace,
+    name: &str,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<(VirtAddr, VirtAddr), ElfError> {
+    let stack_top = VirtAddr::new(USER_STACK_TOP);
+    let stack_bottom = stack_top - USER_STACK_PAGES * Frame::SIZE as u64;
+    let guard = stack_bottom - Frame::SIZE as u64;
+    address_space.insert(Vma::new(guard..stack_bottom, Permissions::KERNEL_DATA, Backing::Guard { task: name.into() }))?;
+    address_space.insert(Vma::new(
+        stack_bottom..stack_top,
+        Permissions {
+            write: true,
+            execute: false,
+            user: true,
+        },
+        Backing::Anonymous,
+    ))?;
+
+    let mut strings = Vec::new();
+    let mut offsets = Vec::new();
+    for s in argv.iter().chain(envp.iter()) {
+        offsets.push(strings.len() as u64);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let strings_start = (stack_top - strings.len() as u64).align_down(16u64);
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    let pointers = offsets.iter().map(|offset| strings_start.as_u64() + offset);
+    words.extend(pointers.clone().take(argv.len()));
+    words.push(0);
+    words.extend(pointers.skip(argv.len()));
+    words.push(0);
+    for (key, value) in auxv {
+        words.push(*key);
+        words.push(*value);
+    }
+    words.push(AT_NULL);
+    words.push(0);
+
+    let stack_pointer = (strings_start - 8 * words.len() as u64).align_down(16u64);
+    if stack_pointer < stack_bottom {
+        return Err(ElfError::StackTooLarge);
+    }
+    address_space.populate(stack_pointer.align_down(Frame::SIZE as u64)..stack_top)?;
+
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space.write_bytes(stack_pointer, &bytes)?;
+    address_space.write_bytes(strings_start, &strings)?;
+
+    Ok((stack_pointer, stack_pointer + 8u64))
+}
@@ -1,2 +1,8 @@
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub enum OsError {}
+pub enum OsError {
+    NotSupported,
+    DeviceNotFound,
+    DeviceError,
+    Timeout,
+    OutOfMemory,
+}
@@ -0,0 +1,347 @@
+// Virtual File System
+//
+// ファイルシステムはFileSystemとInodeを実装し、mountでパスに繋ぐ。
+// 開いたファイルはFileとしてプロセスのFileTableに入る。パスは常に絶対パスで受け取る
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod file;
+pub mod mount;
+pub mod path;
+pub mod ramfs;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use common::syscall::*;
+
+use crate::block::{self, BlockDevice, BufferCache};
+use crate::println;
+
+pub use file::{File, FileTable, InodeFile, SeekFrom};
+pub use mount::mount;
+
+// マウントしたブロックデバイスごとにキャッシュするブロックの数
+const CACHE_BLOCKS: usize = 256;
+
+pub type InodeNumber = u64;
+pub type DeviceId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    NotEmpty,
+    NoSpace,
+    InvalidArgument,
+    NameTooLong,
+    PermissionDenied,
+    ReadOnly,
+    NotSupported,
+    NotSeekable,
+    TooManyLinks,
+    CrossDevice,
+    Busy,
+    TooManyOpenFiles,
+    BadFileDescriptor,
+    OutOfMemory,
+    Interrupted,
+    Io,
+}
+
+impl FsError {
+    pub fn errno(self) -> u64 {
+        match self {
+            FsError::NotFound => ENOENT,
+            FsError::NotADirectory => ENOTDIR,
+            FsError::IsADirectory => EISDIR,
+            FsError::AlreadyExists => EEXIST,
+            FsError::NotEmpty => ENOTEMPTY,
+            FsError::NoSpace => ENOSPC,
+            FsError::InvalidArgument => EINVAL,
+            FsError::NameTooLong => ENAMETOOLONG,
+            FsError::PermissionDenied => EACCES,
+            FsError::ReadOnly => EROFS,
+            FsError::NotSupported => EOPNOTSUPP,
+            FsError::NotSeekable => ESPIPE,
+            FsError::TooManyLinks => ELOOP,
+            FsError::CrossDevice => EXDEV,
+            FsError::Busy => EBUSY,
+            FsError::TooManyOpenFiles => EMFILE,
+            FsError::BadFileDescriptor => EBADF,
+            FsError::OutOfMemory => ENOMEM,
+            FsError::Interrupted => EINTR,
+            FsError::Io => EIO,
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+impl FileType {
+    pub fn dirent_kind(self) -> u32 {
+        match self {
+            FileType::Regular => DT_REG,
+            FileType::Directory => DT_DIR,
+            FileType::Symlink => DT_LNK,
+            FileType::CharDevice => DT_CHR,
+            FileType::BlockDevice => DT_BLK,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub dev: DeviceId,
+    pub inode: InodeNumber,
+    pub file_type: FileType,
+    pub nlink: u32,
+    pub size: u64,
+    pub blocks: u64, // 512バイト単位。疎なファイルではsizeより小さい
+}
+
+impl Metadata {
+    pub fn to_stat(&self) -> Stat {
+        Stat {
+            dev: self.dev,
+            ino: self.inode,
+            kind: self.file_type.dirent_kind(),
+            nlink: self.nlink,
+            size: self.size,
+            blocks: self.blocks,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: InodeNumber,
+    pub file_type: FileType,
+}
+
+// ファイルシステム上の1つのファイル。実装しない操作はデフォルトでエラーを返す
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    // renameで同じファイルシステムのInodeへ戻すために使う
+    fn as_any(&self) -> &dyn Any;
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(self.not_a_file())
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(self.not_a_file())
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(self.not_a_file())
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    // ディレクトリなら空のときだけ消せる
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    // new_dirは同じファイルシステムのディレクトリ。VFSが確かめてから呼ぶ
+    fn rename(&self, _old_name: &str, _new_dir: &dyn Inode, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    // index番目のエントリ。終わりならNone
+    fn readdir(&self, _index: usize) -> Result<Option<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn not_a_file(&self) -> FsError {
+        match self.metadata().file_type {
+            FileType::Directory => FsError::IsADirectory,
+            _ => FsError::NotSupported,
+        }
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Result<Arc<dyn Inode>, FsError>;
+
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(1);
+
+// ファイルシステムごとに振る。(DeviceId, InodeNumber)でInodeを区別する
+pub fn new_device_id() -> DeviceId {
+    NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn init() {
+    mount("/", ramfs::RamFs::new()).unwrap();
+    mkdir("/dev").unwrap();
+    mount("/dev", devfs::devfs()).unwrap();
+    mkdir("/tmp").unwrap();
+    mount("/tmp", ramfs::RamFs::new()).unwrap();
+    mkdir("/mnt").unwrap();
+}
+
+// deviceにあるファイルシステムを見分けてpathにマウントする
+pub fn mount_device(path: &str, device: Arc<dyn BlockDevice>) -> Result<(), FsError> {
+    let cache = BufferCache::new(device, CACHE_BLOCKS);
+    if let Some(fs) = fat::FatFs::probe(cache.clone())? {
+        return mount(path, fs);
+    }
+    if let Some(fs) = ext2::Ext2Fs::probe(cache)? {
+        return mount(path, fs);
+    }
+    Err(FsError::NotSupported)
+}
+
+// 見分けられるファイルシステムがあるブロックデバイスを、全て/mnt/<name>にマウントする
+pub fn mount_block_devices() {
+    for device in block::devices() {
+        let path = format!("/mnt/{}", device.name());
+        if let Err(e) = mkdir(&path) {
+            println!("fs: failed to create {}: {:?}", path, e);
+            continue;
+        }
+        match mount_device(&path, device) {
+            Ok(()) => println!("fs: mounted {}", path),
+            Err(e) => {
+                if e != FsError::NotSupported {
+                    println!("fs: failed to mount {}: {:?}", path, e);
+                }
+                let _ = unlink(&path);
+            }
+        }
+    }
+}
+
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    path::resolve(path, true)
+}
+
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    Ok(lookup(path)?.metadata())
+}
+
+pub fn open(path: &str, flags: u64) -> Result<Arc<dyn File>, FsError> {
+    let inode = match path::resolve(path, true) {
+        Ok(inode) => inode,
+        Err(FsError::NotFound) if flags & O_CREAT != 0 => {
+            let (parent, name) = path::resolve_parent(path)?;
+            parent.create(&name, FileType::Regular)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    let writable = flags & O_ACCMODE != O_RDONLY;
+    let metadata = inode.metadata();
+    if writable && metadata.file_type == FileType::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    if flags & O_TRUNC != 0 && writable && metadata.file_type == FileType::Regular {
+        inode.truncate(0)?;
+    }
+    Ok(Arc::new(InodeFile::new(inode, flags)))
+}
+
+pub fn create(path: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+    let (parent, name) = path::resolve_parent(path)?;
+    parent.create(&name, file_type)
+}
+
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    create(path, FileType::Directory).map(|_| ())
+}
+
+pub fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    let (parent, name) = path::resolve_parent(path)?;
+    parent.symlink(&name, target).map(|_| ())
+}
+
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (parent, name) = path::resolve_parent(path)?;
+    let inode = parent.lookup(&name)?;
+    if mount::is_mount_point(inode.as_ref()) {
+        return Err(FsError::Busy);
+    }
+    parent.unlink(&name)
+}
+
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let (old_parent, old_name) = path::resolve_parent(old_path)?;
+    let (new_parent, new_name) = path::resolve_parent(new_path)?;
+    if old_parent.metadata().dev != new_parent.metadata().dev {
+        return Err(FsError::CrossDevice);
+    }
+    if mount::is_mount_point(old_parent.lookup(&old_name)?.as_ref()) {
+        return Err(FsError::Busy);
+    }
+    old_parent.rename(&old_name, new_parent.as_ref(), &new_name)
+}
+
+pub fn read_to_end(path: &str) -> Result<Vec<u8>, FsError> {
+    let inode = lookup(path)?;
+    let size = inode.metadata().size as usize;
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(size).map_err(|_| FsError::OutOfMemory)?;
+    buf.resize(size, 0);
+    let mut read = 0;
+    while read < size {
+        match inode.read_at(read as u64, &mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    buf.truncate(read);
+    Ok(buf)
+}
+
+pub fn sync() -> Result<(), FsError> {
+    mount::sync_all()?;
+    Ok(crate::block::sync()?)
+}
@@ -0,0 +1,209 @@
+// devfs
+//
+// ドライバはDeviceを実装してregisterすると、/dev/<name>として開けるようになる
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use super::{
+    new_device_id, DeviceId, DirEntry, File, FileSystem, FileType, FsError, Inode, InodeFile, InodeNumber, Metadata,
+};
+use crate::tty::{self, TtyError};
+
+const ROOT_INODE: InodeNumber = 1;
+
+pub trait Device: Send + Sync {
+    // CharDeviceかBlockDevice
+    fn file_type(&self) -> FileType;
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    // CharDeviceではoffsetは常に0
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError>;
+}
+
+pub struct DevFs {
+    dev: DeviceId,
+    next_inode: AtomicU64,
+    devices: Mutex<BTreeMap<String, Arc<DeviceInode>>>,
+}
+
+struct DevDir(Arc<DevFs>);
+
+struct DeviceInode {
+    dev: DeviceId,
+    inode: InodeNumber,
+    device: Arc<dyn Device>,
+}
+
+lazy_static! {
+    static ref DEVFS: Arc<DevFs> = Arc::new(DevFs::new());
+}
+
+impl DevFs {
+    fn new() -> Self {
+        let devfs = Self {
+            dev: new_device_id(),
+            next_inode: AtomicU64::new(ROOT_INODE + 1),
+            devices: Mutex::new(BTreeMap::new()),
+        };
+        devfs.add("console", Arc::new(Console)).unwrap();
+        devfs.add("null", Arc::new(Null)).unwrap();
+        devfs
+    }
+
+    fn add(&self, name: &str, device: Arc<dyn Device>) -> Result<(), FsError> {
+        let mut devices = self.devices.lock();
+        if devices.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = Arc::new(DeviceInode {
+            dev: self.dev,
+            inode: self.next_inode.fetch_add(1, Ordering::Relaxed),
+            device,
+        });
+        devices.insert(name.into(), inode);
+        Ok(())
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, FsError> {
+        Ok(Arc::new(DevDir(DEVFS.clone())))
+    }
+}
+
+impl Inode for DevDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            dev: self.0.dev,
+            inode: ROOT_INODE,
+            file_type: FileType::Directory,
+            nlink: 2,
+            size: 0,
+            blocks: 0,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let device = self.0.devices.lock().get(name).cloned().ok_or(FsError::NotFound)?;
+        Ok(device)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(&self, _old_name: &str, _new_dir: &dyn Inode, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        Ok(self.0.devices.lock().iter().nth(index).map(|(name, device)| DirEntry {
+            name: name.clone(),
+            inode: device.inode,
+            file_type: device.device.file_type(),
+        }))
+    }
+}
+
+impl Inode for DeviceInode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            dev: self.dev,
+            inode: self.inode,
+            file_type: self.device.file_type(),
+            nlink: 1,
+            size: self.device.size(),
+            blocks: 0,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.device.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        self.device.write_at(offset, buf)
+    }
+}
+
+pub fn devfs() -> Arc<DevFs> {
+    DEVFS.clone()
+}
+
+// どこにマウントされているかに関わらず、名前でデバイスを開く
+pub fn open(name: &str, flags: u64) -> Result<Arc<dyn File>, FsError> {
+    let inode = DEVFS.devices.lock().get(name).cloned().ok_or(FsError::NotFound)?;
+    Ok(Arc::new(InodeFile::new(inode, flags)))
+}
+
+pub fn register(name: &str, device: Arc<dyn Device>) -> Result<(), FsError> {
+    DEVFS.add(name, device)
+}
+
+// コンソールのTTY。読むと入力が届くまで待つ
+struct Console;
+
+impl Device for Console {
+    fn file_type(&self) -> FileType {
+        FileType::CharDevice
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        tty::console().read(buf, true).map_err(|e| match e {
+            TtyError::Interrupted => FsError::Interrupted,
+            TtyError::WouldBlock => FsError::Busy,
+        })
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        tty::console().write(buf);
+        Ok(buf.len())
+    }
+}
+
+struct Null;
+
+impl Device for Null {
+    fn file_type(&self) -> FileType {
+        FileType::CharDevice
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,1292 @@
+// ext2 filesystem
+//
+// スーパーブロックとブロックグループディスクリプタを読み、inodeテーブルとビットマップを使って読み書きする。
+// ファイルの中身は直接・間接(1〜3段)ブロックで辿る。extentやジャーナルなどext3/4の機能には対応しない
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use spin::mutex::{Mutex, MutexGuard};
+
+use super::{new_device_id, DeviceId, DirEntry, FileSystem, FileType, FsError, Inode, InodeNumber, Metadata};
+use crate::block::BufferCache;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+
+// スーパーブロックの中の位置
+const SB_INODES_COUNT: u64 = 0;
+const SB_BLOCKS_COUNT: u64 = 4;
+const SB_FREE_BLOCKS_COUNT: u64 = 12;
+const SB_FREE_INODES_COUNT: u64 = 16;
+const SB_FIRST_DATA_BLOCK: u64 = 20;
+const SB_LOG_BLOCK_SIZE: u64 = 24;
+const SB_BLOCKS_PER_GROUP: u64 = 32;
+const SB_INODES_PER_GROUP: u64 = 40;
+const SB_MAGIC: u64 = 56;
+const SB_REV_LEVEL: u64 = 76;
+const SB_FIRST_INO: u64 = 84;
+const SB_INODE_SIZE: u64 = 88;
+const SB_FEATURE_INCOMPAT: u64 = 96;
+const SB_FEATURE_RO_COMPAT: u64 = 100;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const GROUP_DESC_SIZE: u64 = 32;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const ROOT_INODE: u32 = 2;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFLNK: u16 = 0o120000;
+const S_IFREG: u16 = 0o100000;
+const S_IFBLK: u16 = 0o060000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFCHR: u16 = 0o020000;
+
+// 属性やユーザーはまだ無いので、全部rootのものとして作る
+const DEFAULT_FILE_MODE: u16 = 0o644;
+const DEFAULT_DIR_MODE: u16 = 0o755;
+const DEFAULT_SYMLINK_MODE: u16 = 0o777;
+
+// ディレクトリエントリのfile_type
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_SYMLINK: u8 = 7;
+
+// htreeの索引を持つディレクトリ。索引は更新しないので、書き換えたら外す
+const INDEX_FL: u32 = 0x1000;
+
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT_BLOCK: usize = 12;
+const DOUBLE_INDIRECT_BLOCK: usize = 13;
+const TRIPLE_INDIRECT_BLOCK: usize = 14;
+// i_blockに収まる短いシンボリックリンク
+const FAST_SYMLINK_MAX: usize = 60;
+const DIR_ENTRY_HEADER: usize = 8;
+const NAME_MAX: usize = 255;
+
+#[derive(Clone, Copy)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+pub struct Ext2Fs {
+    dev: DeviceId,
+    this: Weak<Ext2Fs>,
+    cache: Arc<BufferCache>,
+    block_size: u64,
+    blocks_count: u32,
+    inodes_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    first_ino: u32,
+    filetype: bool,
+    large_file: bool,
+    // 知らないro_compatの機能があれば読み込みだけにする
+    read_only: bool,
+    state: Mutex<State>,
+    inodes: Mutex<BTreeMap<u32, Weak<Ext2Inode>>>,
+    // リンクが無くなったが開かれていたinode。閉じられたら次の操作のときに解放する
+    orphans: Mutex<Vec<u32>>,
+}
+
+struct State {
+    groups: Vec<Group>,
+    free_blocks: u32,
+    free_inodes: u32,
+}
+
+// ディスク上のinodeの先頭128バイト。それより後ろの拡張部分は触らない
+#[derive(Clone)]
+struct RawInode([u8; GOOD_OLD_INODE_SIZE]);
+
+impl RawInode {
+    fn u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.0[offset], self.0[offset + 1]])
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.0[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn mode(&self) -> u16 {
+        self.u16(0)
+    }
+
+    fn format(&self) -> u16 {
+        self.mode() & S_IFMT
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.format() {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            _ => FileType::Regular,
+        }
+    }
+
+    // 通常のファイルでは上位32ビットがi_dir_aclのところにある
+    fn size(&self) -> u64 {
+        let high = if self.format() == S_IFREG { self.u32(108) as u64 } else { 0 };
+        (high << 32) | self.u32(4) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.set_u32(4, size as u32);
+        if self.format() == S_IFREG {
+            self.set_u32(108, (size >> 32) as u32);
+        }
+    }
+
+    fn links(&self) -> u16 {
+        self.u16(26)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        self.set_u16(26, links);
+    }
+
+    // 512バイト単位
+    fn sectors(&self) -> u32 {
+        self.u32(28)
+    }
+
+    fn set_sectors(&mut self, sectors: u32) {
+        self.set_u32(28, sectors);
+    }
+
+    fn flags(&self) -> u32 {
+        self.u32(32)
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        self.set_u32(32, flags);
+    }
+
+    fn block(&self, index: usize) -> u32 {
+        self.u32(40 + index * 4)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        self.set_u32(40 + index * 4, block);
+    }
+
+    fn file_acl(&self) -> u32 {
+        self.u32(104)
+    }
+
+    // データブロックを持たず、i_blockに中身を入れたシンボリックリンク
+    fn is_fast_symlink(&self, block_size: u64) -> bool {
+        let acl_sectors = if self.file_acl() != 0 { (block_size / 512) as u32 } else { 0 };
+        self.format() == S_IFLNK && self.sectors() == acl_sectors
+    }
+}
+
+// ディレクトリの中の1つのエントリ
+struct RawDirEntry {
+    // ディレクトリの先頭からの位置
+    position: u64,
+    inode: u32,
+    rec_len: u16,
+    file_type: u8,
+    name: Vec<u8>,
+}
+
+impl RawDirEntry {
+    // このエントリが実際に使っている長さ
+    fn used_len(&self) -> usize {
+        entry_len(self.name.len())
+    }
+}
+
+fn entry_len(name_len: usize) -> usize {
+    (DIR_ENTRY_HEADER + name_len).next_multiple_of(4)
+}
+
+fn dir_file_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Regular => FT_REG_FILE,
+        FileType::Directory => FT_DIR,
+        FileType::Symlink => FT_SYMLINK,
+        FileType::CharDevice => FT_CHRDEV,
+        FileType::BlockDevice => FT_BLKDEV,
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(FsError::InvalidArgument);
+    }
+    if name.len() > NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+impl Ext2Fs {
+    // ext2でなければNone
+    pub fn probe(cache: Arc<BufferCache>) -> Result<Option<Arc<Self>>, FsError> {
+        let mut sb = [0u8; 1024];
+        if cache.read(SUPERBLOCK_OFFSET, &mut sb).is_err() {
+            return Ok(None);
+        }
+        let u16_at = |offset: u64| u16::from_le_bytes([sb[offset as usize], sb[offset as usize + 1]]);
+        let u32_at = |offset: u64| u32::from_le_bytes(sb[offset as usize..offset as usize + 4].try_into().unwrap());
+        if u16_at(SB_MAGIC) != EXT2_MAGIC {
+            return Ok(None);
+        }
+
+        // ディレクトリエントリのrec_lenはu16なので、1ブロックを1エントリにできる4KiBまでにする
+        let log_block_size = u32_at(SB_LOG_BLOCK_SIZE);
+        if log_block_size > 2 {
+            return Ok(None);
+        }
+        let block_size = 1024u64 << log_block_size;
+        let (inode_size, first_ino, incompat, ro_compat) = match u32_at(SB_REV_LEVEL) {
+            0 => (GOOD_OLD_INODE_SIZE as u64, GOOD_OLD_FIRST_INO, 0, 0),
+            _ => (
+                u16_at(SB_INODE_SIZE) as u64,
+                u32_at(SB_FIRST_INO),
+                u32_at(SB_FEATURE_INCOMPAT),
+                u32_at(SB_FEATURE_RO_COMPAT),
+            ),
+        };
+        // extentや64bitなど、知らない形式では中身を正しく読めない
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(FsError::NotSupported);
+        }
+        let blocks_count = u32_at(SB_BLOCKS_COUNT);
+        let inodes_count = u32_at(SB_INODES_COUNT);
+        let first_data_block = u32_at(SB_FIRST_DATA_BLOCK);
+        let blocks_per_group = u32_at(SB_BLOCKS_PER_GROUP);
+        let inodes_per_group = u32_at(SB_INODES_PER_GROUP);
+        let device_size = cache.device().block_count() * cache.block_size() as u64;
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || blocks_count <= first_data_block
+            || (inode_size as usize) < GOOD_OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+            || inode_size > block_size
+            || blocks_count as u64 * block_size > device_size
+        {
+            return Ok(None);
+        }
+
+        // ビットマップは1ブロックなので、グループの大きさはそのビット数まで。
+        // inodeの番号とグループ記述子の表も、グループの数と合っていなければ壊れている
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        let table_offset = (first_data_block as u64 + 1) * block_size;
+        let table_len = group_count as u64 * GROUP_DESC_SIZE;
+        if blocks_per_group as u64 > block_size * 8
+            || inodes_per_group as u64 > block_size * 8
+            || inodes_count as u64 != group_count as u64 * inodes_per_group as u64
+            || table_len.div_ceil(block_size) >= blocks_per_group as u64
+            || table_offset + table_len > blocks_count as u64 * block_size
+        {
+            return Err(FsError::Io);
+        }
+        let mut table = vec![0; table_len as usize];
+        cache.read(table_offset, &mut table)?;
+        let groups: Vec<Group> = table
+            .chunks_exact(GROUP_DESC_SIZE as usize)
+            .map(|desc| {
+                let u16_at = |offset: usize| u16::from_le_bytes([desc[offset], desc[offset + 1]]);
+                let u32_at = |offset: usize| u32::from_le_bytes(desc[offset..offset + 4].try_into().unwrap());
+                Group {
+                    block_bitmap: u32_at(0),
+                    inode_bitmap: u32_at(4),
+                    inode_table: u32_at(8),
+                    free_blocks: u16_at(12),
+                    free_inodes: u16_at(14),
+                    used_dirs: u16_at(16),
+                }
+            })
+            .collect();
+        let inode_table_blocks = (inodes_per_group as u64 * inode_size).div_ceil(block_size);
+        if groups.iter().any(|group| {
+            group.block_bitmap >= blocks_count
+                || group.inode_bitmap >= blocks_count
+                || group.inode_table as u64 + inode_table_blocks > blocks_count as u64
+        }) {
+            return Err(FsError::Io);
+        }
+
+        let read_only = cache.device().read_only() || ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) != 0;
+        let fs = Arc::new_cyclic(|this| Self {
+            dev: new_device_id(),
+            this: this.clone(),
+            cache,
+            block_size,
+            blocks_count,
+            inodes_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            first_ino,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only,
+            state: Mutex::new(State {
+                groups,
+                free_blocks: u32_at(SB_FREE_BLOCKS_COUNT),
+                free_inodes: u32_at(SB_FREE_INODES_COUNT),
+            }),
+            inodes: Mutex::new(BTreeMap::new()),
+            orphans: Mutex::new(Vec::new()),
+        });
+        // rootがディレクトリとして読めない壊れたイメージはマウントしない
+        if fs.node(&fs.state.lock(), ROOT_INODE)?.raw.lock().format() != S_IFDIR {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(Some(fs))
+    }
+
+    // 操作の前に取るロック。閉じられたorphanのinodeもここで解放する
+    fn lock(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock();
+        self.reap_orphans(&mut state);
+        state
+    }
+
+    fn reap_orphans(&self, state: &mut State) {
+        let orphans = core::mem::take(&mut *self.orphans.lock());
+        for ino in orphans {
+            let _ = self.free_inode(state, ino);
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.read_only {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, FsError> {
+        let mut bytes = [0; 4];
+        self.cache.read(offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write_u32(&self, offset: u64, value: u32) -> Result<(), FsError> {
+        Ok(self.cache.write(offset, &value.to_le_bytes())?)
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    fn inode_offset(&self, state: &State, ino: u32) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::Io);
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let group = state.groups.get(group).ok_or(FsError::Io)?;
+        Ok(self.block_offset(group.inode_table) + index * self.inode_size)
+    }
+
+    fn read_inode(&self, state: &State, ino: u32) -> Result<RawInode, FsError> {
+        let mut raw = RawInode([0; GOOD_OLD_INODE_SIZE]);
+        self.cache.read(self.inode_offset(state, ino)?, &mut raw.0)?;
+        Ok(raw)
+    }
+
+    fn write_inode(&self, state: &State, ino: u32, raw: &RawInode) -> Result<(), FsError> {
+        Ok(self.cache.write(self.inode_offset(state, ino)?, &raw.0)?)
+    }
+
+    fn write_group(&self, state: &State, index: usize) -> Result<(), FsError> {
+        let group = &state.groups[index];
+        let mut desc = [0u8; 18];
+        desc[0..4].copy_from_slice(&group.block_bitmap.to_le_bytes());
+        desc[4..8].copy_from_slice(&group.inode_bitmap.to_le_bytes());
+        desc[8..12].copy_from_slice(&group.inode_table.to_le_bytes());
+        desc[12..14].copy_from_slice(&group.free_blocks.to_le_bytes());
+        desc[14..16].copy_from_slice(&group.free_inodes.to_le_bytes());
+        desc[16..18].copy_from_slice(&group.used_dirs.to_le_bytes());
+        let offset = self.block_offset(self.first_data_block + 1) + index as u64 * GROUP_DESC_SIZE;
+        self.cache.write(offset, &desc)?;
+        self.write_u32(SUPERBLOCK_OFFSET + SB_FREE_BLOCKS_COUNT, state.free_blocks)?;
+        self.write_u32(SUPERBLOCK_OFFSET + SB_FREE_INODES_COUNT, state.free_inodes)
+    }
+
+    // ビットマップのブロックから、limitより前で最初に0のビットを探して1にする
+    fn take_bit(&self, bitmap: u32, start: usize, limit: usize) -> Result<Option<usize>, FsError> {
+        let mut bits = vec![0; self.block_size as usize];
+        self.cache.read(self.block_offset(bitmap), &mut bits)?;
+        let limit = limit.min(bits.len() * 8);
+        let found = (start..limit).chain(0..start.min(limit)).find(|&i| bits[i / 8] & (1 << (i % 8)) == 0);
+        if let Some(i) = found {
+            self.cache.write(self.block_offset(bitmap) + (i / 8) as u64, &[bits[i / 8] | (1 << (i % 8))])?;
+        }
+        Ok(found)
+    }
+
+    fn clear_bit(&self, bitmap: u32, index: usize) -> Result<(), FsError> {
+        let offset = self.block_offset(bitmap) + (index / 8) as u64;
+        let mut byte = [0];
+        self.cache.read(offset, &mut byte)?;
+        byte[0] &= !(1 << (index % 8));
+        Ok(self.cache.write(offset, &byte)?)
+    }
+
+    fn blocks_in_group(&self, group: usize) -> usize {
+        let start = self.first_data_block + group as u32 * self.blocks_per_group;
+        (self.blocks_count - start).min(self.blocks_per_group) as usize
+    }
+
+    // goalのinodeと同じグループからブロックを探し、ゼロで埋めて返す
+    fn allocate_block(&self, state: &mut State, ino: u32) -> Result<u32, FsError> {
+        let group_count = state.groups.len();
+        let goal = ((ino - 1) / self.inodes_per_group) as usize % group_count;
+        for i in 0..group_count {
+            let index = (goal + i) % group_count;
+            if state.groups[index].free_blocks == 0 {
+                continue;
+            }
+            let bitmap = state.groups[index].block_bitmap;
+            let Some(bit) = self.take_bit(bitmap, 0, self.blocks_in_group(index))? else {
+                continue;
+            };
+            let block = self.first_data_block + (index as u32) * self.blocks_per_group + bit as u32;
+            state.groups[index].free_blocks -= 1;
+            state.free_blocks = state.free_blocks.saturating_sub(1);
+            self.write_group(state, index)?;
+            self.cache.write(self.block_offset(block), &vec![0; self.block_size as usize])?;
+            return Ok(block);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, state: &mut State, block: u32) -> Result<(), FsError> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Io);
+        }
+        let index = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+        let bit = ((block - self.first_data_block) % self.blocks_per_group) as usize;
+        let bitmap = state.groups.get(index).ok_or(FsError::Io)?.block_bitmap;
+        self.clear_bit(bitmap, bit)?;
+        state.groups[index].free_blocks += 1;
+        state.free_blocks += 1;
+        self.write_group(state, index)
+    }
+
+    // ディレクトリは空きの多いグループに散らし、ファイルは親と同じグループに置く
+    fn allocate_inode(&self, state: &mut State, parent: u32, is_dir: bool) -> Result<u32, FsError> {
+        let group_count = state.groups.len();
+        let goal = if is_dir {
+            (0..group_count).max_by_key(|&i| state.groups[i].free_inodes).unwrap_or(0)
+        } else {
+            ((parent - 1) / self.inodes_per_group) as usize
+        };
+        for i in 0..group_count {
+            let index = (goal + i) % group_count;
+            if state.groups[index].free_inodes == 0 {
+                continue;
+            }
+            // 予約されたinodeは使わない
+            let first = self.first_ino.saturating_sub(index as u32 * self.inodes_per_group + 1) as usize;
+            let limit = self.inodes_per_group as usize;
+            if first >= limit {
+                continue;
+            }
+            let bitmap = state.groups[index].inode_bitmap;
+            let Some(bit) = self.take_bit(bitmap, first, limit)? else {
+                continue;
+            };
+            if bit < first {
+                self.clear_bit(bitmap, bit)?;
+                continue;
+            }
+            state.groups[index].free_inodes -= 1;
+            state.free_inodes = state.free_inodes.saturating_sub(1);
+            if is_dir {
+                state.groups[index].used_dirs += 1;
+            }
+            self.write_group(state, index)?;
+            return Ok(index as u32 * self.inodes_per_group + bit as u32 + 1);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    // 中身のブロックを全て解放してからinodeを空きに戻す
+    fn free_inode(&self, state: &mut State, ino: u32) -> Result<(), FsError> {
+        let mut raw = self.read_inode(state, ino)?;
+        if raw.links() != 0 || raw.mode() == 0 {
+            return Ok(());
+        }
+        if !raw.is_fast_symlink(self.block_size) {
+            self.free_blocks_from(state, &mut raw, 0)?;
+        }
+        let is_dir = raw.format() == S_IFDIR;
+        // 時計が無いのでi_dtimeは入れず、modeを消して空きの印にする
+        raw.set_u16(0, 0);
+        self.write_inode(state, ino, &raw)?;
+
+        let index = ((ino - 1) / self.inodes_per_group) as usize;
+        self.clear_bit(state.groups[index].inode_bitmap, ((ino - 1) % self.inodes_per_group) as usize)?;
+        state.groups[index].free_inodes += 1;
+        state.free_inodes += 1;
+        if is_dir {
+            state.groups[index].used_dirs = state.groups[index].used_dirs.saturating_sub(1);
+        }
+        self.write_group(state, index)
+    }
+
+    // 論理ブロック番号から、i_blockの番号と間接ブロックの中の位置の列を求める
+    fn block_path(&self, index: u64) -> Result<(usize, Vec<u64>), FsError> {
+        let per = self.pointers_per_block();
+        let mut index = index;
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok((index as usize, Vec::new()));
+        }
+        index -= DIRECT_BLOCKS as u64;
+        if index < per {
+            return Ok((INDIRECT_BLOCK, vec![index]));
+        }
+        index -= per;
+        if index < per * per {
+            return Ok((DOUBLE_INDIRECT_BLOCK, vec![index / per, index % per]));
+        }
+        index -= per * per;
+        if index < per * per * per {
+            return Ok((TRIPLE_INDIRECT_BLOCK, vec![index / (per * per), (index / per) % per, index % per]));
+        }
+        Err(FsError::NoSpace)
+    }
+
+    // 割り当てられていなければ0
+    fn map_block(&self, raw: &RawInode, index: u64) -> Result<u32, FsError> {
+        let (slot, path) = self.block_path(index)?;
+        let mut block = raw.block(slot);
+        for position in path {
+            if block == 0 {
+                return Ok(0);
+            }
+            block = self.read_u32(self.block_offset(block) + position * 4)?;
+        }
+        Ok(block)
+    }
+
+    // 途中の間接ブロックも含めて、無ければ確保する
+    fn map_block_allocating(&self, state: &mut State, ino: u32, raw: &mut RawInode, index: u64) -> Result<u32, FsError> {
+        let (slot, path) = self.block_path(index)?;
+        let sectors_per_block = (self.block_size / 512) as u32;
+        let mut block = raw.block(slot);
+        if block == 0 {
+            block = self.allocate_block(state, ino)?;
+            raw.set_block(slot, block);
+            raw.set_sectors(raw.sectors() + sectors_per_block);
+        }
+        for position in path {
+            let pointer = self.block_offset(block) + position * 4;
+            let next = self.read_u32(pointer)?;
+            block = if next == 0 {
+                let new = self.allocate_block(state, ino)?;
+                self.write_u32(pointer, new)?;
+                raw.set_sectors(raw.sectors() + sectors_per_block);
+                new
+            } else {
+                next
+            };
+        }
+        Ok(block)
+    }
+
+    // 論理ブロック番号がfirst以降のブロックを全て解放する
+    fn free_blocks_from(&self, state: &mut State, raw: &mut RawInode, first: u64) -> Result<(), FsError> {
+        let per = self.pointers_per_block();
+        let mut freed = 0;
+        for slot in (first as usize).min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+            let block = raw.block(slot);
+            if block != 0 {
+                self.free_block(state, block)?;
+                raw.set_block(slot, 0);
+                freed += 1;
+            }
+        }
+        let mut base = DIRECT_BLOCKS as u64;
+        for (slot, depth) in [(INDIRECT_BLOCK, 1), (DOUBLE_INDIRECT_BLOCK, 2), (TRIPLE_INDIRECT_BLOCK, 3)] {
+            let span = per.pow(depth);
+            let block = raw.block(slot);
+            if block != 0 && first < base + span {
+                let (count, all) = self.free_tree(state, block, depth, first.saturating_sub(base))?;
+                freed += count;
+                if all {
+                    raw.set_block(slot, 0);
+                }
+            }
+            base += span;
+        }
+        let sectors = freed * (self.block_size / 512) as u32;
+        raw.set_sectors(raw.sectors().saturating_sub(sectors));
+        Ok(())
+    }
+
+    // depth段の間接ブロックblockの下で、firstより後ろを解放する。(解放した数, block自体も解放したか)
+    fn free_tree(&self, state: &mut State, block: u32, depth: u32, first: u64) -> Result<(u32, bool), FsError> {
+        let per = self.pointers_per_block();
+        let span = per.pow(depth - 1);
+        let mut freed = 0;
+        for i in first / span..per {
+            let pointer = self.block_offset(block) + i * 4;
+            let child = self.read_u32(pointer)?;
+            if child == 0 {
+                continue;
+            }
+            let child_first = first.saturating_sub(i * span);
+            let all = if depth == 1 {
+                self.free_block(state, child)?;
+                freed += 1;
+                true
+            } else {
+                let (count, all) = self.free_tree(state, child, depth - 1, child_first)?;
+                freed += count;
+                all
+            };
+            if all {
+                self.write_u32(pointer, 0)?;
+            }
+        }
+        if first == 0 {
+            self.free_block(state, block)?;
+            return Ok((freed + 1, true));
+        }
+        Ok((freed, false))
+    }
+
+    fn read_data(&self, raw: &RawInode, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = (position % self.block_size) as usize;
+            let len = (buf.len() - done).min(self.block_size as usize - within);
+            match self.map_block(raw, position / self.block_size)? {
+                // 穴はゼロとして読む
+                0 => buf[done..done + len].fill(0),
+                block => self.cache.read(self.block_offset(block) + within as u64, &mut buf[done..done + len])?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    // 書けたバイト数を返す。途中で空きが無くなったら、書けたところまでで止める
+    fn write_data(&self, state: &mut State, ino: u32, raw: &mut RawInode, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = (position % self.block_size) as usize;
+            let len = (buf.len() - done).min(self.block_size as usize - within);
+            let block = match self.map_block_allocating(state, ino, raw, position / self.block_size) {
+                Ok(block) => block,
+                Err(FsError::NoSpace) if done > 0 => break,
+                Err(e) => return Err(e),
+            };
+            self.cache.write(self.block_offset(block) + within as u64, &buf[done..done + len])?;
+            done += len;
+        }
+        Ok(done)
+    }
+
+    fn dir_entries(&self, raw: &RawInode) -> Result<Vec<RawDirEntry>, FsError> {
+        let size = raw.size();
+        let mut entries = Vec::new();
+        let mut block = vec![0; self.block_size as usize];
+        let mut base = 0;
+        while base < size {
+            self.read_data(raw, base, &mut block)?;
+            let mut offset = 0;
+            while offset + DIR_ENTRY_HEADER <= block.len() {
+                let inode = u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+                let rec_len = u16::from_le_bytes([block[offset + 4], block[offset + 5]]);
+                let name_len = block[offset + 6] as usize;
+                if (rec_len as usize) < entry_len(0)
+                    || !rec_len.is_multiple_of(4)
+                    || offset + rec_len as usize > block.len()
+                    || entry_len(name_len) > rec_len as usize
+                {
+                    return Err(FsError::Io);
+                }
+                entries.push(RawDirEntry {
+                    position: base + offset as u64,
+                    inode,
+                    rec_len,
+                    file_type: if self.filetype { block[offset + 7] } else { 0 },
+                    name: block[offset + DIR_ENTRY_HEADER..offset + DIR_ENTRY_HEADER + name_len].to_vec(),
+                });
+                offset += rec_len as usize;
+            }
+            base += self.block_size;
+        }
+        Ok(entries)
+    }
+
+    fn write_dir_entry(&self, raw: &RawInode, entry: &RawDirEntry) -> Result<(), FsError> {
+        let mut bytes = vec![0; DIR_ENTRY_HEADER + entry.name.len()];
+        bytes[0..4].copy_from_slice(&entry.inode.to_le_bytes());
+        bytes[4..6].copy_from_slice(&entry.rec_len.to_le_bytes());
+        bytes[6] = entry.name.len() as u8;
+        bytes[7] = if self.filetype { entry.file_type } else { 0 };
+        bytes[DIR_ENTRY_HEADER..].copy_from_slice(&entry.name);
+        let block = self.map_block(raw, entry.position / self.block_size)?;
+        if block == 0 {
+            return Err(FsError::Io);
+        }
+        Ok(self.cache.write(self.block_offset(block) + entry.position % self.block_size, &bytes)?)
+    }
+
+    // 空いているところに入れ、無ければディレクトリを1ブロック伸ばす
+    fn add_dir_entry(
+        &self,
+        state: &mut State,
+        dir: u32,
+        raw: &mut RawInode,
+        name: &str,
+        ino: u32,
+        file_type: u8,
+    ) -> Result<(), FsError> {
+        let needed = entry_len(name.len());
+        let mut new = RawDirEntry { position: 0, inode: ino, rec_len: 0, file_type, name: name.as_bytes().to_vec() };
+        for mut entry in self.dir_entries(raw)? {
+            let used = if entry.inode == 0 { 0 } else { entry.used_len() };
+            if entry.rec_len as usize - used < needed {
+                continue;
+            }
+            if entry.inode == 0 {
+                new.position = entry.position;
+                new.rec_len = entry.rec_len;
+            } else {
+                new.position = entry.position + used as u64;
+                new.rec_len = entry.rec_len - used as u16;
+                entry.rec_len = used as u16;
+                self.write_dir_entry(raw, &entry)?;
+            }
+            self.write_dir_entry(raw, &new)?;
+            return self.touch_dir(state, dir, raw);
+        }
+
+        let size = raw.size();
+        self.map_block_allocating(state, dir, raw, size / self.block_size)?;
+        raw.set_size(size + self.block_size);
+        new.position = size;
+        new.rec_len = self.block_size as u16;
+        self.write_dir_entry(raw, &new)?;
+        self.touch_dir(state, dir, raw)
+    }
+
+    // 前のエントリに併合する。ブロックの先頭なら空きの印(inode 0)にする
+    fn remove_dir_entry(&self, state: &State, dir: u32, raw: &mut RawInode, name: &str) -> Result<(), FsError> {
+        let entries = self.dir_entries(raw)?;
+        let index = entries
+            .iter()
+            .position(|e| e.inode != 0 && e.name == name.as_bytes())
+            .ok_or(FsError::NotFound)?;
+        let entry = &entries[index];
+        if entry.position % self.block_size == 0 {
+            let cleared = RawDirEntry { inode: 0, name: Vec::new(), ..*entry };
+            self.write_dir_entry(raw, &cleared)?;
+        } else {
+            let previous = &entries[index - 1];
+            let merged = RawDirEntry {
+                rec_len: previous.rec_len + entry.rec_len,
+                name: previous.name.clone(),
+                ..*previous
+            };
+            self.write_dir_entry(raw, &merged)?;
+        }
+        self.touch_dir(state, dir, raw)
+    }
+
+    fn touch_dir(&self, state: &State, dir: u32, raw: &mut RawInode) -> Result<(), FsError> {
+        raw.set_flags(raw.flags() & !INDEX_FL);
+        self.write_inode(state, dir, raw)
+    }
+
+    fn node(&self, state: &State, ino: u32) -> Result<Arc<Ext2Inode>, FsError> {
+        let mut inodes = self.inodes.lock();
+        if let Some(node) = inodes.get(&ino).and_then(Weak::upgrade) {
+            return Ok(node);
+        }
+        let raw = self.read_inode(state, ino)?;
+        if raw.mode() == 0 {
+            return Err(FsError::Io);
+        }
+        inodes.retain(|_, node| node.strong_count() > 0);
+        let node = Arc::new(Ext2Inode { fs: self.this.upgrade().unwrap(), ino, raw: Mutex::new(raw) });
+        inodes.insert(ino, Arc::downgrade(&node));
+        Ok(node)
+    }
+
+    // 新しいinodeを確保して初期化する
+    fn new_node(&self, state: &mut State, parent: u32, mode: u16) -> Result<Arc<Ext2Inode>, FsError> {
+        let ino = self.allocate_inode(state, parent, mode & S_IFMT == S_IFDIR)?;
+        self.cache.write(self.inode_offset(state, ino)?, &vec![0; self.inode_size as usize])?;
+        let mut raw = RawInode([0; GOOD_OLD_INODE_SIZE]);
+        raw.set_u16(0, mode);
+        raw.set_links(1);
+        self.write_inode(state, ino, &raw)?;
+        self.node(state, ino)
+    }
+
+    // dirがancestorそのものか、その下にあるか
+    fn is_within(&self, state: &State, dir: u32, ancestor: u32) -> Result<bool, FsError> {
+        let mut current = dir;
+        for _ in 0..self.inodes_count {
+            if current == ancestor {
+                return Ok(true);
+            }
+            if current == ROOT_INODE {
+                return Ok(false);
+            }
+            let raw = self.read_inode(state, current)?;
+            current = self
+                .dir_entries(&raw)?
+                .into_iter()
+                .find(|e| e.inode != 0 && e.name == b"..")
+                .ok_or(FsError::Io)?
+                .inode;
+        }
+        Err(FsError::Io)
+    }
+}
+
+// 最後のInodeが閉じられたときに、残っているorphanを解放して書き戻す
+impl Drop for Ext2Fs {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, FsError> {
+        let state = self.state.lock();
+        Ok(self.node(&state, ROOT_INODE)?)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        if self.read_only {
+            return Ok(());
+        }
+        let _state = self.lock();
+        Ok(self.cache.sync()?)
+    }
+}
+
+struct Ext2Inode {
+    fs: Arc<Ext2Fs>,
+    ino: u32,
+    raw: Mutex<RawInode>,
+}
+
+impl Ext2Inode {
+    fn is_dir(&self) -> bool {
+        self.raw.lock().format() == S_IFDIR
+    }
+
+    fn check_dir(&self) -> Result<(), FsError> {
+        let raw = self.raw.lock();
+        if raw.format() != S_IFDIR {
+            return Err(FsError::NotADirectory);
+        }
+        // 削除されたディレクトリ
+        if raw.links() == 0 {
+            return Err(FsError::NotFound);
+        }
+        Ok(())
+    }
+
+    fn check_regular(&self) -> Result<(), FsError> {
+        match self.raw.lock().format() {
+            S_IFREG => Ok(()),
+            S_IFDIR => Err(FsError::IsADirectory),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn find(&self, name: &str) -> Result<RawDirEntry, FsError> {
+        let raw = self.raw.lock();
+        self.fs
+            .dir_entries(&raw)?
+            .into_iter()
+            .find(|e| e.inode != 0 && e.name == name.as_bytes())
+            .ok_or(FsError::NotFound)
+    }
+
+    fn add_links(&self, state: &State, delta: i32) -> Result<(), FsError> {
+        let mut raw = self.raw.lock();
+        let links = (raw.links() as i32 + delta).max(0) as u16;
+        raw.set_links(links);
+        self.fs.write_inode(state, self.ino, &raw)
+    }
+
+    fn is_empty_dir(&self) -> Result<bool, FsError> {
+        let raw = self.raw.lock();
+        Ok(self
+            .fs
+            .dir_entries(&raw)?
+            .iter()
+            .all(|e| e.inode == 0 || e.name == b"." || e.name == b".."))
+    }
+
+    // nameのリンクを外す。ディレクトリなら"."の分と親の".."の分も減らす
+    fn drop_link(&self, state: &State, target: &Ext2Inode) -> Result<(), FsError> {
+        if target.is_dir() {
+            target.add_links(state, -2)?;
+            self.add_links(state, -1)
+        } else {
+            target.add_links(state, -1)
+        }
+    }
+
+    fn create_node(&self, state: &mut State, name: &str, mode: u16) -> Result<Arc<Ext2Inode>, FsError> {
+        check_name(name)?;
+        self.fs.check_writable()?;
+        self.check_dir()?;
+        if self.find(name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        let node = self.fs.new_node(state, self.ino, mode)?;
+        let file_type = node.raw.lock().file_type();
+        let mut raw = self.raw.lock();
+        if let Err(e) = self.fs.add_dir_entry(state, self.ino, &mut raw, name, node.ino, dir_file_type(file_type)) {
+            drop(raw);
+            node.add_links(state, -1)?;
+            return Err(e);
+        }
+        Ok(node)
+    }
+}
+
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        let raw = self.raw.get_mut();
+        if raw.links() == 0 && raw.mode() != 0 {
+            self.fs.orphans.lock().push(self.ino);
+        }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        let raw = self.raw.lock();
+        Metadata {
+            dev: self.fs.dev,
+            inode: self.ino as InodeNumber,
+            file_type: raw.file_type(),
+            nlink: raw.links() as u32,
+            size: raw.size(),
+            blocks: raw.sectors() as u64,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.check_regular()?;
+        let raw = self.raw.lock();
+        let size = raw.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        self.fs.read_data(&raw, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        self.check_regular()?;
+        self.fs.check_writable()?;
+        let end = offset.checked_add(buf.len() as u64).ok_or(FsError::InvalidArgument)?;
+        if !self.fs.large_file && end > i32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.fs.lock();
+        let mut raw = self.raw.lock();
+        let result = self.fs.write_data(&mut state, self.ino, &mut raw, offset, buf);
+        if let Ok(written) = result {
+            let size = raw.size().max(offset + written as u64);
+            raw.set_size(size);
+        }
+        // 途中で失敗しても、確保したブロックはinodeに記録しておく
+        self.fs.write_inode(&state, self.ino, &raw)?;
+        result
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), FsError> {
+        self.check_regular()?;
+        self.fs.check_writable()?;
+        if !self.fs.large_file && new_size > i32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let mut state = self.fs.lock();
+        let mut raw = self.raw.lock();
+        let size = raw.size();
+        if new_size < size {
+            let block_size = self.fs.block_size;
+            self.fs.free_blocks_from(&mut state, &mut raw, new_size.div_ceil(block_size))?;
+            // 後でまた伸ばしたときに古い中身が見えないよう、最後のブロックの残りをゼロにする
+            let tail = new_size % block_size;
+            if tail != 0 {
+                let block = self.fs.map_block(&raw, new_size / block_size)?;
+                if block != 0 {
+                    let zeros = vec![0; (block_size - tail) as usize];
+                    self.fs.cache.write(self.fs.block_offset(block) + tail, &zeros)?;
+                }
+            }
+        }
+        // 伸ばすときは穴にしておく
+        raw.set_size(new_size);
+        self.fs.write_inode(&state, self.ino, &raw)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let state = self.fs.lock();
+        self.check_dir()?;
+        let entry = self.find(name)?;
+        Ok(self.fs.node(&state, entry.inode)?)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let mut state = self.fs.lock();
+        match file_type {
+            FileType::Regular => Ok(self.create_node(&mut state, name, S_IFREG | DEFAULT_FILE_MODE)?),
+            FileType::Directory => {
+                let node = self.create_node(&mut state, name, S_IFDIR | DEFAULT_DIR_MODE)?;
+                let result = {
+                    let mut raw = node.raw.lock();
+                    let fs = &self.fs;
+                    fs.add_dir_entry(&mut state, node.ino, &mut raw, ".", node.ino, FT_DIR)
+                        .and_then(|_| fs.add_dir_entry(&mut state, node.ino, &mut raw, "..", self.ino, FT_DIR))
+                };
+                if let Err(e) = result {
+                    let mut raw = self.raw.lock();
+                    self.fs.remove_dir_entry(&state, self.ino, &mut raw, name)?;
+                    drop(raw);
+                    node.add_links(&state, -1)?;
+                    return Err(e);
+                }
+                // "."と親の".."の分
+                node.add_links(&state, 1)?;
+                self.add_links(&state, 1)?;
+                Ok(node)
+            }
+            _ => Err(FsError::NotSupported),
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if target.is_empty() || target.len() >= self.fs.block_size as usize {
+            return Err(FsError::NameTooLong);
+        }
+        let mut state = self.fs.lock();
+        let node = self.create_node(&mut state, name, S_IFLNK | DEFAULT_SYMLINK_MODE)?;
+        let mut raw = node.raw.lock();
+        if target.len() < FAST_SYMLINK_MAX {
+            raw.0[40..40 + target.len()].copy_from_slice(target.as_bytes());
+        } else {
+            self.fs.write_data(&mut state, node.ino, &mut raw, 0, target.as_bytes())?;
+        }
+        raw.set_size(target.len() as u64);
+        self.fs.write_inode(&state, node.ino, &raw)?;
+        drop(raw);
+        Ok(node)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidArgument);
+        }
+        self.fs.check_writable()?;
+        let mut state = self.fs.lock();
+        self.check_dir()?;
+        let entry = self.find(name)?;
+        let target = self.fs.node(&state, entry.inode)?;
+        if target.is_dir() && !target.is_empty_dir()? {
+            return Err(FsError::NotEmpty);
+        }
+        {
+            let mut raw = self.raw.lock();
+            self.fs.remove_dir_entry(&state, self.ino, &mut raw, name)?;
+        }
+        self.drop_link(&state, &target)?;
+        // 誰も開いていなければここで解放される
+        drop(target);
+        self.fs.reap_orphans(&mut state);
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_dir: &dyn Inode, new_name: &str) -> Result<(), FsError> {
+        let new_dir = new_dir
+            .as_any()
+            .downcast_ref::<Ext2Inode>()
+            .filter(|dir| Arc::ptr_eq(&dir.fs, &self.fs))
+            .ok_or(FsError::CrossDevice)?;
+        if [old_name, new_name].iter().any(|&name| name == "." || name == "..") {
+            return Err(FsError::InvalidArgument);
+        }
+        check_name(new_name)?;
+        self.fs.check_writable()?;
+
+        let fs = &self.fs;
+        let mut state = fs.lock();
+        self.check_dir()?;
+        new_dir.check_dir()?;
+        let entry = self.find(old_name)?;
+        let node = fs.node(&state, entry.inode)?;
+        let is_dir = node.is_dir();
+        let moved = self.ino != new_dir.ino;
+        let existing = match new_dir.find(new_name) {
+            Ok(existing) => Some(fs.node(&state, existing.inode)?),
+            Err(FsError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(existing) = &existing {
+            if existing.ino == node.ino {
+                return Ok(());
+            }
+            match (is_dir, existing.is_dir()) {
+                (true, false) => return Err(FsError::NotADirectory),
+                (false, true) => return Err(FsError::IsADirectory),
+                (true, true) if !existing.is_empty_dir()? => return Err(FsError::NotEmpty),
+                _ => {}
+            }
+        }
+        // ディレクトリを自分の下へは動かせない
+        if is_dir && moved && fs.is_within(&state, new_dir.ino, node.ino)? {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let file_type = if self.fs.filetype { entry.file_type } else { 0 };
+        match &existing {
+            // 置き換えるときは、エントリの指すinodeだけを書き換える
+            Some(existing) => {
+                let mut raw = new_dir.raw.lock();
+                let mut target = fs
+                    .dir_entries(&raw)?
+                    .into_iter()
+                    .find(|e| e.inode != 0 && e.name == new_name.as_bytes())
+                    .ok_or(FsError::NotFound)?;
+                target.inode = node.ino;
+                target.file_type = file_type;
+                fs.write_dir_entry(&raw, &target)?;
+                fs.touch_dir(&state, new_dir.ino, &mut raw)?;
+                drop(raw);
+                new_dir.drop_link(&state, existing)?;
+            }
+            None => {
+                let mut raw = new_dir.raw.lock();
+                fs.add_dir_entry(&mut state, new_dir.ino, &mut raw, new_name, node.ino, file_type)?;
+            }
+        }
+        {
+            let mut raw = self.raw.lock();
+            fs.remove_dir_entry(&state, self.ino, &mut raw, old_name)?;
+        }
+
+        if is_dir && moved {
+            let mut raw = node.raw.lock();
+            let mut dot_dot = fs
+                .dir_entries(&raw)?
+                .into_iter()
+                .find(|e| e.inode != 0 && e.name == b"..")
+                .ok_or(FsError::Io)?;
+            dot_dot.inode = new_dir.ino;
+            fs.write_dir_entry(&raw, &dot_dot)?;
+            fs.touch_dir(&state, node.ino, &mut raw)?;
+            drop(raw);
+            self.add_links(&state, -1)?;
+            new_dir.add_links(&state, 1)?;
+        }
+        drop(existing);
+        fs.reap_orphans(&mut state);
+        Ok(())
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let state = self.fs.lock();
+        self.check_dir()?;
+        let entries = {
+            let raw = self.raw.lock();
+            self.fs.dir_entries(&raw)?
+        };
+        let Some(entry) = entries.into_iter().filter(|e| e.inode != 0).nth(index) else {
+            return Ok(None);
+        };
+        let file_type = match entry.file_type {
+            FT_REG_FILE => FileType::Regular,
+            FT_DIR => FileType::Directory,
+            FT_SYMLINK => FileType::Symlink,
+            FT_CHRDEV => FileType::CharDevice,
+            FT_BLKDEV => FileType::BlockDevice,
+            // FILETYPEが無ければinodeを読む
+            _ => self.fs.read_inode(&state, entry.inode)?.file_type(),
+        };
+        Ok(Some(DirEntry {
+            name: String::from_utf8_lossy(&entry.name).into_owned(),
+            inode: entry.inode as InodeNumber,
+            file_type,
+        }))
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        let raw = self.raw.lock();
+        if raw.format() != S_IFLNK {
+            return Err(FsError::InvalidArgument);
+        }
+        let size = raw.size() as usize;
+        let target = if raw.is_fast_symlink(self.fs.block_size) {
+            raw.0[40..40 + size.min(FAST_SYMLINK_MAX)].to_vec()
+        } else {
+            let mut target = vec![0; size];
+            self.fs.read_data(&raw, 0, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| FsError::Io)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.fs.sync()
+    }
+}
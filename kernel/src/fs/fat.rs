@@ -0,0 +1,1304 @@
+// FAT filesystem
+//
+// FAT12/16/32を読み書きする。長いファイル名(VFAT)に対応し、FAT32ではFSInfoの空きクラスタ数も更新する。
+// FATにはinode番号が無いので、ディレクトリは先頭クラスタ、ファイルはショートエントリの位置から番号をつける
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::ops::Range;
+use common::syscall::NAME_MAX;
+use spin::mutex::{Mutex, MutexGuard};
+
+use super::{new_device_id, DeviceId, DirEntry, FileSystem, FileType, FsError, Inode, InodeNumber, Metadata};
+use crate::block::BufferCache;
+
+const ENTRY_SIZE: usize = 32;
+const DELETED: u8 = 0xe5;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+// ショートエントリの12バイト目。Windows NTが使う、名前と拡張子が小文字であることの印
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LONG_NAME_MAX: usize = 255;
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: u64 = 488;
+const FSINFO_NEXT_FREE: u64 = 492;
+
+// RTCがまだ無いので、日付は1980-01-01にしておく
+const FAT_DATE: u16 = (1 << 5) | 1;
+
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+// ディレクトリのinode番号はクラスタ番号(28ビット以内)なので、ファイルはそれより上を使う
+const FILE_INODE_BASE: InodeNumber = 1 << 32;
+// FAT12/16のルートディレクトリにはクラスタが無い
+const FIXED_ROOT_INODE: InodeNumber = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DirLocation {
+    // FAT12/16のルートディレクトリ。大きさが決まっていて伸ばせない
+    FixedRoot,
+    Clusters(u32),
+}
+
+pub struct FatFs {
+    dev: DeviceId,
+    this: Weak<FatFs>,
+    cache: Arc<BufferCache>,
+    fat_type: FatType,
+    // 以下の位置と大きさはバイト単位
+    fat_offset: u64,
+    fat_size: u64,
+    fat_count: u64,
+    root_dir_offset: u64,
+    root_dir_entries: u64,
+    data_offset: u64,
+    cluster_size: u64,
+    // データ領域のクラスタ数。クラスタ番号は2から始まる
+    cluster_count: u32,
+    // FAT32のルートディレクトリの先頭クラスタ。FAT12/16では0
+    root_cluster: u32,
+    fsinfo_offset: Option<u64>,
+    state: Mutex<State>,
+    inodes: Mutex<BTreeMap<InodeNumber, Weak<FatInode>>>,
+    // 開いたまま削除されたファイルのクラスタ。閉じられたら次の操作のときに解放する
+    orphans: Mutex<Vec<u32>>,
+}
+
+struct State {
+    free_count: u32,
+    next_free: u32,
+    fsinfo_dirty: bool,
+}
+
+// ディレクトリの中の1つのファイル。長い名前があればそのエントリも含む
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    raw: [u8; ENTRY_SIZE],
+    // ショートエントリの位置
+    offset: u64,
+    // 長い名前のエントリとショートエントリの位置
+    slots: Vec<u64>,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.raw[11] & ATTR_DIRECTORY != 0
+    }
+
+    fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+
+    fn first_cluster(&self) -> u32 {
+        first_cluster(&self.raw)
+    }
+
+    fn size(&self) -> u32 {
+        u32::from_le_bytes(self.raw[28..32].try_into().unwrap())
+    }
+}
+
+fn first_cluster(raw: &[u8]) -> u32 {
+    let high = u16::from_le_bytes([raw[20], raw[21]]) as u32;
+    let low = u16::from_le_bytes([raw[26], raw[27]]) as u32;
+    (high << 16) | low
+}
+
+fn set_first_cluster(raw: &mut [u8], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn new_short_entry(name: &[u8; 11], attr: u8, cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0; ENTRY_SIZE];
+    raw[..11].copy_from_slice(name);
+    raw[11] = attr;
+    for offset in [16, 18, 24] {
+        raw[offset..offset + 2].copy_from_slice(&FAT_DATE.to_le_bytes());
+    }
+    set_first_cluster(&mut raw, cluster);
+    raw
+}
+
+impl FatFs {
+    // FATでなければNone
+    pub fn probe(cache: Arc<BufferCache>) -> Result<Option<Arc<Self>>, FsError> {
+        let mut boot = [0u8; 512];
+        if cache.read(0, &mut boot).is_err() {
+            return Ok(None);
+        }
+        if boot[510..512] != [0x55, 0xaa] || !matches!(boot[0], 0xeb | 0xe9) {
+            return Ok(None);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([boot[i], boot[i + 1]]) as u64;
+        let u32_at = |i: usize| u32::from_le_bytes(boot[i..i + 4].try_into().unwrap()) as u64;
+
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = u16_at(14);
+        let fat_count = boot[16] as u64;
+        let root_dir_entries = u16_at(17);
+        let total_sectors = if u16_at(19) != 0 { u16_at(19) } else { u32_at(32) };
+        let fat_sectors = if u16_at(22) != 0 { u16_at(22) } else { u32_at(36) };
+        if !(512..=4096).contains(&bytes_per_sector)
+            || !bytes_per_sector.is_power_of_two()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Ok(None);
+        }
+
+        let root_dir_sectors = (root_dir_entries * ENTRY_SIZE as u64).div_ceil(bytes_per_sector);
+        let data_sector = reserved_sectors + fat_count * fat_sectors + root_dir_sectors;
+        let device_size = cache.device().block_count() * cache.block_size() as u64;
+        if total_sectors <= data_sector || total_sectors * bytes_per_sector > device_size {
+            return Ok(None);
+        }
+        let cluster_count = (total_sectors - data_sector) / sectors_per_cluster;
+        if cluster_count == 0 {
+            return Ok(None);
+        }
+        // 種類はクラスタ数だけで決まる
+        let fat_type = match cluster_count {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let (root_cluster, fsinfo_offset) = match fat_type {
+            FatType::Fat32 => {
+                let fsinfo_sector = u16_at(48);
+                let fsinfo = (fsinfo_sector != 0 && fsinfo_sector != 0xffff).then(|| fsinfo_sector * bytes_per_sector);
+                (u32_at(44) as u32, fsinfo)
+            }
+            _ => (0, None),
+        };
+
+        let fs = Arc::new_cyclic(|this| Self {
+            dev: new_device_id(),
+            this: this.clone(),
+            cache,
+            fat_type,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            fat_size: fat_sectors * bytes_per_sector,
+            fat_count,
+            root_dir_offset: (reserved_sectors + fat_count * fat_sectors) * bytes_per_sector,
+            root_dir_entries,
+            data_offset: data_sector * bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            cluster_count: cluster_count as u32,
+            root_cluster,
+            fsinfo_offset,
+            state: Mutex::new(State { free_count: 0, next_free: 2, fsinfo_dirty: false }),
+            inodes: Mutex::new(BTreeMap::new()),
+            orphans: Mutex::new(Vec::new()),
+        });
+        if fat_type == FatType::Fat32 && !fs.is_cluster(root_cluster) {
+            return Ok(None);
+        }
+        fs.load_free_count()?;
+        Ok(Some(fs))
+    }
+
+    // FSInfoが正しければそれを使い、無ければFATを数える
+    fn load_free_count(&self) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        if let Some(offset) = self.fsinfo_offset {
+            let lead = self.read_u32(offset)?;
+            let signature = self.read_u32(offset + 484)?;
+            let free_count = self.read_u32(offset + FSINFO_FREE_COUNT)?;
+            let next_free = self.read_u32(offset + FSINFO_NEXT_FREE)?;
+            if lead == FSINFO_LEAD_SIGNATURE && signature == FSINFO_STRUCT_SIGNATURE && free_count <= self.cluster_count {
+                state.free_count = free_count;
+                if self.is_cluster(next_free) {
+                    state.next_free = next_free;
+                }
+                return Ok(());
+            }
+        }
+        let mut free_count = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(cluster)? == 0 {
+                free_count += 1;
+            }
+        }
+        state.free_count = free_count;
+        state.fsinfo_dirty = self.fsinfo_offset.is_some();
+        Ok(())
+    }
+
+    // 操作の前に取るロック。閉じられた削除済みファイルのクラスタもここで解放する
+    fn lock(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock();
+        let orphans = core::mem::take(&mut *self.orphans.lock());
+        for cluster in orphans {
+            let _ = self.free_chain(&mut state, cluster);
+        }
+        state
+    }
+
+    fn read_u16(&self, offset: u64) -> Result<u16, FsError> {
+        let mut bytes = [0; 2];
+        self.cache.read(offset, &mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, FsError> {
+        let mut bytes = [0; 4];
+        self.cache.read(offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let n = cluster as u64;
+        Ok(match self.fat_type {
+            FatType::Fat12 => {
+                let value = self.read_u16(self.fat_offset + n + n / 2)?;
+                if cluster & 1 == 1 {
+                    (value >> 4) as u32
+                } else {
+                    (value & 0xfff) as u32
+                }
+            }
+            FatType::Fat16 => self.read_u16(self.fat_offset + n * 2)? as u32,
+            FatType::Fat32 => self.read_u32(self.fat_offset + n * 4)? & 0x0fff_ffff,
+        })
+    }
+
+    // 全てのFATのコピーを書き換える
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let n = cluster as u64;
+        for copy in 0..self.fat_count {
+            let base = self.fat_offset + copy * self.fat_size;
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let offset = base + n + n / 2;
+                    let old = self.read_u16(offset)?;
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000f) | ((value as u16) << 4)
+                    } else {
+                        (old & 0xf000) | (value as u16 & 0x0fff)
+                    };
+                    self.cache.write(offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.cache.write(base + n * 2, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // 上位4ビットは予約されているので残す
+                    let old = self.read_u32(base + n * 4)?;
+                    let new = (old & 0xf000_0000) | (value & 0x0fff_ffff);
+                    self.cache.write(base + n * 4, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        value >= self.end_of_chain() - 7
+    }
+
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        loop {
+            // 壊れたFATでループしないように
+            if !self.is_cluster(cluster) || chain.len() >= self.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            chain.push(cluster);
+            let next = self.fat_entry(cluster)?;
+            if self.is_end_of_chain(next) {
+                return Ok(chain);
+            }
+            cluster = next;
+        }
+    }
+
+    // 空いているクラスタをゼロで埋めて確保し、prevの後ろに繋ぐ
+    fn allocate_cluster(&self, state: &mut State, prev: Option<u32>) -> Result<u32, FsError> {
+        for i in 0..self.cluster_count {
+            let cluster = 2 + (state.next_free - 2 + i) % self.cluster_count;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+            self.cache.write(self.cluster_offset(cluster), &vec![0; self.cluster_size as usize])?;
+            self.set_fat_entry(cluster, self.end_of_chain())?;
+            if let Some(prev) = prev {
+                self.set_fat_entry(prev, cluster)?;
+            }
+            state.free_count = state.free_count.saturating_sub(1);
+            state.next_free = if self.is_cluster(cluster + 1) { cluster + 1 } else { 2 };
+            state.fsinfo_dirty = true;
+            return Ok(cluster);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_clusters(&self, state: &mut State, clusters: &[u32]) -> Result<(), FsError> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, 0)?;
+            state.free_count += 1;
+        }
+        state.fsinfo_dirty = true;
+        Ok(())
+    }
+
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<(), FsError> {
+        if first == 0 {
+            return Ok(());
+        }
+        let chain = self.chain(first)?;
+        self.free_clusters(state, &chain)
+    }
+
+    // クラスタを跨ぐ範囲を、デバイス上で連続した区間に分ける
+    fn segments(&self, chain: &[u32], offset: u64, len: usize) -> Vec<(u64, Range<usize>)> {
+        let mut segments: Vec<(u64, Range<usize>)> = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position % self.cluster_size;
+            let chunk = ((self.cluster_size - within) as usize).min(len - done);
+            let device_offset = self.cluster_offset(chain[(position / self.cluster_size) as usize]) + within;
+            match segments.last_mut() {
+                Some((start, range)) if *start + range.len() as u64 == device_offset => range.end += chunk,
+                _ => segments.push((device_offset, done..done + chunk)),
+            }
+            done += chunk;
+        }
+        segments
+    }
+
+    fn dir_cluster(&self, cluster: u32) -> u32 {
+        // ".."のエントリではルートディレクトリは0
+        if cluster == 0 {
+            self.root_cluster
+        } else {
+            cluster
+        }
+    }
+
+    fn is_root(&self, cluster: u32) -> bool {
+        self.dir_cluster(cluster) == self.root_cluster
+    }
+
+    fn dir_location(&self, cluster: u32) -> DirLocation {
+        match self.dir_cluster(cluster) {
+            0 => DirLocation::FixedRoot,
+            cluster => DirLocation::Clusters(cluster),
+        }
+    }
+
+    fn dir_inode(&self, cluster: u32) -> InodeNumber {
+        match self.dir_cluster(cluster) {
+            0 => FIXED_ROOT_INODE,
+            cluster => cluster as InodeNumber,
+        }
+    }
+
+    fn file_inode(offset: u64) -> InodeNumber {
+        FILE_INODE_BASE + offset / ENTRY_SIZE as u64
+    }
+
+    fn entry_inode(&self, entry: &Entry) -> InodeNumber {
+        if entry.is_dir() {
+            self.dir_inode(entry.first_cluster())
+        } else {
+            Self::file_inode(entry.offset)
+        }
+    }
+
+    // ディレクトリの全てのスロットを、位置と中身の組で返す
+    fn read_dir(&self, dir: DirLocation) -> Result<Vec<(u64, [u8; ENTRY_SIZE])>, FsError> {
+        let regions = match dir {
+            DirLocation::FixedRoot => vec![(self.root_dir_offset, self.root_dir_entries * ENTRY_SIZE as u64)],
+            DirLocation::Clusters(first) => self
+                .chain(first)?
+                .into_iter()
+                .map(|cluster| (self.cluster_offset(cluster), self.cluster_size))
+                .collect(),
+        };
+        let mut slots = Vec::new();
+        for (offset, len) in regions {
+            let mut data = vec![0; len as usize];
+            self.cache.read(offset, &mut data)?;
+            for (i, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+                slots.push((offset + (i * ENTRY_SIZE) as u64, raw.try_into().unwrap()));
+            }
+        }
+        Ok(slots)
+    }
+
+    // ボリュームラベルを除いたエントリ。"."と".."も含む
+    fn entries(&self, dir: DirLocation) -> Result<Vec<Entry>, FsError> {
+        let mut entries = Vec::new();
+        let mut long_name = LongName::default();
+        for (offset, raw) in self.read_dir(dir)? {
+            match raw[0] {
+                0 => break,
+                DELETED => {
+                    long_name = LongName::default();
+                    continue;
+                }
+                _ => {}
+            }
+            if raw[11] & 0x3f == ATTR_LONG_NAME {
+                long_name.push(offset, &raw);
+                continue;
+            }
+            let (name, mut slots) = core::mem::take(&mut long_name).finish(short_name_checksum(&raw));
+            if raw[11] & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            slots.push(offset);
+            entries.push(Entry {
+                name: name.unwrap_or_else(|| short_name_to_string(&raw)),
+                raw,
+                offset,
+                slots,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn find(&self, dir: DirLocation, name: &str) -> Result<Entry, FsError> {
+        self.entries(dir)?
+            .into_iter()
+            .find(|e| !e.is_dot() && e.name.eq_ignore_ascii_case(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn is_empty_dir(&self, cluster: u32) -> Result<bool, FsError> {
+        Ok(self.entries(self.dir_location(cluster))?.iter().all(Entry::is_dot))
+    }
+
+    // rawの名前のところを埋めてdirに書き込み、ショートエントリの位置を返す
+    fn add_entry(
+        &self,
+        state: &mut State,
+        dir: DirLocation,
+        name: &str,
+        mut raw: [u8; ENTRY_SIZE],
+    ) -> Result<u64, FsError> {
+        check_name(name)?;
+        let entries = self.entries(dir)?;
+        if entries.iter().any(|e| e.name.eq_ignore_ascii_case(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+        let taken = |short: &[u8; 11]| entries.iter().any(|e| e.raw[..11] == short[..]);
+        let (short, flags, long) = match exact_short_name(name) {
+            Some((short, flags)) if !taken(&short) => (short, flags, false),
+            _ => (generated_short_name(name, taken)?, 0, true),
+        };
+        raw[..11].copy_from_slice(&short);
+        raw[12] = flags;
+
+        let mut new_slots = if long { long_name_entries(name, short_name_checksum(&raw)) } else { Vec::new() };
+        new_slots.push(raw);
+        let slots = self.free_slots(state, dir, new_slots.len())?;
+        for (offset, raw) in slots.iter().zip(&new_slots) {
+            self.cache.write(*offset, raw)?;
+        }
+        Ok(*slots.last().unwrap())
+    }
+
+    // 連続したcount個の空きスロットを探す。無ければディレクトリを1クラスタ伸ばす
+    fn free_slots(&self, state: &mut State, dir: DirLocation, count: usize) -> Result<Vec<u64>, FsError> {
+        loop {
+            let slots = self.read_dir(dir)?;
+            let mut run = 0;
+            for (i, (_, raw)) in slots.iter().enumerate() {
+                if raw[0] != 0 && raw[0] != DELETED {
+                    run = 0;
+                    continue;
+                }
+                run += 1;
+                if run == count {
+                    return Ok(slots[i + 1 - count..=i].iter().map(|(offset, _)| *offset).collect());
+                }
+            }
+            match dir {
+                DirLocation::FixedRoot => return Err(FsError::NoSpace),
+                DirLocation::Clusters(first) => {
+                    let last = *self.chain(first)?.last().unwrap();
+                    self.allocate_cluster(state, Some(last))?;
+                }
+            }
+        }
+    }
+
+    // スロットを削除済みにし、元の中身を返す
+    fn remove_slots(&self, slots: &[u64]) -> Result<Vec<[u8; ENTRY_SIZE]>, FsError> {
+        let mut old = Vec::new();
+        for &offset in slots {
+            let mut raw = [0; ENTRY_SIZE];
+            self.cache.read(offset, &mut raw)?;
+            old.push(raw);
+            self.cache.write(offset, &[DELETED])?;
+        }
+        Ok(old)
+    }
+
+    fn restore_slots(&self, slots: &[u64], old: &[[u8; ENTRY_SIZE]]) -> Result<(), FsError> {
+        for (&offset, raw) in slots.iter().zip(old) {
+            self.cache.write(offset, raw)?;
+        }
+        Ok(())
+    }
+
+    // ディレクトリから外したエントリのクラスタを解放する。開かれているファイルは閉じられるまで残す
+    fn release(&self, state: &mut State, entry: &Entry) -> Result<(), FsError> {
+        let node = {
+            let mut inodes = self.inodes.lock();
+            inodes.remove(&self.entry_inode(entry)).and_then(|node| node.upgrade())
+        };
+        let Some(node) = node else {
+            return self.free_chain(state, entry.first_cluster());
+        };
+        let mut node_state = node.state.lock();
+        if node.is_dir {
+            node_state.removed = true;
+            self.free_chain(state, entry.first_cluster())
+        } else {
+            node_state.entry = None;
+            Ok(())
+        }
+    }
+
+    fn node(&self, inode: InodeNumber, is_dir: bool, entry: Option<u64>, first_cluster: u32, size: u32) -> Arc<FatInode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(node) = inodes.get(&inode).and_then(Weak::upgrade) {
+            return node;
+        }
+        inodes.retain(|_, node| node.strong_count() > 0);
+        let node = Arc::new(FatInode {
+            fs: self.this.upgrade().unwrap(),
+            is_dir,
+            state: Mutex::new(NodeState {
+                inode,
+                entry,
+                first_cluster,
+                size,
+                chain: None,
+                removed: false,
+            }),
+        });
+        inodes.insert(inode, Arc::downgrade(&node));
+        node
+    }
+
+    fn dir_node(&self, cluster: u32) -> Arc<FatInode> {
+        let cluster = self.dir_cluster(cluster);
+        self.node(self.dir_inode(cluster), true, None, cluster, 0)
+    }
+
+    fn entry_node(&self, entry: &Entry) -> Arc<FatInode> {
+        if entry.is_dir() {
+            self.dir_node(entry.first_cluster())
+        } else {
+            self.node(self.entry_inode(entry), false, Some(entry.offset), entry.first_cluster(), entry.size())
+        }
+    }
+
+    // dirがancestorそのものか、その下にあるか
+    fn is_within(&self, dir: u32, ancestor: u32) -> Result<bool, FsError> {
+        let mut current = self.dir_cluster(dir);
+        for _ in 0..self.cluster_count {
+            if current == self.dir_cluster(ancestor) {
+                return Ok(true);
+            }
+            if self.is_root(current) {
+                return Ok(false);
+            }
+            let parent = self
+                .entries(self.dir_location(current))?
+                .into_iter()
+                .find(|e| e.name == "..")
+                .ok_or(FsError::Io)?;
+            current = self.dir_cluster(parent.first_cluster());
+        }
+        Err(FsError::Io)
+    }
+
+    fn write_fsinfo(&self, state: &mut State) -> Result<(), FsError> {
+        if let Some(offset) = self.fsinfo_offset.filter(|_| state.fsinfo_dirty) {
+            self.cache.write(offset + FSINFO_FREE_COUNT, &state.free_count.to_le_bytes())?;
+            self.cache.write(offset + FSINFO_NEXT_FREE, &state.next_free.to_le_bytes())?;
+        }
+        state.fsinfo_dirty = false;
+        Ok(())
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &str {
+        match self.fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, FsError> {
+        Ok(self.dir_node(0))
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        if self.cache.device().read_only() {
+            return Ok(());
+        }
+        let mut state = self.lock();
+        self.write_fsinfo(&mut state)?;
+        Ok(self.cache.sync()?)
+    }
+}
+
+// 最後のInodeが閉じられたときに、残っている削除済みファイルのクラスタを解放して書き戻す
+impl Drop for FatFs {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+struct FatInode {
+    fs: Arc<FatFs>,
+    is_dir: bool,
+    state: Mutex<NodeState>,
+}
+
+struct NodeState {
+    inode: InodeNumber,
+    // ファイルのショートエントリの位置。削除されたらNone
+    entry: Option<u64>,
+    first_cluster: u32,
+    size: u32,
+    // ファイルのクラスタチェーン。最初に使うときに読む
+    chain: Option<Vec<u32>>,
+    // 削除されたディレクトリ
+    removed: bool,
+}
+
+impl FatInode {
+    fn location(&self) -> Result<DirLocation, FsError> {
+        let state = self.state.lock();
+        if !self.is_dir {
+            return Err(FsError::NotADirectory);
+        }
+        if state.removed {
+            return Err(FsError::NotFound);
+        }
+        Ok(self.fs.dir_location(state.first_cluster))
+    }
+
+    fn cluster(&self) -> u32 {
+        self.state.lock().first_cluster
+    }
+
+    fn load_chain<'a>(&self, state: &'a mut NodeState) -> Result<&'a mut Vec<u32>, FsError> {
+        if state.chain.is_none() {
+            let chain = match state.first_cluster {
+                0 => Vec::new(),
+                first => self.fs.chain(first)?,
+            };
+            state.chain = Some(chain);
+        }
+        Ok(state.chain.as_mut().unwrap())
+    }
+
+    // endまで書けるようにクラスタを足す。足りなければ確保できたところまでのバイト数を返す
+    fn extend(&self, fs_state: &mut State, state: &mut NodeState, end: u64) -> Result<u64, FsError> {
+        let cluster_size = self.fs.cluster_size;
+        let needed = end.div_ceil(cluster_size) as usize;
+        let chain = self.load_chain(state)?;
+        let mut first = None;
+        while chain.len() < needed {
+            match self.fs.allocate_cluster(fs_state, chain.last().copied()) {
+                Ok(cluster) => {
+                    if chain.is_empty() {
+                        first = Some(cluster);
+                    }
+                    chain.push(cluster);
+                }
+                Err(FsError::NoSpace) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let allocated = chain.len() as u64 * cluster_size;
+        if let Some(first) = first {
+            state.first_cluster = first;
+        }
+        Ok(end.min(allocated))
+    }
+
+    // 今の最後のクラスタのうち、sizeより後ろに残っている古い中身を消す
+    fn zero_tail(&self, state: &NodeState, end: u64) -> Result<(), FsError> {
+        let size = state.size as u64;
+        let end = end.min(size.next_multiple_of(self.fs.cluster_size));
+        if end <= size {
+            return Ok(());
+        }
+        let zeros = vec![0; (end - size) as usize];
+        self.write_chain(state, size, &zeros)
+    }
+
+    fn write_chain(&self, state: &NodeState, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        let chain = state.chain.as_deref().unwrap_or_default();
+        for (device_offset, range) in self.fs.segments(chain, offset, buf.len()) {
+            self.fs.cache.write(device_offset, &buf[range])?;
+        }
+        Ok(())
+    }
+
+    fn update_entry(&self, state: &NodeState) -> Result<(), FsError> {
+        let Some(offset) = state.entry else {
+            return Ok(());
+        };
+        let mut raw = [0; ENTRY_SIZE];
+        self.fs.cache.read(offset, &mut raw)?;
+        set_first_cluster(&mut raw, state.first_cluster);
+        raw[28..32].copy_from_slice(&state.size.to_le_bytes());
+        raw[24..26].copy_from_slice(&FAT_DATE.to_le_bytes());
+        raw[11] |= ATTR_ARCHIVE;
+        Ok(self.fs.cache.write(offset, &raw)?)
+    }
+
+    fn dir_entry(&self, name: &str, inode: InodeNumber) -> DirEntry {
+        DirEntry { name: name.into(), inode, file_type: FileType::Directory }
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        if !self.is_dir && state.entry.is_none() && state.first_cluster != 0 {
+            self.fs.orphans.lock().push(state.first_cluster);
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let (inode, first_cluster, size) = {
+            let state = self.state.lock();
+            (state.inode, state.first_cluster, state.size as u64)
+        };
+        let cluster_size = self.fs.cluster_size;
+        let (file_type, size, allocated) = if self.is_dir {
+            let size = match self.fs.dir_location(first_cluster) {
+                DirLocation::FixedRoot => self.fs.root_dir_entries * ENTRY_SIZE as u64,
+                DirLocation::Clusters(first) => self.fs.chain(first).map_or(0, |c| c.len() as u64 * cluster_size),
+            };
+            (FileType::Directory, size, size)
+        } else {
+            (FileType::Regular, size, size.next_multiple_of(cluster_size))
+        };
+        Metadata {
+            dev: self.fs.dev,
+            inode,
+            file_type,
+            nlink: 1,
+            size,
+            blocks: allocated / 512,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        let mut state = self.state.lock();
+        let size = state.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let chain = self.load_chain(&mut state)?;
+        if (chain.len() as u64) * self.fs.cluster_size < offset + len as u64 {
+            return Err(FsError::Io);
+        }
+        for (device_offset, range) in self.fs.segments(chain, offset, len) {
+            self.fs.cache.read(device_offset, &mut buf[range])?;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if self.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= MAX_FILE_SIZE)
+            .ok_or(FsError::NoSpace)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut fs_state = self.fs.lock();
+        let mut state = self.state.lock();
+        let allocated = self.extend(&mut fs_state, &mut state, end)?;
+        if allocated <= offset {
+            self.update_entry(&state)?;
+            return Err(FsError::NoSpace);
+        }
+        // 書けたところまでは返す
+        let len = (allocated - offset) as usize;
+        if offset > state.size as u64 {
+            self.zero_tail(&state, offset)?;
+        }
+        self.write_chain(&state, offset, &buf[..len])?;
+        state.size = state.size.max((offset + len as u64) as u32);
+        self.update_entry(&state)?;
+        Ok(len)
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), FsError> {
+        if self.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        if new_size > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        let mut fs_state = self.fs.lock();
+        let mut state = self.state.lock();
+        let size = state.size as u64;
+        if new_size < size {
+            let keep = new_size.div_ceil(self.fs.cluster_size) as usize;
+            let chain = self.load_chain(&mut state)?;
+            if keep < chain.len() {
+                let freed = chain.split_off(keep);
+                match chain.last().copied() {
+                    Some(last) => self.fs.set_fat_entry(last, self.fs.end_of_chain())?,
+                    None => state.first_cluster = 0,
+                }
+                self.fs.free_clusters(&mut fs_state, &freed)?;
+            }
+        } else if new_size > size {
+            let allocated = self.extend(&mut fs_state, &mut state, new_size)?;
+            if allocated < new_size {
+                self.update_entry(&state)?;
+                return Err(FsError::NoSpace);
+            }
+            self.zero_tail(&state, new_size)?;
+        }
+        state.size = new_size as u32;
+        self.update_entry(&state)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let _fs_state = self.fs.lock();
+        let location = self.location()?;
+        let cluster = self.cluster();
+        match name {
+            "." => Ok(self.fs.dir_node(cluster)),
+            ".." if self.fs.is_root(cluster) => Ok(self.fs.dir_node(cluster)),
+            ".." => {
+                let parent = self.fs.entries(location)?.into_iter().find(|e| e.name == "..");
+                Ok(self.fs.dir_node(parent.ok_or(FsError::Io)?.first_cluster()))
+            }
+            _ => Ok(self.fs.entry_node(&self.fs.find(location, name)?)),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let mut fs_state = self.fs.lock();
+        let location = self.location()?;
+        let fs = &self.fs;
+        match file_type {
+            FileType::Regular => {
+                let raw = new_short_entry(&[b' '; 11], ATTR_ARCHIVE, 0);
+                let offset = fs.add_entry(&mut fs_state, location, name, raw)?;
+                Ok(fs.node(FatFs::file_inode(offset), false, Some(offset), 0, 0))
+            }
+            FileType::Directory => {
+                check_name(name)?;
+                let cluster = fs.allocate_cluster(&mut fs_state, None)?;
+                let parent = if fs.is_root(self.cluster()) { 0 } else { self.cluster() };
+                let dot = new_short_entry(b".          ", ATTR_DIRECTORY, cluster);
+                let dot_dot = new_short_entry(b"..         ", ATTR_DIRECTORY, parent);
+                let base = fs.cluster_offset(cluster);
+                let result = fs
+                    .cache
+                    .write(base, &dot)
+                    .and_then(|_| fs.cache.write(base + ENTRY_SIZE as u64, &dot_dot))
+                    .map_err(FsError::from)
+                    .and_then(|_| {
+                        let raw = new_short_entry(&[b' '; 11], ATTR_DIRECTORY, cluster);
+                        fs.add_entry(&mut fs_state, location, name, raw)
+                    });
+                match result {
+                    Ok(_) => Ok(fs.dir_node(cluster)),
+                    Err(e) => {
+                        fs.free_clusters(&mut fs_state, &[cluster])?;
+                        Err(e)
+                    }
+                }
+            }
+            _ => Err(FsError::NotSupported),
+        }
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.location()?;
+        Err(FsError::NotSupported)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut fs_state = self.fs.lock();
+        let location = self.location()?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidArgument);
+        }
+        let entry = self.fs.find(location, name)?;
+        if entry.is_dir() && !self.fs.is_empty_dir(entry.first_cluster())? {
+            return Err(FsError::NotEmpty);
+        }
+        self.fs.remove_slots(&entry.slots)?;
+        self.fs.release(&mut fs_state, &entry)
+    }
+
+    fn rename(&self, old_name: &str, new_dir: &dyn Inode, new_name: &str) -> Result<(), FsError> {
+        let new_dir = new_dir
+            .as_any()
+            .downcast_ref::<FatInode>()
+            .filter(|dir| Arc::ptr_eq(&dir.fs, &self.fs))
+            .ok_or(FsError::CrossDevice)?;
+        if [old_name, new_name].iter().any(|&name| name == "." || name == "..") {
+            return Err(FsError::InvalidArgument);
+        }
+        check_name(new_name)?;
+
+        let fs = &self.fs;
+        let mut fs_state = fs.lock();
+        let old_location = self.location()?;
+        let new_location = new_dir.location()?;
+        let entry = fs.find(old_location, old_name)?;
+        let existing = match fs.find(new_location, new_name) {
+            Ok(existing) => Some(existing),
+            Err(FsError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        let moved = old_location != new_location;
+        let existing = match existing {
+            // 大文字と小文字だけを変えるときは、見つかるのは自分自身
+            Some(existing) if existing.offset == entry.offset => {
+                if existing.name == new_name {
+                    return Ok(());
+                }
+                None
+            }
+            Some(existing) => {
+                match (entry.is_dir(), existing.is_dir()) {
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (false, true) => return Err(FsError::IsADirectory),
+                    (true, true) if !fs.is_empty_dir(existing.first_cluster())? => return Err(FsError::NotEmpty),
+                    _ => {}
+                }
+                Some(existing)
+            }
+            None => None,
+        };
+        // ディレクトリを自分の下へは動かせない
+        if entry.is_dir() && moved && fs.is_within(new_dir.cluster(), entry.first_cluster())? {
+            return Err(FsError::InvalidArgument);
+        }
+
+        if let Some(existing) = &existing {
+            fs.remove_slots(&existing.slots)?;
+            fs.release(&mut fs_state, existing)?;
+        }
+        let old_slots = fs.remove_slots(&entry.slots)?;
+        let offset = match fs.add_entry(&mut fs_state, new_location, new_name, entry.raw) {
+            Ok(offset) => offset,
+            Err(e) => {
+                fs.restore_slots(&entry.slots, &old_slots)?;
+                return Err(e);
+            }
+        };
+
+        if entry.is_dir() {
+            if moved {
+                let parent = if fs.is_root(new_dir.cluster()) { 0 } else { new_dir.cluster() };
+                let dot_dot = fs.cluster_offset(entry.first_cluster()) + ENTRY_SIZE as u64;
+                let mut raw = [0; ENTRY_SIZE];
+                fs.cache.read(dot_dot, &mut raw)?;
+                set_first_cluster(&mut raw, parent);
+                fs.cache.write(dot_dot, &raw)?;
+            }
+        } else {
+            // 開いているファイルはエントリの新しい位置を知っておく必要がある
+            let new_inode = FatFs::file_inode(offset);
+            let mut inodes = fs.inodes.lock();
+            if let Some(node) = inodes.remove(&FatFs::file_inode(entry.offset)) {
+                if let Some(node) = node.upgrade() {
+                    let mut state = node.state.lock();
+                    state.entry = Some(offset);
+                    state.inode = new_inode;
+                }
+                inodes.insert(new_inode, node);
+            }
+        }
+        Ok(())
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let _fs_state = self.fs.lock();
+        let location = self.location()?;
+        let cluster = self.cluster();
+        let fs = &self.fs;
+        match index {
+            0 => Ok(Some(self.dir_entry(".", fs.dir_inode(cluster)))),
+            1 if fs.is_root(cluster) => Ok(Some(self.dir_entry("..", fs.dir_inode(cluster)))),
+            1 => {
+                let parent = fs.entries(location)?.into_iter().find(|e| e.name == "..");
+                Ok(Some(self.dir_entry("..", fs.dir_inode(parent.ok_or(FsError::Io)?.first_cluster()))))
+            }
+            _ => Ok(fs
+                .entries(location)?
+                .into_iter()
+                .filter(|e| !e.is_dot())
+                .nth(index - 2)
+                .map(|e| DirEntry {
+                    inode: fs.entry_inode(&e),
+                    file_type: if e.is_dir() { FileType::Directory } else { FileType::Regular },
+                    name: e.name,
+                })),
+        }
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.fs.sync()
+    }
+}
+
+// 長い名前のエントリを集める。順番やチェックサムが合わなければ捨てる
+#[derive(Default)]
+struct LongName {
+    units: Vec<u16>,
+    slots: Vec<u64>,
+    checksum: u8,
+    // 次に来るはずの番号。0なら全部揃った
+    next: u8,
+    valid: bool,
+}
+
+impl LongName {
+    fn push(&mut self, offset: u64, raw: &[u8; ENTRY_SIZE]) {
+        let ordinal = raw[0] & 0x1f;
+        if raw[0] & LAST_LONG_ENTRY != 0 {
+            *self = LongName {
+                units: vec![0xffff; ordinal as usize * LONG_NAME_CHARS],
+                slots: Vec::new(),
+                checksum: raw[13],
+                next: ordinal,
+                valid: ordinal != 0,
+            };
+        }
+        if !self.valid || ordinal == 0 || ordinal != self.next || raw[13] != self.checksum {
+            self.valid = false;
+            return;
+        }
+        let base = (ordinal as usize - 1) * LONG_NAME_CHARS;
+        for (i, &position) in LONG_NAME_OFFSETS.iter().enumerate() {
+            self.units[base + i] = u16::from_le_bytes([raw[position], raw[position + 1]]);
+        }
+        self.slots.push(offset);
+        self.next -= 1;
+    }
+
+    // ショートエントリのチェックサムと合えば、長い名前とそのスロットを返す
+    fn finish(self, checksum: u8) -> (Option<String>, Vec<u64>) {
+        if !self.valid || self.next != 0 || self.checksum != checksum {
+            return (None, Vec::new());
+        }
+        let len = self.units.iter().position(|&u| u == 0 || u == 0xffff).unwrap_or(self.units.len());
+        let name: String = char::decode_utf16(self.units[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        // UTF-16で255文字でも、UTF-8では765バイトになりうる。長すぎればショートネームで見せる
+        if name.len() > NAME_MAX {
+            return (None, self.slots);
+        }
+        (Some(name), self.slots)
+    }
+}
+
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() % LONG_NAME_CHARS != 0 {
+        units.push(0);
+        units.resize(units.len().next_multiple_of(LONG_NAME_CHARS), 0xffff);
+    }
+    let count = units.len() / LONG_NAME_CHARS;
+    // ディスク上では番号の大きいものから並べる
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut raw = [0; ENTRY_SIZE];
+            raw[0] = (i + 1) as u8 | if i + 1 == count { LAST_LONG_ENTRY } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+            for (j, &position) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let unit = units[i * LONG_NAME_CHARS + j];
+                raw[position..position + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            raw
+        })
+        .collect()
+}
+
+fn short_name_checksum(raw: &[u8]) -> u8 {
+    raw[..11].iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+fn short_name_to_string(raw: &[u8; ENTRY_SIZE]) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let len = bytes.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+        bytes[..len]
+            .iter()
+            .map(|&c| if lower { c.to_ascii_lowercase() } else { c } as char)
+            .collect()
+    };
+    let mut base = [0; 8];
+    base.copy_from_slice(&raw[..8]);
+    // 0x05は先頭が0xe5の名前
+    if base[0] == 0x05 {
+        base[0] = DELETED;
+    }
+    let mut name = part(&base, raw[12] & NT_LOWER_BASE != 0);
+    let ext = part(&raw[8..11], raw[12] & NT_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidArgument);
+    }
+    if name.len() > NAME_MAX || name.encode_utf16().count() > LONG_NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) || name.ends_with(['.', ' ']) {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIAL.contains(&c)
+}
+
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    }
+}
+
+// nameがそのまま8.3形式で書けるなら、ショートネームと小文字のフラグ
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = split_extension(name);
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || base.contains('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut flags = 0;
+    for (part, start, lower_flag) in [(base, 0, NT_LOWER_BASE), (ext, 8, NT_LOWER_EXT)] {
+        let has_upper = part.bytes().any(|c| c.is_ascii_uppercase());
+        let has_lower = part.bytes().any(|c| c.is_ascii_lowercase());
+        // 大文字と小文字が混ざっていたら長い名前が要る
+        if has_upper && has_lower {
+            return None;
+        }
+        if has_lower {
+            flags |= lower_flag;
+        }
+        for (i, c) in part.bytes().enumerate() {
+            let c = c.to_ascii_uppercase();
+            if !is_short_name_char(c) {
+                return None;
+            }
+            short[start + i] = c;
+        }
+    }
+    Some((short, flags))
+}
+
+// "Long File Name.text" -> "LONGFI~1.TEX"
+fn generated_short_name(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Result<[u8; 11], FsError> {
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.is_ascii() {
+                true if is_short_name_char(c.to_ascii_uppercase() as u8) => c.to_ascii_uppercase() as u8,
+                _ => b'_',
+            })
+            .collect()
+    };
+    let (base, ext) = split_extension(name.trim_start_matches('.'));
+    let (base, ext) = (convert(base), convert(ext));
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        let ext_len = ext.len().min(3);
+        short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+        if !taken(&short) {
+            return Ok(short);
+        }
+    }
+    Err(FsError::NoSpace)
+}
@@ -0,0 +1,164 @@
+// Open files and file descriptor tables
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use common::syscall::{O_ACCMODE, O_APPEND, O_RDONLY, O_RDWR, O_WRONLY};
+use spin::mutex::Mutex;
+
+use super::{DirEntry, FileType, FsError, Inode, Metadata};
+
+const MAX_FILES: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+// 開いているファイル。読み書きの位置はここで持つ
+pub trait File: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError>;
+
+    fn seek(&self, _pos: SeekFrom) -> Result<u64, FsError> {
+        Err(FsError::NotSeekable)
+    }
+
+    fn readdir(&self) -> Result<Option<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn stat(&self) -> Result<Metadata, FsError>;
+}
+
+pub struct InodeFile {
+    inode: Arc<dyn Inode>,
+    readable: bool,
+    writable: bool,
+    append: bool,
+    // 通常のファイルではバイト単位、ディレクトリではエントリの番号
+    position: Mutex<u64>,
+}
+
+impl InodeFile {
+    pub fn new(inode: Arc<dyn Inode>, flags: u64) -> Self {
+        let access = flags & O_ACCMODE;
+        Self {
+            inode,
+            readable: access == O_RDONLY || access == O_RDWR,
+            writable: access == O_WRONLY || access == O_RDWR,
+            append: flags & O_APPEND != 0,
+            position: Mutex::new(0),
+        }
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    fn seekable(&self) -> bool {
+        matches!(self.inode.metadata().file_type, FileType::Regular | FileType::BlockDevice)
+    }
+}
+
+impl File for InodeFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.readable {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let mut position = self.position.lock();
+        let read = self.inode.read_at(*position, buf)?;
+        if self.seekable() {
+            *position += read as u64;
+        }
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.writable {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let mut position = self.position.lock();
+        if self.append {
+            *position = self.inode.metadata().size;
+        }
+        let written = self.inode.write_at(*position, buf)?;
+        if self.seekable() {
+            *position += written as u64;
+        }
+        Ok(written)
+    }
+
+    fn seek(&self, pos: SeekFrom) -> Result<u64, FsError> {
+        if !self.seekable() {
+            return Err(FsError::NotSeekable);
+        }
+        let mut position = self.position.lock();
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.inode.metadata().size.checked_add_signed(offset),
+        }
+        .ok_or(FsError::InvalidArgument)?;
+        *position = new_position;
+        Ok(new_position)
+    }
+
+    fn readdir(&self) -> Result<Option<DirEntry>, FsError> {
+        let mut position = self.position.lock();
+        let entry = self.inode.readdir(*position as usize)?;
+        if entry.is_some() {
+            *position += 1;
+        }
+        Ok(entry)
+    }
+
+    fn stat(&self) -> Result<Metadata, FsError> {
+        Ok(self.inode.metadata())
+    }
+}
+
+// プロセスごとのファイルディスクリプタ表。indexがfd
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 空いている一番小さいfdに入れる
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Result<usize, FsError> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() >= MAX_FILES {
+            return Err(FsError::TooManyOpenFiles);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, fd: usize) -> Result<Arc<dyn File>, FsError> {
+        self.files
+            .get(fd)
+            .cloned()
+            .flatten()
+            .ok_or(FsError::BadFileDescriptor)
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), FsError> {
+        match self.files.get_mut(fd) {
+            Some(file @ Some(_)) => {
+                *file = None;
+                Ok(())
+            }
+            _ => Err(FsError::BadFileDescriptor),
+        }
+    }
+}
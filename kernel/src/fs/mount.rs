@@ -0,0 +1,111 @@
+// Mount table
+//
+// マウントポイントのディレクトリを(DeviceId, InodeNumber)で覚えておき、
+// パスを辿ってそこに着いたらマウントしたファイルシステムのルートに差し替える
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use super::{path, DeviceId, FileSystem, FileType, FsError, Inode, InodeNumber};
+
+struct Mount {
+    path: String,
+    // ルートにはマウントポイントがない
+    mount_point: Option<(DeviceId, InodeNumber)>,
+    fs: Arc<dyn FileSystem>,
+}
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+}
+
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = path::normalize(path);
+    let mount_point = if path == "/" {
+        if MOUNTS.lock().iter().any(|m| m.mount_point.is_none()) {
+            return Err(FsError::Busy);
+        }
+        None
+    } else {
+        let dir = path::resolve(&path, true)?.metadata();
+        if dir.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Some((dir.dev, dir.inode))
+    };
+
+    let mut mounts = MOUNTS.lock();
+    if mount_point.is_some() && mounts.iter().any(|m| m.mount_point == mount_point) {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Mount { path, mount_point, fs });
+    Ok(())
+}
+
+// 下にマウントが残っているファイルシステムは外せない
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let path = path::normalize(path);
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .rposition(|m| m.path == path)
+        .ok_or(FsError::InvalidArgument)?;
+    let dev = mounts[index].fs.root()?.metadata().dev;
+    if mounts.iter().any(|m| m.mount_point.is_some_and(|(d, _)| d == dev)) {
+        return Err(FsError::Busy);
+    }
+    let mount = mounts.remove(index);
+    drop(mounts);
+    mount.fs.sync()?;
+    Ok(mount.fs)
+}
+
+pub fn root() -> Result<Arc<dyn Inode>, FsError> {
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|m| m.mount_point.is_none())
+        .map(|m| m.fs.clone())
+        .ok_or(FsError::NotFound)?
+        .root()
+}
+
+// inodeがマウントポイントなら、その上にマウントされたファイルシステムのルートを返す
+pub fn enter(inode: Arc<dyn Inode>) -> Result<Arc<dyn Inode>, FsError> {
+    let mut inode = inode;
+    loop {
+        let metadata = inode.metadata();
+        let key = Some((metadata.dev, metadata.inode));
+        let mounted = MOUNTS.lock().iter().rev().find(|m| m.mount_point == key).map(|m| m.fs.clone());
+        match mounted {
+            Some(fs) => inode = fs.root()?,
+            None => return Ok(inode),
+        }
+    }
+}
+
+pub fn is_mount_point(inode: &dyn Inode) -> bool {
+    let metadata = inode.metadata();
+    let key = Some((metadata.dev, metadata.inode));
+    MOUNTS.lock().iter().any(|m| m.mount_point == key)
+}
+
+// (マウント先のパス, ファイルシステムの名前)
+pub fn mounts() -> Vec<(String, String)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|m| (m.path.clone(), m.fs.name().into()))
+        .collect()
+}
+
+pub fn sync_all() -> Result<(), FsError> {
+    let filesystems: Vec<_> = MOUNTS.lock().iter().map(|m| m.fs.clone()).collect();
+    for fs in filesystems {
+        fs.sync()?;
+    }
+    Ok(())
+}
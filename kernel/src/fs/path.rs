@@ -0,0 +1,113 @@
+// Path resolution
+//
+// 辿ってきたInodeをスタックに積み、".."ではそれを1つ戻す。
+// こうすればマウントしたファイルシステムのルートから".."で元のファイルシステムへ戻れる
+
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+
+use super::{mount, FileType, FsError, Inode};
+use common::syscall::NAME_MAX;
+
+// シンボリックリンクを辿る回数の上限
+const MAX_SYMLINKS: usize = 40;
+
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
+
+// follow_lastがfalseなら、最後の要素がシンボリックリンクでもそのまま返す
+pub fn resolve(path: &str, follow_last: bool) -> Result<Arc<dyn Inode>, FsError> {
+    let root = mount::root()?;
+    let mut stack = vec![root];
+    let mut pending: VecDeque<String> = components(path).map(String::from).collect();
+    let mut links = 0;
+
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            if stack.len() > 1 {
+                stack.pop();
+            }
+            continue;
+        }
+        if name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+
+        let dir = stack.last().unwrap();
+        if dir.metadata().file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let child = mount::enter(dir.lookup(&name)?)?;
+
+        if child.metadata().file_type == FileType::Symlink && (follow_last || !pending.is_empty()) {
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(FsError::TooManyLinks);
+            }
+            let target = child.read_link()?;
+            if target.starts_with('/') {
+                stack.truncate(1);
+            }
+            for component in components(&target).rev() {
+                pending.push_front(component.to_string());
+            }
+            continue;
+        }
+        stack.push(child);
+    }
+    Ok(stack.pop().unwrap())
+}
+
+// 親ディレクトリと最後の要素の名前を返す
+pub fn resolve_parent(path: &str) -> Result<(Arc<dyn Inode>, String), FsError> {
+    let (parent, name) = split_last(path).ok_or(FsError::InvalidArgument)?;
+    if name.len() > NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+    let parent = resolve(parent, true)?;
+    if parent.metadata().file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((parent, name.into()))
+}
+
+fn split_last(path: &str) -> Option<(&str, &str)> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = match trimmed.rfind('/') {
+        Some(i) => (&trimmed[..i + 1], &trimmed[i + 1..]),
+        None => ("", trimmed),
+    };
+    match name {
+        "" | "." | ".." => None,
+        _ => Some((parent, name)),
+    }
+}
+
+// cwdを基準にした絶対パス。".."は辿るときに解決する
+pub fn absolute(cwd: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        return path.into();
+    }
+    let mut absolute = String::from(cwd.trim_end_matches('/'));
+    absolute.push('/');
+    absolute.push_str(path);
+    absolute
+}
+
+// "."と".."を字面の上で取り除く。シンボリックリンクは解決しないので、"/a/link/.."は"/a"になる
+pub fn normalize(path: &str) -> String {
+    let mut parts = vec![];
+    for component in components(path) {
+        if component == ".." {
+            parts.pop();
+        } else {
+            parts.push(component);
+        }
+    }
+    let mut normalized = String::from("/");
+    normalized.push_str(&parts.join("/"));
+    normalized
+}
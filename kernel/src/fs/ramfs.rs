@@ -0,0 +1,376 @@
+// ramfs
+//
+// 全部メモリ上に置くファイルシステム。ファイルの中身はページ単位でBitmapMemoryManagerから確保し、
+// 書き込まれていないページ(穴)は確保せずにゼロとして読む
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::mutex::Mutex;
+
+use super::{new_device_id, DeviceId, DirEntry, FileSystem, FileType, FsError, Inode, InodeNumber, Metadata};
+use crate::memory_manager::{frame_manager, Frame};
+use crate::paging;
+
+const PAGE_SIZE: u64 = Frame::SIZE as u64;
+
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+struct RamInode {
+    dev: DeviceId,
+    inode: InodeNumber,
+    this: Weak<RamInode>,
+    content: Mutex<Content>,
+}
+
+enum Content {
+    Regular {
+        size: u64,
+        pages: BTreeMap<u64, Frame>, // ページ番号 -> フレーム
+    },
+    Directory {
+        parent: Weak<RamInode>, // ルートでは自分自身
+        entries: BTreeMap<String, Arc<RamInode>>,
+    },
+    Symlink(String),
+}
+
+impl Content {
+    // contentのロックを持ったままnot_a_fileを呼ぶと、metadataで同じロックを取りに行ってしまう
+    fn not_regular(&self) -> FsError {
+        match self {
+            Content::Directory { .. } => FsError::IsADirectory,
+            _ => FsError::InvalidArgument,
+        }
+    }
+}
+
+impl RamFs {
+    pub fn new() -> Arc<Self> {
+        let dev = new_device_id();
+        let root = RamInode::new(dev, |this| Content::Directory {
+            parent: this.clone(),
+            entries: BTreeMap::new(),
+        });
+        Arc::new(Self { root })
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, FsError> {
+        Ok(self.root.clone())
+    }
+}
+
+impl RamInode {
+    fn new(dev: DeviceId, content: impl FnOnce(&Weak<RamInode>) -> Content) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            dev,
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            this: this.clone(),
+            content: Mutex::new(content(this)),
+        })
+    }
+
+    fn file_type(&self) -> FileType {
+        match *self.content.lock() {
+            Content::Regular { .. } => FileType::Regular,
+            Content::Directory { .. } => FileType::Directory,
+            Content::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    fn add_entry(&self, name: &str, content: impl FnOnce(&Weak<RamInode>) -> Content) -> Result<Arc<RamInode>, FsError> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidArgument);
+        }
+        let inode = RamInode::new(self.dev, content);
+        match &mut *self.content.lock() {
+            Content::Directory { entries, .. } => {
+                if entries.contains_key(name) {
+                    return Err(FsError::AlreadyExists);
+                }
+                entries.insert(name.into(), inode.clone());
+                Ok(inode)
+            }
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(&*self.content.lock(), Content::Directory { entries, .. } if entries.is_empty())
+    }
+
+    // selfがdirそのものか、その祖先か
+    fn is_ancestor_of(&self, dir: &Arc<RamInode>) -> bool {
+        let mut current = dir.clone();
+        loop {
+            if current.inode == self.inode {
+                return true;
+            }
+            let parent = match &*current.content.lock() {
+                Content::Directory { parent, .. } => parent.upgrade(),
+                _ => None,
+            };
+            match parent {
+                Some(parent) if parent.inode != current.inode => current = parent,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl Drop for RamInode {
+    fn drop(&mut self) {
+        if let Content::Regular { pages, .. } = self.content.get_mut() {
+            for frame in core::mem::take(pages).into_values() {
+                frame_manager().free(frame, 1);
+            }
+        }
+    }
+}
+
+fn page_ptr(frame: Frame) -> *mut u8 {
+    paging::as_virt_addr(frame.phys_addr()).unwrap().as_mut_ptr()
+}
+
+fn allocate_page() -> Result<Frame, FsError> {
+    let frame = frame_manager().allocate(1).map_err(|_| FsError::NoSpace)?;
+    unsafe { core::ptr::write_bytes(page_ptr(frame), 0, Frame::SIZE) };
+    Ok(frame)
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, nlink, size, pages) = match &*self.content.lock() {
+            Content::Regular { size, pages } => (FileType::Regular, 1, *size, pages.len() as u64),
+            Content::Directory { entries, .. } => {
+                let subdirs = entries.values().filter(|e| e.file_type() == FileType::Directory).count();
+                (FileType::Directory, 2 + subdirs as u32, entries.len() as u64, 0)
+            }
+            Content::Symlink(target) => (FileType::Symlink, 1, target.len() as u64, 0),
+        };
+        Metadata {
+            dev: self.dev,
+            inode: self.inode,
+            file_type,
+            nlink,
+            size,
+            blocks: pages * PAGE_SIZE / 512,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let content = self.content.lock();
+        let Content::Regular { size, pages } = &*content else {
+            return Err(content.not_regular());
+        };
+        if offset >= *size {
+            return Ok(0);
+        }
+        let len = buf.len().min((*size - offset) as usize);
+        let mut read = 0;
+        while read < len {
+            let position = offset + read as u64;
+            let page_offset = (position % PAGE_SIZE) as usize;
+            let chunk = (Frame::SIZE - page_offset).min(len - read);
+            match pages.get(&(position / PAGE_SIZE)) {
+                Some(frame) => unsafe {
+                    core::ptr::copy_nonoverlapping(page_ptr(*frame).add(page_offset), buf[read..].as_mut_ptr(), chunk);
+                },
+                None => buf[read..read + chunk].fill(0),
+            }
+            read += chunk;
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut content = self.content.lock();
+        let Content::Regular { size, pages } = &mut *content else {
+            return Err(content.not_regular());
+        };
+        offset.checked_add(buf.len() as u64).ok_or(FsError::InvalidArgument)?;
+
+        let mut written = 0;
+        while written < buf.len() {
+            let position = offset + written as u64;
+            let page_offset = (position % PAGE_SIZE) as usize;
+            let chunk = (Frame::SIZE - page_offset).min(buf.len() - written);
+            let index = position / PAGE_SIZE;
+            let frame = match pages.get(&index) {
+                Some(frame) => *frame,
+                None => match allocate_page() {
+                    Ok(frame) => {
+                        pages.insert(index, frame);
+                        frame
+                    }
+                    // 書けたところまでは返す
+                    Err(_) if written > 0 => break,
+                    Err(e) => return Err(e),
+                },
+            };
+            unsafe {
+                core::ptr::copy_nonoverlapping(buf[written..].as_ptr(), page_ptr(frame).add(page_offset), chunk);
+            }
+            written += chunk;
+        }
+        *size = (*size).max(offset + written as u64);
+        Ok(written)
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), FsError> {
+        let mut content = self.content.lock();
+        let Content::Regular { size, pages } = &mut *content else {
+            return Err(content.not_regular());
+        };
+        if new_size < *size {
+            let first_unused = new_size.div_ceil(PAGE_SIZE);
+            for frame in pages.split_off(&first_unused).into_values() {
+                frame_manager().free(frame, 1);
+            }
+            // 後でまた伸ばしたときに古い中身が見えないよう、最後のページの残りをゼロにする
+            let tail = (new_size % PAGE_SIZE) as usize;
+            if let Some(frame) = pages.get(&(new_size / PAGE_SIZE)).filter(|_| tail != 0) {
+                unsafe { core::ptr::write_bytes(page_ptr(*frame).add(tail), 0, Frame::SIZE - tail) };
+            }
+        }
+        *size = new_size;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match &*self.content.lock() {
+            Content::Directory { parent, entries } => match name {
+                "." => Ok(self.this.upgrade().unwrap()),
+                ".." => Ok(parent.upgrade().ok_or(FsError::NotFound)?),
+                _ => Ok(entries.get(name).cloned().ok_or(FsError::NotFound)?),
+            },
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let parent = self.this.clone();
+        let inode = match file_type {
+            FileType::Regular => self.add_entry(name, |_| Content::Regular {
+                size: 0,
+                pages: BTreeMap::new(),
+            })?,
+            FileType::Directory => self.add_entry(name, |_| Content::Directory {
+                parent,
+                entries: BTreeMap::new(),
+            })?,
+            _ => return Err(FsError::NotSupported),
+        };
+        Ok(inode)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Ok(self.add_entry(name, |_| Content::Symlink(target.into()))?)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut content = self.content.lock();
+        let Content::Directory { entries, .. } = &mut *content else {
+            return Err(FsError::NotADirectory);
+        };
+        let inode = entries.get(name).ok_or(FsError::NotFound)?;
+        if inode.file_type() == FileType::Directory && !inode.is_empty_directory() {
+            return Err(FsError::NotEmpty);
+        }
+        // 開いているファイルがあれば、閉じられるまで中身は残る
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_dir: &dyn Inode, new_name: &str) -> Result<(), FsError> {
+        let new_dir = new_dir
+            .as_any()
+            .downcast_ref::<RamInode>()
+            .and_then(|dir| dir.this.upgrade())
+            .ok_or(FsError::CrossDevice)?;
+        if new_dir.dev != self.dev {
+            return Err(FsError::CrossDevice);
+        }
+        if new_name.is_empty() || new_name == "." || new_name == ".." || new_name.contains('/') {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let inode = match &*self.content.lock() {
+            Content::Directory { entries, .. } => entries.get(old_name).cloned().ok_or(FsError::NotFound)?,
+            _ => return Err(FsError::NotADirectory),
+        };
+        let is_directory = inode.file_type() == FileType::Directory;
+        // ディレクトリを自分の下へは動かせない
+        if is_directory && inode.is_ancestor_of(&new_dir) {
+            return Err(FsError::InvalidArgument);
+        }
+        let existing = match &*new_dir.content.lock() {
+            Content::Directory { entries, .. } => entries.get(new_name).cloned(),
+            _ => return Err(FsError::NotADirectory),
+        };
+        if let Some(existing) = existing {
+            if Arc::ptr_eq(&existing, &inode) {
+                return Ok(());
+            }
+            match (is_directory, existing.file_type() == FileType::Directory) {
+                (true, false) => return Err(FsError::NotADirectory),
+                (false, true) => return Err(FsError::IsADirectory),
+                (true, true) if !existing.is_empty_directory() => return Err(FsError::NotEmpty),
+                _ => {}
+            }
+        }
+
+        if let Content::Directory { entries, .. } = &mut *self.content.lock() {
+            entries.remove(old_name);
+        }
+        if let Content::Directory { entries, .. } = &mut *new_dir.content.lock() {
+            entries.insert(new_name.into(), inode.clone());
+        }
+        if let Content::Directory { parent, .. } = &mut *inode.content.lock() {
+            *parent = Arc::downgrade(&new_dir);
+        }
+        Ok(())
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let content = self.content.lock();
+        let Content::Directory { parent, entries } = &*content else {
+            return Err(FsError::NotADirectory);
+        };
+        let entry = match index {
+            0 => Some((String::from("."), self.inode, FileType::Directory)),
+            1 => {
+                let parent = parent.upgrade().map_or(self.inode, |p| p.inode);
+                Some((String::from(".."), parent, FileType::Directory))
+            }
+            _ => entries
+                .iter()
+                .nth(index - 2)
+                .map(|(name, inode)| (name.clone(), inode.inode, inode.file_type())),
+        };
+        Ok(entry.map(|(name, inode, file_type)| DirEntry { name, inode, file_type }))
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match &*self.content.lock() {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
@@ -1,52 +1,89 @@
 // Global Descriptor Table
 
+use core::ptr::{addr_of, addr_of_mut};
 use x86_64::registers::segmentation::{Segment, SS};
-use x86_64::VirtAddr;
-use core::ptr::addr_of;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
+use x86_64::VirtAddr;
 use lazy_static::lazy_static;
+use crate::stack::KernelStack;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const NMI_IST_INDEX: u16 = 2;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 3;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe {addr_of!(STACK)});
-            let stack_end = stack_start + STACK_SIZE.try_into().unwrap();
-            stack_end
-        };
-        tss
-    };
-}
+const IST_STACK_PAGES: usize = 5;
+
+// RSP0をタスクごとに書き換えるのでlazy_staticではなくstatic mutにしている
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {code_selector, data_selector, tss_selector })
+        // SYSRETはSTARに書いたセレクタ+8をSS、+16をCSにするので、user data、user codeの順に並べる
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        let tss_selector = gdt.append(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
+        (gdt, Selectors {code_selector, data_selector, user_data_selector, user_code_selector, tss_selector })
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
     data_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+pub fn kernel_code_selector() -> SegmentSelector {
+    GDT.1.code_selector
+}
+
+pub fn kernel_data_selector() -> SegmentSelector {
+    GDT.1.data_selector
+}
+
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
+
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
+// ring 3から割り込みや例外で入ってきたときに使うスタック(RSP0)を切り替える
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        (*addr_of_mut!(TSS)).privilege_stack_table[0] = stack_top;
+    }
+}
+
+// ISTのスタックを確保するので、メモリ管理を初期化してから呼ぶこと
 pub fn init() {
     use x86_64::instructions::segmentation::CS;
     use x86_64::instructions::tables::load_tss;
+
+    let ist_stacks = [
+        (DOUBLE_FAULT_IST_INDEX, "double fault IST"),
+        (PAGE_FAULT_IST_INDEX, "page fault IST"),
+        (NMI_IST_INDEX, "NMI IST"),
+        (MACHINE_CHECK_IST_INDEX, "machine check IST"),
+    ];
+    for (index, name) in ist_stacks {
+        let stack_top = KernelStack::new(name, IST_STACK_PAGES).leak();
+        unsafe {
+            (*addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = stack_top;
+        }
+    }
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
-}
\ No newline at end of file
+}
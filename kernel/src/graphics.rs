@@ -3,6 +3,7 @@ use spin::mutex::Mutex;
 use lazy_static::lazy_static;
 use crate::library::math::vector::Vector2D;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PixelColor {
     pub r: u8,
     pub g: u8,
@@ -76,6 +77,19 @@ impl FrameBufferWriter {
         }
     }
 
+    // y=srcから高さheightの画素の行を、y=dstへそのまま移す。重なっていてもよい
+    pub fn move_rows(&self, dst: u32, src: u32, height: u32) {
+        let row_bytes = 4 * self.config.stride as usize;
+        unsafe {
+            let base = self.config.frame_buffer;
+            core::ptr::copy(
+                base.add(row_bytes * src as usize),
+                base.add(row_bytes * dst as usize),
+                row_bytes * height as usize,
+            );
+        }
+    }
+
     pub fn draw_rectangle(&self, pos: Vector2D<u32>, size: Vector2D<u32>, c: &PixelColor) -> () {
         for x in 0..size.x {
             self.write_pixel(pos.x+x, pos.y, c);
@@ -0,0 +1,177 @@
+// Initial ramdisk
+//
+// bootloaderが読み込んだinitrd(cpio newcかustar形式のtar)をルートファイルシステムに展開する
+
+use alloc::format;
+use alloc::string::String;
+use common::boot_info::Initrd;
+use common::syscall::{O_CREAT, O_TRUNC, O_WRONLY};
+use x86_64::PhysAddr;
+
+use crate::fs::{self, FsError};
+use crate::memory_manager::{frame_manager, Frame};
+
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+// modeの種類
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug)]
+pub enum InitrdError {
+    UnknownFormat,
+    Malformed,
+    Fs(String, FsError),
+}
+
+enum Entry<'a> {
+    Directory,
+    Regular(&'a [u8]),
+    Symlink(&'a str),
+}
+
+pub fn unpack(initrd: Initrd) -> Result<(), InitrdError> {
+    if initrd.is_empty() {
+        return Ok(());
+    }
+    let image = unsafe { initrd.as_slice() };
+    let result = if image.starts_with(CPIO_MAGIC) {
+        unpack_cpio(image)
+    } else if image.len() >= TAR_BLOCK_SIZE && image[257..262] == *TAR_MAGIC {
+        unpack_tar(image)
+    } else {
+        Err(InitrdError::UnknownFormat)
+    };
+
+    // 展開し終わったらinitrdのページはもう要らない
+    let pages = (initrd.size as usize).div_ceil(Frame::SIZE);
+    frame_manager().free(Frame::from_phys_addr(PhysAddr::new(initrd.base)), pages);
+    result
+}
+
+fn unpack_cpio(image: &[u8]) -> Result<(), InitrdError> {
+    let mut offset = 0;
+    loop {
+        let header = image.get(offset..offset + CPIO_HEADER_SIZE).ok_or(InitrdError::Malformed)?;
+        if !header.starts_with(CPIO_MAGIC) {
+            return Err(InitrdError::Malformed);
+        }
+        let field = |index: usize| {
+            let start = 6 + index * 8;
+            core::str::from_utf8(&header[start..start + 8])
+                .ok()
+                .and_then(|s| u32::from_str_radix(s, 16).ok())
+                .ok_or(InitrdError::Malformed)
+        };
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = image
+            .get(name_start..name_start + name_size.saturating_sub(1))
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .ok_or(InitrdError::Malformed)?;
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = image.get(data_start..data_start + file_size).ok_or(InitrdError::Malformed)?;
+        offset = (data_start + file_size).next_multiple_of(4);
+
+        let entry = match mode & S_IFMT {
+            S_IFDIR => Entry::Directory,
+            S_IFREG => Entry::Regular(data),
+            S_IFLNK => Entry::Symlink(core::str::from_utf8(data).map_err(|_| InitrdError::Malformed)?),
+            // デバイスファイルなどは作らない
+            _ => continue,
+        };
+        install(name, entry)?;
+    }
+}
+
+fn unpack_tar(image: &[u8]) -> Result<(), InitrdError> {
+    let mut offset = 0;
+    while let Some(header) = image.get(offset..offset + TAR_BLOCK_SIZE) {
+        // 終わりはゼロ埋めのブロック
+        if header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        if header[257..262] != *TAR_MAGIC {
+            return Err(InitrdError::Malformed);
+        }
+        let size = parse_octal(&header[124..136]).ok_or(InitrdError::Malformed)? as usize;
+        let name = tar_str(&header[0..100])?;
+        let prefix = tar_str(&header[345..500])?;
+        let path = if prefix.is_empty() { String::from(name) } else { format!("{}/{}", prefix, name) };
+
+        let data_start = offset + TAR_BLOCK_SIZE;
+        let data = image.get(data_start..data_start + size).ok_or(InitrdError::Malformed)?;
+        offset = data_start + size.next_multiple_of(TAR_BLOCK_SIZE);
+
+        let entry = match header[156] {
+            b'0' | 0 => Entry::Regular(data),
+            b'5' => Entry::Directory,
+            b'2' => Entry::Symlink(tar_str(&header[157..257])?),
+            _ => continue,
+        };
+        install(&path, entry)?;
+    }
+    Ok(())
+}
+
+fn tar_str(field: &[u8]) -> Result<&str, InitrdError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| InitrdError::Malformed)
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = tar_str(field).ok()?.trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn install(name: &str, entry: Entry) -> Result<(), InitrdError> {
+    let path = fs::path::normalize(name);
+    if path == "/" {
+        return Ok(());
+    }
+    let fs_error = |e| InitrdError::Fs(path.clone(), e);
+    create_parents(&path).map_err(fs_error)?;
+
+    match entry {
+        Entry::Directory => match fs::mkdir(&path) {
+            Ok(()) | Err(FsError::AlreadyExists) => Ok(()),
+            Err(e) => Err(fs_error(e)),
+        },
+        Entry::Regular(data) => {
+            let file = fs::open(&path, O_WRONLY | O_CREAT | O_TRUNC).map_err(fs_error)?;
+            let mut written = 0;
+            while written < data.len() {
+                written += file.write(&data[written..]).map_err(fs_error)?;
+            }
+            Ok(())
+        }
+        Entry::Symlink(target) => fs::symlink(target, &path).map_err(fs_error),
+    }
+}
+
+// アーカイブに親ディレクトリのエントリが無くても展開できるようにする
+fn create_parents(path: &str) -> Result<(), FsError> {
+    for (i, _) in path.match_indices('/').skip(1) {
+        match fs::mkdir(&path[..i]) {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -1,24 +1,83 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
 use crate::println;
+use crate::apic;
+use crate::error::OsError;
 use crate::gdt;
+use crate::paging;
+use crate::process;
+use crate::vma;
 use lazy_static::lazy_static;
 
+pub const TIMER_VECTOR: u8 = 0x20;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+// デバイスのIRQにはここから順にベクタを割り当てる
+const IRQ_VECTOR_BASE: u8 = 0x30;
+const IRQ_VECTORS: usize = 8;
+
+pub type IrqHandler = Box<dyn Fn() + Send + Sync>;
+
+const IRQ_ENTRIES: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_VECTORS] = [
+    irq_handler::<0>,
+    irq_handler::<1>,
+    irq_handler::<2>,
+    irq_handler::<3>,
+    irq_handler::<4>,
+    irq_handler::<5>,
+    irq_handler::<6>,
+    irq_handler::<7>,
+];
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            // ガードページに触れた時点でスタックは使えないので、別のスタックで受ける
+            idt.page_fault.set_handler_fn(page_fault_handler).set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler).set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler).set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
         }
         idt[40].set_handler_fn(xhci_handler);
+        idt[TIMER_VECTOR].set_handler_fn(timer_handler);
+        idt[SPURIOUS_VECTOR].set_handler_fn(spurious_handler);
+        for (i, entry) in IRQ_ENTRIES.iter().enumerate() {
+            idt[IRQ_VECTOR_BASE + i as u8].set_handler_fn(*entry);
+        }
         idt
     };
+    // IRQ_VECTOR_BASEから順に、割り当てたIRQとそのハンドラ
+    static ref IRQ_HANDLERS: Mutex<Vec<(u8, Vec<IrqHandler>)>> = Mutex::new(Vec::new());
 }
 
 pub fn init() {
     IDT.load();
 }
 
+// irqの割り込みでhandlerを呼ぶ。同じIRQを使うデバイスがあれば、どのhandlerも呼ばれる。
+// 割り込みはkernel_main_loopがhltで待っている間しか受け付けないので、handlerでロックを取ってもよい
+pub fn register_irq(irq: u8, pci: bool, handler: IrqHandler) -> Result<(), OsError> {
+    let mut handlers = IRQ_HANDLERS.lock();
+    if let Some((_, shared)) = handlers.iter_mut().find(|(registered, _)| *registered == irq) {
+        shared.push(handler);
+        return Ok(());
+    }
+    if handlers.len() >= IRQ_VECTORS {
+        return Err(OsError::NotSupported);
+    }
+    let vector = IRQ_VECTOR_BASE + handlers.len() as u8;
+    apic::route_irq(irq, vector, pci)?;
+    handlers.push((irq, vec![handler]));
+    Ok(())
+}
+
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame
 ) {
@@ -26,9 +85,63 @@ extern "x86-interrupt" fn breakpoint_handler(
 }
 
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    let overflowed = Cr2::read()
+        .ok()
+        .and_then(vma::guard_owner)
+        .or_else(|| vma::guard_owner(stack_frame.stack_pointer));
+    if let Some(task) = overflowed {
+        panic!("EXEPTION: DOUBLE FAULT (stack overflow in task {})\n{:#?}", task, stack_frame);
+    }
     panic!("EXEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3 {
+        println!("general protection fault in user mode ({:#x}), killing the process", error_code);
+        process::exit(-1);
+    }
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}", error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: NMI\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let addr = Cr2::read().unwrap();
+    let result = if paging::is_user_address(addr) {
+        process::handle_page_fault(addr, error_code)
+    } else {
+        vma::handle_page_fault(addr, error_code)
+    };
+    if let Err(e) = result {
+        if error_code.contains(PageFaultErrorCode::USER_MODE) {
+            println!("page fault in user mode ({}), killing the process", e);
+            process::exit(-1);
+        }
+        panic!("EXCEPTION: PAGE FAULT ({})\n{:?}\n{:#?}", e, error_code, stack_frame);
+    }
+}
+
 extern "x86-interrupt" fn xhci_handler(_stack_frame: InterruptStackFrame) {
     println!("hi");
-}
\ No newline at end of file
+}
+
+extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {}
+
+extern "x86-interrupt" fn irq_handler<const INDEX: usize>(_stack_frame: InterruptStackFrame) {
+    if let Some((_, handlers)) = IRQ_HANDLERS.lock().get(INDEX) {
+        for handler in handlers {
+            handler();
+        }
+    }
+    apic::end_of_interrupt();
+}
@@ -0,0 +1,24 @@
+// Port I/O (asmfunc.asm)
+
+extern "C" {
+    fn IoOut32(addr: u16, data: u32);
+    fn IoIn32(addr: u16) -> u32;
+    fn IoOut8(addr: u16, data: u8);
+    fn IoIn8(addr: u16) -> u8;
+}
+
+pub unsafe fn out32(addr: u16, data: u32) {
+    IoOut32(addr, data)
+}
+
+pub unsafe fn in32(addr: u16) -> u32 {
+    IoIn32(addr)
+}
+
+pub unsafe fn out8(addr: u16, data: u8) {
+    IoOut8(addr, data)
+}
+
+pub unsafe fn in8(addr: u16) -> u8 {
+    IoIn8(addr)
+}
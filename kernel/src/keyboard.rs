@@ -0,0 +1,254 @@
+// Keyboard input events
+//
+// キーボードのドライバはスキャンコードをreceiveに渡す。押下と解放をKeyEventにしてキューに積み、
+// 受け取る側はnext_eventで取り出す。Shiftなどの修飾キーとCapsLockなどのロックはここで覚えておく
+
+pub mod layout;
+pub mod scancode;
+
+use alloc::collections::VecDeque;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use layout::Layout;
+use scancode::{Decoder, ScancodeSet};
+
+// 取り出されないまま溜まったら古いものから捨てる
+const MAX_EVENTS: usize = 128;
+
+// キーの位置。名前はUS配列の刻印に合わせ、JISにしか無いキーは別に持つ
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+
+    Backquote,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equal,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftGui,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightGui,
+    Menu,
+    RightCtrl,
+
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Up,
+    Down,
+    Left,
+    Right,
+
+    NumLock,
+    KeypadSlash,
+    KeypadAsterisk,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+
+    // JIS
+    Yen,
+    Ro,
+    Muhenkan,
+    Henkan,
+    KatakanaHiragana,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub gui: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    // キーボードのSet LEDsコマンドに渡すビット
+    pub fn leds(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+    // このキーを押す前の修飾キーとロックの状態
+    pub modifiers: Modifiers,
+    // 配列で決まる文字。文字の無いキーや解放のときはNone
+    pub char: Option<char>,
+}
+
+struct Keyboard {
+    decoder: Decoder,
+    layout: Layout,
+    modifiers: Modifiers,
+    // 押しっぱなしのリピートを見分けるため、最後に押されたキーを覚えておく
+    last_pressed: Option<KeyCode>,
+}
+
+impl Keyboard {
+    // ロックの状態が変わったらtrue
+    fn update_modifiers(&mut self, key: KeyCode, pressed: bool) -> bool {
+        let modifiers = &mut self.modifiers;
+        match key {
+            KeyCode::LeftShift => modifiers.left_shift = pressed,
+            KeyCode::RightShift => modifiers.right_shift = pressed,
+            KeyCode::LeftCtrl => modifiers.left_ctrl = pressed,
+            KeyCode::RightCtrl => modifiers.right_ctrl = pressed,
+            KeyCode::LeftAlt => modifiers.left_alt = pressed,
+            KeyCode::RightAlt => modifiers.right_alt = pressed,
+            KeyCode::LeftGui | KeyCode::RightGui => modifiers.gui = pressed,
+            // ロックは押すたびに切り替わる。押しっぱなしのリピートでは切り替えない
+            KeyCode::CapsLock | KeyCode::NumLock | KeyCode::ScrollLock if pressed => {
+                let lock = match key {
+                    KeyCode::CapsLock => &mut modifiers.caps_lock,
+                    KeyCode::NumLock => &mut modifiers.num_lock,
+                    _ => &mut modifiers.scroll_lock,
+                };
+                *lock = !*lock;
+                return true;
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
+        decoder: Decoder::new(ScancodeSet::Set1),
+        layout: Layout::Us,
+        modifiers: Modifiers::default(),
+        last_pressed: None,
+    });
+    static ref EVENTS: Mutex<VecDeque<KeyEvent>> = Mutex::new(VecDeque::new());
+}
+
+pub fn set_layout(layout: Layout) {
+    KEYBOARD.lock().layout = layout;
+}
+
+// ドライバがコントローラーの変換の有無に合わせて決める
+pub fn set_scancode_set(set: ScancodeSet) {
+    KEYBOARD.lock().decoder = Decoder::new(set);
+}
+
+// スキャンコードを1バイト受け取る。ロックの状態が変わったら、LEDに送るビットを返す
+pub fn receive(byte: u8) -> Option<u8> {
+    let mut keyboard = KEYBOARD.lock();
+    let (key, pressed) = keyboard.decoder.advance(byte)?;
+    let repeated = pressed && keyboard.last_pressed == Some(key);
+    keyboard.last_pressed = pressed.then_some(key);
+
+    let modifiers = keyboard.modifiers;
+    let char = if pressed { keyboard.layout.char(key, modifiers) } else { None };
+    let locks_changed = !repeated && keyboard.update_modifiers(key, pressed);
+
+    let mut events = EVENTS.lock();
+    if events.len() >= MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(KeyEvent { key, pressed, modifiers, char });
+    locks_changed.then(|| keyboard.modifiers.leds())
+}
+
+pub fn next_event() -> Option<KeyEvent> {
+    EVENTS.lock().pop_front()
+}
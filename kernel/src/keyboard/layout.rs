@@ -0,0 +1,168 @@
+// Keyboard layouts
+//
+// キーの位置から文字を決める。USとJISで違うのは記号の位置だけなので、違う所だけをそれぞれに書く。
+// hankakuフォントの0x5cはバックスラッシュなので、JISの¥キーもバックスラッシュにする
+
+use super::KeyCode::{self, *};
+use super::Modifiers;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Jis,
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "us" => Some(Self::Us),
+            "jis" | "jp" => Some(Self::Jis),
+            _ => None,
+        }
+    }
+
+    // CtrlやAltは見ない。Ctrlを押したときの制御文字は受け取る側で作る
+    pub fn char(self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        let shift = modifiers.shift();
+        let symbols = match self {
+            Self::Us => us(key),
+            Self::Jis => jis(key),
+        };
+        if let Some((normal, shifted)) = symbols {
+            return if shift { shifted } else { Some(normal) };
+        }
+        if let Some(letter) = letter(key) {
+            return Some(if shift != modifiers.caps_lock { letter.to_ascii_uppercase() } else { letter });
+        }
+        if modifiers.num_lock {
+            if let Some(digit) = keypad_digit(key) {
+                return Some(digit);
+            }
+        }
+        let c = match key {
+            Comma if shift => '<',
+            Comma => ',',
+            Period if shift => '>',
+            Period => '.',
+            Slash if shift => '?',
+            Slash => '/',
+            Space => ' ',
+            Enter | KeypadEnter => '\n',
+            Tab => '\t',
+            Backspace => '\x08',
+            Escape => '\x1b',
+            Delete => '\x7f',
+            KeypadAsterisk => '*',
+            KeypadMinus => '-',
+            KeypadPlus => '+',
+            KeypadSlash => '/',
+            _ => return None,
+        };
+        Some(c)
+    }
+}
+
+// 数字の列のShiftと、記号のキー。Shiftで出る文字が無ければNone
+fn us(key: KeyCode) -> Option<(char, Option<char>)> {
+    let symbols = match key {
+        Key1 => ('1', '!'),
+        Key2 => ('2', '@'),
+        Key3 => ('3', '#'),
+        Key4 => ('4', '$'),
+        Key5 => ('5', '%'),
+        Key6 => ('6', '^'),
+        Key7 => ('7', '&'),
+        Key8 => ('8', '*'),
+        Key9 => ('9', '('),
+        Key0 => ('0', ')'),
+        Minus => ('-', '_'),
+        Equal => ('=', '+'),
+        LeftBracket => ('[', '{'),
+        RightBracket => (']', '}'),
+        Backslash => ('\\', '|'),
+        Semicolon => (';', ':'),
+        Quote => ('\'', '"'),
+        Backquote => ('`', '~'),
+        NonUsBackslash => ('\\', '|'),
+        _ => return None,
+    };
+    Some((symbols.0, Some(symbols.1)))
+}
+
+fn jis(key: KeyCode) -> Option<(char, Option<char>)> {
+    let symbols = match key {
+        Key1 => ('1', Some('!')),
+        Key2 => ('2', Some('"')),
+        Key3 => ('3', Some('#')),
+        Key4 => ('4', Some('$')),
+        Key5 => ('5', Some('%')),
+        Key6 => ('6', Some('&')),
+        Key7 => ('7', Some('\'')),
+        Key8 => ('8', Some('(')),
+        Key9 => ('9', Some(')')),
+        Key0 => ('0', None),
+        Minus => ('-', Some('=')),
+        Equal => ('^', Some('~')),
+        Yen => ('\\', Some('|')),
+        LeftBracket => ('@', Some('`')),
+        RightBracket => ('[', Some('{')),
+        Semicolon => (';', Some('+')),
+        Quote => (':', Some('*')),
+        Backslash => (']', Some('}')),
+        Ro => ('\\', Some('_')),
+        _ => return None,
+    };
+    Some(symbols)
+}
+
+fn letter(key: KeyCode) -> Option<char> {
+    let c = match key {
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => return None,
+    };
+    Some(c)
+}
+
+// NumLockが点いているときのテンキー
+fn keypad_digit(key: KeyCode) -> Option<char> {
+    let c = match key {
+        Keypad0 => '0',
+        Keypad1 => '1',
+        Keypad2 => '2',
+        Keypad3 => '3',
+        Keypad4 => '4',
+        Keypad5 => '5',
+        Keypad6 => '6',
+        Keypad7 => '7',
+        Keypad8 => '8',
+        Keypad9 => '9',
+        KeypadPeriod => '.',
+        _ => return None,
+    };
+    Some(c)
+}
@@ -0,0 +1,301 @@
+// Scancode sets
+//
+// キーボードから届くバイト列をキーの押下と解放にする。
+// i8042が変換していればset 1、していなければset 2で届く。set 2はset 1の番号に直してから同じ表で引く
+
+use super::KeyCode;
+use super::KeyCode::*;
+
+const EXTENDED: u8 = 0xe0;
+const PAUSE: u8 = 0xe1;
+const SET1_RELEASE: u8 = 0x80;
+const SET2_RELEASE: u8 = 0xf0;
+// Pauseは解放の無い一続きのバイト列で届く。E1の後に続くバイトの数
+const SET1_PAUSE_LEN: usize = 5;
+const SET2_PAUSE_LEN: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Start,
+    Extended,
+    // set 2のF0の後
+    Release { extended: bool },
+    Pause { remaining: usize },
+}
+
+pub struct Decoder {
+    set: ScancodeSet,
+    state: State,
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Self { set, state: State::Start }
+    }
+
+    // 1バイト受け取り、キーが決まったら(キー, 押されたか)を返す
+    pub fn advance(&mut self, byte: u8) -> Option<(KeyCode, bool)> {
+        match (self.state, byte) {
+            (State::Pause { remaining }, _) => {
+                self.state = if remaining > 1 { State::Pause { remaining: remaining - 1 } } else { State::Start };
+                (remaining == 1).then_some((Pause, true))
+            }
+            (State::Start, PAUSE) => {
+                let remaining = match self.set {
+                    ScancodeSet::Set1 => SET1_PAUSE_LEN,
+                    ScancodeSet::Set2 => SET2_PAUSE_LEN,
+                };
+                self.state = State::Pause { remaining };
+                None
+            }
+            (State::Start, EXTENDED) => {
+                self.state = State::Extended;
+                None
+            }
+            (State::Start | State::Extended, SET2_RELEASE) if self.set == ScancodeSet::Set2 => {
+                self.state = State::Release { extended: matches!(self.state, State::Extended) };
+                None
+            }
+            (state, code) => {
+                self.state = State::Start;
+                match (self.set, state) {
+                    (ScancodeSet::Set1, _) => {
+                        let extended = matches!(state, State::Extended);
+                        Some((set1_key(code & !SET1_RELEASE, extended)?, code & SET1_RELEASE == 0))
+                    }
+                    (ScancodeSet::Set2, State::Release { extended }) => {
+                        Some((set1_key(set2_to_set1(code)?, extended)?, false))
+                    }
+                    (ScancodeSet::Set2, _) => {
+                        Some((set1_key(set2_to_set1(code)?, matches!(state, State::Extended))?, true))
+                    }
+                }
+            }
+        }
+    }
+}
+
+// i8042がset 2をset 1に変換するときと同じ対応
+fn set2_to_set1(code: u8) -> Option<u8> {
+    let code = match code {
+        0x01 => 0x43,
+        0x03 => 0x3f,
+        0x04 => 0x3d,
+        0x05 => 0x3b,
+        0x06 => 0x3c,
+        0x07 => 0x58,
+        0x09 => 0x44,
+        0x0a => 0x42,
+        0x0b => 0x40,
+        0x0c => 0x3e,
+        0x0d => 0x0f,
+        0x0e => 0x29,
+        0x11 => 0x38,
+        0x12 => 0x2a,
+        0x13 => 0x70,
+        0x14 => 0x1d,
+        0x15 => 0x10,
+        0x16 => 0x02,
+        0x1a => 0x2c,
+        0x1b => 0x1f,
+        0x1c => 0x1e,
+        0x1d => 0x11,
+        0x1e => 0x03,
+        0x1f => 0x5b,
+        0x21 => 0x2e,
+        0x22 => 0x2d,
+        0x23 => 0x20,
+        0x24 => 0x12,
+        0x25 => 0x05,
+        0x26 => 0x04,
+        0x27 => 0x5c,
+        0x29 => 0x39,
+        0x2a => 0x2f,
+        0x2b => 0x21,
+        0x2c => 0x14,
+        0x2d => 0x13,
+        0x2e => 0x06,
+        0x2f => 0x5d,
+        0x31 => 0x31,
+        0x32 => 0x30,
+        0x33 => 0x23,
+        0x34 => 0x22,
+        0x35 => 0x15,
+        0x36 => 0x07,
+        0x3a => 0x32,
+        0x3b => 0x24,
+        0x3c => 0x16,
+        0x3d => 0x08,
+        0x3e => 0x09,
+        0x41 => 0x33,
+        0x42 => 0x25,
+        0x43 => 0x17,
+        0x44 => 0x18,
+        0x45 => 0x0b,
+        0x46 => 0x0a,
+        0x49 => 0x34,
+        0x4a => 0x35,
+        0x4b => 0x26,
+        0x4c => 0x27,
+        0x4d => 0x19,
+        0x4e => 0x0c,
+        0x51 => 0x73,
+        0x52 => 0x28,
+        0x54 => 0x1a,
+        0x55 => 0x0d,
+        0x58 => 0x3a,
+        0x59 => 0x36,
+        0x5a => 0x1c,
+        0x5b => 0x1b,
+        0x5d => 0x2b,
+        0x61 => 0x56,
+        0x64 => 0x79,
+        0x66 => 0x0e,
+        0x67 => 0x7b,
+        0x69 => 0x4f,
+        0x6a => 0x7d,
+        0x6b => 0x4b,
+        0x6c => 0x47,
+        0x70 => 0x52,
+        0x71 => 0x53,
+        0x72 => 0x50,
+        0x73 => 0x4c,
+        0x74 => 0x4d,
+        0x75 => 0x48,
+        0x76 => 0x01,
+        0x77 => 0x45,
+        0x78 => 0x57,
+        0x79 => 0x4e,
+        0x7a => 0x51,
+        0x7b => 0x4a,
+        0x7c => 0x37,
+        0x7d => 0x49,
+        0x7e => 0x46,
+        0x83 => 0x41,
+        _ => return None,
+    };
+    Some(code)
+}
+
+// PrintScreenなどに付いてくるE0 2A(E0 12)のような偽のShiftは無視する
+fn set1_key(code: u8, extended: bool) -> Option<KeyCode> {
+    let key = match (extended, code) {
+        (false, 0x01) => Escape,
+        (false, 0x02) => Key1,
+        (false, 0x03) => Key2,
+        (false, 0x04) => Key3,
+        (false, 0x05) => Key4,
+        (false, 0x06) => Key5,
+        (false, 0x07) => Key6,
+        (false, 0x08) => Key7,
+        (false, 0x09) => Key8,
+        (false, 0x0a) => Key9,
+        (false, 0x0b) => Key0,
+        (false, 0x0c) => Minus,
+        (false, 0x0d) => Equal,
+        (false, 0x0e) => Backspace,
+        (false, 0x0f) => Tab,
+        (false, 0x10) => Q,
+        (false, 0x11) => W,
+        (false, 0x12) => E,
+        (false, 0x13) => R,
+        (false, 0x14) => T,
+        (false, 0x15) => Y,
+        (false, 0x16) => U,
+        (false, 0x17) => I,
+        (false, 0x18) => O,
+        (false, 0x19) => P,
+        (false, 0x1a) => LeftBracket,
+        (false, 0x1b) => RightBracket,
+        (false, 0x1c) => Enter,
+        (false, 0x1d) => LeftCtrl,
+        (false, 0x1e) => A,
+        (false, 0x1f) => S,
+        (false, 0x20) => D,
+        (false, 0x21) => F,
+        (false, 0x22) => G,
+        (false, 0x23) => H,
+        (false, 0x24) => J,
+        (false, 0x25) => K,
+        (false, 0x26) => L,
+        (false, 0x27) => Semicolon,
+        (false, 0x28) => Quote,
+        (false, 0x29) => Backquote,
+        (false, 0x2a) => LeftShift,
+        (false, 0x2b) => Backslash,
+        (false, 0x2c) => Z,
+        (false, 0x2d) => X,
+        (false, 0x2e) => C,
+        (false, 0x2f) => V,
+        (false, 0x30) => B,
+        (false, 0x31) => N,
+        (false, 0x32) => M,
+        (false, 0x33) => Comma,
+        (false, 0x34) => Period,
+        (false, 0x35) => Slash,
+        (false, 0x36) => RightShift,
+        (false, 0x37) => KeypadAsterisk,
+        (false, 0x38) => LeftAlt,
+        (false, 0x39) => Space,
+        (false, 0x3a) => CapsLock,
+        (false, 0x3b) => F1,
+        (false, 0x3c) => F2,
+        (false, 0x3d) => F3,
+        (false, 0x3e) => F4,
+        (false, 0x3f) => F5,
+        (false, 0x40) => F6,
+        (false, 0x41) => F7,
+        (false, 0x42) => F8,
+        (false, 0x43) => F9,
+        (false, 0x44) => F10,
+        (false, 0x45) => NumLock,
+        (false, 0x46) => ScrollLock,
+        (false, 0x47) => Keypad7,
+        (false, 0x48) => Keypad8,
+        (false, 0x49) => Keypad9,
+        (false, 0x4a) => KeypadMinus,
+        (false, 0x4b) => Keypad4,
+        (false, 0x4c) => Keypad5,
+        (false, 0x4d) => Keypad6,
+        (false, 0x4e) => KeypadPlus,
+        (false, 0x4f) => Keypad1,
+        (false, 0x50) => Keypad2,
+        (false, 0x51) => Keypad3,
+        (false, 0x52) => Keypad0,
+        (false, 0x53) => KeypadPeriod,
+        (false, 0x56) => NonUsBackslash,
+        (false, 0x57) => F11,
+        (false, 0x58) => F12,
+        (false, 0x70) => KatakanaHiragana,
+        (false, 0x73) => Ro,
+        (false, 0x79) => Henkan,
+        (false, 0x7b) => Muhenkan,
+        (false, 0x7d) => Yen,
+        (true, 0x1c) => KeypadEnter,
+        (true, 0x1d) => RightCtrl,
+        (true, 0x35) => KeypadSlash,
+        (true, 0x37) => PrintScreen,
+        (true, 0x38) => RightAlt,
+        (true, 0x47) => Home,
+        (true, 0x48) => Up,
+        (true, 0x49) => PageUp,
+        (true, 0x4b) => Left,
+        (true, 0x4d) => Right,
+        (true, 0x4f) => End,
+        (true, 0x50) => Down,
+        (true, 0x51) => PageDown,
+        (true, 0x52) => Insert,
+        (true, 0x53) => Delete,
+        (true, 0x5b) => LeftGui,
+        (true, 0x5c) => RightGui,
+        (true, 0x5d) => Menu,
+        _ => return None,
+    };
+    Some(key)
+}
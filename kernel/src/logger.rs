@@ -0,0 +1,157 @@
+// Kernel logger
+//
+// logクレートのマクロの出力を、起動からの時間とCPUの番号を付けてシリアル、コンソール、リングバッファに出す。
+// 出すレベルはcmdlineの"log=warn,net=debug,fs::ext2=trace"のように、全体とモジュールごとに決められる
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::fmt::Write;
+use lazy_static::lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::mutex::Mutex;
+
+use crate::{apic, console, serial, timer};
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+// dmesgで見られる量。あふれたら古い行から捨てる
+const RING_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum LoggerError {
+    InvalidLevel,
+    AlreadyInstalled,
+}
+
+struct Filter {
+    default: LevelFilter,
+    // (モジュールのパス, レベル)。長いパスほど前に並べる
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Result<Self, LoggerError> {
+        let mut filter = Self { default: DEFAULT_LEVEL, modules: Vec::new() };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let level = level.parse().map_err(|_| LoggerError::InvalidLevel)?;
+                    filter.modules.push((module.trim().to_string(), level));
+                }
+                None => filter.default = directive.parse().map_err(|_| LoggerError::InvalidLevel)?,
+            }
+        }
+        filter.modules.sort_by_key(|(module, _)| Reverse(module.len()));
+        Ok(filter)
+    }
+
+    fn level(&self, module: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(path, _)| {
+                module.strip_prefix(path.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+}
+
+// 一杯になったら先頭の行を丸ごと捨てる
+struct Ring {
+    buffer: VecDeque<u8>,
+}
+
+impl Ring {
+    fn push(&mut self, line: &str) {
+        let line = &line.as_bytes()[line.len().saturating_sub(RING_CAPACITY)..];
+        while self.buffer.len() + line.len() > RING_CAPACITY {
+            match self.buffer.iter().position(|&b| b == b'\n') {
+                Some(end) => drop(self.buffer.drain(..=end)),
+                None => self.buffer.clear(),
+            }
+        }
+        self.buffer.extend(line);
+    }
+}
+
+struct KernelLogger;
+
+// SGRの文字の色
+fn level_color(level: Level) -> u8 {
+    match level {
+        Level::Error => 31,
+        Level::Warn => 33,
+        Level::Info => 32,
+        Level::Debug => 36,
+        Level::Trace => 90,
+    }
+}
+
+static LOGGER: KernelLogger = KernelLogger;
+
+lazy_static! {
+    static ref FILTER: Mutex<Filter> = Mutex::new(Filter { default: DEFAULT_LEVEL, modules: Vec::new() });
+    static ref RING: Mutex<Ring> = Mutex::new(Ring { buffer: VecDeque::new() });
+}
+
+// targetは"kernel::net::dhcp"のようなモジュールのパスなので、クレート名を外して見る
+fn module(target: &str) -> &str {
+    target.strip_prefix("kernel::").unwrap_or(target)
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.lock().level(module(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = timer::uptime_ms();
+        let header = format!("[{:5}.{:03}] cpu{} ", now / 1000, now % 1000, apic::id());
+        let level = format!("{:5}", record.level());
+        let mut body = String::new();
+        let _ = writeln!(body, " {}: {}", module(record.target()), record.args());
+        // 端末ではレベルに色を付ける。dmesgで読むものには付けない
+        let colored = format!("{}\x1b[{}m{}\x1b[0m{}", header, level_color(record.level()), level, body);
+        serial::_print(format_args!("{}", colored));
+        console::_print(format_args!("{}", colored));
+        RING.lock().push(&format!("{}{}{}", header, level, body));
+    }
+
+    fn flush(&self) {}
+}
+
+// cmdlineのlog=の指定で始める。指定が読めなければ既定のレベルのまま
+pub fn init(spec: Option<&str>) -> Result<(), LoggerError> {
+    log::set_logger(&LOGGER).map_err(|_| LoggerError::AlreadyInstalled)?;
+    log::set_max_level(DEFAULT_LEVEL);
+    match spec {
+        Some(spec) => set_filter(spec),
+        None => Ok(()),
+    }
+}
+
+pub fn set_filter(spec: &str) -> Result<(), LoggerError> {
+    let filter = Filter::parse(spec)?;
+    log::set_max_level(filter.max_level());
+    *FILTER.lock() = filter;
+    Ok(())
+}
+
+// リングバッファに残っている行を古い順に渡す
+pub fn dmesg(mut f: impl FnMut(&str)) {
+    let ring = RING.lock();
+    let (front, back) = ring.buffer.as_slices();
+    let bytes: Vec<u8> = front.iter().chain(back).copied().collect();
+    drop(ring);
+    for line in String::from_utf8_lossy(&bytes).lines() {
+        f(line);
+    }
+}
@@ -13,6 +13,10 @@ extern crate alloc;
 mod library;
 mod graphics;
 mod console;
+mod serial;
+mod keyboard;
+mod ps2;
+mod logger;
 mod frame_buffer;
 mod interrupts;
 mod gdt;
@@ -21,35 +25,106 @@ mod memory_manager;
 mod allocator;
 mod pci;
 mod error;
+mod vma;
+mod stack;
+mod process;
+mod syscall;
+mod elf;
+mod io;
+mod timer;
+mod fs;
+mod initrd;
+mod acpi_tables;
+mod apic;
+mod dma;
+mod block;
+mod ahci;
+mod e1000;
+mod virtio;
+mod net;
+mod shell;
+mod tty;
 
+use alloc::boxed::Box;
 use core::{panic::PanicInfo, arch::asm};
+use common::boot_info::BootInfo;
 use common::frame_buffer::FrameBufferConfig;
 use common::memory_map::MemoryMap;
 use graphics::pixel_writer;
 use allocator::MemoryAllocator;
 
 #[no_mangle]
-pub extern "sysv64" fn kernel_stack_main(frame_buffer_config: &FrameBufferConfig, memory_map: &MemoryMap) {
-   unsafe { init(frame_buffer_config, memory_map); }
+pub extern "sysv64" fn kernel_stack_main(boot_info: &BootInfo) {
+    let boot_info = boot_info.clone();
+    unsafe { init_memory(&boot_info.frame_buffer_config, &boot_info.memory_map); }
+
+    // asmfunc.asmのkernel_main_stackにはガードページが無いので、
+    // ガードページ付きのスタックを作れるようになったらすぐに移り、残りの初期化はそちらで行う
+    let boot_info: &'static BootInfo = Box::leak(Box::new(boot_info));
+    let stack_top = stack::KernelStack::new("kernel_main", KERNEL_MAIN_STACK_PAGES).leak();
+    stack::switch_to(stack_top, kernel_guarded_main, boot_info)
+}
+
+const KERNEL_MAIN_STACK_PAGES: usize = 256; // 1MiB
+
+extern "sysv64" fn kernel_guarded_main(boot_info: &'static BootInfo) -> ! {
+    let frame_buffer_config = &boot_info.frame_buffer_config;
+    unsafe { init(); }
+    if let Err(e) = logger::init(boot_info.cmdline.get("log")) {
+        println!("logger: {:?}", e);
+    }
+    console::init_scrollback(boot_info.cmdline.get("scrollback"));
     
     pixel_writer().as_mut().unwrap().draw_desktop(frame_buffer_config.width(), frame_buffer_config.height());
 
     println!("Hello World");
-    unsafe {
-        paging::init();
-        memory_manager::frame_manager().init(memory_map); // unsafe
+
+    if let Err(e) = initrd::unpack(boot_info.initrd) {
+        println!("initrd: {:?}", e);
     }
-    
+
+    acpi_tables::init(boot_info.rsdp);
+    apic::init();
+    ps2::init(boot_info.cmdline.get("keymap"));
+    pci::init();
+    fs::mount_block_devices();
+    net::init();
+    shell::init();
+
+    kernel_main_loop()
+}
+
+fn kernel_main_loop() -> ! {
+    // 割り込みはhltで待っている間だけ受け付ける。NICの受信かタイマーで起きたらpollする
     loop {
-        unsafe {asm!("hlt")}
+        net::poll();
+        tty::poll();
+        shell::poll();
+        if apic::ticking() {
+            x86_64::instructions::interrupts::enable_and_hlt();
+            x86_64::instructions::interrupts::disable();
+        } else {
+            core::hint::spin_loop();
+        }
     }
 }
 
-unsafe fn init(config: &FrameBufferConfig, _memory_map: &MemoryMap) {
+// ここまででヒープとガードページ付きのスタックが使えるようになる
+unsafe fn init_memory(config: &FrameBufferConfig, memory_map: &MemoryMap) {
+    serial::init();
     graphics::init(*config);
     console::init();
+    paging::init();
+    memory_manager::frame_manager().init(memory_map);
+    vma::init();
     gdt::init();
+}
+
+unsafe fn init() {
     interrupts::init();
+    syscall::init();
+    timer::init();
+    fs::init();
 }
 
 #[panic_handler]
@@ -82,15 +82,15 @@ impl Frame {
         Self(self.0 + offset)
     }
 
-    fn phys_addr(self) -> PhysAddr {
+    pub fn phys_addr(self) -> PhysAddr {
         PhysAddr::new((self.0 * Frame::SIZE) as u64)
     }
 
-    fn from_phys_addr(addr: PhysAddr) -> Self {
+    pub fn from_phys_addr(addr: PhysAddr) -> Self {
         Self(addr.as_u64() as usize / Frame::SIZE)
     }
 
-    fn phys_frame(self) -> PhysFrame {
+    pub fn phys_frame(self) -> PhysFrame {
         PhysFrame::from_start_address(self.phys_addr()).unwrap()
     }
 
@@ -187,6 +187,30 @@ impl BitmapMemoryManager {
             self.set_bit(frame.offset(i), false);
         }
     }
+
+    // beginからendまでのフレームの数と、そのうち使用中のもの
+    pub fn stats(&self) -> MemoryStats {
+        let mut used_frames = 0;
+        for line_index in self.begin.0 / BITS_PER_MAP_LINE..self.end.0.div_ceil(BITS_PER_MAP_LINE) {
+            let first = line_index * BITS_PER_MAP_LINE;
+            let mut line = self.alloc_map[line_index];
+            // 範囲の外にはみ出したビットは数えない
+            if first < self.begin.0 {
+                line &= MapLine::MAX << (self.begin.0 - first);
+            }
+            if first + BITS_PER_MAP_LINE > self.end.0 {
+                line &= (1 << (self.end.0 - first)) - 1;
+            }
+            used_frames += line.count_ones() as usize;
+        }
+        MemoryStats { total_frames: self.end.0 - self.begin.0, used_frames }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryStats {
+    pub total_frames: usize,
+    pub used_frames: usize,
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BitmapMemoryManager {
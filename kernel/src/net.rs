@@ -0,0 +1,195 @@
+// Network devices and stack
+//
+// NICのドライバはNetworkDeviceを実装してregisterする。登録したデバイスごとにInterfaceができる。
+// NICの割り込みはhltで待っているメインループを起こすだけで、受信もタイマーもpollを呼んだときにまとめて処理する。
+// 割り込みの中でスタックやインターフェースのロックを取らないので、pollとの間でデッドロックしない
+
+pub mod arp;
+pub mod dhcp;
+pub mod dns;
+pub mod ethernet;
+pub mod icmp;
+pub mod interface;
+pub mod ipv4;
+pub mod tcp;
+pub mod udp;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use crate::shell::{self, Command};
+use crate::{println, timer};
+
+pub use core::net::{Ipv4Addr, SocketAddrV4};
+pub use interface::{Interface, Ipv4Config};
+
+pub type MacAddress = [u8; 6];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetError {
+    // 送信キューが一杯
+    Busy,
+    FrameTooLarge,
+    LinkDown,
+    DeviceError,
+    NoRoute,
+    AddressInUse,
+    ConnectionRefused,
+    ConnectionReset,
+    NotConnected,
+    TimedOut,
+    // DNSで名前が見つからない
+    NameNotFound,
+    InvalidName,
+    ServerFailure,
+}
+
+pub trait NetworkDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn mac_address(&self) -> MacAddress;
+
+    fn link_up(&self) -> bool;
+
+    // Ethernetフレーム(FCSを除く)を送る
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    // 受信したフレームを1つ取り出す。無ければNone
+    fn receive(&self) -> Option<Vec<u8>>;
+}
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<Arc<dyn NetworkDevice>>> = Mutex::new(Vec::new());
+    static ref INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+}
+
+static NEXT_DEVICE: AtomicUsize = AtomicUsize::new(0);
+
+// 1回のpollで1つのインターフェースから取り出すフレームの数
+const RECEIVE_BUDGET: usize = 64;
+
+pub fn register(device: Arc<dyn NetworkDevice>) {
+    let mac = device.mac_address();
+    println!(
+        "net: {} {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} link {}",
+        device.name(),
+        mac[0],
+        mac[1],
+        mac[2],
+        mac[3],
+        mac[4],
+        mac[5],
+        if device.link_up() { "up" } else { "down" }
+    );
+    DEVICES.lock().push(device.clone());
+    INTERFACES.lock().push(Interface::new(device));
+}
+
+// ドライバが付けるデバイスの名前。eth0から順に
+pub fn next_name() -> String {
+    format!("eth{}", NEXT_DEVICE.fetch_add(1, Ordering::Relaxed))
+}
+
+pub fn devices() -> Vec<Arc<dyn NetworkDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+pub fn interface(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES.lock().iter().find(|interface| interface.name() == name).cloned()
+}
+
+// 全てのインターフェースでDHCPを始める。アドレスはpollを回しているうちに設定される
+pub fn init() {
+    for interface in interfaces() {
+        dhcp::start(&interface);
+    }
+    shell::register(Arc::new(Ifconfig));
+}
+
+struct Ifconfig;
+
+impl Command for Ifconfig {
+    fn name(&self) -> &str {
+        "ifconfig"
+    }
+
+    fn description(&self) -> &str {
+        "show network interfaces"
+    }
+
+    fn run(&self, _args: &[&str]) {
+        for interface in interfaces() {
+            let mac = interface.mac_address();
+            println!(
+                "  {} {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} link {}",
+                interface.name(),
+                mac[0],
+                mac[1],
+                mac[2],
+                mac[3],
+                mac[4],
+                mac[5],
+                if interface.device().link_up() { "up" } else { "down" }
+            );
+            match interface.config() {
+                Some(config) => println!("    inet {}/{}", config.address, config.prefix_len),
+                None => println!("    no address"),
+            }
+        }
+        println!("  dns {:?}", dns::servers());
+    }
+}
+
+// 受信したフレームを処理し、ARPやTCP、DHCPのタイマーを進める
+pub fn poll() {
+    for interface in interfaces() {
+        for _ in 0..RECEIVE_BUDGET {
+            let Some(frame) = interface.device().receive() else {
+                break;
+            };
+            ethernet::receive(&interface, &frame);
+        }
+        arp::poll(&interface);
+    }
+    ipv4::poll_loopback();
+    tcp::poll();
+    dhcp::poll();
+}
+
+// conditionがSomeを返すまでpollを回して待つ。timeout_msが経ったらNone
+pub fn wait_for<T>(timeout_ms: Option<u64>, mut condition: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = timeout_ms.map(|ms| timer::uptime_ms() + ms);
+    loop {
+        poll();
+        if let Some(value) = condition() {
+            return Some(value);
+        }
+        if deadline.is_some_and(|deadline| timer::uptime_ms() >= deadline) {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(*EPHEMERAL_PORTS.start());
+
+// in_useでないポートを順番に探す
+fn ephemeral_port(in_use: impl Fn(u16) -> bool) -> Option<u16> {
+    let count = EPHEMERAL_PORTS.len();
+    (0..count).find_map(|_| {
+        let port = NEXT_EPHEMERAL_PORT.load(Ordering::Relaxed);
+        let next = if port == *EPHEMERAL_PORTS.end() { *EPHEMERAL_PORTS.start() } else { port + 1 };
+        NEXT_EPHEMERAL_PORT.store(next, Ordering::Relaxed);
+        (!in_use(port)).then_some(port)
+    })
+}
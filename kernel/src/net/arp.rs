@@ -0,0 +1,167 @@
+// ARP
+//
+// 宛先のMACアドレスが分かるまで、送ろうとしたパケットはキャッシュのエントリに溜めておく
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{ethernet, Interface, Ipv4Addr, MacAddress, NetError};
+use crate::timer;
+
+const HTYPE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+const PACKET_LEN: usize = 28;
+
+const ENTRY_TIMEOUT_MS: u64 = 60_000;
+const REQUEST_INTERVAL_MS: u64 = 1000;
+const MAX_REQUESTS: u32 = 3;
+const MAX_PENDING: usize = 16;
+
+pub struct ArpCache {
+    entries: BTreeMap<Ipv4Addr, Entry>,
+}
+
+struct Entry {
+    mac: Option<MacAddress>,
+    // 解決したとき、または最後に要求を送ったとき
+    updated: u64,
+    requests: u32,
+    pending: Vec<Vec<u8>>,
+}
+
+impl ArpCache {
+    pub fn new() -> Self {
+        Self { entries: BTreeMap::new() }
+    }
+
+    pub fn entries(&self) -> Vec<(Ipv4Addr, MacAddress)> {
+        self.entries.iter().filter_map(|(&ip, entry)| Some((ip, entry.mac?))).collect()
+    }
+}
+
+// IPv4のパケットをnext_hopへ送る
+pub fn send(interface: &Arc<Interface>, next_hop: Ipv4Addr, packet: Vec<u8>) -> Result<(), NetError> {
+    let now = timer::uptime_ms();
+    let request = {
+        let mut cache = interface.arp();
+        let entry = cache.entries.entry(next_hop).or_insert_with(|| Entry {
+            mac: None,
+            updated: now,
+            requests: 0,
+            pending: Vec::new(),
+        });
+        if let Some(mac) = entry.mac {
+            drop(cache);
+            return interface.send_frame(mac, ethernet::ETHERTYPE_IPV4, &packet);
+        }
+        if entry.pending.len() >= MAX_PENDING {
+            return Err(NetError::Busy);
+        }
+        entry.pending.push(packet);
+        let first = entry.requests == 0;
+        if first {
+            entry.requests = 1;
+            entry.updated = now;
+        }
+        first
+    };
+    if request {
+        send_request(interface, next_hop);
+    }
+    Ok(())
+}
+
+pub fn receive(interface: &Arc<Interface>, packet: &[u8]) {
+    if packet.len() < PACKET_LEN
+        || u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET
+        || u16::from_be_bytes([packet[2], packet[3]]) != ethernet::ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let operation = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac: MacAddress = packet[8..14].try_into().unwrap();
+    let sender_ip = Ipv4Addr::new(packet[14], packet[15], packet[16], packet[17]);
+    let target_ip = Ipv4Addr::new(packet[24], packet[25], packet[26], packet[27]);
+    let for_us = interface.address() == Some(target_ip);
+
+    // 自分宛てなら覚え、そうでなくても知っている相手なら更新する
+    let pending = {
+        let mut cache = interface.arp();
+        if for_us && !sender_ip.is_unspecified() {
+            cache.entries.entry(sender_ip).or_insert_with(|| Entry {
+                mac: None,
+                updated: 0,
+                requests: 0,
+                pending: Vec::new(),
+            });
+        }
+        match cache.entries.get_mut(&sender_ip) {
+            Some(entry) => {
+                entry.mac = Some(sender_mac);
+                entry.updated = timer::uptime_ms();
+                entry.requests = 0;
+                core::mem::take(&mut entry.pending)
+            }
+            None => Vec::new(),
+        }
+    };
+    for packet in pending {
+        let _ = interface.send_frame(sender_mac, ethernet::ETHERTYPE_IPV4, &packet);
+    }
+
+    if for_us && operation == OP_REQUEST {
+        let reply = build(OP_REPLY, interface.mac_address(), target_ip, sender_mac, sender_ip);
+        let _ = interface.send_frame(sender_mac, ethernet::ETHERTYPE_ARP, &reply);
+    }
+}
+
+// 応答が無ければ要求を送り直し、古くなったエントリは消す
+pub fn poll(interface: &Arc<Interface>) {
+    let now = timer::uptime_ms();
+    let mut retry = Vec::new();
+    interface.arp().entries.retain(|&ip, entry| match entry.mac {
+        Some(_) => now < entry.updated + ENTRY_TIMEOUT_MS,
+        None if now < entry.updated + REQUEST_INTERVAL_MS => true,
+        None if entry.requests < MAX_REQUESTS => {
+            entry.requests += 1;
+            entry.updated = now;
+            retry.push(ip);
+            true
+        }
+        // 解決できなかったので、溜めていたパケットごと捨てる
+        None => false,
+    });
+    for ip in retry {
+        send_request(interface, ip);
+    }
+}
+
+fn send_request(interface: &Interface, target: Ipv4Addr) {
+    let sender = interface.address().unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let request = build(OP_REQUEST, interface.mac_address(), sender, [0; 6], target);
+    let _ = interface.send_frame(ethernet::BROADCAST, ethernet::ETHERTYPE_ARP, &request);
+}
+
+fn build(
+    operation: u16,
+    sender_mac: MacAddress,
+    sender_ip: Ipv4Addr,
+    target_mac: MacAddress,
+    target_ip: Ipv4Addr,
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(PACKET_LEN);
+    packet.extend_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&ethernet::ETHERTYPE_IPV4.to_be_bytes());
+    packet.push(6);
+    packet.push(4);
+    packet.extend_from_slice(&operation.to_be_bytes());
+    packet.extend_from_slice(&sender_mac);
+    packet.extend_from_slice(&sender_ip.octets());
+    packet.extend_from_slice(&target_mac);
+    packet.extend_from_slice(&target_ip.octets());
+    packet
+}
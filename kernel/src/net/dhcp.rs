@@ -0,0 +1,411 @@
+// DHCP client
+//
+// インターフェースごとにRFC 2131の状態を持ち、net::pollから進める。
+// 割り当てられたアドレス、デフォルトゲートウェイ、DNSサーバーを設定し、T1/T2で更新する
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use super::udp::UdpSocket;
+use super::{dns, ipv4, Interface, Ipv4Addr, Ipv4Config, NetError, SocketAddrV4};
+use crate::timer;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// op(1) htype(1) hlen(1) hops(1) xid(4) secs(2) flags(2) ciaddr yiaddr siaddr giaddr chaddr(16) sname(64) file(128)
+const HEADER_LEN: usize = 236;
+// BOOTPのメッセージより短いと受け取らないサーバーがある
+const MIN_MESSAGE_LEN: usize = 300;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+// 応答が無ければ4秒から倍にしながら64秒まで待って送り直す
+const INITIAL_TIMEOUT_MS: u64 = 4000;
+const MAX_TIMEOUT_MS: u64 = 64_000;
+// REQUESTへの応答がこれだけ無ければDISCOVERからやり直す
+const MAX_REQUESTS: u32 = 4;
+// RENEWINGとREBINDINGで送り直す間隔の最小
+const MIN_RENEW_INTERVAL_MS: u64 = 60_000;
+// サーバーがリース期間を付けてこなかったとき
+const DEFAULT_LEASE_SECS: u32 = 3600;
+
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub config: Ipv4Config,
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    // 受け取った時刻(ms)と、そこからのT1、T2、期限(秒)
+    pub acquired: u64,
+    pub renewal_secs: u32,
+    pub rebinding_secs: u32,
+    pub lease_secs: u32,
+}
+
+impl Lease {
+    fn renewal_time(&self) -> u64 {
+        self.acquired + self.renewal_secs as u64 * 1000
+    }
+
+    fn rebinding_time(&self) -> u64 {
+        self.acquired + self.rebinding_secs as u64 * 1000
+    }
+
+    fn expiry_time(&self) -> u64 {
+        self.acquired + self.lease_secs as u64 * 1000
+    }
+}
+
+enum State {
+    Selecting,
+    // OFFERで提示されたリースを要求している
+    Requesting(Lease),
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+struct Client {
+    interface: Arc<Interface>,
+    state: State,
+    xid: u32,
+    // 今の状態で最初に送った時刻
+    started: u64,
+    // 次に送る時刻
+    deadline: u64,
+    attempts: u32,
+    lease: Option<Lease>,
+}
+
+struct Reply {
+    message_type: u8,
+    xid: u32,
+    chaddr: [u8; 6],
+    lease: Lease,
+}
+
+lazy_static! {
+    static ref CLIENTS: Mutex<Vec<Client>> = Mutex::new(Vec::new());
+    static ref SOCKET: Mutex<Option<UdpSocket>> = Mutex::new(None);
+}
+
+// interfaceのアドレスをDHCPで取りに行く。結果はpollを回しているうちに設定される
+pub fn start(interface: &Arc<Interface>) {
+    {
+        let mut socket = SOCKET.lock();
+        if socket.is_none() {
+            match UdpSocket::bind(CLIENT_PORT) {
+                Ok(bound) => *socket = Some(bound),
+                Err(e) => return log::error!("cannot bind port {}: {:?}", CLIENT_PORT, e),
+            }
+        }
+    }
+    let mut clients = CLIENTS.lock();
+    clients.retain(|client| !Arc::ptr_eq(&client.interface, interface));
+    let mut client = Client {
+        interface: interface.clone(),
+        state: State::Selecting,
+        xid: 0,
+        started: 0,
+        deadline: 0,
+        attempts: 0,
+        lease: None,
+    };
+    client.enter(State::Selecting);
+    clients.push(client);
+}
+
+pub fn lease(interface: &Arc<Interface>) -> Option<Lease> {
+    CLIENTS.lock().iter().find(|client| Arc::ptr_eq(&client.interface, interface))?.lease.clone()
+}
+
+pub(super) fn poll() {
+    let mut clients = CLIENTS.lock();
+    if clients.is_empty() {
+        return;
+    }
+    let socket = SOCKET.lock();
+    let Some(socket) = socket.as_ref() else {
+        return;
+    };
+    while let Some((message, from)) = socket.try_recv_from() {
+        if from.port() != SERVER_PORT {
+            continue;
+        }
+        let Some(reply) = parse(&message) else {
+            continue;
+        };
+        if let Some(client) =
+            clients.iter_mut().find(|client| client.xid == reply.xid && client.interface.mac_address() == reply.chaddr)
+        {
+            client.receive(socket, reply);
+        }
+    }
+    let now = timer::uptime_ms();
+    for client in clients.iter_mut() {
+        client.on_timer(socket, now);
+    }
+}
+
+impl Client {
+    fn enter(&mut self, state: State) {
+        let now = timer::uptime_ms();
+        self.state = state;
+        self.started = now;
+        self.attempts = 0;
+        self.deadline = match &self.state {
+            State::Bound => self.lease.as_ref().map_or(now, Lease::renewal_time),
+            _ => now,
+        };
+        // 新しくやり取りを始めるときは別のxidにする
+        if matches!(self.state, State::Selecting | State::Renewing | State::Rebinding) {
+            self.xid = unsafe { core::arch::x86_64::_rdtsc() } as u32;
+        }
+    }
+
+    fn receive(&mut self, socket: &UdpSocket, reply: Reply) {
+        match (&self.state, reply.message_type) {
+            (State::Selecting, DHCPOFFER) => {
+                self.enter(State::Requesting(reply.lease));
+                self.on_timer(socket, timer::uptime_ms());
+            }
+            (State::Requesting(_) | State::Renewing | State::Rebinding, DHCPACK) => self.bind(reply.lease),
+            (State::Requesting(_) | State::Renewing | State::Rebinding, DHCPNAK) => {
+                log::warn!("{} request refused", self.interface.name());
+                self.unbind();
+            }
+            _ => {}
+        }
+    }
+
+    fn on_timer(&mut self, socket: &UdpSocket, now: u64) {
+        let next = self.lease.as_ref().and_then(|lease| match self.state {
+            State::Bound if now >= lease.renewal_time() => Some(State::Renewing),
+            State::Renewing if now >= lease.rebinding_time() => Some(State::Rebinding),
+            // 期限が切れた
+            State::Rebinding if now >= lease.expiry_time() => Some(State::Selecting),
+            _ => None,
+        });
+        match next {
+            Some(State::Selecting) => {
+                log::warn!("{} lease expired", self.interface.name());
+                return self.unbind();
+            }
+            Some(state) => self.enter(state),
+            None => {}
+        }
+        if now < self.deadline {
+            return;
+        }
+        let sent = match &self.state {
+            State::Selecting => self.send(socket, DHCPDISCOVER, None, None),
+            State::Requesting(offer) => {
+                if self.attempts >= MAX_REQUESTS {
+                    return self.enter(State::Selecting);
+                }
+                self.send(socket, DHCPREQUEST, Some(offer.config.address), Some(offer.server))
+            }
+            State::Bound => return,
+            State::Renewing | State::Rebinding => self.send(socket, DHCPREQUEST, None, None),
+        };
+        if let Err(e) = sent {
+            log::warn!("{} send failed: {:?}", self.interface.name(), e);
+        }
+        self.attempts += 1;
+        self.deadline = now
+            + match (&self.state, &self.lease) {
+                // 残り時間の半分ごとに送り直す
+                (State::Renewing, Some(lease)) => {
+                    (lease.rebinding_time().saturating_sub(now) / 2).max(MIN_RENEW_INTERVAL_MS)
+                }
+                (State::Rebinding, Some(lease)) => {
+                    (lease.expiry_time().saturating_sub(now) / 2).max(MIN_RENEW_INTERVAL_MS)
+                }
+                _ => (INITIAL_TIMEOUT_MS << (self.attempts - 1).min(4)).min(MAX_TIMEOUT_MS),
+            };
+    }
+
+    fn bind(&mut self, mut lease: Lease) {
+        lease.acquired = timer::uptime_ms();
+        let renewed = self.lease.as_ref().is_some_and(|old| old.config == lease.config && old.router == lease.router);
+        if !renewed {
+            log::info!(
+                "{} {}/{} router {:?} dns {:?} lease {}s",
+                self.interface.name(),
+                lease.config.address,
+                lease.config.prefix_len,
+                lease.router,
+                lease.dns_servers,
+                lease.lease_secs
+            );
+            self.interface.configure(Some(lease.config));
+            ipv4::set_default_gateway(&self.interface, lease.router);
+        }
+        if !lease.dns_servers.is_empty() {
+            dns::set_servers(lease.dns_servers.clone());
+        }
+        self.lease = Some(lease);
+        self.enter(State::Bound);
+    }
+
+    // アドレスを外し、DISCOVERからやり直す
+    fn unbind(&mut self) {
+        if self.lease.take().is_some() {
+            ipv4::set_default_gateway(&self.interface, None);
+            self.interface.configure(None);
+        }
+        self.enter(State::Selecting);
+    }
+
+    fn send(
+        &self,
+        socket: &UdpSocket,
+        message_type: u8,
+        requested: Option<Ipv4Addr>,
+        server: Option<Ipv4Addr>,
+    ) -> Result<(), NetError> {
+        // RENEWINGとREBINDINGではアドレスを持っているので、ciaddrに入れて送る
+        let ciaddr = match self.state {
+            State::Renewing | State::Rebinding => self.interface.address().unwrap_or(Ipv4Addr::UNSPECIFIED),
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        let secs = ((timer::uptime_ms() - self.started) / 1000).min(u16::MAX as u64) as u16;
+        let mut message = Vec::with_capacity(MIN_MESSAGE_LEN);
+        message.extend_from_slice(&[OP_REQUEST, HTYPE_ETHERNET, 6, 0]);
+        message.extend_from_slice(&self.xid.to_be_bytes());
+        message.extend_from_slice(&secs.to_be_bytes());
+        // アドレスが無いうちはユニキャストで受け取れないので、ブロードキャストで返してもらう
+        let flags = if ciaddr.is_unspecified() { FLAG_BROADCAST } else { 0 };
+        message.extend_from_slice(&flags.to_be_bytes());
+        message.extend_from_slice(&ciaddr.octets());
+        message.resize(28, 0);
+        message.extend_from_slice(&self.interface.mac_address());
+        message.resize(HEADER_LEN, 0);
+        message.extend_from_slice(&MAGIC_COOKIE);
+        message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+        if let Some(requested) = requested {
+            message.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4]);
+            message.extend_from_slice(&requested.octets());
+        }
+        if let Some(server) = server {
+            message.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+            message.extend_from_slice(&server.octets());
+        }
+        message.extend_from_slice(&[
+            OPTION_PARAMETER_LIST,
+            5,
+            OPTION_SUBNET_MASK,
+            OPTION_ROUTER,
+            OPTION_DNS_SERVER,
+            OPTION_RENEWAL_TIME,
+            OPTION_REBINDING_TIME,
+        ]);
+        message.push(OPTION_END);
+        message.resize(message.len().max(MIN_MESSAGE_LEN), OPTION_PAD);
+
+        match (&self.state, &self.lease) {
+            // 更新はリースをくれたサーバーへ直接送る
+            (State::Renewing, Some(lease)) => socket.send_to(&message, SocketAddrV4::new(lease.server, SERVER_PORT)),
+            _ => socket.send_broadcast(&self.interface, &message, SERVER_PORT),
+        }
+    }
+}
+
+fn parse(message: &[u8]) -> Option<Reply> {
+    if message.len() < HEADER_LEN + MAGIC_COOKIE.len()
+        || message[0] != OP_REPLY
+        || message[1] != HTYPE_ETHERNET
+        || message[2] != 6
+        || message[HEADER_LEN..HEADER_LEN + 4] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let address = |bytes: &[u8]| Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    let seconds = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    let mut message_type = None;
+    let mut netmask = None;
+    let mut router = None;
+    let mut dns_servers = Vec::new();
+    let mut server = None;
+    let mut lease_secs = None;
+    let mut renewal_secs = None;
+    let mut rebinding_secs = None;
+    let mut options = &message[HEADER_LEN + 4..];
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let [len, rest @ ..] = rest else {
+            return None;
+        };
+        let (value, rest) = rest.split_at_checked(*len as usize)?;
+        match (*code, value.len()) {
+            (OPTION_MESSAGE_TYPE, 1) => message_type = Some(value[0]),
+            (OPTION_SUBNET_MASK, 4) => netmask = Some(u32::from(address(value))),
+            (OPTION_ROUTER, 4..) => router = Some(address(value)),
+            (OPTION_DNS_SERVER, 4..) => dns_servers = value.chunks_exact(4).map(address).collect(),
+            (OPTION_SERVER_ID, 4) => server = Some(address(value)),
+            (OPTION_LEASE_TIME, 4) => lease_secs = Some(seconds(value)),
+            (OPTION_RENEWAL_TIME, 4) => renewal_secs = Some(seconds(value)),
+            (OPTION_REBINDING_TIME, 4) => rebinding_secs = Some(seconds(value)),
+            _ => {}
+        }
+        options = rest;
+    }
+
+    let yiaddr = address(&message[16..20]);
+    // マスクが無ければアドレスのクラスから決める
+    let prefix_len = match netmask {
+        Some(mask) => mask.leading_ones() as u8,
+        None if yiaddr.octets()[0] < 128 => 8,
+        None if yiaddr.octets()[0] < 192 => 16,
+        None => 24,
+    };
+    let lease_secs = lease_secs.unwrap_or(DEFAULT_LEASE_SECS);
+    Some(Reply {
+        message_type: message_type?,
+        xid: u32::from_be_bytes([message[4], message[5], message[6], message[7]]),
+        chaddr: message[28..34].try_into().unwrap(),
+        lease: Lease {
+            config: Ipv4Config { address: yiaddr, prefix_len },
+            router,
+            dns_servers,
+            server: server.unwrap_or(address(&message[20..24])),
+            acquired: 0,
+            renewal_secs: renewal_secs.unwrap_or(lease_secs / 2),
+            rebinding_secs: rebinding_secs.unwrap_or((lease_secs as u64 * 7 / 8) as u32),
+            lease_secs,
+        },
+    })
+}
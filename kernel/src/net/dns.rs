@@ -0,0 +1,247 @@
+// DNS stub resolver
+//
+// DHCPで教わったサーバーに再帰問い合わせでAレコードを引き、TTLの間は答えを覚えておく。
+// 見つからなかったという答えも短い間だけ覚える
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use super::udp::UdpSocket;
+use super::{Ipv4Addr, NetError, SocketAddrV4};
+use crate::timer;
+
+const SERVER_PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NO_ERROR: u16 = 0;
+const RCODE_NAME_ERROR: u16 = 3;
+
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+// 圧縮ポインタやCNAMEを辿る回数の上限
+const MAX_JUMPS: usize = 16;
+
+const QUERY_TIMEOUT_MS: u64 = 2000;
+// サーバーを一巡する回数
+const ATTEMPTS: usize = 2;
+const MAX_TTL_SECS: u32 = 86_400;
+const NEGATIVE_TTL_SECS: u32 = 60;
+const MAX_CACHE_ENTRIES: usize = 256;
+
+struct Entry {
+    // Noneなら名前が無かった
+    address: Option<Ipv4Addr>,
+    expires: u64,
+}
+
+// 問い合わせへの答え
+struct Answer {
+    address: Option<Ipv4Addr>,
+    ttl: u32,
+}
+
+lazy_static! {
+    static ref SERVERS: Mutex<Vec<Ipv4Addr>> = Mutex::new(Vec::new());
+    static ref CACHE: Mutex<BTreeMap<String, Entry>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn set_servers(servers: Vec<Ipv4Addr>) {
+    *SERVERS.lock() = servers;
+}
+
+pub fn servers() -> Vec<Ipv4Addr> {
+    SERVERS.lock().clone()
+}
+
+pub fn flush_cache() {
+    CACHE.lock().clear();
+}
+
+// 名前のIPv4アドレスを引く。答えが返るまでpollを回して待つ
+pub fn resolve(name: &str) -> Result<Ipv4Addr, NetError> {
+    if let Ok(address) = name.parse::<Ipv4Addr>() {
+        return Ok(address);
+    }
+    let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+    if name == "localhost" {
+        return Ok(Ipv4Addr::LOCALHOST);
+    }
+    let question = encode_name(&name)?;
+    if let Some(entry) = CACHE.lock().get(&name).filter(|entry| timer::uptime_ms() < entry.expires) {
+        return entry.address.ok_or(NetError::NameNotFound);
+    }
+
+    let servers = servers();
+    if servers.is_empty() {
+        return Err(NetError::NoRoute);
+    }
+    let mut result = Err(NetError::TimedOut);
+    for server in (0..ATTEMPTS).flat_map(|_| servers.iter()) {
+        match query(*server, &question) {
+            Ok(answer) => {
+                insert(name, &answer);
+                return answer.address.ok_or(NetError::NameNotFound);
+            }
+            Err(e) => result = Err(e),
+        }
+    }
+    result
+}
+
+fn insert(name: String, answer: &Answer) {
+    let now = timer::uptime_ms();
+    let mut cache = CACHE.lock();
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        cache.retain(|_, entry| now < entry.expires);
+    }
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        cache.pop_first();
+    }
+    let ttl = if answer.address.is_some() { answer.ttl.min(MAX_TTL_SECS) } else { NEGATIVE_TTL_SECS };
+    cache.insert(name, Entry { address: answer.address, expires: now + ttl as u64 * 1000 });
+}
+
+// "example.com"を長さ付きのラベルの並びにする
+fn encode_name(name: &str) -> Result<Vec<u8>, NetError> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(NetError::InvalidName);
+    }
+    let mut encoded = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(NetError::InvalidName);
+        }
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    Ok(encoded)
+}
+
+fn query(server: Ipv4Addr, question: &[u8]) -> Result<Answer, NetError> {
+    let socket = UdpSocket::bind(0)?;
+    let id = unsafe { core::arch::x86_64::_rdtsc() } as u16;
+    let mut message = Vec::with_capacity(HEADER_LEN + question.len() + 4);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // 質問が1つで、他のセクションは空
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    message.extend_from_slice(question);
+    message.extend_from_slice(&TYPE_A.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    let server = SocketAddrV4::new(server, SERVER_PORT);
+    socket.send_to(&message, server)?;
+
+    let deadline = timer::uptime_ms() + QUERY_TIMEOUT_MS;
+    loop {
+        let remaining = deadline.saturating_sub(timer::uptime_ms());
+        if remaining == 0 {
+            return Err(NetError::TimedOut);
+        }
+        let (response, from) = socket.recv_from(Some(remaining))?;
+        // 関係の無いものは無視して待ち続ける
+        if from == server {
+            if let Some(answer) = parse_response(&response, id, question) {
+                return answer;
+            }
+        }
+    }
+}
+
+fn parse_response(message: &[u8], id: u16, question: &[u8]) -> Option<Result<Answer, NetError>> {
+    let u16_at = |offset: usize| Some(u16::from_be_bytes(message.get(offset..offset + 2)?.try_into().unwrap()));
+    let u32_at = |offset: usize| Some(u32::from_be_bytes(message.get(offset..offset + 4)?.try_into().unwrap()));
+    if u16_at(0)? != id {
+        return None;
+    }
+    let flags = u16_at(2)?;
+    if flags & FLAG_RESPONSE == 0 || u16_at(4)? != 1 {
+        return None;
+    }
+    let (name, mut offset) = read_name(message, HEADER_LEN)?;
+    if name != question || u16_at(offset)? != TYPE_A {
+        return None;
+    }
+    offset += 4;
+    match flags & RCODE_MASK {
+        RCODE_NO_ERROR => {}
+        RCODE_NAME_ERROR => return Some(Ok(Answer { address: None, ttl: NEGATIVE_TTL_SECS })),
+        _ => return Some(Err(NetError::ServerFailure)),
+    }
+
+    // (名前, 型, TTL, データの位置, データの長さ)
+    let mut records = Vec::new();
+    for _ in 0..u16_at(6)? {
+        let (name, next) = read_name(message, offset)?;
+        let rdlength = u16_at(next + 8)? as usize;
+        let rdata = next + 10;
+        if message.len() < rdata + rdlength || u16_at(next + 2)? != CLASS_IN {
+            return None;
+        }
+        records.push((name, u16_at(next)?, u32_at(next + 4)?, rdata, rdlength));
+        offset = rdata + rdlength;
+    }
+
+    // CNAMEを辿りながらAレコードを探す。TTLは辿ったものの中で一番短いもの
+    let mut target = name;
+    let mut ttl = u32::MAX;
+    for _ in 0..MAX_JUMPS {
+        let record =
+            records.iter().find(|(name, kind, ..)| *name == target && (*kind == TYPE_A || *kind == TYPE_CNAME));
+        let Some((_, kind, record_ttl, rdata, rdlength)) = record else {
+            break;
+        };
+        ttl = ttl.min(*record_ttl);
+        if *kind == TYPE_A && *rdlength == 4 {
+            let address = Ipv4Addr::new(message[*rdata], message[rdata + 1], message[rdata + 2], message[rdata + 3]);
+            return Some(Ok(Answer { address: Some(address), ttl }));
+        }
+        target = read_name(message, *rdata)?.0;
+    }
+    Some(Ok(Answer { address: None, ttl: NEGATIVE_TTL_SECS }))
+}
+
+// offsetから名前を読み、圧縮を展開して小文字にしたものと、名前の次の位置を返す
+fn read_name(message: &[u8], offset: usize) -> Option<(Vec<u8>, usize)> {
+    let mut name = Vec::new();
+    let mut offset = offset;
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *message.get(offset)? as usize;
+        match len {
+            0 => {
+                name.push(0);
+                return Some((name, end.unwrap_or(offset + 1)));
+            }
+            // 圧縮ポインタ
+            0xc0.. => {
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return None;
+                }
+                let pointer = u16::from_be_bytes([message[offset], *message.get(offset + 1)?]) as usize & 0x3fff;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            1..=MAX_LABEL_LEN => {
+                let label = message.get(offset + 1..offset + 1 + len)?;
+                name.push(len as u8);
+                name.extend(label.iter().map(u8::to_ascii_lowercase));
+                offset += 1 + len;
+                if name.len() > MAX_NAME_LEN + 2 {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+}
@@ -0,0 +1,43 @@
+// Ethernet
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{arp, ipv4, Interface, MacAddress};
+
+pub const BROADCAST: MacAddress = [0xff; 6];
+pub const HEADER_LEN: usize = 14;
+pub const MTU: usize = 1500;
+// これより短いフレームは0で埋める
+const MIN_FRAME_LEN: usize = 60;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub fn receive(interface: &Arc<Interface>, frame: &[u8]) {
+    if frame.len() < HEADER_LEN {
+        return;
+    }
+    let dst: MacAddress = frame[0..6].try_into().unwrap();
+    if dst != interface.mac_address() && dst != BROADCAST {
+        return;
+    }
+    let payload = &frame[HEADER_LEN..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => arp::receive(interface, payload),
+        ETHERTYPE_IPV4 => ipv4::receive(interface, payload),
+        _ => {}
+    }
+}
+
+pub fn frame(dst: MacAddress, src: MacAddress, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity((HEADER_LEN + payload.len()).max(MIN_FRAME_LEN));
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    if frame.len() < MIN_FRAME_LEN {
+        frame.resize(MIN_FRAME_LEN, 0);
+    }
+    frame
+}
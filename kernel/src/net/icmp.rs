@@ -0,0 +1,93 @@
+// ICMP
+//
+// echo requestに応え、pingを送れるようにする
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use super::ipv4::{self, Header};
+use super::{wait_for, Ipv4Addr, NetError};
+use crate::timer;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const CODE_PORT_UNREACHABLE: u8 = 3;
+const HEADER_LEN: usize = 8;
+// 元のパケットのIPヘッダの後ろに付ける長さ
+const UNREACHABLE_DATA_LEN: usize = 8;
+
+const PING_ID: u16 = 0x4b4e;
+const PING_DATA_LEN: usize = 32;
+
+lazy_static! {
+    // 送ったpingのシーケンス番号と、応答を受け取った時刻
+    static ref PINGS: Mutex<BTreeMap<u16, Option<u64>>> = Mutex::new(BTreeMap::new());
+}
+
+static NEXT_SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+pub fn receive(header: &Header, message: &[u8]) {
+    if message.len() < HEADER_LEN || ipv4::checksum(message, 0) != 0 {
+        return;
+    }
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            // ブロードキャストには答えない
+            if !ipv4::is_local(header.dst) {
+                return;
+            }
+            let mut reply = message.to_vec();
+            reply[0] = TYPE_ECHO_REPLY;
+            let _ = send(Some(header.dst), header.src, &mut reply);
+        }
+        TYPE_ECHO_REPLY => {
+            let id = u16::from_be_bytes([message[4], message[5]]);
+            let sequence = u16::from_be_bytes([message[6], message[7]]);
+            if id == PING_ID {
+                if let Some(received) = PINGS.lock().get_mut(&sequence) {
+                    received.get_or_insert(timer::uptime_ms());
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// 応答が来るまでのミリ秒を返す
+pub fn ping(dst: Ipv4Addr, timeout_ms: u64) -> Result<u64, NetError> {
+    let sequence = NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let mut message = Vec::with_capacity(HEADER_LEN + PING_DATA_LEN);
+    message.extend_from_slice(&[TYPE_ECHO_REQUEST, 0, 0, 0]);
+    message.extend_from_slice(&PING_ID.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend((0..PING_DATA_LEN).map(|i| i as u8));
+
+    PINGS.lock().insert(sequence, None);
+    let sent = timer::uptime_ms();
+    let result = send(None, dst, &mut message).and_then(|_| {
+        wait_for(Some(timeout_ms), || PINGS.lock().get(&sequence).copied().flatten()).ok_or(NetError::TimedOut)
+    });
+    PINGS.lock().remove(&sequence);
+    result.map(|received| received - sent)
+}
+
+// 受け取ったパケットpacketの宛先のポートが無いことを送り主に伝える
+pub(super) fn send_port_unreachable(header: &Header, packet: &[u8]) {
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let quoted = &packet[..packet.len().min(header_len + UNREACHABLE_DATA_LEN)];
+    let mut message = Vec::with_capacity(HEADER_LEN + quoted.len());
+    message.extend_from_slice(&[TYPE_DESTINATION_UNREACHABLE, CODE_PORT_UNREACHABLE, 0, 0, 0, 0, 0, 0]);
+    message.extend_from_slice(quoted);
+    let _ = send(Some(header.dst), header.src, &mut message);
+}
+
+fn send(src: Option<Ipv4Addr>, dst: Ipv4Addr, message: &mut [u8]) -> Result<(), NetError> {
+    message[2..4].fill(0);
+    let sum = ipv4::checksum(message, 0);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    ipv4::send(src, dst, ipv4::PROTOCOL_ICMP, message)
+}
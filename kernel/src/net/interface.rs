@@ -0,0 +1,93 @@
+// Network interfaces
+//
+// NetworkDeviceにIPv4のアドレスとARPのキャッシュを結び付けたもの
+
+use alloc::sync::Arc;
+use spin::mutex::{Mutex, MutexGuard};
+
+use super::arp::ArpCache;
+use super::{ethernet, ipv4, Ipv4Addr, MacAddress, NetError, NetworkDevice};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Config {
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(ipv4::prefix_mask(self.prefix_len))
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) & ipv4::prefix_mask(self.prefix_len))
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) | !ipv4::prefix_mask(self.prefix_len))
+    }
+
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        let mask = ipv4::prefix_mask(self.prefix_len);
+        u32::from(address) & mask == u32::from(self.address) & mask
+    }
+}
+
+pub struct Interface {
+    device: Arc<dyn NetworkDevice>,
+    config: Mutex<Option<Ipv4Config>>,
+    arp: Mutex<ArpCache>,
+}
+
+impl Interface {
+    pub fn new(device: Arc<dyn NetworkDevice>) -> Arc<Self> {
+        Arc::new(Self { device, config: Mutex::new(None), arp: Mutex::new(ArpCache::new()) })
+    }
+
+    pub fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    pub fn device(&self) -> &Arc<dyn NetworkDevice> {
+        &self.device
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.device.mac_address()
+    }
+
+    pub fn config(&self) -> Option<Ipv4Config> {
+        *self.config.lock()
+    }
+
+    pub fn address(&self) -> Option<Ipv4Addr> {
+        self.config().map(|config| config.address)
+    }
+
+    // 繋がっているネットワークへの経路も付け替える
+    pub fn configure(self: &Arc<Self>, config: Option<Ipv4Config>) {
+        let old = core::mem::replace(&mut *self.config.lock(), config);
+        if let Some(old) = old {
+            ipv4::remove_route(old.network(), old.prefix_len, self);
+        }
+        if let Some(config) = config {
+            ipv4::add_route(ipv4::Route {
+                destination: config.network(),
+                prefix_len: config.prefix_len,
+                gateway: None,
+                interface: self.clone(),
+            });
+        }
+    }
+
+    pub fn send_frame(&self, dst: MacAddress, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+        if payload.len() > ethernet::MTU {
+            return Err(NetError::FrameTooLarge);
+        }
+        self.device.transmit(&ethernet::frame(dst, self.mac_address(), ethertype, payload))
+    }
+
+    pub(super) fn arp(&self) -> MutexGuard<'_, ArpCache> {
+        self.arp.lock()
+    }
+}
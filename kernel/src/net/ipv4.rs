@@ -0,0 +1,250 @@
+// IPv4
+//
+// 経路表を最長一致で引いて送る。分割されたパケットの組み立てはせず、受信したら捨てる。
+// 自分宛てのパケットはキューに入れておき、次のpollで受信したものとして扱う
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use super::{arp, ethernet, icmp, interfaces, tcp, udp, Interface, Ipv4Addr, NetError};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_LEN: usize = 20;
+const VERSION_IHL: u8 = 0x45;
+const DEFAULT_TTL: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+const LOOPBACK_QUEUE_LIMIT: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+}
+
+#[derive(Clone)]
+pub struct Route {
+    pub destination: Ipv4Addr,
+    pub prefix_len: u8,
+    // Noneなら直接繋がっている
+    pub gateway: Option<Ipv4Addr>,
+    pub interface: Arc<Interface>,
+}
+
+lazy_static! {
+    static ref ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+    static ref LOOPBACK: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+}
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+pub fn prefix_mask(prefix_len: u8) -> u32 {
+    match prefix_len {
+        0 => 0,
+        len => u32::MAX << (32 - len.min(32) as u32),
+    }
+}
+
+pub fn add_route(route: Route) {
+    let mut routes = ROUTES.lock();
+    routes.retain(|r| {
+        r.destination != route.destination
+            || r.prefix_len != route.prefix_len
+            || !Arc::ptr_eq(&r.interface, &route.interface)
+    });
+    routes.push(route);
+}
+
+pub fn remove_route(destination: Ipv4Addr, prefix_len: u8, interface: &Arc<Interface>) {
+    ROUTES.lock().retain(|r| {
+        r.destination != destination || r.prefix_len != prefix_len || !Arc::ptr_eq(&r.interface, interface)
+    });
+}
+
+pub fn routes() -> Vec<Route> {
+    ROUTES.lock().clone()
+}
+
+// Noneならinterfaceのデフォルトゲートウェイを消す
+pub fn set_default_gateway(interface: &Arc<Interface>, gateway: Option<Ipv4Addr>) {
+    remove_route(Ipv4Addr::UNSPECIFIED, 0, interface);
+    if let Some(gateway) = gateway {
+        add_route(Route {
+            destination: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+            gateway: Some(gateway),
+            interface: interface.clone(),
+        });
+    }
+}
+
+// 送り出すインターフェースと、次に渡す相手のアドレス
+pub fn route(dst: Ipv4Addr) -> Option<(Arc<Interface>, Ipv4Addr)> {
+    let routes = ROUTES.lock();
+    let route = routes
+        .iter()
+        .filter(|r| u32::from(dst) & prefix_mask(r.prefix_len) == u32::from(r.destination) & prefix_mask(r.prefix_len))
+        .max_by_key(|r| r.prefix_len)?;
+    Some((route.interface.clone(), route.gateway.unwrap_or(dst)))
+}
+
+// dstへ送るときに使う自分のアドレス
+pub fn source_address(dst: Ipv4Addr) -> Option<Ipv4Addr> {
+    if is_local(dst) {
+        return Some(if dst.is_loopback() { Ipv4Addr::LOCALHOST } else { dst });
+    }
+    route(dst)?.0.address()
+}
+
+pub fn is_local(address: Ipv4Addr) -> bool {
+    address.is_loopback() || interfaces().iter().any(|interface| interface.address() == Some(address))
+}
+
+// srcがNoneなら送り出すインターフェースのアドレスを使う
+pub fn send(src: Option<Ipv4Addr>, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    if is_local(dst) {
+        let src = src.or_else(|| source_address(dst)).unwrap();
+        let mut loopback = LOOPBACK.lock();
+        if loopback.len() >= LOOPBACK_QUEUE_LIMIT {
+            return Err(NetError::Busy);
+        }
+        loopback.push_back(packet(src, dst, protocol, payload)?);
+        return Ok(());
+    }
+    let (interface, next_hop) = route(dst).ok_or(NetError::NoRoute)?;
+    let src = src.or_else(|| interface.address()).ok_or(NetError::NoRoute)?;
+    let packet = packet(src, dst, protocol, payload)?;
+    let broadcast = dst == Ipv4Addr::BROADCAST || interface.config().is_some_and(|config| config.broadcast() == dst);
+    if broadcast {
+        return interface.send_frame(ethernet::BROADCAST, ethernet::ETHERTYPE_IPV4, &packet);
+    }
+    arp::send(&interface, next_hop, packet)
+}
+
+// 経路を引かずにinterfaceから255.255.255.255へ送る。アドレスがまだ無いときはsrcに0.0.0.0を使う
+pub fn send_broadcast(interface: &Interface, src: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    let packet = packet(src, Ipv4Addr::BROADCAST, protocol, payload)?;
+    interface.send_frame(ethernet::BROADCAST, ethernet::ETHERTYPE_IPV4, &packet)
+}
+
+fn packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<Vec<u8>, NetError> {
+    let total_len = HEADER_LEN + payload.len();
+    if total_len > ethernet::MTU {
+        return Err(NetError::FrameTooLarge);
+    }
+    let mut packet = Vec::with_capacity(total_len);
+    packet.push(VERSION_IHL);
+    packet.push(0);
+    packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+    packet.extend_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet.push(DEFAULT_TTL);
+    packet.push(protocol);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    let sum = checksum(&packet, 0);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    Ok(packet)
+}
+
+pub fn receive(interface: &Arc<Interface>, packet: &[u8]) {
+    let Some((header, payload)) = parse(packet) else {
+        return;
+    };
+    // アドレスが無い間は、DHCPの応答のために全て受け取る
+    let accepted = match interface.config() {
+        Some(config) => {
+            header.dst == config.address || header.dst == config.broadcast() || header.dst == Ipv4Addr::BROADCAST
+        }
+        None => true,
+    };
+    if accepted {
+        deliver(&header, payload, packet);
+    }
+}
+
+fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+    if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if header_len < HEADER_LEN || total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+    if checksum(&packet[..header_len], 0) != 0 {
+        return None;
+    }
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0 {
+        return None;
+    }
+    let header = Header {
+        src: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+        dst: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+        protocol: packet[9],
+        ttl: packet[8],
+    };
+    Some((header, &packet[header_len..total_len]))
+}
+
+fn deliver(header: &Header, payload: &[u8], packet: &[u8]) {
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::receive(header, payload),
+        PROTOCOL_TCP => tcp::receive(header, payload),
+        // 受け取るソケットが無ければport unreachableを返す
+        PROTOCOL_UDP if !udp::receive(header, payload) && !is_broadcast(header.dst) => {
+            icmp::send_port_unreachable(header, packet)
+        }
+        _ => {}
+    }
+}
+
+fn is_broadcast(address: Ipv4Addr) -> bool {
+    address == Ipv4Addr::BROADCAST
+        || interfaces().iter().any(|interface| interface.config().is_some_and(|config| config.broadcast() == address))
+}
+
+// 自分宛てに送ったパケットを受信する
+pub(super) fn poll_loopback() {
+    let packets: Vec<Vec<u8>> = LOOPBACK.lock().drain(..).collect();
+    for packet in packets {
+        if let Some((header, payload)) = parse(&packet) {
+            deliver(&header, payload, &packet);
+        }
+    }
+}
+
+// 1の補数和。sumに擬似ヘッダなどの途中までの和を渡せる
+pub fn checksum(data: &[u8], sum: u32) -> u16 {
+    let mut sum = sum;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// TCPとUDPのチェックサムに含める擬似ヘッダの和
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let src = u32::from(src);
+    let dst = u32::from(dst);
+    (src >> 16) + (src & 0xffff) + (dst >> 16) + (dst & 0xffff) + protocol as u32 + len as u32
+}
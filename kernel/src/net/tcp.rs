@@ -0,0 +1,854 @@
+// TCP
+//
+// セグメントの処理と再送のタイマーはnet::pollの中で進む。TcpStreamとTcpListenerの操作は
+// 必要なだけpollを回して待つ。順番が飛んで届いたセグメントは受信ウィンドウに入る分だけ取っておき、
+// 穴が埋まったら受信バッファへ移す。輻輳制御はしない
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use super::ipv4::{self, Header};
+use super::{ephemeral_port, wait_for, NetError, SocketAddrV4};
+use crate::timer;
+
+const HEADER_LEN: usize = 20;
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+// 相手がMSSを言ってこなければ536
+const DEFAULT_MSS: usize = 536;
+const MAX_MSS: usize = 1460;
+// 送信と受信のバッファ。ウィンドウスケールは使わないので受信側はこれが上限
+const BUFFER_SIZE: usize = 65535;
+const BACKLOG: usize = 16;
+
+const INITIAL_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 200;
+const MAX_RTO_MS: u64 = 60_000;
+const MAX_RETRIES: u32 = 8;
+const MAX_SYN_RETRIES: u32 = 5;
+// 本来は2MSLだが、短めにしておく
+const TIME_WAIT_MS: u64 = 4000;
+const FIN_WAIT_2_MS: u64 = 60_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+// シーケンス番号は一周するので差で比べる
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+struct Segment<'a> {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &'a [u8],
+}
+
+impl Segment<'_> {
+    // SYNとFINも1つずつ数える
+    fn len(&self) -> usize {
+        self.payload.len() + (self.flags & SYN != 0) as usize + (self.flags & FIN != 0) as usize
+    }
+}
+
+fn parse<'a>(header: &Header, data: &'a [u8]) -> Option<Segment<'a>> {
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+    if ipv4::checksum(data, ipv4::pseudo_header_sum(header.src, header.dst, ipv4::PROTOCOL_TCP, data.len())) != 0 {
+        return None;
+    }
+    let data_offset = (data[12] >> 4) as usize * 4;
+    if data_offset < HEADER_LEN || data_offset > data.len() {
+        return None;
+    }
+    let mut mss = None;
+    let mut options = &data[HEADER_LEN..data_offset];
+    while let Some(&kind) = options.first() {
+        match kind {
+            OPTION_END => break,
+            OPTION_NOP => options = &options[1..],
+            _ => {
+                let len = *options.get(1)? as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if kind == OPTION_MSS && len == 4 {
+                    mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+    Some(Segment {
+        src_port: u16::from_be_bytes([data[0], data[1]]),
+        dst_port: u16::from_be_bytes([data[2], data[3]]),
+        seq: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+        ack: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+        flags: data[13],
+        window: u16::from_be_bytes([data[14], data[15]]),
+        mss,
+        payload: &data[data_offset..],
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn send_segment(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    options: &[u8],
+    payload: &[u8],
+) {
+    let header_len = HEADER_LEN + options.len();
+    let mut segment = Vec::with_capacity(header_len + payload.len());
+    segment.extend_from_slice(&local.port().to_be_bytes());
+    segment.extend_from_slice(&remote.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.push(((header_len / 4) as u8) << 4);
+    segment.push(flags);
+    segment.extend_from_slice(&window.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(options);
+    segment.extend_from_slice(payload);
+    let sum =
+        ipv4::checksum(&segment, ipv4::pseudo_header_sum(*local.ip(), *remote.ip(), ipv4::PROTOCOL_TCP, segment.len()));
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    // 送れなくても再送で取り戻す
+    let _ = ipv4::send(Some(*local.ip()), *remote.ip(), ipv4::PROTOCOL_TCP, &segment);
+}
+
+static ISS_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+// 接続ごとに初期シーケンス番号をずらす
+fn initial_sequence() -> u32 {
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    (tsc as u32).wrapping_add(ISS_COUNTER.fetch_add(64_000, Ordering::Relaxed))
+}
+
+struct Tcb {
+    state: State,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    // 受動的に開いた接続なら、確立したときにここのキューへ入れる
+    listener: Option<Weak<Listener>>,
+
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    snd_wl1: u32,
+    snd_wl2: u32,
+    // 先頭はsnd_unaの位置のバイト。確認応答されたら取り除く
+    send_buffer: VecDeque<u8>,
+    fin_sent: bool,
+    mss: usize,
+    duplicate_acks: u32,
+    // 高速再送したときのsnd_nxt。ここまで確認応答されるまで、穴を1つずつ埋める
+    recovery: Option<u32>,
+
+    rcv_nxt: u32,
+    recv_buffer: VecDeque<u8>,
+    // 先に届いたセグメント。(シーケンス番号, データ, FIN)。互いに重ならず、全部ウィンドウの中にある
+    out_of_order: Vec<(u32, Vec<u8>, bool)>,
+    fin_received: bool,
+
+    rto: u64,
+    srtt: Option<u64>,
+    rttvar: u64,
+    // 再送、ゼロウィンドウの確認、TIME-WAITとFIN-WAIT-2の終わり
+    deadline: Option<u64>,
+    retries: u32,
+    // RTTを測っているセグメントの終わりと送った時刻
+    rtt_sample: Option<(u32, u64)>,
+    error: Option<NetError>,
+}
+
+impl Tcb {
+    fn new(state: State, local: SocketAddrV4, remote: SocketAddrV4) -> Self {
+        let iss = initial_sequence();
+        let now = timer::uptime_ms();
+        Self {
+            state,
+            local,
+            remote,
+            listener: None,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            send_buffer: VecDeque::new(),
+            fin_sent: false,
+            mss: DEFAULT_MSS,
+            duplicate_acks: 0,
+            recovery: None,
+            rcv_nxt: 0,
+            recv_buffer: VecDeque::new(),
+            out_of_order: Vec::new(),
+            fin_received: false,
+            rto: INITIAL_RTO_MS,
+            srtt: None,
+            rttvar: 0,
+            deadline: Some(now + INITIAL_RTO_MS),
+            retries: 0,
+            // SYNの往復で最初のRTTを測る
+            rtt_sample: Some((iss.wrapping_add(1), now)),
+            error: None,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (BUFFER_SIZE - self.recv_buffer.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn send(&self, seq: u32, flags: u8, payload: &[u8]) {
+        let mss = (MAX_MSS as u16).to_be_bytes();
+        let options: &[u8] = if flags & SYN != 0 { &[OPTION_MSS, 4, mss[0], mss[1]] } else { &[] };
+        let ack = if flags & ACK != 0 { self.rcv_nxt } else { 0 };
+        send_segment(self.local, self.remote, seq, ack, flags, self.window(), options, payload);
+    }
+
+    fn send_ack(&self) {
+        self.send(self.snd_nxt, ACK, &[]);
+    }
+
+    fn send_syn(&self) {
+        let flags = if self.state == State::SynReceived { SYN | ACK } else { SYN };
+        self.send(self.iss, flags, &[]);
+    }
+
+    fn abort(&mut self, error: NetError) {
+        if !matches!(self.state, State::SynSent | State::Closed) {
+            self.send(self.snd_nxt, RST | ACK, &[]);
+        }
+        self.error = Some(error);
+        self.close_now();
+    }
+
+    fn close_now(&mut self) {
+        self.state = State::Closed;
+        self.deadline = None;
+    }
+
+    fn synchronized(&self) -> bool {
+        !matches!(self.state, State::SynSent | State::SynReceived | State::Closed)
+    }
+
+    // closeされていてFINを送る必要がある
+    fn closing(&self) -> bool {
+        matches!(self.state, State::FinWait1 | State::Closing | State::LastAck)
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.fin_sent && self.snd_una == self.snd_nxt
+    }
+
+    fn update_rtt(&mut self, rtt: u64) {
+        // RFC 6298
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+        }
+        self.rto = (self.srtt.unwrap() + 4 * self.rttvar).clamp(MIN_RTO_MS, MAX_RTO_MS);
+    }
+
+    // ウィンドウの許す限り送る。probeならゼロウィンドウでも1バイト送って様子を見る
+    fn output(&mut self, probe: bool) {
+        if !self.synchronized() {
+            return;
+        }
+        let now = timer::uptime_ms();
+        while !self.fin_sent {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let unsent = self.send_buffer.len() - in_flight;
+            let mut window = (self.snd_wnd as usize).saturating_sub(in_flight);
+            if probe && window == 0 && in_flight == 0 {
+                window = 1;
+            }
+            let len = unsent.min(window).min(self.mss);
+            let fin = self.closing() && len == unsent;
+            if len == 0 && !fin {
+                break;
+            }
+            let payload: Vec<u8> = self.send_buffer.range(in_flight..in_flight + len).copied().collect();
+            let flags = ACK | if len > 0 { PSH } else { 0 } | if fin { FIN } else { 0 };
+            self.send(self.snd_nxt, flags, &payload);
+            self.snd_nxt = self.snd_nxt.wrapping_add((len + fin as usize) as u32);
+            self.fin_sent = fin;
+            self.rtt_sample.get_or_insert((self.snd_nxt, now));
+            self.deadline.get_or_insert(now + self.rto);
+            if probe {
+                break;
+            }
+        }
+        // 相手のウィンドウが開くのを待つ間も、定期的に確認する
+        if self.snd_una == self.snd_nxt && !self.send_buffer.is_empty() {
+            self.deadline.get_or_insert(now + self.rto);
+        }
+    }
+
+    // 確認応答されていない先頭のセグメントだけを送り直す
+    fn retransmit_first(&mut self) {
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        let len = in_flight.min(self.send_buffer.len()).min(self.mss);
+        let fin = self.fin_sent && len == self.send_buffer.len();
+        let payload: Vec<u8> = self.send_buffer.range(..len).copied().collect();
+        let flags = ACK | if len > 0 { PSH } else { 0 } | if fin { FIN } else { 0 };
+        self.send(self.snd_una, flags, &payload);
+        self.rtt_sample = None;
+        self.deadline = Some(timer::uptime_ms() + self.rto);
+    }
+
+    // seqから始まるデータのうち、まだ受け取っていない部分を受信バッファへ入れる。FINまで受け取ったらtrue
+    fn accept(&mut self, seq: u32, payload: &[u8], fin: bool) -> bool {
+        let skip = self.rcv_nxt.wrapping_sub(seq) as usize;
+        if skip > payload.len() {
+            return false;
+        }
+        let data = &payload[skip..];
+        let accepted = data.len().min(BUFFER_SIZE - self.recv_buffer.len());
+        self.recv_buffer.extend(&data[..accepted]);
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+        // 入りきらなかった分の後ろのFINはまだ受け取れない
+        fin && accepted == data.len()
+    }
+
+    // ウィンドウからはみ出す分は捨て、もう取ってある範囲と重なる部分は足さない。
+    // なので取っておくのは合わせてもウィンドウの大きさまで
+    fn queue_out_of_order(&mut self, seq: u32, payload: &[u8], fin: bool) {
+        let room = (self.window() as usize).saturating_sub(seq.wrapping_sub(self.rcv_nxt) as usize);
+        let len = payload.len().min(room);
+        // FINは前のデータを全部取っておけたときだけ
+        let fin = fin && len == payload.len();
+        let end = seq.wrapping_add(len as u32);
+
+        let rcv_nxt = self.rcv_nxt;
+        self.out_of_order.sort_by_key(|(queued, _, _)| queued.wrapping_sub(rcv_nxt));
+        let mut pieces = Vec::new();
+        let (mut start, mut rest) = (seq, &payload[..len]);
+        for (queued, data, _) in &self.out_of_order {
+            if rest.is_empty() || seq_le(end, *queued) {
+                break;
+            }
+            let queued_end = queued.wrapping_add(data.len() as u32);
+            if seq_le(queued_end, start) {
+                continue;
+            }
+            if seq_lt(start, *queued) {
+                let head = queued.wrapping_sub(start) as usize;
+                pieces.push((start, rest[..head].to_vec(), false));
+            }
+            let covered = (queued_end.wrapping_sub(start) as usize).min(rest.len());
+            start = start.wrapping_add(covered as u32);
+            rest = &rest[covered..];
+        }
+        if !rest.is_empty() {
+            pieces.push((start, rest.to_vec(), false));
+        }
+        if fin && !self.out_of_order.iter().any(|(_, _, queued_fin)| *queued_fin) {
+            pieces.push((end, Vec::new(), true));
+        }
+        self.out_of_order.extend(pieces);
+    }
+
+    fn on_timeout(&mut self) {
+        let now = timer::uptime_ms();
+        match self.state {
+            State::TimeWait | State::FinWait2 => return self.close_now(),
+            State::Closed => return,
+            _ => {}
+        }
+        self.retries += 1;
+        let limit = if self.synchronized() { MAX_RETRIES } else { MAX_SYN_RETRIES };
+        if self.retries > limit {
+            return self.abort(NetError::TimedOut);
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO_MS);
+        // 再送したセグメントではRTTを測らない
+        self.rtt_sample = None;
+        self.deadline = Some(now + self.rto);
+        if !self.synchronized() {
+            return self.send_syn();
+        }
+        let probe = self.snd_una == self.snd_nxt;
+        // 確認応答されていないところから送り直す
+        self.snd_nxt = self.snd_una;
+        self.fin_sent = false;
+        self.output(probe);
+    }
+
+    fn receive_syn_sent(&mut self, segment: &Segment) {
+        let acceptable = segment.flags & ACK != 0 && segment.ack == self.snd_nxt;
+        if segment.flags & ACK != 0 && !acceptable {
+            if segment.flags & RST == 0 {
+                send_segment(self.local, self.remote, segment.ack, 0, RST, 0, &[], &[]);
+            }
+            return;
+        }
+        if segment.flags & RST != 0 {
+            if acceptable {
+                self.error = Some(NetError::ConnectionRefused);
+                self.close_now();
+            }
+            return;
+        }
+        if segment.flags & SYN == 0 {
+            return;
+        }
+        self.rcv_nxt = segment.seq.wrapping_add(1);
+        self.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize).min(MAX_MSS);
+        if !acceptable {
+            // 同時に開いた
+            self.state = State::SynReceived;
+            return self.send_syn();
+        }
+        self.snd_una = segment.ack;
+        self.snd_wnd = segment.window as u32;
+        self.snd_wl1 = segment.seq;
+        self.snd_wl2 = segment.ack;
+        self.state = State::Established;
+        self.retries = 0;
+        self.deadline = None;
+        if let Some((_, sent)) = self.rtt_sample.take() {
+            self.update_rtt(timer::uptime_ms() - sent);
+        }
+        self.send_ack();
+    }
+
+    // RFC 793の3.9 SEGMENT ARRIVESに沿って処理する
+    fn receive(&mut self, segment: &Segment) {
+        if self.state == State::SynSent {
+            return self.receive_syn_sent(segment);
+        }
+        let now = timer::uptime_ms();
+        let mut seq = segment.seq;
+        let mut payload = segment.payload;
+        let mut syn = segment.flags & SYN != 0;
+        let rst = segment.flags & RST != 0;
+        let fin = segment.flags & FIN != 0;
+
+        // 受け取り済みの部分は取り除く
+        if seq_lt(seq, self.rcv_nxt) {
+            let mut duplicate = self.rcv_nxt.wrapping_sub(seq) as usize;
+            if duplicate >= segment.len() {
+                if !rst {
+                    self.send_ack();
+                }
+                return;
+            }
+            if syn {
+                syn = false;
+                duplicate -= 1;
+            }
+            payload = &payload[duplicate.min(payload.len())..];
+            seq = self.rcv_nxt;
+        }
+        // ウィンドウの中で先のものは穴が埋まるまで取っておく
+        let ahead = seq_lt(self.rcv_nxt, seq);
+        if ahead && (rst || seq.wrapping_sub(self.rcv_nxt) as usize >= self.window() as usize) {
+            if !rst {
+                self.send_ack();
+            }
+            return;
+        }
+
+        if rst {
+            // listenから来た接続ならacceptされる前に消えるだけ
+            if self.state != State::SynReceived || self.listener.is_none() {
+                self.error = Some(NetError::ConnectionReset);
+            }
+            return self.close_now();
+        }
+        if syn {
+            return self.abort(NetError::ConnectionReset);
+        }
+        if segment.flags & ACK == 0 {
+            return;
+        }
+
+        if self.state == State::SynReceived {
+            if !(seq_lt(self.snd_una, segment.ack) && seq_le(segment.ack, self.snd_nxt)) {
+                return send_segment(self.local, self.remote, segment.ack, 0, RST, 0, &[], &[]);
+            }
+            self.state = State::Established;
+            self.snd_wl1 = segment.seq;
+            self.snd_wl2 = segment.ack;
+            self.snd_wnd = segment.window as u32;
+        }
+        if seq_lt(self.snd_nxt, segment.ack) {
+            return self.send_ack();
+        }
+        let duplicate = segment.ack == self.snd_una
+            && segment.len() == 0
+            && segment.window as u32 == self.snd_wnd
+            && self.snd_una != self.snd_nxt;
+        if duplicate {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == 3 {
+                self.recovery = Some(self.snd_nxt);
+                self.retransmit_first();
+            }
+        }
+        if seq_lt(self.snd_una, segment.ack) {
+            let acked = segment.ack.wrapping_sub(self.snd_una) as usize;
+            // SYNの分はバッファに無い
+            let data = acked.min(self.send_buffer.len());
+            self.send_buffer.drain(..data);
+            self.snd_una = segment.ack;
+            if let Some((end, sent)) = self.rtt_sample {
+                if seq_le(end, segment.ack) {
+                    self.update_rtt(now - sent);
+                    self.rtt_sample = None;
+                }
+            }
+            self.retries = 0;
+            self.duplicate_acks = 0;
+            self.deadline = (self.snd_una != self.snd_nxt).then(|| now + self.rto);
+            if let Some(recovery) = self.recovery {
+                if seq_lt(segment.ack, recovery) {
+                    self.retransmit_first();
+                } else {
+                    self.recovery = None;
+                }
+            }
+        }
+        if seq_lt(self.snd_wl1, segment.seq) || (self.snd_wl1 == segment.seq && seq_le(self.snd_wl2, segment.ack)) {
+            self.snd_wnd = segment.window as u32;
+            self.snd_wl1 = segment.seq;
+            self.snd_wl2 = segment.ack;
+        }
+        match self.state {
+            State::FinWait1 if self.fin_acked() => {
+                self.state = State::FinWait2;
+                self.deadline = Some(now + FIN_WAIT_2_MS);
+            }
+            State::Closing if self.fin_acked() => {
+                self.state = State::TimeWait;
+                self.deadline = Some(now + TIME_WAIT_MS);
+            }
+            State::LastAck if self.fin_acked() => return self.close_now(),
+            _ => {}
+        }
+
+        let receiving = matches!(self.state, State::Established | State::FinWait1 | State::FinWait2);
+        if ahead {
+            if receiving {
+                self.queue_out_of_order(seq, payload, fin);
+            }
+            // 重複ACKで穴があることを知らせる
+            self.send_ack();
+            return self.output(false);
+        }
+        let mut fin_reached = receiving && self.accept(seq, payload, fin);
+        while let Some(i) = self.out_of_order.iter().position(|(queued, _, _)| seq_le(*queued, self.rcv_nxt)) {
+            let (queued, data, queued_fin) = self.out_of_order.swap_remove(i);
+            fin_reached |= receiving && self.accept(queued, &data, queued_fin);
+        }
+        if fin_reached {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.state = match self.state {
+                State::Established => State::CloseWait,
+                State::FinWait1 if self.fin_acked() => State::TimeWait,
+                State::FinWait1 => State::Closing,
+                State::FinWait2 => State::TimeWait,
+                state => state,
+            };
+            if self.state == State::TimeWait {
+                self.deadline = Some(now + TIME_WAIT_MS);
+            }
+        }
+        if !payload.is_empty() || fin {
+            self.send_ack();
+        }
+        self.output(false);
+    }
+}
+
+struct Connection {
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    tcb: Mutex<Tcb>,
+}
+
+struct Listener {
+    port: u16,
+    queue: Mutex<VecDeque<Arc<Connection>>>,
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<Vec<Arc<Connection>>> = Mutex::new(Vec::new());
+    static ref LISTENERS: Mutex<BTreeMap<u16, Arc<Listener>>> = Mutex::new(BTreeMap::new());
+}
+
+fn find(local: SocketAddrV4, remote: SocketAddrV4) -> Option<Arc<Connection>> {
+    CONNECTIONS.lock().iter().find(|c| c.local == local && c.remote == remote).cloned()
+}
+
+fn port_in_use(port: u16) -> bool {
+    LISTENERS.lock().contains_key(&port) || CONNECTIONS.lock().iter().any(|c| c.local.port() == port)
+}
+
+pub(super) fn receive(header: &Header, data: &[u8]) {
+    let Some(segment) = parse(header, data) else {
+        return;
+    };
+    let local = SocketAddrV4::new(header.dst, segment.dst_port);
+    let remote = SocketAddrV4::new(header.src, segment.src_port);
+
+    let Some(connection) = find(local, remote) else {
+        return listen(local, remote, &segment);
+    };
+    let established = {
+        let mut tcb = connection.tcb.lock();
+        let before = tcb.state;
+        tcb.receive(&segment);
+        (before == State::SynReceived && tcb.state != State::SynReceived && tcb.state != State::Closed)
+            .then(|| tcb.listener.take())
+            .flatten()
+    };
+    if let Some(listener) = established.and_then(|listener| listener.upgrade()) {
+        listener.queue.lock().push_back(connection);
+    }
+}
+
+// 接続の無いセグメント。listenしていればSYNを受け付け、そうでなければRSTを返す
+fn listen(local: SocketAddrV4, remote: SocketAddrV4, segment: &Segment) {
+    if segment.flags & RST != 0 {
+        return;
+    }
+    if segment.flags & ACK != 0 {
+        return send_segment(local, remote, segment.ack, 0, RST, 0, &[], &[]);
+    }
+    let listener = LISTENERS.lock().get(&local.port()).cloned();
+    let Some(listener) = listener.filter(|_| segment.flags & SYN != 0) else {
+        let ack = segment.seq.wrapping_add(segment.len() as u32);
+        return send_segment(local, remote, 0, ack, RST | ACK, 0, &[], &[]);
+    };
+    if listener.queue.lock().len() >= BACKLOG {
+        return;
+    }
+
+    let mut tcb = Tcb::new(State::SynReceived, local, remote);
+    tcb.listener = Some(Arc::downgrade(&listener));
+    tcb.rcv_nxt = segment.seq.wrapping_add(1);
+    tcb.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize).min(MAX_MSS);
+    tcb.send_syn();
+    CONNECTIONS.lock().push(Arc::new(Connection { local, remote, tcb: Mutex::new(tcb) }));
+}
+
+// タイマーを進め、閉じた接続を片付ける
+pub(super) fn poll() {
+    let now = timer::uptime_ms();
+    let connections = CONNECTIONS.lock().clone();
+    for connection in connections {
+        let mut tcb = connection.tcb.lock();
+        if tcb.deadline.is_some_and(|deadline| now >= deadline) {
+            tcb.on_timeout();
+        }
+    }
+    CONNECTIONS.lock().retain(|c| c.tcb.lock().state != State::Closed);
+}
+
+pub struct TcpListener {
+    listener: Arc<Listener>,
+}
+
+impl TcpListener {
+    // portが0なら空いているポートを使う
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let port = match port {
+            0 => ephemeral_port(port_in_use).ok_or(NetError::AddressInUse)?,
+            port => port,
+        };
+        let mut listeners = LISTENERS.lock();
+        if listeners.contains_key(&port) {
+            return Err(NetError::AddressInUse);
+        }
+        let listener = Arc::new(Listener { port, queue: Mutex::new(VecDeque::new()) });
+        listeners.insert(port, listener.clone());
+        Ok(Self { listener })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.listener.port
+    }
+
+    pub fn try_accept(&self) -> Option<TcpStream> {
+        let connection = self.listener.queue.lock().pop_front()?;
+        Some(TcpStream { connection })
+    }
+
+    // timeout_msがNoneなら接続が来るまで待つ
+    pub fn accept(&self, timeout_ms: Option<u64>) -> Result<TcpStream, NetError> {
+        wait_for(timeout_ms, || self.try_accept()).ok_or(NetError::TimedOut)
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().remove(&self.listener.port);
+        for connection in self.listener.queue.lock().drain(..) {
+            connection.tcb.lock().abort(NetError::ConnectionReset);
+        }
+    }
+}
+
+pub struct TcpStream {
+    connection: Arc<Connection>,
+}
+
+impl TcpStream {
+    // 接続が確立するか、失敗するまで待つ
+    pub fn connect(remote: SocketAddrV4) -> Result<Self, NetError> {
+        let local_ip = ipv4::source_address(*remote.ip()).ok_or(NetError::NoRoute)?;
+        let port = ephemeral_port(port_in_use).ok_or(NetError::AddressInUse)?;
+        let local = SocketAddrV4::new(local_ip, port);
+        let tcb = Tcb::new(State::SynSent, local, remote);
+        tcb.send_syn();
+        let connection = Arc::new(Connection { local, remote, tcb: Mutex::new(tcb) });
+        CONNECTIONS.lock().push(connection.clone());
+
+        wait_for(None, || {
+            let tcb = connection.tcb.lock();
+            match tcb.state {
+                State::SynSent | State::SynReceived => None,
+                State::Closed => Some(Err(tcb.error.unwrap_or(NetError::ConnectionReset))),
+                _ => Some(Ok(())),
+            }
+        })
+        .unwrap()?;
+        Ok(Self { connection })
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.connection.local
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.connection.remote
+    }
+
+    pub fn state(&self) -> State {
+        self.connection.tcb.lock().state
+    }
+
+    // 何か届くまで待つ。相手が閉じたら0を返す
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        wait_for(None, || self.try_read(buf)).unwrap()
+    }
+
+    // 読めるものが無くまだ閉じられていなければNone
+    pub fn try_read(&self, buf: &mut [u8]) -> Option<Result<usize, NetError>> {
+        let mut tcb = self.connection.tcb.lock();
+        if !tcb.recv_buffer.is_empty() {
+            let window = tcb.window() as usize;
+            let len = buf.len().min(tcb.recv_buffer.len());
+            for (dst, src) in buf.iter_mut().zip(tcb.recv_buffer.drain(..len)) {
+                *dst = src;
+            }
+            // ウィンドウが小さくて相手が止まっていたかもしれないので知らせる
+            if window < tcb.mss && tcb.window() as usize >= tcb.mss && tcb.synchronized() {
+                tcb.send_ack();
+            }
+            return Some(Ok(len));
+        }
+        if let Some(error) = tcb.error {
+            return Some(Err(error));
+        }
+        (tcb.fin_received || tcb.state == State::Closed).then_some(Ok(0))
+    }
+
+    // 送信バッファに入れられた分だけ返す。一杯なら空くまで待つ
+    pub fn write(&self, data: &[u8]) -> Result<usize, NetError> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        wait_for(None, || {
+            let mut tcb = self.connection.tcb.lock();
+            if let Some(error) = tcb.error {
+                return Some(Err(error));
+            }
+            if !matches!(tcb.state, State::Established | State::CloseWait) {
+                return Some(Err(NetError::NotConnected));
+            }
+            let len = data.len().min(BUFFER_SIZE - tcb.send_buffer.len());
+            if len == 0 {
+                return None;
+            }
+            tcb.send_buffer.extend(&data[..len]);
+            tcb.output(false);
+            Some(Ok(len))
+        })
+        .unwrap()
+    }
+
+    pub fn write_all(&self, data: &[u8]) -> Result<(), NetError> {
+        let mut written = 0;
+        while written < data.len() {
+            written += self.write(&data[written..])?;
+        }
+        Ok(())
+    }
+
+    // 送信バッファに残っているものを送ってからFINを送る。受信はできる
+    pub fn close(&self) {
+        let mut tcb = self.connection.tcb.lock();
+        match tcb.state {
+            State::Established => tcb.state = State::FinWait1,
+            State::CloseWait => tcb.state = State::LastAck,
+            State::SynSent | State::SynReceived => tcb.close_now(),
+            _ => {}
+        }
+        tcb.output(false);
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -0,0 +1,119 @@
+// UDP
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use super::ipv4::{self, Header};
+use super::{ephemeral_port, wait_for, Interface, Ipv4Addr, NetError, SocketAddrV4};
+
+const HEADER_LEN: usize = 8;
+// これを超えて溜まったら新しく届いたものを捨てる
+const QUEUE_LIMIT: usize = 64;
+
+type Queue = Arc<Mutex<VecDeque<(Vec<u8>, SocketAddrV4)>>>;
+
+lazy_static! {
+    static ref SOCKETS: Mutex<BTreeMap<u16, Queue>> = Mutex::new(BTreeMap::new());
+}
+
+pub struct UdpSocket {
+    port: u16,
+    queue: Queue,
+}
+
+impl UdpSocket {
+    // portが0なら空いているポートを使う
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => ephemeral_port(|port| sockets.contains_key(&port)).ok_or(NetError::AddressInUse)?,
+            port if sockets.contains_key(&port) => return Err(NetError::AddressInUse),
+            port => port,
+        };
+        let queue = Queue::default();
+        sockets.insert(port, queue.clone());
+        Ok(Self { port, queue })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&self, data: &[u8], dst: SocketAddrV4) -> Result<(), NetError> {
+        let src_ip = ipv4::source_address(*dst.ip()).ok_or(NetError::NoRoute)?;
+        let datagram = datagram(SocketAddrV4::new(src_ip, self.port), dst, data);
+        ipv4::send(Some(src_ip), *dst.ip(), ipv4::PROTOCOL_UDP, &datagram)
+    }
+
+    // interfaceから255.255.255.255のportへ送る。DHCPのようにアドレスが決まる前でも使える
+    pub fn send_broadcast(&self, interface: &Interface, data: &[u8], port: u16) -> Result<(), NetError> {
+        let src_ip = interface.address().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let datagram =
+            datagram(SocketAddrV4::new(src_ip, self.port), SocketAddrV4::new(Ipv4Addr::BROADCAST, port), data);
+        ipv4::send_broadcast(interface, src_ip, ipv4::PROTOCOL_UDP, &datagram)
+    }
+
+    pub fn try_recv_from(&self) -> Option<(Vec<u8>, SocketAddrV4)> {
+        self.queue.lock().pop_front()
+    }
+
+    // timeout_msがNoneなら届くまで待つ
+    pub fn recv_from(&self, timeout_ms: Option<u64>) -> Result<(Vec<u8>, SocketAddrV4), NetError> {
+        wait_for(timeout_ms, || self.try_recv_from()).ok_or(NetError::TimedOut)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+fn datagram(src: SocketAddrV4, dst: SocketAddrV4, data: &[u8]) -> Vec<u8> {
+    let len = HEADER_LEN + data.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&src.port().to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+    let sum = match ipv4::checksum(&datagram, ipv4::pseudo_header_sum(*src.ip(), *dst.ip(), ipv4::PROTOCOL_UDP, len)) {
+        // 0はチェックサム無しの意味になるので、全部1で送る
+        0 => 0xffff,
+        sum => sum,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    datagram
+}
+
+// 受け取るソケットが無ければfalse
+pub(super) fn receive(header: &Header, datagram: &[u8]) -> bool {
+    if datagram.len() < HEADER_LEN {
+        return true;
+    }
+    let src_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let dst_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    let sum = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if len < HEADER_LEN || len > datagram.len() {
+        return true;
+    }
+    let datagram = &datagram[..len];
+    if sum != 0
+        && ipv4::checksum(datagram, ipv4::pseudo_header_sum(header.src, header.dst, ipv4::PROTOCOL_UDP, len)) != 0
+    {
+        return true;
+    }
+
+    let Some(queue) = SOCKETS.lock().get(&dst_port).cloned() else {
+        return false;
+    };
+    let mut queue = queue.lock();
+    if queue.len() < QUEUE_LIMIT {
+        queue.push_back((datagram[HEADER_LEN..].to_vec(), SocketAddrV4::new(header.src, src_port)));
+    }
+    true
+}
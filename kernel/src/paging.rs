@@ -1,25 +1,85 @@
 // 4levelではなく、3levelになっている
+//
+// PML4の下位半分(0..256)はカーネルが使い、すべてのアドレス空間で共有する。
+// 上位半分はプロセスごとのユーザー空間になる
 
 use core::ptr::NonNull;
 
-use x86_64::{registers::control::{Cr3, Cr3Flags}, structures::paging::{PageSize, PageTable, PhysFrame, Size1GiB, Size2MiB}, PhysAddr};
+use x86_64::{registers::{control::{Cr0, Cr0Flags, Cr3, Cr3Flags}, model_specific::{Efer, EferFlags}}, structures::paging::{FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB}, PhysAddr, VirtAddr};
 use acpi::{AcpiHandler, PhysicalMapping};
 use spin::Lazy;
 
 const EMPTY_PAGE_TABLE: PageTable = PageTable::new();
 
+pub const USER_SPACE_START: u64 = 0xffff_8000_0000_0000;
+pub const USER_SPACE_END: u64 = 0xffff_ffff_ffff_f000; // 最後のページは使わない
+const KERNEL_PML4_ENTRIES: usize = 256;
+
 static PAGE_TABLE: Lazy<PhysFrame> = Lazy::new(|| unsafe { init_identity_page_table() }); 
 static mut PML4_TABLE: PageTable = PageTable::new(); // Page Map Level4 Table
 static mut PDP_TABLE: PageTable = PageTable::new();  // Page Directory Pointer Table
 static mut PAGE_DIRECTORY: [PageTable; 64] = [EMPTY_PAGE_TABLE; 64];
 
 pub unsafe fn init() {
+    // NO_EXECUTEはEFER.NXEを立てないと予約ビット扱いになる
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    // カーネルからの書き込みでもcopy-on-writeのページで#PFを起こすようにする
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
     Cr3::write(*PAGE_TABLE, Cr3Flags::empty());
 }
 
-unsafe fn init_identity_page_table() -> PhysFrame {
-    use x86_64::structures::paging::PageTableFlags;
+// 物理メモリはidentity mapされているので、offset 0のOffsetPageTableとして扱える
+pub unsafe fn page_table(pml4: PhysFrame) -> OffsetPageTable<'static> {
+    let pml4_table: *mut PageTable = as_virt_addr(pml4.start_address()).unwrap().as_mut_ptr();
+    OffsetPageTable::new(&mut *pml4_table, VirtAddr::new(0))
+}
+
+pub unsafe fn active_page_table() -> OffsetPageTable<'static> {
+    page_table(Cr3::read().0)
+}
 
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    (USER_SPACE_START..USER_SPACE_END).contains(&addr.as_u64())
+}
+
+// カーネル側の半分を今のPML4と共有する、新しいPML4を作る
+pub unsafe fn new_user_page_table(allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<PhysFrame> {
+    let frame = allocator.allocate_frame()?;
+    let table: &mut PageTable = &mut *as_virt_addr(frame.start_address()).unwrap().as_mut_ptr();
+    table.zero();
+
+    let kernel_table = active_page_table();
+    for i in 0..KERNEL_PML4_ENTRIES {
+        table[i] = kernel_table.level_4_table()[i].clone();
+    }
+    Some(frame)
+}
+
+// new_user_page_tableで作ったPML4と、ユーザー空間のページテーブルを解放する
+// マップされていたフレーム自体はVmaを取り除いたときに解放済みであること
+pub unsafe fn free_user_page_table(pml4: PhysFrame, deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+    unsafe fn free_table(frame: PhysFrame, level: usize, deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+        if level > 1 {
+            let table: &PageTable = &*as_virt_addr(frame.start_address()).unwrap().as_ptr();
+            for entry in table.iter() {
+                if entry.flags().contains(PageTableFlags::PRESENT) && !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                    free_table(entry.frame().unwrap(), level - 1, deallocator);
+                }
+            }
+        }
+        deallocator.deallocate_frame(frame);
+    }
+
+    let table: &PageTable = &*as_virt_addr(pml4.start_address()).unwrap().as_ptr();
+    for entry in table.iter().skip(KERNEL_PML4_ENTRIES) {
+        if entry.flags().contains(PageTableFlags::PRESENT) {
+            free_table(entry.frame().unwrap(), 3, deallocator);
+        }
+    }
+    deallocator.deallocate_frame(pml4);
+}
+
+unsafe fn init_identity_page_table() -> PhysFrame {
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL;
 
     // PML4_TABLE[0] -> PDP_TABLE
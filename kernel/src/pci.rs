@@ -1,9 +1,32 @@
 use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+use spin::Once;
 
 use crate::error::OsError;
-use core::{marker::PhantomData, ops::Range, ptr::read_volatile, ptr::write_volatile};
+use crate::{acpi_tables, ahci, e1000, io, println, virtio};
+use core::{fmt, marker::PhantomData, ops::Range, ptr::read_volatile, ptr::write_volatile};
 
-#[derive(Clone, PartialEq, Eq)]
+// ECAMが無いとき(QEMUのpcマシンなど)に使うI/Oポート
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// configuration spaceのレジスタ
+pub const REG_COMMAND: usize = 0x04;
+pub const REG_STATUS: usize = 0x06;
+pub const REG_CLASS: usize = 0x08;
+pub const REG_HEADER_TYPE: usize = 0x0e;
+pub const REG_BAR0: usize = 0x10;
+pub const REG_CAPABILITIES: usize = 0x34;
+pub const REG_INTERRUPT_LINE: usize = 0x3c;
+
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct VendorDeviceId {
     pub vendor: u16,
     pub device: u16,
@@ -18,6 +41,30 @@ impl BusDeviceFunction {
     pub fn iter() -> BusDeviceFunctionIterator {
         BusDeviceFunctionIterator { next_id: 0 }
     }
+
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            id: (bus as u16) << 8 | ((device as u16) & 0x1f) << 3 | (function as u16) & 0x7,
+        }
+    }
+
+    pub fn bus(self) -> u8 {
+        (self.id >> 8) as u8
+    }
+
+    pub fn device(self) -> u8 {
+        ((self.id >> 3) & 0x1f) as u8
+    }
+
+    pub fn function(self) -> u8 {
+        (self.id & 0x7) as u8
+    }
+}
+
+impl fmt::Debug for BusDeviceFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus(), self.device(), self.function())
+    }
 }
 
 pub struct BusDeviceFunctionIterator {
@@ -53,23 +100,103 @@ impl<T> ConfigRegisters<T> {
     }
 }
 
-pub trait PciDeviceDriver {
-    fn supports(&self, vd: VendorDeviceId) -> bool;
+pub trait PciDeviceDriver: Sync {
+    fn supports(&self, device: &PciDevice) -> bool;
     fn attach(&self, bdf: BusDeviceFunction) -> Result<Box<dyn PciDeviceDriverInstance>, OsError>;
     fn name(&self) -> &str;
 }
 
-pub trait PciDeviceDriverInstance {
+pub trait PciDeviceDriverInstance: Send {
     fn name(&self) -> &str;
+    fn bdf(&self) -> BusDeviceFunction;
+}
+
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+    pub bdf: BusDeviceFunction,
+    pub vd: VendorDeviceId,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    Memory(u64),
+    Io(u16),
 }
 
 pub struct Pci {
-    ecm_range: Range<usize>,
+    // Noneなら0xcf8/0xcfcのI/Oポートでアクセスする
+    ecm_range: Option<Range<usize>>,
+}
+
+static PCI: Once<Pci> = Once::new();
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+    static ref DRIVER_INSTANCES: Mutex<Vec<Box<dyn PciDeviceDriverInstance>>> = Mutex::new(Vec::new());
+}
+
+static DRIVERS: &[&dyn PciDeviceDriver] = &[
+    &ahci::AhciDriver,
+    &virtio::blk::VirtioBlkDriver,
+    &virtio::net::VirtioNetDriver,
+    &e1000::E1000Driver,
+];
+
+pub fn init() {
+    let pci = PCI.call_once(|| {
+        let ecm_range = acpi_tables::pci_config_base().map(|base| {
+            let base = base as usize;
+            base..base + (256 << 20)
+        });
+        Pci { ecm_range }
+    });
+
+    let devices = pci.search_devices().unwrap();
+    for device in devices.iter() {
+        for driver in DRIVERS.iter().filter(|d| d.supports(device)) {
+            match driver.attach(device.bdf) {
+                Ok(instance) => DRIVER_INSTANCES.lock().push(instance),
+                Err(e) => println!("{}: failed to attach {:?}: {:?}", driver.name(), device.bdf, e),
+            }
+        }
+    }
+    *DEVICES.lock() = devices;
+}
+
+pub fn pci() -> &'static Pci {
+    PCI.get().expect("PCI is not initialized")
+}
+
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+// bdfに付いたドライバの名前
+pub fn driver_name(bdf: BusDeviceFunction) -> Option<String> {
+    DRIVER_INSTANCES.lock().iter().find(|instance| instance.bdf() == bdf).map(|instance| instance.name().into())
 }
 
 impl Pci {
     pub fn ecm_base<T>(&self, id: BusDeviceFunction) -> *mut T {
-        (self.ecm_range.start + ((id.id as usize) << 12)) as *mut T
+        let start = self.ecm_range.as_ref().expect("no ECAM").start;
+        (start + ((id.id as usize) << 12)) as *mut T
+    }
+
+    fn port_io_read(&self, bdf: BusDeviceFunction, byte_offset: usize) -> u32 {
+        unsafe {
+            io::out32(CONFIG_ADDRESS, 0x8000_0000 | (bdf.id as u32) << 8 | (byte_offset as u32 & 0xfc));
+            io::in32(CONFIG_DATA)
+        }
+    }
+
+    fn port_io_write(&self, bdf: BusDeviceFunction, byte_offset: usize, data: u32) {
+        unsafe {
+            io::out32(CONFIG_ADDRESS, 0x8000_0000 | (bdf.id as u32) << 8 | (byte_offset as u32 & 0xfc));
+            io::out32(CONFIG_DATA, data)
+        }
     }
 
     pub fn read_register_u8(
@@ -77,7 +204,10 @@ impl Pci {
         bdf: BusDeviceFunction,
         byte_offset: usize,
     ) -> Result<u8, OsError> {
-        ConfigRegisters::read(self.ecm_base(bdf), byte_offset)
+        match self.ecm_range {
+            Some(_) => ConfigRegisters::read(self.ecm_base(bdf), byte_offset),
+            None => Ok((self.port_io_read(bdf, byte_offset) >> ((byte_offset & 3) * 8)) as u8),
+        }
     }
 
     pub fn read_register_u16(
@@ -85,7 +215,10 @@ impl Pci {
         bdf: BusDeviceFunction,
         byte_offset: usize,
     ) -> Result<u16, OsError> {
-        ConfigRegisters::read(self.ecm_base(bdf), byte_offset)
+        match self.ecm_range {
+            Some(_) => ConfigRegisters::read(self.ecm_base(bdf), byte_offset),
+            None => Ok((self.port_io_read(bdf, byte_offset) >> ((byte_offset & 2) * 8)) as u16),
+        }
     }
 
     pub fn read_register_u32(
@@ -93,7 +226,10 @@ impl Pci {
         bdf: BusDeviceFunction,
         byte_offset: usize,
     ) -> Result<u32, OsError> {
-        ConfigRegisters::read(self.ecm_base(bdf), byte_offset)
+        match self.ecm_range {
+            Some(_) => ConfigRegisters::read(self.ecm_base(bdf), byte_offset),
+            None => Ok(self.port_io_read(bdf, byte_offset)),
+        }
     }
 
     pub fn read_register_u64(
@@ -106,13 +242,36 @@ impl Pci {
         Ok(((hi as u64) << 32) | (lo as u64))
     }
 
+    pub fn write_register_u16(
+        &self,
+        bdf: BusDeviceFunction,
+        byte_offset: usize,
+        data: u16,
+    ) -> Result<(), OsError> {
+        match self.ecm_range {
+            Some(_) => ConfigRegisters::write(self.ecm_base(bdf), byte_offset, data),
+            None => {
+                let shift = (byte_offset & 2) * 8;
+                let old = self.port_io_read(bdf, byte_offset) & !(0xffff << shift);
+                self.port_io_write(bdf, byte_offset, old | (data as u32) << shift);
+                Ok(())
+            }
+        }
+    }
+
     pub fn write_register_u32(
         &self,
         bdf: BusDeviceFunction,
         byte_offset: usize,
         data: u32,
     ) -> Result<(), OsError> {
-        ConfigRegisters::write(self.ecm_base(bdf), byte_offset, data)
+        match self.ecm_range {
+            Some(_) => ConfigRegisters::write(self.ecm_base(bdf), byte_offset, data),
+            None => {
+                self.port_io_write(bdf, byte_offset, data);
+                Ok(())
+            }
+        }
     }
     pub fn write_register_u64(
         &self,
@@ -137,13 +296,74 @@ impl Pci {
         }
     }
 
-    pub fn search_devices(&self) -> Result<(), OsError> {
-        for bdf in BusDeviceFunction::iter() {
-            if let Some(vd) = self.read_vendor_id_and_device_id(bdf) {
-                todo!()
+    pub fn search_devices(&self) -> Result<Vec<PciDevice>, OsError> {
+        let mut devices = Vec::new();
+        for bus in 0..=255u8 {
+            for device in 0..32u8 {
+                for function in 0..8u8 {
+                    let bdf = BusDeviceFunction::new(bus, device, function);
+                    let Some(vd) = self.read_vendor_id_and_device_id(bdf) else {
+                        if function == 0 {
+                            break;
+                        }
+                        continue;
+                    };
+                    let class = self.read_register_u32(bdf, REG_CLASS)?;
+                    devices.push(PciDevice {
+                        bdf,
+                        vd,
+                        class: (class >> 24) as u8,
+                        subclass: (class >> 16) as u8,
+                        prog_if: (class >> 8) as u8,
+                    });
+                    // マルチファンクションでなければfunction 1以降は無い
+                    if function == 0 && self.read_register_u8(bdf, REG_HEADER_TYPE)? & 0x80 == 0 {
+                        break;
+                    }
+                }
             }
         }
+        Ok(devices)
+    }
 
-        Ok(())
+    pub fn read_bar(&self, bdf: BusDeviceFunction, index: usize) -> Result<Bar, OsError> {
+        let offset = REG_BAR0 + index * 4;
+        let bar = self.read_register_u32(bdf, offset)?;
+        if bar & 1 != 0 {
+            return Ok(Bar::Io((bar & !0x3) as u16));
+        }
+        let address = (bar & !0xf) as u64;
+        // type 0b10は64bitのBARで、次のBARが上位32bit
+        if (bar >> 1) & 0x3 == 0x2 {
+            let high = self.read_register_u32(bdf, offset + 4)? as u64;
+            return Ok(Bar::Memory(high << 32 | address));
+        }
+        Ok(Bar::Memory(address))
+    }
+
+    // MMIOとDMAを使えるようにする
+    pub fn enable_bus_master(&self, bdf: BusDeviceFunction) -> Result<(), OsError> {
+        let command = self.read_register_u16(bdf, REG_COMMAND)?;
+        self.write_register_u16(bdf, REG_COMMAND, command | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER)
+    }
+
+    pub fn interrupt_line(&self, bdf: BusDeviceFunction) -> Result<u8, OsError> {
+        self.read_register_u8(bdf, REG_INTERRUPT_LINE)
+    }
+
+    // (capability ID, configuration space上のオフセット)
+    pub fn capabilities(&self, bdf: BusDeviceFunction) -> Result<Vec<(u8, usize)>, OsError> {
+        let mut capabilities = Vec::new();
+        if self.read_register_u16(bdf, REG_STATUS)? & STATUS_CAPABILITIES_LIST == 0 {
+            return Ok(capabilities);
+        }
+        let mut offset = (self.read_register_u8(bdf, REG_CAPABILITIES)? & 0xfc) as usize;
+        // 壊れたリストで無限ループしないよう、数に上限を設ける
+        while offset != 0 && capabilities.len() < 48 {
+            let id = self.read_register_u8(bdf, offset)?;
+            capabilities.push((id, offset));
+            offset = (self.read_register_u8(bdf, offset + 1)? & 0xfc) as usize;
+        }
+        Ok(capabilities)
     }
 }
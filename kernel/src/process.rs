@@ -0,0 +1,178 @@
+// Process
+//
+// プロセスはそれぞれ自分のPML4を持ち、カーネル側の半分は全プロセスで共有する。
+// まだスケジューラがないので、runしたプロセスはexitするまで呼び出し元を止めて走る
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use common::syscall::{O_RDWR, STDERR, STDIN, STDOUT};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::VirtAddr;
+
+use crate::fs::{self, FileTable};
+use crate::gdt;
+use crate::memory_manager::frame_manager;
+use crate::paging;
+use crate::stack::KernelStack;
+use crate::syscall;
+use crate::vma::{self, AddressSpace, PageFaultError};
+
+pub type Pid = u64;
+
+const KERNEL_STACK_PAGES: usize = 16;
+
+extern "C" {
+    fn CallApp(argc: u64, argv: u64, ss: u16, rip: u64, rsp: u64, os_stack_ptr: *mut u64) -> i32;
+    fn ExitApp(rsp: u64, ret_val: i32) -> !;
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    OutOfMemory,
+}
+
+pub struct Process {
+    pid: Pid,
+    name: String,
+    address_space: Mutex<AddressSpace>,
+    files: Mutex<FileTable>,
+    cwd: Mutex<String>,
+    kernel_stack: KernelStack,
+    os_stack_pointer: AtomicU64, // CallAppが保存した呼び出し元のrsp
+}
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    // 実行中のプロセス。プロセスの中から別のプロセスを走らせると積み上がり、末尾が今のプロセスになる
+    static ref RUNNING: Mutex<Vec<Arc<Process>>> = Mutex::new(Vec::new());
+}
+
+impl Process {
+    pub fn new(name: &str) -> Result<Arc<Self>, ProcessError> {
+        let pml4 = unsafe { paging::new_user_page_table(&mut *frame_manager()) }
+            .ok_or(ProcessError::OutOfMemory)?;
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        let kernel_stack = KernelStack::new(&format!("{} (pid {})", name, pid), KERNEL_STACK_PAGES);
+
+        Ok(Arc::new(Self {
+            pid,
+            name: name.into(),
+            address_space: Mutex::new(AddressSpace::new(pml4)),
+            files: Mutex::new(standard_files()),
+            cwd: Mutex::new("/".into()),
+            kernel_stack,
+            os_stack_pointer: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn address_space(&self) -> spin::MutexGuard<AddressSpace> {
+        self.address_space.lock()
+    }
+
+    pub fn files(&self) -> spin::MutexGuard<FileTable> {
+        self.files.lock()
+    }
+
+    // 正規化した絶対パス
+    pub fn cwd(&self) -> spin::MutexGuard<String> {
+        self.cwd.lock()
+    }
+
+    // entryからring 3で実行を始め、プロセスがexitしたらその終了コードを返す
+    pub fn run(self: &Arc<Self>, entry: VirtAddr, stack_pointer: VirtAddr, argc: u64, argv: VirtAddr) -> i32 {
+        RUNNING.lock().push(self.clone());
+        self.activate();
+
+        let code = unsafe {
+            CallApp(
+                argc,
+                argv.as_u64(),
+                gdt::user_data_selector().0,
+                entry.as_u64(),
+                stack_pointer.as_u64(),
+                self.os_stack_pointer.as_ptr(),
+            )
+        };
+
+        let finished = RUNNING.lock().pop();
+        match current() {
+            Some(parent) => parent.activate(),
+            None => {
+                let kernel_pml4 = vma::kernel_address_space().as_ref().unwrap().pml4();
+                unsafe { Cr3::write(kernel_pml4, Cr3Flags::empty()) }
+            }
+        }
+        drop(finished);
+        code
+    }
+
+    fn activate(&self) {
+        gdt::set_kernel_stack(self.kernel_stack.top());
+        syscall::set_kernel_stack(self.kernel_stack.top());
+        unsafe { Cr3::write(self.address_space.lock().pml4(), Cr3Flags::empty()) }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let address_space = self.address_space.get_mut();
+        let ranges: Vec<_> = address_space.vmas().iter().map(|vma| vma.range.clone()).collect();
+        for range in ranges {
+            address_space.remove(range).unwrap();
+        }
+        unsafe { paging::free_user_page_table(address_space.pml4(), &mut *frame_manager()) }
+    }
+}
+
+// 0,1,2にコンソールを開いておく
+fn standard_files() -> FileTable {
+    let mut files = FileTable::new();
+    if let Ok(console) = fs::devfs::open("console", O_RDWR) {
+        for _ in [STDIN, STDOUT, STDERR] {
+            files.insert(console.clone()).unwrap();
+        }
+    }
+    files
+}
+
+// 走らせた順に並ぶ。末尾以外は、自分がrunしたプロセスの終わりを待っている
+pub fn running() -> Vec<Arc<Process>> {
+    RUNNING.lock().clone()
+}
+
+pub fn current() -> Option<Arc<Process>> {
+    RUNNING.lock().last().cloned()
+}
+
+// 今のプロセスを終わらせ、そのプロセスをrunした所へ戻る
+pub fn exit(code: i32) -> ! {
+    let os_stack_pointer = current()
+        .expect("exit outside of a process")
+        .os_stack_pointer
+        .load(Ordering::Relaxed);
+    unsafe { ExitApp(os_stack_pointer, code) }
+}
+
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), PageFaultError> {
+    let process = current().ok_or(PageFaultError::NotMapped(addr))?;
+    let mut address_space = process
+        .address_space
+        .try_lock()
+        .ok_or(PageFaultError::AddressSpaceBusy)?;
+    address_space.handle_page_fault(addr, error_code)
+}
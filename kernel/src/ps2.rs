@@ -0,0 +1,172 @@
+// PS/2 controller (i8042)
+//
+// 1つ目のポートのキーボードだけを使う。IRQ1をI/O APICから受け、届いたスキャンコードをkeyboardに渡す。
+// コントローラーがset 2をset 1に変換する設定ならそのまま使い、そうでなければset 2として読む。
+// 割り込みの中ではコントローラーに書かず、LEDを変えるコマンドはpollで送る
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use crate::error::OsError;
+use crate::keyboard::layout::Layout;
+use crate::keyboard::scancode::ScancodeSet;
+use crate::{interrupts, io, keyboard, println, timer};
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND_PORT: u8 = 0xa7;
+const COMMAND_SELF_TEST: u8 = 0xaa;
+const COMMAND_ENABLE_FIRST_PORT: u8 = 0xae;
+const COMMAND_DISABLE_FIRST_PORT: u8 = 0xad;
+const SELF_TEST_PASSED: u8 = 0x55;
+
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// キーボードへのコマンドと応答
+const KEYBOARD_SET_LEDS: u8 = 0xed;
+const KEYBOARD_ENABLE_SCANNING: u8 = 0xf4;
+const KEYBOARD_ACK: u8 = 0xfa;
+const KEYBOARD_RESEND: u8 = 0xfe;
+// キーボードのバッファがあふれたときなどに届く
+const KEYBOARD_ERROR: [u8; 2] = [0x00, 0xff];
+
+const KEYBOARD_IRQ: u8 = 1;
+const TIMEOUT_MS: u64 = 100;
+
+// Set LEDsを送る手順。コマンドを送ってACKを待ち、LEDのビットを送ってまたACKを待つ
+const LEDS_IDLE: u8 = 0;
+const LEDS_COMMAND_SENT: u8 = 1;
+const LEDS_COMMAND_ACKED: u8 = 2;
+const LEDS_DATA_SENT: u8 = 3;
+static LEDS_STEP: AtomicU8 = AtomicU8::new(LEDS_IDLE);
+// 送りたいLEDのビットと、まだ送っていないことの印
+static LEDS: AtomicU8 = AtomicU8::new(0);
+static LEDS_CHANGED: AtomicBool = AtomicBool::new(false);
+// ACKを待ち始めた時刻。届かなければあきらめる
+static LEDS_SENT_AT: AtomicU64 = AtomicU64::new(0);
+
+fn wait_output() -> Result<u8, OsError> {
+    if !timer::wait_until(TIMEOUT_MS, || unsafe { io::in8(STATUS) } & STATUS_OUTPUT_FULL != 0) {
+        return Err(OsError::DeviceNotFound);
+    }
+    Ok(unsafe { io::in8(DATA) })
+}
+
+fn write(port: u16, value: u8) -> Result<(), OsError> {
+    if !timer::wait_until(TIMEOUT_MS, || unsafe { io::in8(STATUS) } & STATUS_INPUT_FULL == 0) {
+        return Err(OsError::DeviceNotFound);
+    }
+    unsafe { io::out8(port, value) };
+    Ok(())
+}
+
+fn flush() {
+    while unsafe { io::in8(STATUS) } & STATUS_OUTPUT_FULL != 0 {
+        unsafe { io::in8(DATA) };
+    }
+}
+
+// keymapはcmdlineのkeymap=で指定された配列の名前
+pub fn init(keymap: Option<&str>) {
+    if let Some(name) = keymap {
+        match Layout::from_name(name) {
+            Some(layout) => keyboard::set_layout(layout),
+            None => println!("ps2: unknown keymap {}", name),
+        }
+    }
+    // コントローラーが無ければSTATUSは0xffに読める
+    if unsafe { io::in8(STATUS) } == 0xff {
+        return println!("ps2: no controller");
+    }
+    if let Err(e) = init_controller() {
+        println!("ps2: {:?}", e);
+    }
+}
+
+fn init_controller() -> Result<(), OsError> {
+    write(COMMAND, COMMAND_DISABLE_FIRST_PORT)?;
+    write(COMMAND, COMMAND_DISABLE_SECOND_PORT)?;
+    flush();
+
+    write(COMMAND, COMMAND_READ_CONFIG)?;
+    let config = wait_output()? & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+    write(COMMAND, COMMAND_WRITE_CONFIG)?;
+    write(DATA, config)?;
+
+    write(COMMAND, COMMAND_SELF_TEST)?;
+    if wait_output()? != SELF_TEST_PASSED {
+        return Err(OsError::DeviceNotFound);
+    }
+    // セルフテストで設定が戻ってしまうコントローラーがある
+    write(COMMAND, COMMAND_WRITE_CONFIG)?;
+    write(DATA, config)?;
+
+    keyboard::set_scancode_set(if config & CONFIG_TRANSLATION != 0 { ScancodeSet::Set1 } else { ScancodeSet::Set2 });
+    write(COMMAND, COMMAND_ENABLE_FIRST_PORT)?;
+    write(DATA, KEYBOARD_ENABLE_SCANNING)?;
+    if wait_output()? != KEYBOARD_ACK {
+        return Err(OsError::DeviceNotFound);
+    }
+
+    interrupts::register_irq(KEYBOARD_IRQ, false, Box::new(handle_interrupt))?;
+    write(COMMAND, COMMAND_WRITE_CONFIG)?;
+    write(DATA, config | CONFIG_FIRST_IRQ)?;
+    Ok(())
+}
+
+fn handle_interrupt() {
+    while unsafe { io::in8(STATUS) } & STATUS_OUTPUT_FULL != 0 {
+        let byte = unsafe { io::in8(DATA) };
+        match byte {
+            KEYBOARD_ACK => {
+                let next = match LEDS_STEP.load(Ordering::Relaxed) {
+                    LEDS_COMMAND_SENT => LEDS_COMMAND_ACKED,
+                    _ => LEDS_IDLE,
+                };
+                LEDS_STEP.store(next, Ordering::Relaxed);
+            }
+            // 送り直しを求められたら、初めからやり直す
+            KEYBOARD_RESEND => {
+                LEDS_STEP.store(LEDS_IDLE, Ordering::Relaxed);
+                LEDS_CHANGED.store(true, Ordering::Relaxed);
+            }
+            _ if KEYBOARD_ERROR.contains(&byte) => {}
+            _ => {
+                if let Some(leds) = keyboard::receive(byte) {
+                    LEDS.store(leds, Ordering::Relaxed);
+                    LEDS_CHANGED.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+// 割り込みの外から呼び、LEDを変えるコマンドを1段ずつ進める
+pub fn poll() {
+    let step = LEDS_STEP.load(Ordering::Relaxed);
+    let next = match step {
+        LEDS_IDLE if LEDS_CHANGED.swap(false, Ordering::Relaxed) => {
+            write(DATA, KEYBOARD_SET_LEDS).map_or(LEDS_IDLE, |_| LEDS_COMMAND_SENT)
+        }
+        LEDS_COMMAND_ACKED => write(DATA, LEDS.load(Ordering::Relaxed)).map_or(LEDS_IDLE, |_| LEDS_DATA_SENT),
+        LEDS_COMMAND_SENT | LEDS_DATA_SENT
+            if timer::uptime_ms() >= LEDS_SENT_AT.load(Ordering::Relaxed) + TIMEOUT_MS =>
+        {
+            LEDS_IDLE
+        }
+        _ => return,
+    };
+    if next != step {
+        LEDS_SENT_AT.store(timer::uptime_ms(), Ordering::Relaxed);
+        LEDS_STEP.store(next, Ordering::Relaxed);
+    }
+}
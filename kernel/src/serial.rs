@@ -0,0 +1,120 @@
+// Serial port (16550 UART)
+//
+// COM1を115200bps、8N1で使う。printkの出力をここにも流すので、qemu -serial stdioで起動ログが取れる。
+// 割り込みは使わず、送信は空くまで待ち、受信はポーリングで読む
+
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use crate::io;
+
+const COM1: u16 = 0x3f8;
+
+// ポートのオフセット
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+// LINE_CONTROLのDLABを立てている間はDATAとINTERRUPT_ENABLEが分周比になる
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+
+const BASE_BAUD: u32 = 115_200;
+const LINE_8N1: u8 = 0x03;
+const LINE_DLAB: u8 = 0x80;
+// FIFOを有効にして送受信の両方を空にする
+const FIFO_ENABLE_CLEAR: u8 = 0xc7;
+const MODEM_DTR_RTS_OUT2: u8 = 0x0b;
+const MODEM_LOOPBACK: u8 = 0x10;
+const STATUS_DATA_READY: u8 = 1 << 0;
+const STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+// 繋がっていないUARTで止まらないよう、送信が空くのを待つ回数に上限を設ける
+const TRANSMIT_SPIN_LIMIT: usize = 100_000;
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    // ループバックで折り返せなければ、UARTが無いとみなす
+    pub fn probe(base: u16, baud: u32) -> Option<Self> {
+        let port = Self { base };
+        let divisor = (BASE_BAUD / baud.clamp(1, BASE_BAUD)) as u16;
+        unsafe {
+            port.write(INTERRUPT_ENABLE, 0);
+            port.write(LINE_CONTROL, LINE_DLAB);
+            port.write(DIVISOR_LOW, divisor as u8);
+            port.write(DIVISOR_HIGH, (divisor >> 8) as u8);
+            port.write(LINE_CONTROL, LINE_8N1);
+            port.write(FIFO_CONTROL, FIFO_ENABLE_CLEAR);
+
+            port.write(MODEM_CONTROL, MODEM_DTR_RTS_OUT2 | MODEM_LOOPBACK);
+            port.write(DATA, 0xae);
+            if port.read(DATA) != 0xae {
+                return None;
+            }
+            port.write(MODEM_CONTROL, MODEM_DTR_RTS_OUT2);
+        }
+        Some(port)
+    }
+
+    unsafe fn read(&self, offset: u16) -> u8 {
+        io::in8(self.base + offset)
+    }
+
+    unsafe fn write(&self, offset: u16, value: u8) {
+        io::out8(self.base + offset, value)
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            for _ in 0..TRANSMIT_SPIN_LIMIT {
+                if self.read(LINE_STATUS) & STATUS_TRANSMIT_EMPTY != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            self.write(DATA, byte);
+        }
+    }
+
+    // 受信したバイトが無ければNone
+    pub fn try_receive(&mut self) -> Option<u8> {
+        unsafe { (self.read(LINE_STATUS) & STATUS_DATA_READY != 0).then(|| self.read(DATA)) }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // 端末では改行だけだと行頭に戻らない
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref SERIAL: Mutex<Option<SerialPort>> = Mutex::new(None);
+}
+
+pub fn init() {
+    *SERIAL.lock() = SerialPort::probe(COM1, BASE_BAUD);
+}
+
+pub fn try_receive() -> Option<u8> {
+    SERIAL.lock().as_mut()?.try_receive()
+}
+
+pub fn _print(args: fmt::Arguments) {
+    if let Some(serial) = SERIAL.lock().as_mut() {
+        let _ = fmt::Write::write_fmt(serial, args);
+    }
+}
@@ -0,0 +1,92 @@
+// Kernel shell
+//
+// コンソールのTTYから1行ずつ読み、登録されたコマンドを実行する。
+// 行の編集と↑↓での履歴の呼び出しは、TTYのカノニカルモードに任せる
+
+mod builtin;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use crate::tty::{self, TtyError};
+use crate::{printk, println};
+
+const PROMPT: &str = "> ";
+
+// サブシステムはこれを実装してregisterすれば、自分のコマンドを足せる
+pub trait Command: Send + Sync {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    // args[0]はコマンドの名前
+    fn run(&self, args: &[&str]);
+}
+
+lazy_static! {
+    static ref COMMANDS: Mutex<Vec<Arc<dyn Command>>> = Mutex::new(Vec::new());
+    // 改行が届くまでの、読んだ途中の行
+    static ref LINE: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+}
+
+pub fn init() {
+    builtin::register();
+    printk!("{}", PROMPT);
+}
+
+// 同じ名前のコマンドがあれば置き換える
+pub fn register(command: Arc<dyn Command>) {
+    let mut commands = COMMANDS.lock();
+    commands.retain(|c| c.name() != command.name());
+    commands.push(command);
+}
+
+pub fn commands() -> Vec<Arc<dyn Command>> {
+    COMMANDS.lock().clone()
+}
+
+// 読むものが無ければすぐに戻る
+pub fn poll() {
+    let tty = tty::console();
+    let mut buf = [0; 256];
+    loop {
+        let len = match tty.read(&mut buf, false) {
+            Ok(len) => len,
+            // Ctrl-Cで打っていた行は捨てられている
+            Err(TtyError::Interrupted) => {
+                LINE.lock().clear();
+                printk!("{}", PROMPT);
+                continue;
+            }
+            Err(TtyError::WouldBlock) => return,
+        };
+        let line = {
+            let mut line = LINE.lock();
+            line.extend_from_slice(&buf[..len]);
+            // bufに入りきらない行や、Ctrl-Dで途中まで渡された行は、改行が来るまで溜める
+            if line.last() != Some(&b'\n') {
+                continue;
+            }
+            String::from_utf8_lossy(&core::mem::take(&mut *line)).into_owned()
+        };
+        // コマンドがTTYのモードを変えても、シェルはカノニカルモードのまま読む
+        let termios = tty.termios();
+        execute(line.trim_end());
+        tty.set_termios(termios);
+        printk!("{}", PROMPT);
+    }
+}
+
+pub fn execute(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some(name) = args.first() else {
+        return;
+    };
+    // コマンドの中からhelpなどがCOMMANDSを見るので、ロックを外してから走らせる
+    let command = COMMANDS.lock().iter().find(|command| command.name() == *name).cloned();
+    match command {
+        Some(command) => command.run(&args),
+        None => println!("{}: command not found (try help)", name),
+    }
+}
@@ -0,0 +1,274 @@
+// シェルに最初から入っているコマンド
+
+use alloc::sync::Arc;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use super::Command;
+use crate::memory_manager::{frame_manager, Frame};
+use crate::{acpi_tables, console, io, logger, pci, println, process, serial, timer};
+
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xfe;
+const RESET_CONTROL: u16 = 0xcf9;
+const RESET_CONTROL_SYSTEM: u8 = 0x02;
+const RESET_CONTROL_FULL: u8 = 0x04;
+
+pub(super) fn register() {
+    let commands: [Arc<dyn Command>; 10] = [
+        Arc::new(Help),
+        Arc::new(Clear),
+        Arc::new(Mem),
+        Arc::new(Lspci),
+        Arc::new(Ps),
+        Arc::new(Acpi),
+        Arc::new(Dmesg),
+        Arc::new(Log),
+        Arc::new(Scrollback),
+        Arc::new(Reboot),
+    ];
+    for command in commands {
+        super::register(command);
+    }
+}
+
+// コンソールとシリアルの端末の両方を消す
+fn clear_screen() {
+    console::clear();
+    serial::_print(format_args!("\x1b[2J\x1b[H"));
+}
+
+struct Help;
+
+impl Command for Help {
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn description(&self) -> &str {
+        "list commands"
+    }
+
+    fn run(&self, _args: &[&str]) {
+        let mut commands = super::commands();
+        commands.sort_by(|a, b| a.name().cmp(b.name()));
+        for command in commands {
+            println!("  {:<10} {}", command.name(), command.description());
+        }
+    }
+}
+
+struct Clear;
+
+impl Command for Clear {
+    fn name(&self) -> &str {
+        "clear"
+    }
+
+    fn description(&self) -> &str {
+        "clear the screen"
+    }
+
+    fn run(&self, _args: &[&str]) {
+        clear_screen();
+    }
+}
+
+struct Mem;
+
+impl Command for Mem {
+    fn name(&self) -> &str {
+        "mem"
+    }
+
+    fn description(&self) -> &str {
+        "show physical memory usage"
+    }
+
+    fn run(&self, _args: &[&str]) {
+        let stats = frame_manager().stats();
+        let mib = |frames: usize| frames * Frame::SIZE / (1024 * 1024);
+        let free_frames = stats.total_frames - stats.used_frames;
+        println!("  total {:>8} frames {:>6} MiB", stats.total_frames, mib(stats.total_frames));
+        println!("  used  {:>8} frames {:>6} MiB", stats.used_frames, mib(stats.used_frames));
+        println!("  free  {:>8} frames {:>6} MiB", free_frames, mib(free_frames));
+    }
+}
+
+struct Lspci;
+
+impl Command for Lspci {
+    fn name(&self) -> &str {
+        "lspci"
+    }
+
+    fn description(&self) -> &str {
+        "list PCI devices"
+    }
+
+    fn run(&self, _args: &[&str]) {
+        for device in pci::devices() {
+            let driver = pci::driver_name(device.bdf).unwrap_or_default();
+            println!(
+                "  {:?} {:04x}:{:04x} class {:02x}{:02x}{:02x} {}",
+                device.bdf, device.vd.vendor, device.vd.device, device.class, device.subclass, device.prog_if, driver
+            );
+        }
+    }
+}
+
+struct Ps;
+
+impl Command for Ps {
+    fn name(&self) -> &str {
+        "ps"
+    }
+
+    fn description(&self) -> &str {
+        "list the kernel task and running processes"
+    }
+
+    // カーネルのメインループ(シェル)をpid 0とし、runで積まれたプロセスを続ける。
+    // 実行中なのは最後の1つだけで、他はrunしたプロセスが終わるのを待っている
+    fn run(&self, _args: &[&str]) {
+        let processes = process::running();
+        let state = |waiting: bool| if waiting { "waiting" } else { "running" };
+        println!("  {:>5} {:<8} NAME", "PID", "STATE");
+        println!("  {:>5} {:<8} kernel (shell)", 0, state(!processes.is_empty()));
+        for (i, process) in processes.iter().enumerate() {
+            println!("  {:>5} {:<8} {}", process.pid(), state(i + 1 < processes.len()), process.name());
+        }
+    }
+}
+
+struct Acpi;
+
+impl Command for Acpi {
+    fn name(&self) -> &str {
+        "acpi"
+    }
+
+    fn description(&self) -> &str {
+        "show ACPI tables and interrupt controllers"
+    }
+
+    fn run(&self, _args: &[&str]) {
+        let Some(revision) = acpi_tables::revision() else {
+            return println!("  ACPI tables are not available");
+        };
+        println!("  revision {}", revision);
+        for table in acpi_tables::tables() {
+            println!("  {} at {:#010x} ({} bytes)", table.signature, table.address, table.length);
+        }
+        if let Some(base) = acpi_tables::pci_config_base() {
+            println!("  PCI ECAM at {:#x}", base);
+        }
+        let Some(apic) = acpi_tables::apic_info() else {
+            return;
+        };
+        println!("  local APIC at {:#x}", apic.local_apic_address);
+        for io_apic in apic.io_apics {
+            println!("  I/O APIC at {:#x} GSI {}", io_apic.address, io_apic.gsi_base);
+        }
+        for o in apic.overrides {
+            println!("  IRQ {} -> GSI {} active_low {:?} level {:?}", o.irq, o.gsi, o.active_low, o.level_triggered);
+        }
+    }
+}
+
+struct Dmesg;
+
+impl Command for Dmesg {
+    fn name(&self) -> &str {
+        "dmesg"
+    }
+
+    fn description(&self) -> &str {
+        "show the kernel log"
+    }
+
+    fn run(&self, _args: &[&str]) {
+        logger::dmesg(|line| println!("{}", line));
+    }
+}
+
+struct Log;
+
+impl Command for Log {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn description(&self) -> &str {
+        "set log levels (e.g. log info,net=debug)"
+    }
+
+    fn run(&self, args: &[&str]) {
+        if args.len() < 2 {
+            return println!("usage: log <level>[,<module>=<level>...]");
+        }
+        if let Err(e) = logger::set_filter(&args[1..].join(",")) {
+            println!("log: {:?}", e);
+        }
+    }
+}
+
+struct Scrollback;
+
+impl Command for Scrollback {
+    fn name(&self) -> &str {
+        "scrollback"
+    }
+
+    fn description(&self) -> &str {
+        "search the console scrollback (e.g. scrollback panicked)"
+    }
+
+    fn run(&self, args: &[&str]) {
+        if args.len() < 2 {
+            return println!("usage: scrollback <text>");
+        }
+        // 見つかった行を出すとscrollbackが伸びるので、先に全部探しておく
+        let matches = console::search(&args[1..].join(" "));
+        if matches.is_empty() {
+            return println!("scrollback: not found");
+        }
+        println!("  (0 is the top line of the screen, -N is N lines above it)");
+        for (line, text) in matches {
+            println!("{:5}: {}", line, text);
+        }
+    }
+}
+
+struct Reboot;
+
+impl Command for Reboot {
+    fn name(&self) -> &str {
+        "reboot"
+    }
+
+    fn description(&self) -> &str {
+        "restart the machine"
+    }
+
+    fn run(&self, _args: &[&str]) {
+        println!("rebooting...");
+        reboot();
+    }
+}
+
+// キーボードコントローラー、PCIのリセットレジスタの順に試し、だめならトリプルフォルトを起こす
+fn reboot() -> ! {
+    unsafe {
+        io::out8(KEYBOARD_CONTROLLER_COMMAND, KEYBOARD_CONTROLLER_RESET);
+        timer::sleep_ms(100);
+        io::out8(RESET_CONTROL, RESET_CONTROL_SYSTEM);
+        io::out8(RESET_CONTROL, RESET_CONTROL_SYSTEM | RESET_CONTROL_FULL);
+        timer::sleep_ms(100);
+        x86_64::instructions::tables::lidt(&DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) });
+        x86_64::instructions::interrupts::int3();
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,92 @@
+// Kernel stacks
+//
+// スタックは専用の領域に確保し、直下にマップしないガードページを置く。
+// 溢れるとガードページで#PFが起き、どのスタックが溢れたのか報告できる
+
+use alloc::string::String;
+use core::ops::Range;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+use x86_64::VirtAddr;
+
+use crate::memory_manager::Frame;
+use crate::vma::{self, Backing, Permissions, Vma};
+
+const KERNEL_STACK_REGION_START: u64 = 0x_4000_0000_0000;
+const GUARD_PAGES: usize = 1;
+
+lazy_static! {
+    static ref NEXT_STACK: Mutex<VirtAddr> = Mutex::new(VirtAddr::new(KERNEL_STACK_REGION_START));
+}
+
+pub struct KernelStack {
+    name: String,
+    range: Range<VirtAddr>,
+}
+
+impl KernelStack {
+    pub fn new(name: &str, number_of_pages: usize) -> Self {
+        let guard_start = {
+            let mut next = NEXT_STACK.lock();
+            let start = *next;
+            *next += ((GUARD_PAGES + number_of_pages) * Frame::SIZE) as u64;
+            start
+        };
+        let start = guard_start + (GUARD_PAGES * Frame::SIZE) as u64;
+        let end = start + (number_of_pages * Frame::SIZE) as u64;
+
+        let mut address_space = vma::kernel_address_space();
+        let address_space = address_space.as_mut().unwrap();
+        address_space
+            .insert(Vma::new(
+                guard_start..start,
+                Permissions::KERNEL_DATA,
+                Backing::Guard { task: name.into() },
+            ))
+            .unwrap();
+        address_space
+            .insert(Vma::new(start..end, Permissions::KERNEL_DATA, Backing::Anonymous))
+            .unwrap();
+        // スタック上で#PFを起こすと例外フレームを積めないので、最初から全部マップしておく
+        address_space.populate(start..end).unwrap();
+
+        Self {
+            name: name.into(),
+            range: start..end,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.range.end
+    }
+
+    // 二度と解放しないスタック(IST、kernel_mainなど)の先頭を返す
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let guard_start = self.range.start - (GUARD_PAGES * Frame::SIZE) as u64;
+        if let Some(address_space) = vma::kernel_address_space().as_mut() {
+            address_space.remove(guard_start..self.range.end).unwrap();
+        }
+    }
+}
+
+extern "C" {
+    fn SwitchStack(stack_top: u64, entry: *const (), arg: *const ()) -> !;
+}
+
+// 今のスタックを捨ててstack_topに切り替え、entry(arg)を呼ぶ。
+// 元のスタックには戻らないので、argはそこに置かれていないものを渡す
+pub fn switch_to<T>(stack_top: VirtAddr, entry: extern "sysv64" fn(&'static T) -> !, arg: &'static T) -> ! {
+    unsafe { SwitchStack(stack_top.as_u64(), entry as *const (), arg as *const T as *const ()) }
+}
@@ -0,0 +1,361 @@
+// System calls
+//
+// 呼び出し規約と番号はcommon::syscallにまとめてあり、ユーザープログラムと共有する
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::addr_of_mut;
+use common::syscall::*;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::elf;
+use crate::fs::{self, File, FsError, SeekFrom};
+use crate::gdt;
+use crate::memory_manager::Frame;
+use crate::paging::{self, USER_SPACE_END, USER_SPACE_START};
+use crate::process::{self, Process};
+use crate::timer;
+use crate::vma::{Backing, Permissions, Vma};
+
+// SyscallEntryがカーネルに入るときに使うスタック。ring 3に入る前にset_kernel_stackで設定する
+#[export_name = "syscall_kernel_stack"]
+static mut SYSCALL_KERNEL_STACK: u64 = 0;
+
+// mmapはユーザー空間の後ろ半分から探す。前半はプログラム本体、末尾はスタック
+const MMAP_START: u64 = USER_SPACE_START + (USER_SPACE_END - USER_SPACE_START) / 2;
+const MMAP_END: u64 = USER_SPACE_END - 0x1_0000_0000;
+
+const MAX_PATH_LEN: u64 = 4096;
+const MAX_ARGS_LEN: u64 = 4096;
+
+// 成功したら戻り値、失敗したらerrno
+type SyscallResult = Result<u64, u64>;
+type SyscallFn = fn(&[u64; 6]) -> SyscallResult;
+
+// common::syscallのSYS_*の順に並べる
+const SYSCALL_TABLE: [SyscallFn; SYSCALL_COUNT] = [
+    sys_exit,
+    sys_write,
+    sys_read,
+    sys_getpid,
+    sys_sleep,
+    sys_mmap,
+    sys_munmap,
+    sys_open,
+    sys_close,
+    sys_spawn,
+    sys_seek,
+    sys_readdir,
+    sys_stat,
+    sys_fstat,
+    sys_chdir,
+    sys_mkdir,
+    sys_unlink,
+    sys_rename,
+];
+
+// asmfunc.asmのSyscallEntryがスタックに積むレジスタ
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub number: u64, // rax
+    pub args: [u64; 6], // rdi, rsi, rdx, r10, r8, r9
+}
+
+extern "C" {
+    fn SyscallEntry();
+}
+
+pub fn init() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+    LStar::write(VirtAddr::new(SyscallEntry as usize as u64));
+    Star::write(
+        gdt::user_code_selector(),
+        gdt::user_data_selector(),
+        gdt::kernel_code_selector(),
+        gdt::kernel_data_selector(),
+    )
+    .unwrap();
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+}
+
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        *addr_of_mut!(SYSCALL_KERNEL_STACK) = stack_top.as_u64();
+    }
+}
+
+#[no_mangle]
+extern "sysv64" fn syscall_handler(frame: &SyscallFrame) -> i64 {
+    let result = match SYSCALL_TABLE.get(frame.number as usize) {
+        Some(syscall) => syscall(&frame.args),
+        None => Err(ENOSYS),
+    };
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => -(errno as i64),
+    }
+}
+
+impl From<FsError> for u64 {
+    fn from(e: FsError) -> Self {
+        e.errno()
+    }
+}
+
+fn current() -> Arc<Process> {
+    process::current().expect("system call outside of a process")
+}
+
+// [addr, addr + len)がユーザー空間にあり、呼び出し元のVmaで覆われていることを確かめる。
+// ページはまだ無くてもよい(触れたときに#PFで用意される)
+fn check_user_range(addr: u64, len: u64, write: bool) -> Result<(), u64> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(EFAULT)?;
+    let start = VirtAddr::try_new(addr).map_err(|_| EFAULT)?;
+    if !paging::is_user_address(start) || end > USER_SPACE_END {
+        return Err(EFAULT);
+    }
+
+    let process = current();
+    let address_space = process.address_space();
+    let mut cursor = start;
+    while cursor.as_u64() < end {
+        let vma = address_space.find(cursor).ok_or(EFAULT)?;
+        let accessible = vma.permissions.user
+            && (!write || vma.permissions.write)
+            && vma.backing == Backing::Anonymous;
+        if !accessible {
+            return Err(EFAULT);
+        }
+        cursor = vma.range.end;
+    }
+    Ok(())
+}
+
+fn user_slice<'a>(addr: u64, len: u64) -> Result<&'a [u8], u64> {
+    check_user_range(addr, len, false)?;
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+fn user_slice_mut<'a>(addr: u64, len: u64) -> Result<&'a mut [u8], u64> {
+    check_user_range(addr, len, true)?;
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
+}
+
+fn user_str<'a>(addr: u64, len: u64, max_len: u64) -> Result<&'a str, u64> {
+    if len > max_len {
+        return Err(ENAMETOOLONG);
+    }
+    core::str::from_utf8(user_slice(addr, len)?).map_err(|_| EINVAL)
+}
+
+fn open_file(fd: u64) -> Result<Arc<dyn File>, u64> {
+    Ok(current().files().get(fd as usize)?)
+}
+
+// 呼び出し元のcwdを基準にした絶対パス
+fn user_path(addr: u64, len: u64) -> Result<String, u64> {
+    let path = user_str(addr, len, MAX_PATH_LEN)?;
+    if path.is_empty() {
+        return Err(ENOENT);
+    }
+    Ok(fs::path::absolute(&current().cwd(), path))
+}
+
+fn write_user<T: Copy>(addr: u64, value: &T) -> Result<(), u64> {
+    let size = core::mem::size_of::<T>() as u64;
+    let buf = user_slice_mut(addr, size)?;
+    let bytes = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size as usize) };
+    buf.copy_from_slice(bytes);
+    Ok(())
+}
+
+fn sys_exit(args: &[u64; 6]) -> SyscallResult {
+    process::exit(args[0] as i32)
+}
+
+fn sys_write(args: &[u64; 6]) -> SyscallResult {
+    let file = open_file(args[0])?;
+    let buf = user_slice(args[1], args[2])?;
+    Ok(file.write(buf)? as u64)
+}
+
+fn sys_read(args: &[u64; 6]) -> SyscallResult {
+    let file = open_file(args[0])?;
+    let buf = user_slice_mut(args[1], args[2])?;
+    Ok(file.read(buf)? as u64)
+}
+
+fn sys_getpid(_args: &[u64; 6]) -> SyscallResult {
+    Ok(current().pid())
+}
+
+fn sys_sleep(args: &[u64; 6]) -> SyscallResult {
+    timer::sleep_ms(args[0]);
+    Ok(0)
+}
+
+fn sys_mmap(args: &[u64; 6]) -> SyscallResult {
+    let (hint, len, prot, flags) = (args[0], args[1], args[2], args[3]);
+    if len == 0 || flags & MAP_ANONYMOUS == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(EINVAL);
+    }
+    let len = len.checked_next_multiple_of(Frame::SIZE as u64).ok_or(ENOMEM)?;
+    let permissions = Permissions {
+        write: prot & PROT_WRITE != 0,
+        execute: prot & PROT_EXEC != 0,
+        user: true,
+    };
+
+    let process = current();
+    let mut address_space = process.address_space();
+    let mmap_area = VirtAddr::new(MMAP_START)..VirtAddr::new(MMAP_END);
+    // ヒントの場所が空いていればそこを使う
+    let start = VirtAddr::try_new(hint)
+        .ok()
+        .filter(|start| hint != 0 && start.is_aligned(Frame::SIZE as u64) && paging::is_user_address(*start))
+        .filter(|_| hint.checked_add(len).is_some_and(|end| end <= USER_SPACE_END))
+        .and_then(|start| address_space.find_free(start..start + len, len))
+        .or_else(|| address_space.find_free(mmap_area, len))
+        .ok_or(ENOMEM)?;
+    address_space
+        .insert(Vma::new(start..start + len, permissions, Backing::Anonymous))
+        .map_err(|_| ENOMEM)?;
+    Ok(start.as_u64())
+}
+
+fn sys_munmap(args: &[u64; 6]) -> SyscallResult {
+    let (addr, len) = (args[0], args[1]);
+    let start = VirtAddr::try_new(addr).map_err(|_| EINVAL)?;
+    let len = len.checked_next_multiple_of(Frame::SIZE as u64).ok_or(EINVAL)?;
+    let end = addr.checked_add(len).ok_or(EINVAL)?;
+    if !paging::is_user_address(start) || end > USER_SPACE_END {
+        return Err(EINVAL);
+    }
+    current()
+        .address_space()
+        .remove(start..VirtAddr::new(end))
+        .map_err(|_| EINVAL)?;
+    Ok(0)
+}
+
+fn sys_open(args: &[u64; 6]) -> SyscallResult {
+    let path = user_path(args[0], args[1])?;
+    let file = fs::open(&path, args[2])?;
+    Ok(current().files().insert(file)? as u64)
+}
+
+fn sys_close(args: &[u64; 6]) -> SyscallResult {
+    current().files().close(args[0] as usize)?;
+    Ok(0)
+}
+
+// 子プロセスは終了するまで走らせ、その終了コードを返す
+fn sys_spawn(args: &[u64; 6]) -> SyscallResult {
+    let path = user_str(args[0], args[1], MAX_PATH_LEN)?;
+    let args = user_str(args[2], args[3], MAX_ARGS_LEN)?;
+    let parent = current();
+    let image = fs::read_to_end(&fs::path::absolute(&parent.cwd(), path))?;
+
+    let mut argv: Vec<&str> = Vec::new();
+    argv.push(path);
+    argv.extend(args.split('\0').filter(|arg| !arg.is_empty()));
+
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let child = Process::new(name).map_err(|_| ENOMEM)?;
+    // ファイルディスクリプタとcwdは親から引き継ぐ
+    *child.files() = parent.files().clone();
+    *child.cwd() = parent.cwd().clone();
+    let loaded = elf::load(&child, &image, &argv, &[]).map_err(|e| match e {
+        elf::ElfError::OutOfMemory => ENOMEM,
+        _ => ENOEXEC,
+    })?;
+    drop(image);
+    let code = child.run(loaded.entry, loaded.stack_pointer, loaded.argc, loaded.argv);
+    Ok(code as u8 as u64)
+}
+
+fn sys_seek(args: &[u64; 6]) -> SyscallResult {
+    let file = open_file(args[0])?;
+    let offset = args[1] as i64;
+    let pos = match args[2] {
+        SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(EINVAL),
+    };
+    Ok(file.seek(pos)?)
+}
+
+fn sys_readdir(args: &[u64; 6]) -> SyscallResult {
+    let file = open_file(args[0])?;
+    check_user_range(args[1], core::mem::size_of::<Dirent>() as u64, true)?;
+    let Some(entry) = file.readdir()? else {
+        return Ok(0);
+    };
+    if entry.name.len() > NAME_MAX {
+        return Err(ENAMETOOLONG);
+    }
+    let mut dirent = Dirent {
+        ino: entry.inode,
+        kind: entry.file_type.dirent_kind(),
+        name_len: entry.name.len() as u32,
+        name: [0; NAME_MAX + 1],
+    };
+    dirent.name[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+    write_user(args[1], &dirent)?;
+    Ok(1)
+}
+
+fn sys_stat(args: &[u64; 6]) -> SyscallResult {
+    let path = user_path(args[0], args[1])?;
+    let stat = fs::stat(&path)?.to_stat();
+    write_user(args[2], &stat)?;
+    Ok(0)
+}
+
+fn sys_fstat(args: &[u64; 6]) -> SyscallResult {
+    let stat = open_file(args[0])?.stat()?.to_stat();
+    write_user(args[1], &stat)?;
+    Ok(0)
+}
+
+fn sys_chdir(args: &[u64; 6]) -> SyscallResult {
+    let path = user_path(args[0], args[1])?;
+    if fs::stat(&path)?.file_type != fs::FileType::Directory {
+        return Err(ENOTDIR);
+    }
+    *current().cwd() = fs::path::normalize(&path);
+    Ok(0)
+}
+
+fn sys_mkdir(args: &[u64; 6]) -> SyscallResult {
+    fs::mkdir(&user_path(args[0], args[1])?)?;
+    Ok(0)
+}
+
+fn sys_unlink(args: &[u64; 6]) -> SyscallResult {
+    fs::unlink(&user_path(args[0], args[1])?)?;
+    Ok(0)
+}
+
+fn sys_rename(args: &[u64; 6]) -> SyscallResult {
+    let old_path = user_path(args[0], args[1])?;
+    let new_path = user_path(args[2], args[3])?;
+    fs::rename(&old_path, &new_path)?;
+    Ok(0)
+}
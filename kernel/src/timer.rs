@@ -0,0 +1,67 @@
+// Timer
+//
+// 起動時にPITのチャンネル2でTSCの周波数を測っておき、以降はTSCで時間を測る
+
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::io;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_CHANNEL2_GATE: u16 = 0x61;
+const CALIBRATION_MS: u64 = 10;
+
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+    let elapsed = unsafe {
+        // スピーカーには出さずにゲートだけ開ける
+        let gate = io::in8(PIT_CHANNEL2_GATE) & !0x02;
+        io::out8(PIT_CHANNEL2_GATE, gate & !0x01);
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        io::out8(PIT_COMMAND, 0b1011_0000);
+        io::out8(PIT_CHANNEL2_DATA, count as u8);
+        io::out8(PIT_CHANNEL2_DATA, (count >> 8) as u8);
+
+        io::out8(PIT_CHANNEL2_GATE, gate | 0x01);
+        let start = _rdtsc();
+        while io::in8(PIT_CHANNEL2_GATE) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        _rdtsc() - start
+    };
+
+    TSC_PER_MS.store((elapsed / CALIBRATION_MS).max(1), Ordering::Relaxed);
+    BOOT_TSC.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+}
+
+pub fn uptime_ms() -> u64 {
+    let tsc_per_ms = TSC_PER_MS.load(Ordering::Relaxed);
+    if tsc_per_ms == 0 {
+        return 0;
+    }
+    (unsafe { _rdtsc() } - BOOT_TSC.load(Ordering::Relaxed)) / tsc_per_ms
+}
+
+pub fn sleep_ms(ms: u64) {
+    let deadline = uptime_ms() + ms;
+    while uptime_ms() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+// conditionが成り立つまで待つ。timeout_ms経っても成り立たなければfalse
+pub fn wait_until(timeout_ms: u64, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = uptime_ms() + timeout_ms;
+    while !condition() {
+        if uptime_ms() >= deadline {
+            return condition();
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
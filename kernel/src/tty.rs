@@ -0,0 +1,430 @@
+// TTY
+//
+// 入力(キーボードとシリアルポート)と出力(コンソール)をまとめたもの。
+// カノニカルモードでは行を編集してから1行ずつ渡し、rawモードでは届いたバイトをそのまま渡す。
+// カノニカルモードでは↑↓で前に渡した行を呼び出せる。
+// カーネルのシェルも/dev/consoleを読むプログラムも、ここから入力を受け取る
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+use crate::keyboard::{self, KeyCode, KeyEvent};
+use crate::{apic, console, ps2, serial};
+
+// 編集中の行と、読まれるのを待っている入力の長さの上限。あふれた分は捨てる
+const MAX_LINE_LEN: usize = 1024;
+const MAX_PENDING_LEN: usize = 4096;
+const MAX_HISTORY: usize = 32;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const ESCAPE: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TtyError {
+    // Ctrl-Cで読むのをやめた
+    Interrupted,
+    // 読めるものが無く、待たなかった
+    WouldBlock,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Termios {
+    // 行を編集し、改行かCtrl-Dが来てから渡す
+    pub canonical: bool,
+    pub echo: bool,
+    // Ctrl-Cで読んでいる途中の入力を捨て、readをInterruptedで終わらせる
+    pub signals: bool,
+}
+
+impl Termios {
+    pub const CANONICAL: Self = Self { canonical: true, echo: true, signals: true };
+}
+
+pub trait TtyOutput: Send + Sync {
+    fn write(&self, bytes: &[u8]);
+}
+
+// 矢印キーはESC [ Aのようなエスケープシーケンスで届く
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+struct State {
+    termios: Termios,
+    // カノニカルモードで編集中の行
+    line: Vec<u8>,
+    // カノニカルモードで渡した行。改行は含まない
+    history: VecDeque<Vec<u8>>,
+    // 遡っている履歴の位置。Noneなら新しい行を打っている
+    history_index: Option<usize>,
+    // 履歴を遡る前に打っていた行
+    draft: Vec<u8>,
+    escape: Escape,
+    // 読まれるのを待っている入力。カノニカルモードでは1つが1行で、空ならCtrl-DでのEOF
+    pending: VecDeque<Vec<u8>>,
+    interrupted: bool,
+    // CR LFで送ってくる端末で、改行を二回にしないようにする
+    after_cr: bool,
+}
+
+impl State {
+    fn pending_len(&self) -> usize {
+        self.pending.iter().map(Vec::len).sum()
+    }
+
+    fn push_pending(&mut self, bytes: Vec<u8>) {
+        if self.pending_len() + bytes.len() <= MAX_PENDING_LEN {
+            self.pending.push_back(bytes);
+        }
+    }
+
+    fn push_history(&mut self, line: &[u8]) {
+        self.history_index = None;
+        if line.iter().all(|&b| b == b' ') || self.history.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.history.len() >= MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(line.to_vec());
+    }
+}
+
+pub struct Tty {
+    output: Box<dyn TtyOutput>,
+    state: Mutex<State>,
+}
+
+impl Tty {
+    pub fn new(output: Box<dyn TtyOutput>) -> Self {
+        Self {
+            output,
+            state: Mutex::new(State {
+                termios: Termios::CANONICAL,
+                line: Vec::new(),
+                history: VecDeque::new(),
+                history_index: None,
+                draft: Vec::new(),
+                escape: Escape::None,
+                pending: VecDeque::new(),
+                interrupted: false,
+                after_cr: false,
+            }),
+        }
+    }
+
+    pub fn termios(&self) -> Termios {
+        self.state.lock().termios
+    }
+
+    // rawモードに切り替えるときは、編集中の行をそのまま読めるようにする
+    pub fn set_termios(&self, termios: Termios) {
+        let mut state = self.state.lock();
+        if state.termios.canonical && !termios.canonical && !state.line.is_empty() {
+            let line = core::mem::take(&mut state.line);
+            state.push_pending(line);
+        }
+        state.termios = termios;
+    }
+
+    pub fn write(&self, bytes: &[u8]) {
+        self.output.write(bytes);
+    }
+
+    fn echo(&self, state: &State, bytes: &[u8]) {
+        if state.termios.echo {
+            self.output.write(bytes);
+        }
+    }
+
+    // 打ってある文字を消して書き直す
+    fn replace_line(&self, state: &mut State, line: Vec<u8>) {
+        self.echo(state, &b"\x08 \x08".repeat(char_count(&state.line)));
+        self.echo(state, &line);
+        state.line = line;
+    }
+
+    fn history_previous(&self, state: &mut State) {
+        let index = match state.history_index {
+            None if state.history.is_empty() => return,
+            None => {
+                state.draft = state.line.clone();
+                state.history.len() - 1
+            }
+            Some(0) => return,
+            Some(index) => index - 1,
+        };
+        state.history_index = Some(index);
+        let line = state.history[index].clone();
+        self.replace_line(state, line);
+    }
+
+    fn history_next(&self, state: &mut State) {
+        let Some(index) = state.history_index else {
+            return;
+        };
+        let line = if index + 1 < state.history.len() {
+            state.history_index = Some(index + 1);
+            state.history[index + 1].clone()
+        } else {
+            state.history_index = None;
+            core::mem::take(&mut state.draft)
+        };
+        self.replace_line(state, line);
+    }
+
+    // キーボードやシリアルポートから届いた1バイトを渡す
+    pub fn input(&self, byte: u8) {
+        let mut state = self.state.lock();
+        let after_cr = core::mem::replace(&mut state.after_cr, byte == b'\r');
+        if state.termios.signals && byte == CTRL_C {
+            state.line.clear();
+            state.pending.clear();
+            state.history_index = None;
+            state.escape = Escape::None;
+            state.interrupted = true;
+            return self.echo(&state, b"^C\n");
+        }
+        if !state.termios.canonical {
+            self.echo(&state, &[byte]);
+            match state.pending.back_mut() {
+                Some(last) if !last.is_empty() && last.len() < MAX_LINE_LEN => last.push(byte),
+                _ => state.push_pending(vec![byte]),
+            }
+            return;
+        }
+
+        // シーケンスにならないバイトが来たら、ESCだけを捨ててそのバイトは普通に扱う
+        match state.escape {
+            Escape::Esc => {
+                state.escape = Escape::None;
+                if byte == b'[' {
+                    state.escape = Escape::Csi;
+                    return;
+                }
+            }
+            Escape::Csi => match byte {
+                // 数字や;の引数は読み飛ばし、最後の文字で判断する
+                0x20..=0x3f => return,
+                0x40..=0x7e => {
+                    state.escape = Escape::None;
+                    match byte {
+                        b'A' => self.history_previous(&mut state),
+                        b'B' => self.history_next(&mut state),
+                        _ => {}
+                    }
+                    return;
+                }
+                _ => state.escape = Escape::None,
+            },
+            Escape::None => {}
+        }
+
+        match byte {
+            b'\n' if after_cr => {}
+            b'\r' | b'\n' => {
+                self.echo(&state, b"\n");
+                let mut line = core::mem::take(&mut state.line);
+                state.push_history(&line);
+                line.push(b'\n');
+                state.push_pending(line);
+            }
+            ESCAPE => state.escape = Escape::Esc,
+            // 行の途中なら、そこまでを改行無しで渡す。空の行ならEOFになる
+            CTRL_D => {
+                let line = core::mem::take(&mut state.line);
+                state.push_pending(line);
+            }
+            // UTF-8の文字は途中で切らず、1文字ずつ消す
+            BACKSPACE | DELETE => {
+                if !state.line.is_empty() {
+                    let start = state.line.iter().rposition(|&b| !is_continuation(b)).unwrap_or(0);
+                    state.line.truncate(start);
+                    self.echo(&state, b"\x08 \x08");
+                }
+            }
+            CTRL_U => {
+                let len = char_count(&state.line);
+                state.line.clear();
+                self.echo(&state, &b"\x08 \x08".repeat(len));
+            }
+            CTRL_W => {
+                let trimmed = state.line.iter().rposition(|&b| b != b' ').map_or(0, |end| end + 1);
+                let keep = state.line[..trimmed].iter().rposition(|&b| b == b' ').map_or(0, |space| space + 1);
+                let len = char_count(&state.line[keep..]);
+                state.line.truncate(keep);
+                self.echo(&state, &b"\x08 \x08".repeat(len));
+            }
+            _ if state.line.len() < MAX_LINE_LEN => {
+                state.line.push(byte);
+                self.echo(&state, &[byte]);
+            }
+            _ => {}
+        }
+    }
+
+    // カノニカルモードでは1行までしか返さない。EOFなら0
+    fn try_read(&self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        let mut state = self.state.lock();
+        if core::mem::take(&mut state.interrupted) {
+            return Err(TtyError::Interrupted);
+        }
+        let canonical = state.termios.canonical;
+        let mut read = 0;
+        while let Some(front) = state.pending.front_mut() {
+            let len = front.len().min(buf.len() - read);
+            buf[read..read + len].copy_from_slice(&front[..len]);
+            front.drain(..len);
+            read += len;
+            let consumed = front.is_empty();
+            // EOFの印も、読み切った行も取り除く
+            if consumed {
+                state.pending.pop_front();
+            }
+            if canonical || read == buf.len() || !consumed {
+                return Ok(read);
+            }
+        }
+        if read == 0 {
+            return Err(TtyError::WouldBlock);
+        }
+        Ok(read)
+    }
+
+    // blockingなら何か届くまで待つ
+    pub fn read(&self, buf: &mut [u8], blocking: bool) -> Result<usize, TtyError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // 誰も待っていない間のCtrl-Cは、これから待つreadを終わらせない
+        if blocking {
+            self.state.lock().interrupted = false;
+        }
+        loop {
+            match self.try_read(buf) {
+                Err(TtyError::WouldBlock) if blocking => {}
+                result => return result,
+            }
+            // キーボードの割り込みはhltで待っている間に受ける
+            poll();
+            if apic::ticking() {
+                x86_64::instructions::interrupts::enable_and_hlt();
+                x86_64::instructions::interrupts::disable();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+// UTF-8で2バイト目以降のバイト
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+// コンソールでは1文字が1マスなので、消すときはバイトではなく文字の数だけ戻る
+fn char_count(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| !is_continuation(b)).count()
+}
+
+struct ConsoleOutput {
+    // 1バイトずつエコーされる文字のために、UTF-8の途中で切れた分を次まで取っておく
+    partial: Mutex<Vec<u8>>,
+}
+
+impl TtyOutput for ConsoleOutput {
+    fn write(&self, bytes: &[u8]) {
+        let mut partial = self.partial.lock();
+        partial.extend_from_slice(bytes);
+        let mut text = String::new();
+        let mut rest = &partial[..];
+        loop {
+            match core::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    text.push_str(core::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        // 末尾で切れている
+                        None => {
+                            rest = invalid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        *partial = rest.to_vec();
+        console::_printk(format_args!("{}", text));
+    }
+}
+
+lazy_static! {
+    static ref CONSOLE: Arc<Tty> = Arc::new(Tty::new(Box::new(ConsoleOutput { partial: Mutex::new(Vec::new()) })));
+}
+
+// フレームバッファのコンソールとCOM1に繋がったTTY
+pub fn console() -> Arc<Tty> {
+    CONSOLE.clone()
+}
+
+// シリアルポートは割り込みを使っていないので読みに行く。
+// キーボードは割り込みで積まれたイベントを取り出し、端末から届くのと同じバイト列にする
+pub fn poll() {
+    ps2::poll();
+    let tty = console();
+    while let Some(byte) = serial::try_receive() {
+        tty.input(byte);
+    }
+    while let Some(event) = keyboard::next_event() {
+        // Shift+PageUp/PageDownはコンソールのscrollbackを見るためのもので、読む側には渡さない
+        match event.key {
+            KeyCode::PageUp if event.pressed && event.modifiers.shift() => console::page_up(),
+            KeyCode::PageDown if event.pressed && event.modifiers.shift() => console::page_down(),
+            _ => {
+                for byte in key_bytes(&event) {
+                    tty.input(byte);
+                }
+            }
+        }
+    }
+}
+
+fn key_bytes(event: &KeyEvent) -> Vec<u8> {
+    if !event.pressed {
+        return Vec::new();
+    }
+    match (event.key, event.char) {
+        (KeyCode::Up, _) => b"\x1b[A".to_vec(),
+        (KeyCode::Down, _) => b"\x1b[B".to_vec(),
+        (KeyCode::Right, _) => b"\x1b[C".to_vec(),
+        (KeyCode::Left, _) => b"\x1b[D".to_vec(),
+        // Ctrl-AからCtrl-Zは0x01から0x1a
+        (_, Some(c)) if event.modifiers.ctrl() && c.is_ascii_alphabetic() => {
+            vec![c.to_ascii_lowercase() as u8 - b'a' + 1]
+        }
+        (_, Some(c)) if c.is_ascii() => vec![c as u8],
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,311 @@
+// Virtio over PCI
+//
+// modern(virtio 1.0)のPCIトランスポート。レジスタの場所はPCIのvendor specific capabilityから探す。
+// virtqueueはsplit形式で、frame managerから確保した連続したページに置く。割り込みはまだ使わない
+
+pub mod blk;
+pub mod net;
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use x86_64::PhysAddr;
+
+use crate::dma::DmaBuffer;
+use crate::error::OsError;
+use crate::paging;
+use crate::pci::{self, Bar, BusDeviceFunction};
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// common configurationのレジスタ
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+// ドライバが使う分にはこれで足りる
+const MAX_QUEUE_SIZE: u16 = 64;
+
+// レジスタの並びを仮想アドレスで持つ
+#[derive(Clone, Copy)]
+pub struct Mmio(usize);
+
+impl Mmio {
+    pub fn read<T: Copy>(self, offset: usize) -> T {
+        unsafe { read_volatile((self.0 + offset) as *const T) }
+    }
+
+    pub fn write<T: Copy>(self, offset: usize, value: T) {
+        unsafe { write_volatile((self.0 + offset) as *mut T, value) }
+    }
+}
+
+pub struct VirtioPci {
+    common: Mmio,
+    notify: Mmio,
+    notify_off_multiplier: u32,
+    device: Mmio,
+}
+
+impl VirtioPci {
+    pub fn new(bdf: BusDeviceFunction) -> Result<Self, OsError> {
+        let pci = pci::pci();
+        pci.enable_bus_master(bdf)?;
+
+        let (mut common, mut notify, mut device) = (None, None, None);
+        let mut notify_off_multiplier = 0;
+        for (id, offset) in pci.capabilities(bdf)? {
+            if id != PCI_CAP_ID_VENDOR {
+                continue;
+            }
+            let cfg_type = pci.read_register_u8(bdf, offset + 3)?;
+            let bar = pci.read_register_u8(bdf, offset + 4)?;
+            let bar_offset = pci.read_register_u32(bdf, offset + 8)? as u64;
+            // 同じ種類が複数あるときは最初のものを使う
+            let region = || -> Result<Mmio, OsError> {
+                let Bar::Memory(base) = pci.read_bar(bdf, bar as usize)? else {
+                    return Err(OsError::NotSupported);
+                };
+                let virt = paging::as_virt_addr(PhysAddr::new(base + bar_offset)).ok_or(OsError::NotSupported)?;
+                Ok(Mmio(virt.as_u64() as usize))
+            };
+            match cfg_type {
+                CAP_COMMON_CFG if common.is_none() => common = Some(region()?),
+                CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = Some(region()?);
+                    notify_off_multiplier = pci.read_register_u32(bdf, offset + 16)?;
+                }
+                CAP_DEVICE_CFG if device.is_none() => device = Some(region()?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            common: common.ok_or(OsError::NotSupported)?,
+            notify: notify.ok_or(OsError::NotSupported)?,
+            notify_off_multiplier,
+            device: device.ok_or(OsError::NotSupported)?,
+        })
+    }
+
+    // デバイスをリセットし、supportedのうちデバイスも対応している機能を有効にする
+    pub fn negotiate(&self, supported: u64) -> Result<u64, OsError> {
+        self.common.write::<u8>(COMMON_DEVICE_STATUS, 0);
+        while self.common.read::<u8>(COMMON_DEVICE_STATUS) != 0 {
+            core::hint::spin_loop();
+        }
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let mut device_features = 0u64;
+        for select in 0..2u32 {
+            self.common.write(COMMON_DEVICE_FEATURE_SELECT, select);
+            device_features |= (self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64) << (32 * select);
+        }
+        let features = device_features & (supported | VIRTIO_F_VERSION_1);
+        if features & VIRTIO_F_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(OsError::NotSupported);
+        }
+        for select in 0..2u32 {
+            self.common.write(COMMON_DRIVER_FEATURE_SELECT, select);
+            self.common.write(COMMON_DRIVER_FEATURE, (features >> (32 * select)) as u32);
+        }
+
+        self.add_status(STATUS_FEATURES_OK);
+        if self.common.read::<u8>(COMMON_DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(OsError::NotSupported);
+        }
+        Ok(features)
+    }
+
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, OsError> {
+        self.common.write(COMMON_QUEUE_SELECT, index);
+        let size = self.common.read::<u16>(COMMON_QUEUE_SIZE).min(MAX_QUEUE_SIZE);
+        if size == 0 {
+            return Err(OsError::DeviceNotFound);
+        }
+        self.common.write(COMMON_QUEUE_SIZE, size);
+
+        let queue = Virtqueue::new(size)?;
+        self.common.write(COMMON_QUEUE_DESC, queue.desc_addr());
+        self.common.write(COMMON_QUEUE_DRIVER, queue.avail_addr());
+        self.common.write(COMMON_QUEUE_DEVICE, queue.used_addr());
+        let notify_off = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF) as usize;
+        let notify = Mmio(self.notify.0 + notify_off * self.notify_off_multiplier as usize);
+        self.common.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+        Ok(Virtqueue { index, notify, ..queue })
+    }
+
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    pub fn device_config(&self) -> Mmio {
+        self.device
+    }
+
+    fn add_status(&self, status: u8) {
+        let current = self.common.read::<u8>(COMMON_DEVICE_STATUS);
+        self.common.write(COMMON_DEVICE_STATUS, current | status);
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+// デバイスに渡すバッファ。writableならデバイスが書き込む
+#[derive(Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub writable: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    notify: Mmio,
+    free: Vec<u16>,
+    avail_index: u16,
+    last_used_index: u16,
+}
+
+impl Virtqueue {
+    fn new(size: u16) -> Result<Self, OsError> {
+        let n = size as usize;
+        let avail_offset = 16 * n;
+        let used_offset = (avail_offset + 6 + 2 * n).next_multiple_of(4);
+        let memory = DmaBuffer::new(used_offset + 6 + 8 * n)?;
+        Ok(Self {
+            index: 0,
+            size,
+            memory,
+            avail_offset,
+            used_offset,
+            notify: Mmio(0),
+            free: (0..size).rev().collect(),
+            avail_index: 0,
+            last_used_index: 0,
+        })
+    }
+
+    fn desc_addr(&self) -> u64 {
+        self.memory.phys_addr().as_u64()
+    }
+
+    fn avail_addr(&self) -> u64 {
+        self.desc_addr() + self.avail_offset as u64
+    }
+
+    fn used_addr(&self) -> u64 {
+        self.desc_addr() + self.used_offset as u64
+    }
+
+    fn ring(&self) -> Mmio {
+        Mmio(self.memory.as_mut_ptr::<u8>() as usize)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    // buffersを1つのチェーンにしてavailable ringに入れ、先頭の番号を返す。空きが無ければNone
+    pub fn push(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let ids: Vec<u16> = (0..buffers.len()).map(|_| self.free.pop().unwrap()).collect();
+        let ring = self.ring();
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut flags = 0;
+            if buffer.writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            let next = ids.get(i + 1).copied();
+            if next.is_some() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let descriptor = Descriptor {
+                addr: buffer.addr.as_u64(),
+                len: buffer.len,
+                flags,
+                next: next.unwrap_or(0),
+            };
+            ring.write(16 * ids[i] as usize, descriptor);
+        }
+
+        let slot = self.avail_offset + 4 + 2 * (self.avail_index % self.size) as usize;
+        ring.write(slot, ids[0]);
+        fence(Ordering::SeqCst);
+        self.avail_index = self.avail_index.wrapping_add(1);
+        ring.write(self.avail_offset + 2, self.avail_index);
+        fence(Ordering::SeqCst);
+        Some(ids[0])
+    }
+
+    pub fn notify(&self) {
+        self.notify.write(0, self.index);
+    }
+
+    // デバイスが使い終わったチェーンを1つ取り出す。(先頭の番号, 書き込まれたバイト数)
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let ring = self.ring();
+        fence(Ordering::SeqCst);
+        let used_index: u16 = ring.read(self.used_offset + 2);
+        if used_index == self.last_used_index {
+            return None;
+        }
+        let slot = self.used_offset + 4 + 8 * (self.last_used_index % self.size) as usize;
+        let head = ring.read::<u32>(slot) as u16;
+        let len = ring.read::<u32>(slot + 4);
+        self.last_used_index = self.last_used_index.wrapping_add(1);
+
+        // チェーンの記述子を空きに戻す
+        let mut id = head;
+        loop {
+            let descriptor: Descriptor = ring.read(16 * id as usize);
+            self.free.push(id);
+            if descriptor.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            id = descriptor.next;
+        }
+        Some((head, len))
+    }
+}
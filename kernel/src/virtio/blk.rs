@@ -0,0 +1,203 @@
+// virtio-blk
+//
+// 1つのリクエストを header / データ / status の3つの記述子で送り、完了をポーリングで待つ
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::mutex::Mutex;
+use x86_64::PhysAddr;
+
+use super::{Buffer, VirtioPci, Virtqueue, VIRTIO_VENDOR_ID};
+use crate::block::{self, BlockDevice, BlockError};
+use crate::dma::DmaBuffer;
+use crate::error::OsError;
+use crate::pci::{BusDeviceFunction, PciDevice, PciDeviceDriver, PciDeviceDriverInstance};
+use crate::timer;
+
+const SUPPORTED_DEVICES: &[u16] = &[
+    0x1001, // transitional
+    0x1042,
+];
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// device configuration
+const CONFIG_CAPACITY: usize = 0x00;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+// capacityは常に512バイト単位
+const SECTOR_SIZE: usize = 512;
+const HEADER_SIZE: usize = 16;
+const STATUS_OFFSET: usize = HEADER_SIZE;
+const BOUNCE_BUFFER_SIZE: usize = 64 * 1024;
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+pub struct VirtioBlkDriver;
+
+impl PciDeviceDriver for VirtioBlkDriver {
+    fn supports(&self, device: &PciDevice) -> bool {
+        device.vd.vendor == VIRTIO_VENDOR_ID && SUPPORTED_DEVICES.contains(&device.vd.device)
+    }
+
+    fn attach(&self, bdf: BusDeviceFunction) -> Result<Box<dyn PciDeviceDriverInstance>, OsError> {
+        let transport = VirtioPci::new(bdf)?;
+        let features = transport.negotiate(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH)?;
+        let queue = transport.setup_queue(0)?;
+        let block_count = transport.device_config().read::<u64>(CONFIG_CAPACITY);
+        transport.driver_ok();
+
+        let disk = Arc::new(VirtioBlk {
+            name: block::disk_name("vd", NEXT_DISK.fetch_add(1, Ordering::Relaxed)),
+            block_count,
+            read_only: features & VIRTIO_BLK_F_RO != 0,
+            flush: features & VIRTIO_BLK_F_FLUSH != 0,
+            request: Mutex::new(Request {
+                queue,
+                header: DmaBuffer::new(HEADER_SIZE + 1)?,
+                bounce: DmaBuffer::new(BOUNCE_BUFFER_SIZE)?,
+                failed: false,
+            }),
+        });
+        block::register(disk.clone());
+        Ok(Box::new(VirtioBlkInstance { bdf, _disk: disk }))
+    }
+
+    fn name(&self) -> &str {
+        "virtio-blk"
+    }
+}
+
+pub struct VirtioBlkInstance {
+    bdf: BusDeviceFunction,
+    _disk: Arc<VirtioBlk>,
+}
+
+impl PciDeviceDriverInstance for VirtioBlkInstance {
+    fn name(&self) -> &str {
+        "virtio-blk"
+    }
+
+    fn bdf(&self) -> BusDeviceFunction {
+        self.bdf
+    }
+}
+
+pub struct VirtioBlk {
+    name: String,
+    block_count: u64,
+    read_only: bool,
+    flush: bool,
+    request: Mutex<Request>,
+}
+
+struct Request {
+    queue: Virtqueue,
+    header: DmaBuffer,
+    bounce: DmaBuffer,
+    // タイムアウトしたリクエストの記述子とバッファは、まだデバイスが使っているかもしれない。
+    // 遅れて完了すると次のリクエストを壊すので、それ以降は何も送らない
+    failed: bool,
+}
+
+impl Request {
+    // dataの長さ分だけbounce bufferを使う
+    fn submit(&mut self, request_type: u32, sector: u64, len: usize) -> Result<(), BlockError> {
+        if self.failed {
+            return Err(BlockError::DeviceError);
+        }
+        let header = self.header.as_mut_slice();
+        header[0..4].copy_from_slice(&request_type.to_le_bytes());
+        header[4..8].fill(0);
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        header[STATUS_OFFSET] = 0xff;
+
+        let header_addr = self.header.phys_addr();
+        let mut buffers = [Buffer { addr: header_addr, len: HEADER_SIZE as u32, writable: false }; 3];
+        let mut count = 1;
+        if len > 0 {
+            buffers[count] = Buffer {
+                addr: self.bounce.phys_addr(),
+                len: len as u32,
+                writable: request_type == VIRTIO_BLK_T_IN,
+            };
+            count += 1;
+        }
+        buffers[count] = Buffer {
+            addr: PhysAddr::new(header_addr.as_u64() + STATUS_OFFSET as u64),
+            len: 1,
+            writable: true,
+        };
+        count += 1;
+
+        let head = self.queue.push(&buffers[..count]).ok_or(BlockError::DeviceError)?;
+        self.queue.notify();
+        let queue = &mut self.queue;
+        if !timer::wait_until(REQUEST_TIMEOUT_MS, || matches!(queue.pop_used(), Some((id, _)) if id == head)) {
+            self.failed = true;
+            return Err(BlockError::Timeout);
+        }
+        match self.header.as_slice()[STATUS_OFFSET] {
+            VIRTIO_BLK_S_OK => Ok(()),
+            _ => Err(BlockError::DeviceError),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(lba, buf.len())?;
+        let mut request = self.request.lock();
+        for (i, chunk) in buf.chunks_mut(BOUNCE_BUFFER_SIZE).enumerate() {
+            let lba = lba + (i * BOUNCE_BUFFER_SIZE / SECTOR_SIZE) as u64;
+            request.submit(VIRTIO_BLK_T_IN, lba, chunk.len())?;
+            chunk.copy_from_slice(&request.bounce.as_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_request(lba, buf.len())?;
+        let mut request = self.request.lock();
+        for (i, chunk) in buf.chunks(BOUNCE_BUFFER_SIZE).enumerate() {
+            let lba = lba + (i * BOUNCE_BUFFER_SIZE / SECTOR_SIZE) as u64;
+            request.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            request.submit(VIRTIO_BLK_T_OUT, lba, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.flush || self.read_only {
+            return Ok(());
+        }
+        self.request.lock().submit(VIRTIO_BLK_T_FLUSH, 0, 0)
+    }
+}
@@ -0,0 +1,209 @@
+// virtio-net
+//
+// queue 0が受信、queue 1が送信。どちらのバッファもvirtio_net_hdrの後ろにEthernetフレームが続く。
+// 受信用のバッファは予め全部渡しておき、取り出したらまた渡し直す
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use x86_64::PhysAddr;
+
+use super::{Buffer, Mmio, VirtioPci, Virtqueue, VIRTIO_VENDOR_ID};
+use crate::dma::DmaBuffer;
+use crate::error::OsError;
+use crate::net::{self, MacAddress, NetError, NetworkDevice};
+use crate::pci::{BusDeviceFunction, PciDevice, PciDeviceDriver, PciDeviceDriverInstance};
+
+const SUPPORTED_DEVICES: &[u16] = &[
+    0x1000, // transitional
+    0x1041,
+];
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+// device configuration
+const CONFIG_MAC: usize = 0x00;
+const CONFIG_STATUS: usize = 0x06;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+// VIRTIO_F_VERSION_1ではnum_buffersまで含めて12バイト
+const NET_HEADER_SIZE: usize = 12;
+const BUFFER_SIZE: usize = 2048;
+const MAX_FRAME_SIZE: usize = BUFFER_SIZE - NET_HEADER_SIZE;
+
+pub struct VirtioNetDriver;
+
+impl PciDeviceDriver for VirtioNetDriver {
+    fn supports(&self, device: &PciDevice) -> bool {
+        device.vd.vendor == VIRTIO_VENDOR_ID && SUPPORTED_DEVICES.contains(&device.vd.device)
+    }
+
+    fn attach(&self, bdf: BusDeviceFunction) -> Result<Box<dyn PciDeviceDriverInstance>, OsError> {
+        let transport = VirtioPci::new(bdf)?;
+        let features = transport.negotiate(VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS)?;
+        if features & VIRTIO_NET_F_MAC == 0 {
+            return Err(OsError::NotSupported);
+        }
+        let rx = Rx::new(transport.setup_queue(RX_QUEUE)?)?;
+        let tx = Tx::new(transport.setup_queue(TX_QUEUE)?)?;
+        let config = transport.device_config();
+        let mut mac = [0u8; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = config.read(CONFIG_MAC + i);
+        }
+        transport.driver_ok();
+        rx.queue.notify();
+
+        let device = Arc::new(VirtioNet {
+            name: net::next_name(),
+            mac,
+            config,
+            has_status: features & VIRTIO_NET_F_STATUS != 0,
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+        });
+        net::register(device.clone());
+        Ok(Box::new(VirtioNetInstance { bdf, _device: device }))
+    }
+
+    fn name(&self) -> &str {
+        "virtio-net"
+    }
+}
+
+pub struct VirtioNetInstance {
+    bdf: BusDeviceFunction,
+    _device: Arc<VirtioNet>,
+}
+
+impl PciDeviceDriverInstance for VirtioNetInstance {
+    fn name(&self) -> &str {
+        "virtio-net"
+    }
+
+    fn bdf(&self) -> BusDeviceFunction {
+        self.bdf
+    }
+}
+
+pub struct VirtioNet {
+    name: String,
+    mac: MacAddress,
+    config: Mmio,
+    has_status: bool,
+    rx: Mutex<Rx>,
+    tx: Mutex<Tx>,
+}
+
+// 記述子1つにバッファ1つを割り当てるので、記述子の番号でバッファを引ける
+struct Rx {
+    queue: Virtqueue,
+    buffers: DmaBuffer,
+}
+
+impl Rx {
+    fn new(mut queue: Virtqueue) -> Result<Self, OsError> {
+        let buffers = DmaBuffer::new(queue.size() as usize * BUFFER_SIZE)?;
+        for i in 0..queue.size() as usize {
+            let addr = PhysAddr::new(buffers.phys_addr().as_u64() + (i * BUFFER_SIZE) as u64);
+            // 記述子は番号の小さい順に取られるので、i番目のバッファはi番の記述子に入る
+            let id = queue.push(&[Buffer { addr, len: BUFFER_SIZE as u32, writable: true }]);
+            debug_assert_eq!(id, Some(i as u16));
+        }
+        Ok(Self { queue, buffers })
+    }
+
+    fn buffer_addr(&self, id: u16) -> PhysAddr {
+        PhysAddr::new(self.buffers.phys_addr().as_u64() + (id as usize * BUFFER_SIZE) as u64)
+    }
+}
+
+struct Tx {
+    queue: Virtqueue,
+    buffers: DmaBuffer,
+    // 記述子の番号ごとに、送信中のバッファ
+    in_flight: Vec<Option<usize>>,
+    free: Vec<usize>,
+}
+
+impl Tx {
+    fn new(queue: Virtqueue) -> Result<Self, OsError> {
+        let size = queue.size() as usize;
+        Ok(Self {
+            buffers: DmaBuffer::new(size * BUFFER_SIZE)?,
+            in_flight: vec![None; size],
+            free: (0..size).rev().collect(),
+            queue,
+        })
+    }
+
+    // 送信が終わったバッファを回収する
+    fn reclaim(&mut self) {
+        while let Some((id, _)) = self.queue.pop_used() {
+            if let Some(slot) = self.in_flight[id as usize].take() {
+                self.free.push(slot);
+            }
+        }
+    }
+}
+
+impl NetworkDevice for VirtioNet {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        !self.has_status || self.config.read::<u16>(CONFIG_STATUS) & VIRTIO_NET_S_LINK_UP != 0
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::FrameTooLarge);
+        }
+        if !self.link_up() {
+            return Err(NetError::LinkDown);
+        }
+        let mut tx = self.tx.lock();
+        tx.reclaim();
+        let slot = tx.free.pop().ok_or(NetError::Busy)?;
+        let offset = slot * BUFFER_SIZE;
+        let buffer = &mut tx.buffers.as_mut_slice()[offset..offset + BUFFER_SIZE];
+        buffer[..NET_HEADER_SIZE].fill(0);
+        buffer[NET_HEADER_SIZE..NET_HEADER_SIZE + frame.len()].copy_from_slice(frame);
+
+        let addr = PhysAddr::new(tx.buffers.phys_addr().as_u64() + offset as u64);
+        let len = (NET_HEADER_SIZE + frame.len()) as u32;
+        let Some(id) = tx.queue.push(&[Buffer { addr, len, writable: false }]) else {
+            tx.free.push(slot);
+            return Err(NetError::Busy);
+        };
+        tx.in_flight[id as usize] = Some(slot);
+        tx.queue.notify();
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut rx = self.rx.lock();
+        let (id, len) = rx.queue.pop_used()?;
+        let offset = id as usize * BUFFER_SIZE;
+        let len = (len as usize).clamp(NET_HEADER_SIZE, BUFFER_SIZE);
+        let frame = rx.buffers.as_slice()[offset + NET_HEADER_SIZE..offset + len].to_vec();
+
+        // 同じバッファをまた受信に使う
+        let addr = rx.buffer_addr(id);
+        rx.queue.push(&[Buffer { addr, len: BUFFER_SIZE as u32, writable: true }]);
+        rx.queue.notify();
+        Some(frame)
+    }
+}
@@ -0,0 +1,451 @@
+// Virtual Memory Area
+//
+// アドレス空間をVmaの集合として管理し、ページは最初に触れられたときに#PFハンドラで用意する
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+use crate::memory_manager::{frame_manager, Frame};
+use crate::paging;
+
+// PTEのOS用ビット。共有されていて、書き込み時にコピーが必要なページに立てる
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Permissions {
+    pub write: bool,
+    pub execute: bool,
+    pub user: bool,
+}
+
+impl Permissions {
+    pub const KERNEL_DATA: Self = Self {
+        write: true,
+        execute: false,
+        user: false,
+    };
+
+    fn page_table_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.write {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.execute {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        if self.user {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        flags
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Backing {
+    // 初めて触れられたときにゼロ埋めしたフレームを割り当てる
+    Anonymous,
+    // 決してマップしない。触れられたらすぐ上のスタックが溢れたということ
+    Guard { task: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct Vma {
+    pub range: Range<VirtAddr>,
+    pub permissions: Permissions,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn new(range: Range<VirtAddr>, permissions: Permissions, backing: Backing) -> Self {
+        Self {
+            range,
+            permissions,
+            backing,
+        }
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.range.contains(&addr)
+    }
+
+    fn overlaps(&self, range: &Range<VirtAddr>) -> bool {
+        self.range.start < range.end && range.start < self.range.end
+    }
+
+    fn pages(&self) -> PageRange {
+        pages(&self.range)
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    Unaligned,
+    Overlap,
+    OutOfMemory,
+}
+
+#[derive(Debug)]
+pub enum PageFaultError {
+    NotMapped(VirtAddr),
+    AccessViolation(VirtAddr),
+    StackOverflow { task: String },
+    AddressSpaceBusy,
+    OutOfMemory,
+}
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PageFaultError::NotMapped(addr) => write!(f, "no memory area at {:?}", addr),
+            PageFaultError::AccessViolation(addr) => write!(f, "access violation at {:?}", addr),
+            PageFaultError::StackOverflow { task } => write!(f, "stack overflow in task {}", task),
+            PageFaultError::AddressSpaceBusy => write!(f, "page fault while the address space is locked"),
+            PageFaultError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    vmas: Vec<Vma>, // sorted by range.start
+}
+
+impl AddressSpace {
+    pub fn new(pml4: PhysFrame) -> Self {
+        Self {
+            pml4,
+            vmas: Vec::new(),
+        }
+    }
+
+    pub fn pml4(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas
+    }
+
+    fn page_table(&self) -> OffsetPageTable<'static> {
+        unsafe { paging::page_table(self.pml4) }
+    }
+
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.vmas.iter().find(|vma| vma.contains(addr))
+    }
+
+    // within内でどのVmaとも重ならない、大きさlenの最初の領域を探す
+    pub fn find_free(&self, within: Range<VirtAddr>, len: u64) -> Option<VirtAddr> {
+        let mut start = within.start;
+        for vma in self.vmas.iter().filter(|vma| vma.overlaps(&within)) {
+            if vma.range.start >= start && vma.range.start - start >= len {
+                break;
+            }
+            start = start.max(vma.range.end);
+        }
+        (start < within.end && within.end - start >= len).then_some(start)
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if !is_page_aligned(&vma.range) {
+            return Err(VmaError::Unaligned);
+        }
+        if self.vmas.iter().any(|v| v.overlaps(&vma.range)) {
+            return Err(VmaError::Overlap);
+        }
+        let index = self
+            .vmas
+            .iter()
+            .position(|v| v.range.start > vma.range.start)
+            .unwrap_or(self.vmas.len());
+        self.vmas.insert(index, vma);
+        Ok(())
+    }
+
+    // rangeに掛かるVmaを取り除き、マップ済みのフレームを解放する。はみ出した部分は残す
+    pub fn remove(&mut self, range: Range<VirtAddr>) -> Result<(), VmaError> {
+        if !is_page_aligned(&range) {
+            return Err(VmaError::Unaligned);
+        }
+
+        let mut vmas = Vec::with_capacity(self.vmas.len() + 1);
+        for vma in self.vmas.drain(..) {
+            if !vma.overlaps(&range) {
+                vmas.push(vma);
+                continue;
+            }
+            if vma.range.start < range.start {
+                vmas.push(Vma::new(vma.range.start..range.start, vma.permissions, vma.backing.clone()));
+            }
+            if range.end < vma.range.end {
+                vmas.push(Vma::new(range.end..vma.range.end, vma.permissions, vma.backing.clone()));
+            }
+        }
+        self.vmas = vmas;
+
+        let mut page_table = self.page_table();
+        for page in pages(&range) {
+            if let Ok((frame, flush)) = page_table.unmap(page) {
+                flush.flush();
+                release_frame(Frame::from_phys_addr(frame.start_address()));
+            }
+        }
+        Ok(())
+    }
+
+    // 遅延させずにrangeのページをすべて用意する
+    pub fn populate(&mut self, range: Range<VirtAddr>) -> Result<(), PageFaultError> {
+        for page in pages(&range) {
+            let vma = self
+                .find(page.start_address())
+                .ok_or(PageFaultError::NotMapped(page.start_address()))?;
+            if let Backing::Guard { task } = &vma.backing {
+                return Err(PageFaultError::StackOverflow { task: task.clone() });
+            }
+            if self.translate(page.start_address()).is_none() {
+                self.map_zeroed(page, vma.permissions)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysFrame, PageTableFlags)> {
+        match self.page_table().translate(addr) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => Some((frame, flags)),
+            _ => None,
+        }
+    }
+
+    // ページテーブルを切り替えずに、このアドレス空間のaddrへdataを書き込む。
+    // 書き込み先のページは用意済みであること。読み込み専用のページにも書ける
+    pub fn write_bytes(&self, addr: VirtAddr, data: &[u8]) -> Result<(), PageFaultError> {
+        let mut written = 0;
+        while written < data.len() {
+            let dest = addr + written as u64;
+            let (frame, _) = self.translate(dest).ok_or(PageFaultError::NotMapped(dest))?;
+            let offset = (dest.as_u64() % Size4KiB::SIZE) as usize;
+            let len = (Frame::SIZE - offset).min(data.len() - written);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    frame_ptr(frame).add(offset),
+                    len,
+                );
+            }
+            written += len;
+        }
+        Ok(())
+    }
+
+    // rangeにあるVmaとページをtargetの同じアドレスへ共有する。以降どちらかが書き込むとコピーされる
+    pub fn share_copy_on_write(
+        &mut self,
+        range: Range<VirtAddr>,
+        target: &mut AddressSpace,
+    ) -> Result<(), VmaError> {
+        if !is_page_aligned(&range) {
+            return Err(VmaError::Unaligned);
+        }
+
+        let vmas: Vec<Vma> = self
+            .vmas
+            .iter()
+            .filter(|vma| vma.overlaps(&range))
+            .map(|vma| {
+                let start = vma.range.start.max(range.start);
+                let end = vma.range.end.min(range.end);
+                Vma::new(start..end, vma.permissions, vma.backing.clone())
+            })
+            .collect();
+        for vma in vmas {
+            for page in vma.pages() {
+                let Some((frame, flags)) = self.translate(page.start_address()) else {
+                    continue;
+                };
+                let shared_flags = (flags & !PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                unsafe {
+                    self.page_table().update_flags(page, shared_flags).unwrap().flush();
+                }
+                share_frame(Frame::from_phys_addr(frame.start_address()));
+                target.map_frame(page, frame, shared_flags)?;
+            }
+            target.insert(vma)?;
+        }
+        Ok(())
+    }
+
+    pub fn handle_page_fault(
+        &mut self,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> Result<(), PageFaultError> {
+        let vma = self.find(addr).ok_or(PageFaultError::NotMapped(addr))?;
+        if let Backing::Guard { task } = &vma.backing {
+            return Err(PageFaultError::StackOverflow { task: task.clone() });
+        }
+
+        let permissions = vma.permissions;
+        let allowed = (permissions.user || !error_code.contains(PageFaultErrorCode::USER_MODE))
+            && (permissions.write || !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE))
+            && (permissions.execute || !error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH));
+        if !allowed {
+            return Err(PageFaultError::AccessViolation(addr));
+        }
+
+        let page = Page::containing_address(addr);
+        if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return self.map_zeroed(page, permissions);
+        }
+
+        match self.translate(addr) {
+            Some((frame, flags))
+                if flags.contains(COPY_ON_WRITE)
+                    && error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) =>
+            {
+                self.copy_on_write(page, frame, permissions)
+            }
+            _ => Err(PageFaultError::AccessViolation(addr)),
+        }
+    }
+
+    fn map_zeroed(&mut self, page: Page, permissions: Permissions) -> Result<(), PageFaultError> {
+        let frame = frame_manager()
+            .allocate(1)
+            .map_err(|_| PageFaultError::OutOfMemory)?;
+        unsafe {
+            core::ptr::write_bytes(frame_ptr(frame.phys_frame()), 0, Frame::SIZE);
+        }
+        self.map_frame(page, frame.phys_frame(), permissions.page_table_flags())
+            .map_err(|_| PageFaultError::OutOfMemory)
+    }
+
+    fn copy_on_write(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        permissions: Permissions,
+    ) -> Result<(), PageFaultError> {
+        let mut page_table = self.page_table();
+        let flags = permissions.page_table_flags();
+
+        // もう他に共有している相手がいなければ、書き込みを許すだけでよい
+        if !is_shared(Frame::from_phys_addr(frame.start_address())) {
+            unsafe {
+                page_table.update_flags(page, flags).unwrap().flush();
+            }
+            return Ok(());
+        }
+
+        let copy = frame_manager()
+            .allocate(1)
+            .map_err(|_| PageFaultError::OutOfMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame_ptr(frame), frame_ptr(copy.phys_frame()), Frame::SIZE);
+        }
+        page_table.unmap(page).unwrap().1.flush();
+        release_frame(Frame::from_phys_addr(frame.start_address()));
+        self.map_frame(page, copy.phys_frame(), flags)
+            .map_err(|_| PageFaultError::OutOfMemory)
+    }
+
+    fn map_frame(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), VmaError> {
+        let mut page_table = self.page_table();
+        unsafe {
+            page_table
+                .map_to(page, frame, flags, &mut *frame_manager())
+                .map_err(|_| VmaError::OutOfMemory)?
+                .flush();
+        }
+        Ok(())
+    }
+}
+
+fn pages(range: &Range<VirtAddr>) -> PageRange {
+    Page::range(Page::containing_address(range.start), Page::containing_address(range.end))
+}
+
+fn is_page_aligned(range: &Range<VirtAddr>) -> bool {
+    range.start.is_aligned(Size4KiB::SIZE) && range.end.is_aligned(Size4KiB::SIZE) && range.start < range.end
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    paging::as_virt_addr(frame.start_address()).unwrap().as_mut_ptr()
+}
+
+lazy_static! {
+    // 2つ以上のアドレス空間から参照されているフレームの参照数
+    static ref SHARED_FRAMES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+}
+
+fn share_frame(frame: Frame) {
+    let mut shared = SHARED_FRAMES.lock();
+    *shared.entry(frame.frame_id()).or_insert(1) += 1;
+}
+
+fn is_shared(frame: Frame) -> bool {
+    SHARED_FRAMES.lock().contains_key(&frame.frame_id())
+}
+
+fn release_frame(frame: Frame) {
+    let mut shared = SHARED_FRAMES.lock();
+    match shared.get_mut(&frame.frame_id()) {
+        None => {
+            drop(shared);
+            frame_manager().free(frame, 1);
+        }
+        Some(2) => {
+            shared.remove(&frame.frame_id());
+        }
+        Some(count) => *count -= 1,
+    }
+}
+
+lazy_static! {
+    static ref KERNEL_ADDRESS_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+}
+
+pub fn init() {
+    let mut address_space = KERNEL_ADDRESS_SPACE.lock();
+    *address_space = Some(AddressSpace::new(Cr3::read().0));
+}
+
+pub fn kernel_address_space() -> spin::MutexGuard<'static, Option<AddressSpace>> {
+    KERNEL_ADDRESS_SPACE.lock()
+}
+
+// #DFなど#PFを経由しない経路で、addrがどのスタックのガードページか調べる
+pub fn guard_owner(addr: VirtAddr) -> Option<String> {
+    let address_space = KERNEL_ADDRESS_SPACE.try_lock()?;
+    match &address_space.as_ref()?.find(addr)?.backing {
+        Backing::Guard { task } => Some(task.clone()),
+        Backing::Anonymous => None,
+    }
+}
+
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), PageFaultError> {
+    // ロックを持ったまま#PFが起きた場合にデッドロックしないようにする
+    let mut address_space = KERNEL_ADDRESS_SPACE
+        .try_lock()
+        .ok_or(PageFaultError::AddressSpaceBusy)?;
+    address_space
+        .as_mut()
+        .ok_or(PageFaultError::NotMapped(addr))?
+        .handle_page_fault(addr, error_code)
+}
@@ -0,0 +1,17 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use userland::{env, println, syscall};
+
+#[no_mangle]
+fn main() -> i32 {
+    let args: Vec<&str> = env::args().collect();
+    println!("Hello from pid {}!", syscall::getpid());
+    for (i, arg) in args.iter().enumerate() {
+        println!("argv[{}] = {}", i, arg);
+    }
+    0
+}
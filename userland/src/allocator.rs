@@ -0,0 +1,99 @@
+// Heap on top of mmap
+//
+// 2048バイトまでは2のべき乗のサイズごとのフリーリストから切り出す。
+// フリーリストが空なら64KiBをmmapして補充する。それより大きいものは直接mmap/munmapする
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+use spin::Mutex;
+
+use crate::syscall::{self, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+const PAGE_SIZE: usize = 4096;
+const CHUNK_SIZE: usize = 64 * 1024;
+const MIN_BLOCK_SIZE: usize = 16;
+const MAX_BLOCK_SIZE: usize = 2048;
+const SIZE_CLASSES: usize = 8; // 16, 32, ..., 2048
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+struct Heap {
+    free_lists: [*mut FreeBlock; SIZE_CLASSES],
+}
+
+unsafe impl Send for Heap {}
+
+pub struct Allocator {
+    heap: Mutex<Heap>,
+}
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator {
+    heap: Mutex::new(Heap {
+        free_lists: [null_mut(); SIZE_CLASSES],
+    }),
+};
+
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_BLOCK_SIZE);
+    if size > MAX_BLOCK_SIZE {
+        return None;
+    }
+    Some(size.next_power_of_two().trailing_zeros() as usize - MIN_BLOCK_SIZE.trailing_zeros() as usize)
+}
+
+fn map(len: usize) -> *mut u8 {
+    syscall::mmap(0, len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS).unwrap_or(null_mut())
+}
+
+impl Heap {
+    unsafe fn refill(&mut self, class: usize) -> bool {
+        let chunk = map(CHUNK_SIZE);
+        if chunk.is_null() {
+            return false;
+        }
+        let block_size = MIN_BLOCK_SIZE << class;
+        for offset in (0..CHUNK_SIZE).step_by(block_size) {
+            let block = chunk.add(offset) as *mut FreeBlock;
+            (*block).next = self.free_lists[class];
+            self.free_lists[class] = block;
+        }
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(class) = size_class(&layout) else {
+            // mmapはページ境界を返すので、それ以上のalignは扱えない
+            if layout.align() > PAGE_SIZE {
+                return null_mut();
+            }
+            return map(layout.size());
+        };
+
+        let mut heap = self.heap.lock();
+        if heap.free_lists[class].is_null() && !heap.refill(class) {
+            return null_mut();
+        }
+        let block = heap.free_lists[class];
+        heap.free_lists[class] = (*block).next;
+        block as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(&layout) {
+            Some(class) => {
+                let mut heap = self.heap.lock();
+                let block = ptr as *mut FreeBlock;
+                (*block).next = heap.free_lists[class];
+                heap.free_lists[class] = block;
+            }
+            None => {
+                let _ = syscall::munmap(ptr, layout.size());
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+
+pub(crate) fn init(argc: usize, argv: *const *const u8) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv as *mut _, Ordering::Relaxed);
+}
+
+pub struct Args {
+    index: usize,
+}
+
+// spawnに渡した引数。先頭はプログラムのパス
+pub fn args() -> Args {
+    Args { index: 0 }
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= ARGC.load(Ordering::Relaxed) {
+            return None;
+        }
+        let arg = unsafe {
+            let ptr = *ARGV.load(Ordering::Relaxed).add(self.index);
+            let len = (0..).take_while(|&i| *ptr.add(i) != 0).count();
+            core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len))
+        };
+        self.index += 1;
+        Some(arg)
+    }
+}
@@ -0,0 +1,45 @@
+// Runtime for programs running on top of the kernel
+//
+// Programs define `#[no_mangle] fn main() -> i32` and link against this crate, which provides
+// `_start`, the system call wrappers, `print!`/`println!`, a heap and a panic handler.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod allocator;
+pub mod env;
+pub mod io;
+pub mod syscall;
+
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+
+extern "Rust" {
+    fn main() -> i32;
+}
+
+// カーネルはSystem V ABIの形でスタックを作る。rspはargcを指し、16バイト境界に揃っている
+global_asm!(
+    ".global _start",
+    "_start:",
+    "    xor rbp, rbp",
+    "    mov rdi, rsp",
+    "    call {start}",
+    "    ud2",
+    start = sym start,
+);
+
+unsafe extern "C" fn start(stack: *const u64) -> ! {
+    let argc = *stack as usize;
+    let argv = stack.add(1) as *const *const u8;
+    env::init(argc, argv);
+
+    syscall::exit(main())
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    syscall::exit(101)
+}
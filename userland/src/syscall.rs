@@ -0,0 +1,146 @@
+use core::arch::asm;
+pub use common::syscall::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u64);
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+pub unsafe fn syscall6(number: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64, a6: u64) -> u64 {
+    let ret: u64;
+    asm!(
+        "syscall",
+        inlateout("rax") number => ret,
+        in("rdi") a1,
+        in("rsi") a2,
+        in("rdx") a3,
+        in("r10") a4,
+        in("r8") a5,
+        in("r9") a6,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+fn check(ret: u64) -> Result<u64> {
+    if ret > u64::MAX - MAX_ERRNO {
+        Err(Errno(ret.wrapping_neg()))
+    } else {
+        Ok(ret)
+    }
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall6(SYS_EXIT, code as u64, 0, 0, 0, 0, 0) };
+    unreachable!()
+}
+
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize> {
+    check(unsafe { syscall6(SYS_WRITE, fd, buf.as_ptr() as u64, buf.len() as u64, 0, 0, 0) })
+        .map(|n| n as usize)
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize> {
+    check(unsafe { syscall6(SYS_READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0) })
+        .map(|n| n as usize)
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall6(SYS_GETPID, 0, 0, 0, 0, 0, 0) }
+}
+
+pub fn sleep(ms: u64) {
+    unsafe { syscall6(SYS_SLEEP, ms, 0, 0, 0, 0, 0) };
+}
+
+pub fn mmap(addr_hint: u64, len: usize, prot: u64, flags: u64) -> Result<*mut u8> {
+    check(unsafe { syscall6(SYS_MMAP, addr_hint, len as u64, prot, flags, 0, 0) })
+        .map(|addr| addr as *mut u8)
+}
+
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<()> {
+    check(syscall6(SYS_MUNMAP, addr as u64, len as u64, 0, 0, 0, 0)).map(|_| ())
+}
+
+pub fn open(path: &str, flags: u64) -> Result<u64> {
+    check(unsafe { syscall6(SYS_OPEN, path.as_ptr() as u64, path.len() as u64, flags, 0, 0, 0) })
+}
+
+pub fn close(fd: u64) -> Result<()> {
+    check(unsafe { syscall6(SYS_CLOSE, fd, 0, 0, 0, 0, 0) }).map(|_| ())
+}
+
+// argsは'\0'区切りの引数。子プロセスが終わるまで戻らず、終了コードを返す
+pub fn spawn(path: &str, args: &str) -> Result<u8> {
+    check(unsafe {
+        syscall6(
+            SYS_SPAWN,
+            path.as_ptr() as u64,
+            path.len() as u64,
+            args.as_ptr() as u64,
+            args.len() as u64,
+            0,
+            0,
+        )
+    })
+    .map(|code| code as u8)
+}
+
+pub fn seek(fd: u64, offset: i64, whence: u64) -> Result<u64> {
+    check(unsafe { syscall6(SYS_SEEK, fd, offset as u64, whence, 0, 0, 0) })
+}
+
+// ディレクトリの終わりならNone
+pub fn readdir(fd: u64) -> Result<Option<Dirent>> {
+    let mut dirent = Dirent {
+        ino: 0,
+        kind: 0,
+        name_len: 0,
+        name: [0; NAME_MAX + 1],
+    };
+    let ret = check(unsafe { syscall6(SYS_READDIR, fd, &mut dirent as *mut Dirent as u64, 0, 0, 0, 0) })?;
+    Ok(if ret == 0 { None } else { Some(dirent) })
+}
+
+pub fn stat(path: &str) -> Result<Stat> {
+    let mut stat = Stat::default();
+    check(unsafe {
+        syscall6(SYS_STAT, path.as_ptr() as u64, path.len() as u64, &mut stat as *mut Stat as u64, 0, 0, 0)
+    })?;
+    Ok(stat)
+}
+
+pub fn fstat(fd: u64) -> Result<Stat> {
+    let mut stat = Stat::default();
+    check(unsafe { syscall6(SYS_FSTAT, fd, &mut stat as *mut Stat as u64, 0, 0, 0, 0) })?;
+    Ok(stat)
+}
+
+pub fn chdir(path: &str) -> Result<()> {
+    check(unsafe { syscall6(SYS_CHDIR, path.as_ptr() as u64, path.len() as u64, 0, 0, 0, 0) }).map(|_| ())
+}
+
+pub fn mkdir(path: &str) -> Result<()> {
+    check(unsafe { syscall6(SYS_MKDIR, path.as_ptr() as u64, path.len() as u64, 0, 0, 0, 0) }).map(|_| ())
+}
+
+pub fn unlink(path: &str) -> Result<()> {
+    check(unsafe { syscall6(SYS_UNLINK, path.as_ptr() as u64, path.len() as u64, 0, 0, 0, 0) }).map(|_| ())
+}
+
+pub fn rename(old_path: &str, new_path: &str) -> Result<()> {
+    check(unsafe {
+        syscall6(
+            SYS_RENAME,
+            old_path.as_ptr() as u64,
+            old_path.len() as u64,
+            new_path.as_ptr() as u64,
+            new_path.len() as u64,
+            0,
+            0,
+        )
+    })
+    .map(|_| ())
+}